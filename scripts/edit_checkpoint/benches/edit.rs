@@ -0,0 +1,404 @@
+//! Benchmarks of the edit pass on generated checkpoints: the tar loop (from a
+//! stream, and file to file with the passthrough copy), the JSON patchers and
+//! the zstd and address codecs.
+//!
+//!     cargo bench --bench edit [-- FILTER] 2>/dev/null
+//!
+//! crit is stubbed with a script that passes the (JSON) images through,
+//! behind the v2 header images start with, so
+//! the tar loop numbers are the loop's own plus two process spawns, not
+//! crit's. The edit logs to stderr as it goes, hence the redirect.
+//!
+//! The binary has no library target, so the modules the edit pass is made of
+//! are compiled into the bench directly.
+
+// unused_imports: the modules' unit tests are left out without a test
+// harness, and the imports they use with them
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/action.rs"]
+mod action;
+#[path = "../src/applied.rs"]
+mod applied;
+#[path = "../src/arch.rs"]
+mod arch;
+#[path = "../src/audit.rs"]
+mod audit;
+#[path = "../src/buffers.rs"]
+mod buffers;
+#[path = "../src/cgroup.rs"]
+mod cgroup;
+#[path = "../src/compress.rs"]
+mod compress;
+#[path = "../src/crit.rs"]
+mod crit;
+#[path = "../src/edit.rs"]
+mod edit;
+#[path = "../src/events.rs"]
+mod events;
+#[path = "../src/filelocks.rs"]
+mod filelocks;
+#[path = "../src/ghosts.rs"]
+mod ghosts;
+#[path = "../src/images.rs"]
+mod images;
+#[path = "../src/inaddr.rs"]
+mod inaddr;
+#[path = "../src/integrity.rs"]
+mod integrity;
+#[path = "../src/interrupt.rs"]
+mod interrupt;
+#[path = "../src/inventory.rs"]
+mod inventory;
+#[path = "../src/metadata.rs"]
+mod metadata;
+#[path = "../src/nat64.rs"]
+mod nat64;
+#[path = "../src/policy.rs"]
+mod policy;
+#[path = "../src/ports.rs"]
+mod ports;
+#[path = "../src/proto.rs"]
+mod proto;
+#[path = "../src/redact.rs"]
+mod redact;
+#[path = "../src/remote.rs"]
+mod remote;
+#[path = "../src/report.rs"]
+mod report;
+#[path = "../src/resources.rs"]
+mod resources;
+#[path = "../src/resume.rs"]
+mod resume;
+#[path = "../src/rootfs.rs"]
+mod rootfs;
+#[path = "../src/secrets.rs"]
+mod secrets;
+#[path = "../src/security.rs"]
+mod security;
+#[path = "../src/sign.rs"]
+mod sign;
+#[path = "../src/sockets.rs"]
+mod sockets;
+#[path = "../src/spec.rs"]
+mod spec;
+#[path = "../src/summary.rs"]
+mod summary;
+#[path = "../src/timeline.rs"]
+mod timeline;
+#[path = "../src/timens.rs"]
+mod timens;
+#[path = "../src/tmpfs.rs"]
+mod tmpfs;
+#[path = "../src/trace.rs"]
+mod trace;
+#[path = "../src/userns.rs"]
+mod userns;
+
+use std::env;
+use std::fs;
+use std::hint::black_box;
+use std::io::{BufWriter, Cursor, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use serde_json::json;
+
+use edit::EditOptions;
+use metadata::AddrPatch;
+use report::Report;
+use timeline::Timeline;
+
+const OLD_ADDR: &str = "192.168.12.2";
+const NEW_ADDR: &str = "192.168.13.7";
+const STUB_CRIT: &str = "#!/bin/sh\n\
+    cmd=$1; shift\n\
+    while [ $# -gt 0 ]; do case $1 in -i) in=$2; shift;; esac; shift; done\n\
+    case $cmd in decode) tail -c +9 \"$in\";; \
+        encode) printf '\\031\\103\\126\\124\\070\\061\\060\\126'; cat \"$in\";; \
+        *) exit 1;; esac\n";
+
+/// Put the stub crit first on PATH for the rest of the run.
+fn stub_crit() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let crit = dir.path().join("crit");
+    fs::write(&crit, STUB_CRIT).expect("write crit stub");
+    fs::set_permissions(&crit, fs::Permissions::from_mode(0o755)).expect("chmod crit stub");
+    let path = env::var("PATH").unwrap_or_default();
+    env::set_var("PATH", format!("{}:{}", dir.path().display(), path));
+    dir
+}
+
+/// IMG_COMMON_MAGIC and FILES_MAGIC, little endian.
+const FILES_HEADER: [u8; 8] = [0x19, 0x43, 0x56, 0x54, 0x38, 0x31, 0x30, 0x56];
+
+/// files.img as the stub crit has it, with `sockets` TCP sockets on
+/// OLD_ADDR.
+fn files_img(sockets: usize) -> Vec<u8> {
+    let entries: Vec<_> = (0..sockets)
+        .map(|i| {
+            json!({
+                "type": "INETSK",
+                "id": i + 1,
+                "isk": {
+                    "id": i + 100,
+                    "ino": i + 1000,
+                    "family": "INET",
+                    "type": "STREAM",
+                    "proto": "TCP",
+                    "state": if i == 0 { "LISTEN" } else { "ESTABLISHED" },
+                    "src_port": 8080,
+                    "dst_port": if i == 0 { 0 } else { 40000 + i },
+                    "src_addr": [OLD_ADDR],
+                    "dst_addr": [if i == 0 { "0.0.0.0" } else { "192.168.12.100" }],
+                },
+            })
+        })
+        .collect();
+    let json = serde_json::to_vec(&json!({"magic": "FILES", "entries": entries})).unwrap();
+    [&FILES_HEADER[..], &json].concat()
+}
+
+fn network_status() -> Vec<u8> {
+    serde_json::to_vec(&json!([{
+        "interfaces": [{"name": "eth0", "mac": "02:42:c0:a8:0c:02"}],
+        "ips": [{
+            "version": "4",
+            "interface": 0,
+            "address": format!("{}/24", OLD_ADDR),
+            "gateway": "192.168.12.1",
+        }],
+        "dns": {"nameservers": ["192.168.12.1"]},
+    }]))
+    .unwrap()
+}
+
+fn spec() -> serde_json::Value {
+    let caps = [
+        "CAP_CHOWN",
+        "CAP_KILL",
+        "CAP_NET_BIND_SERVICE",
+        "CAP_SETUID",
+    ];
+    json!({
+        "ociVersion": "1.0.2",
+        "process": {
+            "args": ["/server"],
+            "capabilities": {
+                "bounding": caps,
+                "effective": caps,
+                "inheritable": caps,
+                "permitted": caps,
+            },
+        },
+        "mounts": [{
+            "destination": "/run/secrets/token",
+            "type": "bind",
+            "source": "/var/lib/containers/storage/secrets/filedriver/secretsdata/abc",
+            "options": ["rbind", "ro"],
+        }],
+        "linux": {
+            "cgroupsPath": "machine.slice:libpod:abc",
+            "namespaces": [{"type": "network"}, {"type": "pid"}, {"type": "mount"}],
+        },
+    })
+}
+
+fn config_dump() -> Vec<u8> {
+    serde_json::to_vec(&json!({
+        "id": "abc",
+        "name": "server",
+        "rootfsImageName": "localhost/server:latest",
+        "rootfsImageID": "1111",
+        "staticIP": OLD_ADDR,
+        "staticMAC": "02:42:c0:a8:0c:02",
+        "cgroupParent": "machine.slice",
+        "secretsPath": "/var/lib/containers/storage/secrets",
+        "createCommand": ["podman", "run", "-d", "--ip", OLD_ADDR, "localhost/server:latest"],
+        "spec": spec(),
+    }))
+    .unwrap()
+}
+
+fn spec_dump() -> Vec<u8> {
+    serde_json::to_vec(&spec()).unwrap()
+}
+
+/// A checkpoint archive with `pages` bytes of memory pages, half of them
+/// zero as in a typical dump.
+fn checkpoint(pages: usize) -> Vec<u8> {
+    let mut memory = vec![0u8; pages];
+    let mut x = 0x2545_f491_4f6c_dd1du64;
+    for chunk in memory[pages / 2..].chunks_mut(8) {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        chunk.copy_from_slice(&x.to_ne_bytes()[..chunk.len()]);
+    }
+    let entries = [
+        ("config.dump", config_dump()),
+        ("spec.dump", spec_dump()),
+        ("network.status", network_status()),
+        (
+            "checkpoint/inventory.img",
+            b"{\"magic\":\"INVENTORY\"}".to_vec(),
+        ),
+        ("checkpoint/files.img", files_img(64)),
+        ("checkpoint/core-1.img", b"{\"magic\":\"CORE\"}".to_vec()),
+        ("checkpoint/pages-1.img", memory),
+    ];
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, content) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o600);
+        builder
+            .append_data(&mut header, path, content.as_slice())
+            .unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+fn map(old: &str, new: &str) -> Vec<(String, String)> {
+    vec![(old.to_string(), new.to_string())]
+}
+
+fn tar_loop(c: &mut Criterion) {
+    let _crit = stub_crit();
+    let opts = EditOptions::default();
+    let net = opts.network_patch(OLD_ADDR.parse().unwrap(), 24, AddrPatch::Replace(NEW_ADDR));
+    let dir = tempfile::tempdir().expect("temp dir");
+    let mut group = c.benchmark_group("tar_loop");
+    group.sample_size(10);
+    for mb in [1, 64] {
+        let archive = checkpoint(mb << 20);
+        let input = dir.path().join(format!("{}M.tar", mb));
+        let output = dir.path().join(format!("{}M.tar.new", mb));
+        fs::write(&input, &archive).unwrap();
+        group.throughput(Throughput::Bytes(archive.len() as u64));
+        group.bench_with_input(BenchmarkId::new("stream", mb), &archive, |b, archive| {
+            b.iter(|| {
+                edit::stream(
+                    Cursor::new(archive),
+                    Vec::with_capacity(archive.len() + 64 * 1024),
+                    &net,
+                    &opts,
+                    &mut Timeline::default(),
+                    &mut Report::default(),
+                )
+                .unwrap()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("stream_file", mb), &input, |b, input| {
+            b.iter(|| {
+                let out = fs::File::create(&output).unwrap();
+                edit::stream_file(
+                    input,
+                    buffers::DEFAULT_SIZE,
+                    BufWriter::with_capacity(buffers::DEFAULT_SIZE, out),
+                    None,
+                    &net,
+                    &opts,
+                    &mut Timeline::default(),
+                    &mut Report::default(),
+                )
+                .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn json_patchers(c: &mut Criterion) {
+    let opts = EditOptions::default();
+    let net = opts.network_patch(OLD_ADDR.parse().unwrap(), 24, AddrPatch::Replace(NEW_ADDR));
+    let status = network_status();
+    let config = config_dump();
+    let spec = spec_dump();
+    let security = security::SecurityPatch {
+        cap_drop: vec!["CAP_KILL".to_string()],
+        cap_add: vec!["CAP_NET_ADMIN".to_string()],
+        ..Default::default()
+    };
+    let cgroups = map("machine.slice", "tenant-a.slice");
+    let secrets = map(
+        "/var/lib/containers/storage/secrets",
+        "/srv/containers/secrets",
+    );
+    let images = map("localhost/server:latest", "mirror.local/server:1");
+
+    let mut group = c.benchmark_group("json");
+    group.bench_function("network_status", |b| {
+        b.iter(|| metadata::patch_network_status(black_box(&status), &net).unwrap())
+    });
+    group.bench_function("config_dump", |b| {
+        b.iter(|| metadata::patch_config_dump(black_box(&config), &net).unwrap())
+    });
+    group.bench_function("config_dump_cgroups", |b| {
+        b.iter(|| cgroup::patch_config_dump(black_box(&config), &cgroups).unwrap())
+    });
+    group.bench_function("config_dump_secrets", |b| {
+        b.iter(|| secrets::patch_config_dump(black_box(&config), &secrets).unwrap())
+    });
+    group.bench_function("config_dump_image", |b| {
+        b.iter(|| {
+            rootfs::patch_config_dump(black_box(&config), &images, &mut Report::default()).unwrap()
+        })
+    });
+    group.bench_function("spec_dump_capabilities", |b| {
+        b.iter(|| security::patch_spec_dump(black_box(&spec), &security).unwrap())
+    });
+    group.finish();
+}
+
+fn codecs(c: &mut Criterion) {
+    let pages = checkpoint(4 << 20);
+    let zstd = compress::OutputCompression {
+        kind: compress::Compression::Zstd,
+        level: 3,
+        workers: 1,
+    };
+    let mut writer = compress::Writer::new(Vec::new(), &zstd).unwrap();
+    writer.write_all(&pages).unwrap();
+    let compressed = writer.finish().unwrap();
+
+    let mut group = c.benchmark_group("zstd");
+    group.throughput(Throughput::Bytes(pages.len() as u64));
+    group.sample_size(20);
+    group.bench_function("compress", |b| {
+        b.iter(|| {
+            let mut writer = compress::Writer::new(Vec::new(), &zstd).unwrap();
+            writer.write_all(black_box(&pages)).unwrap();
+            writer.finish().unwrap()
+        })
+    });
+    group.bench_function("decompress", |b| {
+        b.iter_batched(
+            || compressed.clone(),
+            |compressed| {
+                let mut out = Vec::with_capacity(pages.len());
+                compress::reader(Cursor::new(compressed))
+                    .unwrap()
+                    .read_to_end(&mut out)
+                    .unwrap();
+                out
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+
+    let v4 = json!([0x020c_a8c0u32]);
+    let v6 = json!([0xb80d_0120u32, 0, 0, 0x0100_0000u32]);
+    let mut group = c.benchmark_group("addr");
+    group.bench_function("v4", |b| {
+        b.iter(|| sockets::addr(Some(black_box(&v4)), false))
+    });
+    group.bench_function("v6", |b| {
+        b.iter(|| sockets::addr(Some(black_box(&v6)), true))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, tar_loop, json_patchers, codecs);
+criterion_main!(benches);
@@ -0,0 +1,120 @@
+//! CRIU action scripts injected into the checkpoint, for network fixes on the
+//! target that have to wait for the restore.
+//!
+//! The scripts travel next to spec.dump like criu-restore.conf, which gets an
+//! `action-script` line for each, so CRIU on the target runs them at every
+//! restore stage with CRTOOLS_SCRIPT_ACTION and CRTOOLS_INIT_PID set. The
+//! generated one acts at network-unlock, when the restored stack is about to
+//! send and receive again: it adds new_addr to the interface if it is
+//! missing (as for --add-addr, which netavark does not configure), adds the
+//! --restore-route routes and announces new_addr with gratuitous ARP, all in
+//! the container's network namespace. --action-script adds one of your own.
+
+use std::fs;
+use std::path::Path;
+
+use crate::metadata::{AddrPatch, NetworkPatch};
+use crate::remote::shell_quote;
+
+pub const ACTION_SCRIPT_PATH: &str = "criu-action.sh";
+pub const USER_SCRIPT_PATH: &str = "criu-action-user.sh";
+
+/// The action scripts of one edit.
+#[derive(Clone, Default)]
+pub struct ActionPatch {
+    /// Generate the network fix-up script.
+    pub fixup: bool,
+    /// Routes for the fix-up script to add, as `ip route` takes them.
+    pub routes: Vec<String>,
+    /// A script of the user's own.
+    pub script: Option<Script>,
+}
+
+/// The content of an --action-script.
+#[derive(Clone, Debug)]
+pub struct Script(Vec<u8>);
+
+pub fn parse_script(path: &str) -> Result<Script, String> {
+    let content = fs::read(path).map_err(|e| format!("read {}: {}", path, e))?;
+    if !content.starts_with(b"#!") {
+        return Err(format!("{} does not start with #!", path));
+    }
+    Ok(Script(content))
+}
+
+pub fn is_action_script(path: &str) -> bool {
+    path == ACTION_SCRIPT_PATH || path == USER_SCRIPT_PATH
+}
+
+impl ActionPatch {
+    pub fn is_empty(&self) -> bool {
+        !self.fixup && self.routes.is_empty() && self.script.is_none()
+    }
+
+    fn fixup_script(&self, net: &NetworkPatch) -> Vec<u8> {
+        let mut script = String::from(
+            "#!/bin/sh\n\
+             # Network fixes after a migration, written by edit_checkpoint\n\
+             [ \"$CRTOOLS_SCRIPT_ACTION\" = network-unlock ] || exit 0\n\
+             in_ns() { nsenter -t \"$CRTOOLS_INIT_PID\" -n \"$@\"; }\n",
+        );
+        let new_addr = match net.addr {
+            AddrPatch::Replace(addr) | AddrPatch::Add(addr) => Some(addr),
+            AddrPatch::Clear => None,
+        };
+        if let Some(addr) = new_addr.filter(|_| self.fixup) {
+            script += &format!(
+                "ADDR={}\n\
+                 IFACE=$(in_ns ip -o -4 addr show | awk -v a=\"$ADDR/\" -v o={}/ \\\n    \
+                 'index($4, a) == 1 || index($4, o) == 1 {{print $2; exit}}')\n\
+                 IFACE=${{IFACE:-eth0}}\n\
+                 if ! in_ns ip -o -4 addr show dev \"$IFACE\" | grep -q \" $ADDR/\"; then\n    \
+                 PREFIX=$(in_ns ip -o -4 addr show dev \"$IFACE\" | awk '{{split($4, a, \"/\"); print a[2]; exit}}')\n    \
+                 in_ns ip addr add \"$ADDR/${{PREFIX:-24}}\" dev \"$IFACE\"\n\
+                 fi\n",
+                shell_quote(addr),
+                shell_quote(&net.old_addr.to_string())
+            );
+        }
+        for route in &self.routes {
+            let words: Vec<String> = route.split_whitespace().map(shell_quote).collect();
+            script += &format!("in_ns ip route replace {}\n", words.join(" "));
+        }
+        if self.fixup && new_addr.is_some() {
+            script += "in_ns arping -U -c 3 -I \"$IFACE\" \"$ADDR\" >/dev/null 2>&1 &\n";
+        }
+        script += "exit 0\n";
+        script.into_bytes()
+    }
+
+    /// The scripts to add to the checkpoint, by path.
+    pub fn scripts(&self, net: &NetworkPatch) -> Vec<(&'static str, Vec<u8>)> {
+        let mut scripts = Vec::new();
+        if self.fixup || !self.routes.is_empty() {
+            scripts.push((ACTION_SCRIPT_PATH, self.fixup_script(net)));
+        }
+        if let Some(script) = &self.script {
+            scripts.push((USER_SCRIPT_PATH, script.0.clone()));
+        }
+        scripts
+    }
+
+    /// criu-restore.conf options running the scripts from `userdata`, where
+    /// podman unpacks them on the target.
+    pub fn criu_opts(&self, userdata: &Path) -> Vec<String> {
+        let mut opts = Vec::new();
+        if self.fixup || !self.routes.is_empty() {
+            opts.push(format!(
+                "action-script={}",
+                userdata.join(ACTION_SCRIPT_PATH).display()
+            ));
+        }
+        if self.script.is_some() {
+            opts.push(format!(
+                "action-script={}",
+                userdata.join(USER_SCRIPT_PATH).display()
+            ));
+        }
+        opts
+    }
+}
@@ -0,0 +1,512 @@
+//! `anonymize`: write a copy of a checkpoint archive that can be shared with
+//! upstream CRIU and podman developers without giving away the lab it was
+//! dumped in.
+//!
+//! Every string of the metadata entries (config.dump, spec.dump,
+//! network.status, the audit log, the CRIU config and action scripts, ...)
+//! is scrubbed, as are the socket addresses of files.img and the host and
+//! domain names of the utsns images:
+//!
+//! - IP addresses get pseudonyms, consistently across the archive. An IPv4
+//!   address keeps its host part and its /24 becomes one of 10.0.0.0/8, an
+//!   IPv6 one becomes an address in fd00:0:0:K::/64 for its /64, so
+//!   addresses in the same subnet stay in one. Unspecified, loopback,
+//!   multicast and broadcast addresses and netmasks are kept.
+//! - MAC addresses become locally administered ones (02:00:00:00:..).
+//! - The container, host, domain, pod and namespace names and the user and
+//!   host of the audit log are replaced wherever they occur as a whole word.
+//! - The values of environment variables (the spec's process.env and the
+//!   -e/--env arguments of createCommand) are redacted, but for a few
+//!   harmless ones like PATH.
+//!
+//! The signature and the restore index no longer hold and are left out.
+//! Memory pages and file contents (rootfs-diff.tar, devshm-checkpoint.tar)
+//! are kept as dumped.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::audit::AUDIT_PATH;
+use crate::compress;
+use crate::crit;
+use crate::edit::RESTORE_INDEX_PATH;
+use crate::images::{self, FILES_IMG_PATH};
+use crate::inaddr;
+use crate::lock;
+use crate::metadata::{CONFIG_DUMP_PATH, SPEC_DUMP_PATH};
+use crate::rewrite::Rewrite;
+use crate::sign::{MANIFEST_PATH, SIGNATURE_PATH};
+
+#[derive(Args)]
+pub struct AnonymizeArgs {
+    /// Checkpoint archive to anonymize (left as it is)
+    archive: PathBuf,
+    /// Anonymized copy to write
+    output: PathBuf,
+    /// Write the pseudonyms (original → pseudonym) to FILE as JSON, for
+    /// mapping findings on the copy back; it is as sensitive as the original
+    #[arg(long, value_name = "FILE")]
+    mapping: Option<PathBuf>,
+}
+
+/// Environment variables whose values are kept.
+const ENV_KEPT: [&str; 8] = [
+    "PATH",
+    "HOME",
+    "TERM",
+    "LANG",
+    "LC_ALL",
+    "TZ",
+    "HOSTNAME",
+    "container",
+];
+const REDACTED: &str = "REDACTED";
+/// Names too common to replace: replacing them would garble the metadata
+/// and hide nothing.
+const NAMES_KEPT: [&str; 5] = ["root", "localhost", "localdomain", "default", "(none)"];
+/// Metadata entries larger than this are copied unscrubbed.
+const MAX_METADATA_SIZE: u64 = 16 << 20;
+
+/// Where the names replaced are, within the spec.
+const SPEC_NAMES: [(&str, &str); 5] = [
+    ("/hostname", "host"),
+    ("/domainname", "domain"),
+    ("/annotations/io.kubernetes.pod.name", "pod"),
+    ("/annotations/io.kubernetes.pod.namespace", "namespace"),
+    ("/annotations/io.kubernetes.container.name", "container"),
+];
+
+fn is_utsns_img(path: &str) -> bool {
+    path.starts_with("checkpoint/utsns-") && path.ends_with(".img")
+}
+
+/// A top-level entry of podman's or edit_checkpoint's, rather than CRIU's.
+fn is_metadata(path: &str) -> bool {
+    !path.contains('/')
+}
+
+/// Whether `s` is a contiguous netmask, like 255.255.255.0.
+fn is_netmask(addr: Ipv4Addr) -> bool {
+    let inverted = !u32::from(addr);
+    inverted & inverted.wrapping_add(1) == 0
+}
+
+fn is_mac(token: &str) -> bool {
+    token.len() == 17
+        && token
+            .split(':')
+            .all(|b| b.len() == 2 && b.bytes().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// `s` with the whole-word occurrences of `word` replaced by `with`.
+fn replace_word(s: &str, word: &str, with: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = 0;
+    for (i, _) in s.match_indices(word) {
+        let before = s[..i].chars().next_back();
+        let after = s[i + word.len()..].chars().next();
+        if before.is_some_and(is_word_char) || after.is_some_and(is_word_char) {
+            continue;
+        }
+        out.push_str(&s[rest..i]);
+        out.push_str(with);
+        rest = i + word.len();
+    }
+    out.push_str(&s[rest..]);
+    out
+}
+
+/// The pseudonyms given so far.
+#[derive(Default)]
+struct Pseudonyms {
+    /// Longest first, so a FQDN is replaced before its host name.
+    names: Vec<(String, String)>,
+    addrs: BTreeMap<IpAddr, IpAddr>,
+    v4_nets: BTreeMap<[u8; 3], u32>,
+    v6_nets: BTreeMap<u64, u64>,
+    macs: BTreeMap<String, String>,
+    env: usize,
+}
+
+impl Pseudonyms {
+    /// Give `name` a pseudonym of `kind`. A FQDN's host name gets the same
+    /// one and its domain one of its own.
+    fn add_name(&mut self, kind: &str, name: &str) {
+        if name.len() < 2 || NAMES_KEPT.contains(&name) || self.names.iter().any(|(n, _)| n == name)
+        {
+            return;
+        }
+        let given: BTreeSet<&String> = self
+            .names
+            .iter()
+            .map(|(_, p)| p)
+            .filter(|p| p.starts_with(kind))
+            .collect();
+        let pseudonym = format!("{}-{}", kind, given.len() + 1);
+        self.names.push((name.to_string(), pseudonym.clone()));
+        if let Some((host, domain)) = name.split_once('.').filter(|_| kind == "host") {
+            if host.len() >= 2 && !self.names.iter().any(|(n, _)| n == host) {
+                self.names.push((host.to_string(), pseudonym));
+            }
+            self.add_name("domain", domain);
+        }
+        self.names.sort_by_key(|(n, _)| std::cmp::Reverse(n.len()));
+    }
+
+    /// Take the names to replace from the entry at `path`.
+    fn collect(&mut self, path: &str, doc: &Value) {
+        let name = |pointer: &str| doc.pointer(pointer).and_then(|v| v.as_str());
+        let spec = match path {
+            CONFIG_DUMP_PATH => {
+                if let Some(container) = name("/name") {
+                    self.add_name("container", container);
+                }
+                "/spec"
+            }
+            SPEC_DUMP_PATH => "",
+            AUDIT_PATH => {
+                for entry in doc.as_array().into_iter().flatten() {
+                    for (key, kind) in [("host", "host"), ("user", "user")] {
+                        if let Some(value) = entry.get(key).and_then(|v| v.as_str()) {
+                            self.add_name(kind, value.trim());
+                        }
+                    }
+                }
+                return;
+            }
+            _ if is_utsns_img(path) => {
+                for (key, kind) in [("nodename", "host"), ("domainname", "domain")] {
+                    if let Some(value) = name(&format!("/entries/0/{}", key)) {
+                        self.add_name(kind, value);
+                    }
+                }
+                return;
+            }
+            _ => return,
+        };
+        for (pointer, kind) in SPEC_NAMES {
+            if let Some(value) = name(&format!("{}{}", spec, pointer)) {
+                self.add_name(kind, value);
+            }
+        }
+    }
+
+    fn addr(&mut self, addr: IpAddr) -> IpAddr {
+        let kept = match addr {
+            IpAddr::V4(a) => {
+                a.is_unspecified() || a.is_loopback() || a.is_multicast() || is_netmask(a)
+            }
+            IpAddr::V6(a) => a.is_unspecified() || a.is_loopback() || a.is_multicast(),
+        };
+        if kept {
+            return addr;
+        }
+        if let Some(pseudonym) = self.addrs.get(&addr) {
+            return *pseudonym;
+        }
+        let pseudonym = match addr {
+            IpAddr::V4(a) => {
+                let [x, y, z, host] = a.octets();
+                let next = self.v4_nets.len() as u32 + 1;
+                let net = *self.v4_nets.entry([x, y, z]).or_insert(next);
+                IpAddr::V4(Ipv4Addr::new(10, (net >> 8) as u8, net as u8, host))
+            }
+            IpAddr::V6(a) => {
+                let next = self.v6_nets.len() as u64 + 1;
+                let net = *self
+                    .v6_nets
+                    .entry((u128::from(a) >> 64) as u64)
+                    .or_insert(next);
+                let host = self.addrs.keys().filter(|a| a.is_ipv6()).count() as u128 + 1;
+                IpAddr::V6(Ipv6Addr::from(0xfd00 << 112 | u128::from(net) << 64 | host))
+            }
+        };
+        self.addrs.insert(addr, pseudonym);
+        pseudonym
+    }
+
+    fn mac(&mut self, mac: &str) -> String {
+        let mac = mac.to_ascii_lowercase();
+        if mac == "00:00:00:00:00:00" || mac == "ff:ff:ff:ff:ff:ff" {
+            return mac;
+        }
+        let n = self.macs.len() + 1;
+        self.macs
+            .entry(mac)
+            .or_insert_with(|| format!("02:00:00:00:{:02x}:{:02x}", n >> 8 & 0xff, n & 0xff))
+            .clone()
+    }
+
+    /// The pseudonym of an address or MAC address `token`, if it is one.
+    fn token(&mut self, token: &str) -> Option<String> {
+        if is_mac(token) {
+            return Some(self.mac(token));
+        }
+        if let Ok(addr) = token.parse::<Ipv4Addr>() {
+            return Some(self.addr(addr.into()).to_string());
+        }
+        if let Ok(addr) = token.parse::<Ipv6Addr>() {
+            return Some(self.addr(addr.into()).to_string());
+        }
+        // ADDR:PORT
+        let (addr, port) = token.rsplit_once(':')?;
+        let addr = addr.parse::<Ipv4Addr>().ok()?;
+        port.parse::<u16>().ok()?;
+        Some(format!("{}:{}", self.addr(addr.into()), port))
+    }
+
+    fn scrub_str(&mut self, s: &str) -> String {
+        let mut named = s.to_string();
+        for (name, pseudonym) in &self.names {
+            if named.contains(name.as_str()) {
+                named = replace_word(&named, name, pseudonym);
+            }
+        }
+        // Addresses are runs of hex digits, dots and colons
+        let mut out = String::with_capacity(named.len());
+        let mut run = String::new();
+        for c in named.chars().chain(std::iter::once('\0')) {
+            if c.is_ascii_hexdigit() || c == '.' || c == ':' {
+                run.push(c);
+                continue;
+            }
+            if !run.is_empty() {
+                // Punctuation after an address, as at the end of a sentence
+                let token = run.trim_end_matches(['.', ':']);
+                match self.token(token) {
+                    Some(pseudonym) => {
+                        out.push_str(&pseudonym);
+                        out.push_str(&run[token.len()..]);
+                    }
+                    None => out.push_str(&run),
+                }
+                run.clear();
+            }
+            if c != '\0' {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    /// VAR=VALUE with VALUE redacted, unless VAR is harmless.
+    fn env_var(&mut self, var: &str) -> String {
+        match var.split_once('=') {
+            Some((name, _)) if !ENV_KEPT.contains(&name) => {
+                self.env += 1;
+                format!("{}={}", name, REDACTED)
+            }
+            _ => self.scrub_str(var),
+        }
+    }
+
+    /// Scrub `value`, found under `key`.
+    fn scrub(&mut self, value: &mut Value, key: Option<&str>) {
+        match value {
+            Value::String(s) => *s = self.scrub_str(s),
+            Value::Array(items) if key == Some("env") => {
+                for item in items {
+                    if let Value::String(var) = item {
+                        *var = self.env_var(var);
+                    }
+                }
+            }
+            Value::Array(items) if key == Some("createCommand") => {
+                let mut env_next = false;
+                for item in items {
+                    let Value::String(arg) = item else {
+                        continue;
+                    };
+                    *arg = if env_next {
+                        self.env_var(arg)
+                    } else if let Some(var) = arg.strip_prefix("--env=") {
+                        format!("--env={}", self.env_var(var))
+                    } else {
+                        self.scrub_str(arg)
+                    };
+                    env_next = arg == "-e" || arg == "--env";
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.scrub(item, None)),
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    self.scrub(value, Some(key));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Give the addresses of the sockets in the decoded files.img `data`
+    /// their pseudonyms. crit renders them as u32 words in network byte
+    /// order (one for IPv4, four for IPv6) or as strings.
+    fn scrub_sockets(&mut self, data: &mut Value) {
+        let entries = data.get_mut("entries").and_then(|e| e.as_array_mut());
+        for isk in entries
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.get_mut("isk"))
+        {
+            for key in ["src_addr", "dst_addr"] {
+                let Some(words) = isk.get_mut(key).filter(|w| w.is_array()) else {
+                    continue;
+                };
+                let numbers = words.get(0).is_some_and(Value::is_number);
+                match inaddr::decode(words).filter(|_| numbers) {
+                    Some(addr) => *words = inaddr::encode(self.addr(addr), words),
+                    None => self.scrub(words, None),
+                }
+            }
+        }
+    }
+
+    fn mapping(&self) -> Value {
+        let names: BTreeMap<&str, &str> = self
+            .names
+            .iter()
+            .map(|(n, p)| (n.as_str(), p.as_str()))
+            .collect();
+        let addrs: BTreeMap<String, String> = self
+            .addrs
+            .iter()
+            .map(|(a, p)| (a.to_string(), p.to_string()))
+            .collect();
+        json!({ "names": names, "addresses": addrs, "macs": self.macs })
+    }
+}
+
+fn read_entry(entry: &mut impl Read, path: &str) -> Result<Vec<u8>, String> {
+    let mut content = Vec::new();
+    entry
+        .read_to_end(&mut content)
+        .map_err(|e| format!("read {}: {}", path, e))?;
+    Ok(content)
+}
+
+/// Whether the entry at `path` of `size` bytes is read and scrubbed.
+fn scrubbed(path: &str, size: u64) -> bool {
+    (is_metadata(path) && size <= MAX_METADATA_SIZE) || path == FILES_IMG_PATH || is_utsns_img(path)
+}
+
+/// The names in the archive at `path` to replace.
+fn collect_names(path: &Path) -> Result<Pseudonyms, String> {
+    let mut pseudonyms = Pseudonyms::default();
+    let mut archive = tar::Archive::new(compress::open(path)?);
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let name = entry
+            .path()
+            .map_err(|e| e.to_string())?
+            .display()
+            .to_string();
+        let doc = match name.as_str() {
+            CONFIG_DUMP_PATH | SPEC_DUMP_PATH | AUDIT_PATH => {
+                serde_json::from_slice(&read_entry(&mut entry, &name)?).ok()
+            }
+            _ if is_utsns_img(&name) => Some(crit::decode(&read_entry(&mut entry, &name)?)?),
+            _ => None,
+        };
+        if let Some(doc) = doc {
+            pseudonyms.collect(&name, &doc);
+        }
+    }
+    Ok(pseudonyms)
+}
+
+/// The scrubbed `content` of the entry at `path`.
+fn scrub_entry(pseudonyms: &mut Pseudonyms, path: &str, content: &[u8]) -> Result<Vec<u8>, String> {
+    if path == FILES_IMG_PATH || is_utsns_img(path) {
+        images::check_version(path, content)?;
+        let mut data = crit::decode(content)?;
+        if path == FILES_IMG_PATH {
+            pseudonyms.scrub_sockets(&mut data);
+        } else {
+            pseudonyms.scrub(&mut data, None);
+        }
+        return crit::encode(&data);
+    }
+    if let Ok(mut doc) = serde_json::from_slice::<Value>(content) {
+        pseudonyms.scrub(&mut doc, None);
+        let pretty = content.trim_ascii_end().contains(&b'\n');
+        let serialized = if pretty {
+            serde_json::to_vec_pretty(&doc)
+        } else {
+            serde_json::to_vec(&doc)
+        };
+        return serialized.map_err(|e| format!("serialize {}: {}", path, e));
+    }
+    match std::str::from_utf8(content) {
+        Ok(text) => Ok(pseudonyms.scrub_str(text).into_bytes()),
+        // Binary, like a tar of files
+        Err(_) => Ok(content.to_vec()),
+    }
+}
+
+pub fn run(args: &AnonymizeArgs) -> Result<(), String> {
+    let path = args.archive.as_path();
+    let _input_lock = lock::input(path)?;
+    let mut pseudonyms = collect_names(path)?;
+
+    let mut rewrite = Rewrite::start(path, &args.output)?;
+    let builder = rewrite.builder();
+    let mut archive = tar::Archive::new(compress::open(path)?);
+    let mut dropped = Vec::new();
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let name = entry
+            .path()
+            .map_err(|e| e.to_string())?
+            .display()
+            .to_string();
+        if [MANIFEST_PATH, SIGNATURE_PATH, RESTORE_INDEX_PATH].contains(&name.as_str()) {
+            dropped.push(name);
+            continue;
+        }
+        let mut header = entry.header().clone();
+        let result = if header.entry_type().is_file() && scrubbed(&name, header.size().unwrap_or(0))
+        {
+            let content = read_entry(&mut entry, &name)?;
+            let scrubbed = scrub_entry(&mut pseudonyms, &name, &content)?;
+            header.set_size(scrubbed.len() as u64);
+            header.set_cksum();
+            builder.append(&header, scrubbed.as_slice())
+        } else {
+            builder.append(&header, &mut entry)
+        };
+        result.map_err(|e| format!("write {}: {}", name, e))?;
+    }
+    rewrite.finish(None)?;
+
+    if let Some(mapping) = &args.mapping {
+        let content =
+            serde_json::to_vec_pretty(&pseudonyms.mapping()).map_err(|e| e.to_string())?;
+        fs::write(mapping, content).map_err(|e| format!("write {}: {}", mapping.display(), e))?;
+    }
+    eprintln!(
+        "Wrote {}: replaced {} names, {} addresses and {} MAC addresses, redacted {} \
+         environment values",
+        args.output.display(),
+        pseudonyms.names.len(),
+        pseudonyms.addrs.len(),
+        pseudonyms.macs.len(),
+        pseudonyms.env
+    );
+    if !dropped.is_empty() {
+        eprintln!("Left out {}, which no longer hold", dropped.join(", "));
+    }
+    eprintln!(
+        "Note: memory pages and file contents (rootfs-diff.tar, devshm-checkpoint.tar) are \
+         kept as dumped"
+    );
+    Ok(())
+}
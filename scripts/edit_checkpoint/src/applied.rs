@@ -0,0 +1,32 @@
+//! The idempotency marker of an edited archive.
+//!
+//! An orchestrator that retries a migration step cannot tell whether the
+//! edit it asked for went through before the step failed, and editing the
+//! same archive twice fails (old_addr is detected as new_addr by then). So
+//! every edit writes APPLIED_PATH as the first entry of the archive, holding
+//! a hash of the addresses and patch flags it was asked for; an edit asked
+//! for the same again finds the marker among the metadata it reads first and
+//! leaves the archive alone. Any other edit drops the marker and writes its
+//! own.
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+pub const APPLIED_PATH: &str = "edit-applied.json";
+
+/// The hash of an edit request, given as the text of its arguments.
+pub fn fingerprint(request: &str) -> String {
+    format!("{:x}", Sha256::digest(request.as_bytes()))
+}
+
+/// The content of APPLIED_PATH for the edit with `fingerprint`.
+pub fn marker(fingerprint: &str) -> Vec<u8> {
+    let marker = json!({"sha256": fingerprint, "version": env!("CARGO_PKG_VERSION")});
+    serde_json::to_vec_pretty(&marker).unwrap_or_default()
+}
+
+/// The fingerprint recorded in an APPLIED_PATH entry.
+pub fn recorded(content: &[u8]) -> Option<String> {
+    let marker: Value = serde_json::from_slice(content).ok()?;
+    marker.get("sha256")?.as_str().map(str::to_string)
+}
@@ -0,0 +1,179 @@
+//! The platform a checkpoint can be restored on, checked against the target's
+//! (--target-arch, --target-page-size) before the edited archive is shipped.
+//!
+//! CRIU restores only on the architecture it dumped on, which every core
+//! image records (mtype). The page size is not recorded anywhere; it is
+//! inferred from the alignment of the dumped pages in the pagemap images. A
+//! page not aligned to the target's page size proves the source used smaller
+//! pages; all of them aligned to a larger size than the target's is taken as
+//! proof the other way once there are enough of them to rule out chance.
+
+use serde_json::Value;
+
+use crate::crit;
+use crate::report::Report;
+
+/// CRIU's march names and the `uname -m` names of each architecture.
+const ARCHES: &[(&str, &[&str])] = &[
+    ("X86_64", &["x86_64", "amd64"]),
+    ("ARM", &["arm", "armv7l", "armv6l", "armhf"]),
+    ("AARCH64", &["aarch64", "arm64"]),
+    ("PPC64", &["ppc64le", "ppc64"]),
+    ("S390", &["s390x"]),
+    ("MIPS", &["mips64", "mips"]),
+    ("LOONGARCH64", &["loongarch64"]),
+    ("RISCV64", &["riscv64"]),
+];
+/// The page sizes told apart, smallest first.
+const PAGE_SIZES: [u64; 3] = [4 << 10, 16 << 10, 64 << 10];
+/// Dumped pages needed to conclude the source had larger pages than the target.
+const MIN_PAGES_FOR_LARGER: usize = 16;
+
+/// The target's platform, as far as it was declared.
+#[derive(Clone, Debug, Default)]
+pub struct TargetPlatform {
+    /// CRIU march name, e.g. AARCH64.
+    pub arch: Option<String>,
+    pub page_size: Option<u64>,
+}
+
+/// Parse --target-arch: a `uname -m` name (or CRIU's) into CRIU's.
+pub fn parse_arch(s: &str) -> Result<String, String> {
+    ARCHES
+        .iter()
+        .find(|(march, names)| march.eq_ignore_ascii_case(s) || names.contains(&s))
+        .map(|(march, _)| march.to_string())
+        .ok_or_else(|| format!("unknown architecture {} (as `uname -m` prints it)", s))
+}
+
+/// Parse --target-page-size: 4K, 16K or 64K.
+pub fn parse_page_size(s: &str) -> Result<u64, String> {
+    let size = crate::buffers::parse_size(s)? as u64;
+    if !PAGE_SIZES.contains(&size) {
+        return Err(format!("{} is not a page size (4K, 16K or 64K)", s));
+    }
+    Ok(size)
+}
+
+/// The `uname -m` name of a CRIU march name, for messages.
+fn uname(march: &str) -> &str {
+    ARCHES
+        .iter()
+        .find(|(m, _)| *m == march)
+        .map_or(march, |(_, names)| names[0])
+}
+
+/// CRIU's march enum by name, or by number for images decoded with
+/// descriptors that lack the enum.
+fn march_name(value: &Value) -> Option<String> {
+    if let Some(name) = value.as_str() {
+        return Some(name.to_string());
+    }
+    let march = value.as_u64()?.checked_sub(1)?;
+    ARCHES.get(march as usize).map(|(m, _)| m.to_string())
+}
+
+/// checkpoint/pagemap-PID.img, the list of a task's dumped pages.
+pub fn is_pagemap_img(path: &str) -> bool {
+    path.strip_prefix("checkpoint/pagemap-")
+        .and_then(|n| n.strip_suffix(".img"))
+        .is_some_and(|pid| !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit()))
+}
+
+impl TargetPlatform {
+    pub fn is_empty(&self) -> bool {
+        self.arch.is_none() && self.page_size.is_none()
+    }
+
+    /// Check the architecture of the core image `content` at `path`.
+    pub fn check_core(
+        &self,
+        path: &str,
+        content: &[u8],
+        report: &mut Report,
+    ) -> Result<(), String> {
+        let Some(target) = &self.arch else {
+            return Ok(());
+        };
+        let data = crit::decode(content)?;
+        let mtype = data
+            .pointer("/entries/0/mtype")
+            .and_then(march_name)
+            .ok_or_else(|| format!("{} records no architecture (mtype)", path))?;
+        report.set("arch", uname(&mtype));
+        if mtype != *target {
+            return Err(format!(
+                "the checkpoint was dumped on {} ({} mtype {}) and cannot be restored on the \
+                 {} target; CRIU does not migrate across architectures",
+                uname(&mtype),
+                path,
+                mtype,
+                uname(target)
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The alignment of the pages dumped, over the pagemap images seen.
+#[derive(Default)]
+pub struct PageAlignment {
+    /// The largest of PAGE_SIZES every page start is a multiple of.
+    align: Option<u64>,
+    pages: usize,
+}
+
+impl PageAlignment {
+    pub fn add_pagemap(&mut self, content: &[u8]) -> Result<(), String> {
+        let data = crit::decode(content)?;
+        let entries = data.get("entries").and_then(|e| e.as_array());
+        // The first entry is the pagemap head (pages_id), without a vaddr
+        for vaddr in entries.into_iter().flatten().filter_map(|e| e.get("vaddr")) {
+            let vaddr = match vaddr {
+                Value::String(s) => u64::from_str_radix(s.trim_start_matches("0x"), 16).ok(),
+                other => other.as_u64(),
+            };
+            let Some(vaddr) = vaddr else {
+                continue;
+            };
+            let align = PAGE_SIZES
+                .iter()
+                .rev()
+                .copied()
+                .find(|size| vaddr % size == 0)
+                .unwrap_or(1);
+            self.align = Some(self.align.map_or(align, |a| a.min(align)));
+            self.pages += 1;
+        }
+        Ok(())
+    }
+
+    /// Check the inferred page size against the target's.
+    pub fn check(&self, target: &TargetPlatform, report: &mut Report) -> Result<(), String> {
+        let (Some(target), Some(align)) = (target.page_size, self.align) else {
+            return Ok(());
+        };
+        let kib = |size: u64| format!("{}K", size >> 10);
+        if align < target {
+            return Err(format!(
+                "the checkpoint has pages at addresses not aligned to the target's {} pages, \
+                 so it was dumped with smaller ones; CRIU cannot restore it there",
+                kib(target)
+            ));
+        }
+        if align > target && self.pages >= MIN_PAGES_FOR_LARGER {
+            return Err(format!(
+                "all {} dumped pages are {}-aligned, so the checkpoint was dumped with {} \
+                 pages; CRIU cannot restore it on the target's {} pages",
+                self.pages,
+                kib(align),
+                kib(align),
+                kib(target)
+            ));
+        }
+        if align == PAGE_SIZES[0] || self.pages >= MIN_PAGES_FOR_LARGER {
+            report.set("page_size", align);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+//! The audit trail of a checkpoint's edits (AUDIT_PATH).
+//!
+//! Every edit appends an entry: who ran it, where and when, the tool version,
+//! the address change and mappings asked for, and each value it changed in
+//! the podman metadata with its value before and after, as a JSON pointer
+//! into network.status, config.dump or spec.dump. The entries of earlier
+//! edits are kept, so the chain of edits from the original checkpoint to the
+//! one restored can be read back from the archive alone, and `undo` can
+//! reverse the last one.
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+
+use crate::edit::EditOptions;
+use crate::metadata::{AddrPatch, NetworkPatch};
+
+pub const AUDIT_PATH: &str = "edit-audit.json";
+
+/// The audit entry of one edit, and those of the edits before it.
+#[derive(Default)]
+pub struct Audit {
+    earlier: Vec<Value>,
+    changes: Vec<Value>,
+}
+
+/// The JSON pointer form of an object key.
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Record where `before` and `after` differ, as {pointer, before, after};
+/// a side without the value (an added or removed key) has no field.
+/// Arrays whose length changed are recorded whole.
+fn diff(pointer: &str, before: Option<&Value>, after: Option<&Value>, out: &mut Vec<Value>) {
+    match (before, after) {
+        (Some(Value::Object(b)), Some(Value::Object(a))) => {
+            for (key, value) in b {
+                diff(
+                    &format!("{}/{}", pointer, escape(key)),
+                    Some(value),
+                    a.get(key),
+                    out,
+                );
+            }
+            for (key, value) in a.iter().filter(|(key, _)| !b.contains_key(*key)) {
+                diff(
+                    &format!("{}/{}", pointer, escape(key)),
+                    None,
+                    Some(value),
+                    out,
+                );
+            }
+        }
+        (Some(Value::Array(b)), Some(Value::Array(a))) if a.len() == b.len() => {
+            for (i, (b, a)) in b.iter().zip(a).enumerate() {
+                diff(&format!("{}/{}", pointer, i), Some(b), Some(a), out);
+            }
+        }
+        _ if before == after => {}
+        _ => {
+            let mut change = Map::new();
+            change.insert("pointer".to_string(), pointer.into());
+            if let Some(before) = before {
+                change.insert("before".to_string(), before.clone());
+            }
+            if let Some(after) = after {
+                change.insert("after".to_string(), after.clone());
+            }
+            out.push(Value::Object(change));
+        }
+    }
+}
+
+/// Who is editing: the user sudo was run by, or the current one.
+fn user() -> String {
+    ["SUDO_USER", "USER", "LOGNAME"]
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|v| !v.is_empty()))
+        .or_else(|| {
+            let uid = fs::metadata("/proc/self").ok()?.uid();
+            Some(format!("uid {}", uid))
+        })
+        .unwrap_or_default()
+}
+
+impl Audit {
+    /// Keep the entries of the edits before this one.
+    pub fn merge_entry(&mut self, content: &[u8]) {
+        match serde_json::from_slice(content) {
+            Ok(Value::Array(entries)) => self.earlier = entries,
+            _ => eprintln!("Warning: ignoring unparsable {}", AUDIT_PATH),
+        }
+    }
+
+    /// Record the values changed in the metadata entry at `path`; whether
+    /// any did. Entries that are not JSON (an empty network.status) have
+    /// nothing to record, and count as changed if their bytes did.
+    pub fn record(&mut self, path: &str, before: &[u8], after: &[u8]) -> bool {
+        let (Ok(before_json), Ok(after_json)) = (
+            serde_json::from_slice::<Value>(before),
+            serde_json::from_slice::<Value>(after),
+        ) else {
+            return before != after;
+        };
+        let mut changes = Vec::new();
+        diff("", Some(&before_json), Some(&after_json), &mut changes);
+        let changed = !changes.is_empty();
+        for mut change in changes {
+            change["entry"] = path.into();
+            self.changes.push(change);
+        }
+        changed
+    }
+
+    /// This edit's changes, as recorded.
+    pub fn changes(&self) -> &[Value] {
+        &self.changes
+    }
+
+    /// The content of AUDIT_PATH: the earlier entries and this edit's.
+    pub fn to_json(&self, net: &NetworkPatch, opts: &EditOptions) -> Vec<u8> {
+        let (mode, new_addr) = match net.addr {
+            AddrPatch::Replace(addr) => ("replace", Some(addr)),
+            AddrPatch::Add(addr) => ("add", Some(addr)),
+            AddrPatch::Clear => ("clear", None),
+        };
+        let maps = |map: &[(String, String)]| -> Value {
+            map.iter().map(|(old, new)| json!([old, new])).collect()
+        };
+        let entry = json!({
+            "time": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            "user": user(),
+            "host": fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|h| h.trim().to_string())
+                .unwrap_or_default(),
+            "tool": format!("edit_checkpoint {}", env!("CARGO_PKG_VERSION")),
+            "fingerprint": opts.fingerprint,
+            "old_addr": net.old_addr.to_string(),
+            "new_addr": new_addr,
+            "addr_mode": mode,
+            "mappings": {
+                "dns_servers": net.dns_servers,
+                "dns_search": net.dns_search,
+                "aliases": net.aliases,
+                "secrets": maps(&opts.secrets_map),
+                "lock_paths": maps(&opts.lock_path_map),
+                "ghost_paths": maps(&opts.ghosts.map),
+                "tmpfs_paths": maps(&opts.tmpfs.path_map),
+                "cgroups": maps(&opts.cgroup_map),
+                "images": maps(&opts.image_map),
+            },
+            "changes": self.changes,
+        });
+        let mut entries = self.earlier.clone();
+        entries.push(entry);
+        serde_json::to_vec_pretty(&entries).unwrap_or_default()
+    }
+}
@@ -0,0 +1,180 @@
+//! Read and write buffer sizes of the edit pass.
+//!
+//! 256 KiB suits a local disk. On tmpfs the archive is already in memory and
+//! a smaller buffer stays in the CPU cache; on NFS or SMB every buffer costs
+//! at least one round trip to the server, and 1 MiB (the usual rsize/wsize)
+//! cuts their number by four. --read-buffer and --write-buffer set the sizes
+//! (e.g. 64K, 4M); --auto-buffers picks them from the filesystem the archive
+//! is on and its size, leaving sizes given explicitly as they are.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::report::Report;
+
+pub const DEFAULT_SIZE: usize = 256 * 1024;
+const MIN_SIZE: usize = 4 * 1024;
+const MAX_SIZE: usize = 256 * 1024 * 1024;
+
+const MEMORY_FS: [&str; 2] = ["tmpfs", "ramfs"];
+const NETWORK_FS: [&str; 8] = [
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "ceph",
+    "9p",
+    "fuse.sshfs",
+    "fuse.glusterfs",
+];
+
+/// Buffer sizes as asked for on the command line.
+#[derive(Clone, Copy, Default)]
+pub struct BufferConfig {
+    pub read: Option<usize>,
+    pub write: Option<usize>,
+    pub auto: bool,
+}
+
+/// Buffer sizes of one edit, in bytes.
+#[derive(Clone, Copy)]
+pub struct BufferSizes {
+    pub read: usize,
+    pub write: usize,
+}
+
+/// A buffer size: parse_bytes, from 4K to 256M.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let size = parse_bytes(s)?;
+    if !(MIN_SIZE..=MAX_SIZE).contains(&size) {
+        return Err(format!("size {} is out of range (4K to 256M)", s));
+    }
+    Ok(size)
+}
+
+/// A size in bytes, with an optional K, M or G suffix (powers of 1024).
+pub fn parse_bytes(s: &str) -> Result<usize, String> {
+    let upper = s.trim().to_ascii_uppercase();
+    let number = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+    let (digits, unit) = match number.strip_suffix(['K', 'M', 'G']) {
+        Some(digits) => (digits, &number[digits.len()..]),
+        None => (number, ""),
+    };
+    let multiplier: usize = match unit {
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => 1,
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size {:?} (expected e.g. 65536, 64K, 1M)", s))
+}
+
+/// Undo the octal escapes (\040 for a space) of a mountinfo path.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|d| u8::from_str_radix(std::str::from_utf8(d).ok()?, 8).ok());
+        match code {
+            Some(c) => {
+                out.push(c);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The type of the filesystem `path` is on, from /proc/self/mountinfo.
+pub fn fs_type(path: &Path) -> Option<String> {
+    let path = fs::canonicalize(path).ok()?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    let mut best: Option<(PathBuf, String)> = None;
+    for line in mountinfo.lines() {
+        // ID PARENT MAJ:MIN ROOT MOUNT_POINT OPTIONS [TAGS...] - FSTYPE ...
+        let Some((fields, rest)) = line.split_once(" - ") else {
+            continue;
+        };
+        let (Some(mount_point), Some(fstype)) = (fields.split(' ').nth(4), rest.split(' ').next())
+        else {
+            continue;
+        };
+        let mount_point = PathBuf::from(unescape(mount_point));
+        // Later entries are mounted over earlier ones at the same point
+        let longer = best
+            .as_ref()
+            .is_none_or(|(b, _)| mount_point.components().count() >= b.components().count());
+        if path.starts_with(&mount_point) && longer {
+            best = Some((mount_point, fstype.to_string()));
+        }
+    }
+    best.map(|(_, fstype)| fstype)
+}
+
+/// Whether `fstype` is a filesystem served over the network.
+pub fn is_network_fs(fstype: &str) -> bool {
+    NETWORK_FS.contains(&fstype)
+}
+
+/// The buffer size for a filesystem of type `fstype`.
+fn for_fs(fstype: Option<&str>) -> usize {
+    match fstype {
+        Some(t) if MEMORY_FS.contains(&t) => 64 * 1024,
+        Some(t) if is_network_fs(t) => 1024 * 1024,
+        _ => DEFAULT_SIZE,
+    }
+}
+
+impl BufferConfig {
+    /// The sizes to edit the archive at `input` with, recorded in the
+    /// report. The output is written next to the input, on the same
+    /// filesystem.
+    pub fn resolve(&self, input: &Path, report: &mut Report) -> BufferSizes {
+        let mut sizes = BufferSizes {
+            read: self.read.unwrap_or(DEFAULT_SIZE),
+            write: self.write.unwrap_or(DEFAULT_SIZE),
+        };
+        let mut fstype = None;
+        if self.auto {
+            fstype = fs_type(input);
+            let size = for_fs(fstype.as_deref());
+            // A buffer past the end of a small archive is never filled
+            let len = fs::metadata(input).map_or(size as u64, |m| m.len());
+            let read = len.next_multiple_of(MIN_SIZE as u64).max(MIN_SIZE as u64);
+            let read = size.min(read.try_into().unwrap_or(usize::MAX));
+            sizes.read = self.read.unwrap_or(read);
+            sizes.write = self.write.unwrap_or(size);
+            eprintln!(
+                "Buffers for {}: read {} KiB, write {} KiB",
+                fstype.as_deref().unwrap_or("unknown filesystem"),
+                sizes.read / 1024,
+                sizes.write / 1024
+            );
+        }
+        report.set(
+            "buffers",
+            serde_json::json!({
+                "read": sizes.read,
+                "write": sizes.write,
+                "auto": self.auto,
+                "fs": fstype,
+            }),
+        );
+        sizes
+    }
+}
@@ -0,0 +1,120 @@
+//! Remap the systemd slices and cgroup parents the container runs under.
+//!
+//! The container's cgroup is cgroupParent in config.dump (podman
+//! --cgroup-parent, which conmon's scope is placed under as well) and linux.cgroupsPath in spec.dump and the copy of the
+//! spec in config.dump: `SLICE:PREFIX:NAME` with the systemd cgroup manager,
+//! a path such as /machine.slice/libpod-ID.scope with cgroupfs. --cgroup-map
+//! OLD=NEW replaces every slice, scope or directory named OLD in those, e.g.
+//! machine.slice=tenant-a.slice. CRIU restores the task cgroups relative to
+//! the container's cgroup, so the images need no change.
+
+use serde_json::Value;
+
+use crate::report::Report;
+
+/// One remapped value, for the report.
+pub struct Remapped {
+    pub field: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+fn lookup<'a>(name: &'a str, map: &'a [(String, String)]) -> &'a str {
+    map.iter()
+        .find(|(old, _)| old == name)
+        .map_or(name, |(_, new)| new.as_str())
+}
+
+/// Map the components of a cgroupfs path, or the slice of a systemd
+/// `SLICE:PREFIX:NAME` (or bare slice) value.
+fn remap_cgroup(value: &str, map: &[(String, String)]) -> String {
+    if value.contains('/') {
+        return value
+            .split('/')
+            .map(|c| lookup(c, map))
+            .collect::<Vec<_>>()
+            .join("/");
+    }
+    match value.split_once(':') {
+        Some((slice, rest)) => format!("{}:{}", lookup(slice, map), rest),
+        None => lookup(value, map).to_string(),
+    }
+}
+
+fn patch_field(
+    parent: &mut Value,
+    key: &str,
+    field: &'static str,
+    map: &[(String, String)],
+    remapped: &mut Vec<Remapped>,
+) {
+    let Some(old) = parent.get(key).and_then(|v| v.as_str()) else {
+        return;
+    };
+    let new = remap_cgroup(old, map);
+    if new != old {
+        remapped.push(Remapped {
+            field,
+            old: old.to_string(),
+            new: new.clone(),
+        });
+        parent[key] = new.into();
+    }
+}
+
+fn patch_spec(spec: &mut Value, map: &[(String, String)], remapped: &mut Vec<Remapped>) {
+    if let Some(linux) = spec.get_mut("linux") {
+        patch_field(linux, "cgroupsPath", "linux.cgroupsPath", map, remapped);
+    }
+}
+
+pub fn patch_config_dump(
+    content: &[u8],
+    map: &[(String, String)],
+) -> Result<(Vec<u8>, Vec<Remapped>), String> {
+    let mut data: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse config.dump: {}", e))?;
+    let mut remapped = Vec::new();
+    patch_field(
+        &mut data,
+        "cgroupParent",
+        "cgroupParent",
+        map,
+        &mut remapped,
+    );
+    if let Some(spec) = data.get_mut("spec").filter(|s| s.is_object()) {
+        patch_spec(spec, map, &mut remapped);
+    }
+    let content = serde_json::to_vec(&data).map_err(|e| format!("serialize config.dump: {}", e))?;
+    Ok((content, remapped))
+}
+
+pub fn patch_spec_dump(
+    content: &[u8],
+    map: &[(String, String)],
+) -> Result<(Vec<u8>, Vec<Remapped>), String> {
+    let mut data: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse spec.dump: {}", e))?;
+    let mut remapped = Vec::new();
+    patch_spec(&mut data, map, &mut remapped);
+    let content = serde_json::to_vec(&data).map_err(|e| format!("serialize spec.dump: {}", e))?;
+    Ok((content, remapped))
+}
+
+/// Log the remapped values of `file` and add them to the report's list.
+pub fn record(file: &str, remapped: Vec<Remapped>, report: &mut Report) {
+    for r in &remapped {
+        eprintln!("Cgroup {} in {}: {} → {}", r.field, file, r.old, r.new);
+    }
+    report.extend(
+        "cgroups",
+        remapped.into_iter().map(|r| {
+            serde_json::json!({
+                "file": file,
+                "field": r.field,
+                "old": r.old,
+                "new": r.new,
+            })
+        }),
+    );
+}
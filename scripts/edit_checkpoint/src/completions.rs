@@ -0,0 +1,53 @@
+//! `completions` and `manpage`: shell completions and man pages generated
+//! from the command line definition, to install on the migration hosts.
+//!
+//!     edit_checkpoint completions bash > /etc/bash_completion.d/edit_checkpoint
+//!     edit_checkpoint manpage --dir /usr/local/share/man/man1
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use clap::{Args, CommandFactory};
+use clap_complete::Shell;
+
+use crate::Cli;
+
+#[derive(Args)]
+pub struct CompletionsArgs {
+    /// Shell to complete for
+    #[arg(value_enum)]
+    shell: Shell,
+}
+
+#[derive(Args)]
+pub struct ManpageArgs {
+    /// Write edit_checkpoint.1 and a page per subcommand (e.g.
+    /// edit_checkpoint-sockets.1) to DIR instead of printing the main page
+    #[arg(long, value_name = "DIR")]
+    dir: Option<PathBuf>,
+}
+
+pub fn completions(args: &CompletionsArgs) -> Result<(), String> {
+    let mut cmd = Cli::command();
+    let name = cmd.get_name().to_string();
+    // Into a buffer: generate panics on a write error, e.g. a closed pipe
+    let mut script = Vec::new();
+    clap_complete::generate(args.shell, &mut cmd, name, &mut script);
+    io::stdout().write_all(&script).map_err(|e| e.to_string())
+}
+
+pub fn manpage(args: &ManpageArgs) -> Result<(), String> {
+    let cmd = Cli::command();
+    match &args.dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir).map_err(|e| format!("create {}: {}", dir.display(), e))?;
+            clap_mangen::generate_to(cmd, dir)
+                .map_err(|e| format!("write man pages to {}: {}", dir.display(), e))?;
+            eprintln!("Wrote the man pages to {}", dir.display());
+            Ok(())
+        }
+        None => clap_mangen::Man::new(cmd)
+            .render(&mut io::stdout())
+            .map_err(|e| e.to_string()),
+    }
+}
@@ -0,0 +1,111 @@
+//! zstd-compressed checkpoint archives (`podman container checkpoint
+//! --compress zstd`).
+//!
+//! Archives are recognized by their magic on input, wherever one is read.
+//! Output stays uncompressed unless `--compress zstd`; compression then runs
+//! on `--zstd-workers` threads (default: one per CPU), since the pages images
+//! make up most of a multi-GB archive and one core cannot keep up with the
+//! tar loop.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use clap::ValueEnum;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const BUF_SIZE: usize = 256 * 1024;
+
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+/// How to compress the edited archive.
+#[derive(Clone, Copy, Default)]
+pub struct OutputCompression {
+    pub kind: Compression,
+    pub level: i32,
+    /// Compression threads; 0 for one per CPU.
+    pub workers: u32,
+}
+
+/// The archive at `path`, decompressed if it is zstd.
+pub fn open(path: &Path) -> Result<Box<dyn Read>, String> {
+    open_buffered(path, BUF_SIZE)
+}
+
+/// Like `open`, reading the file `capacity` bytes at a time.
+pub fn open_buffered(path: &Path, capacity: usize) -> Result<Box<dyn Read>, String> {
+    let file = fs::File::open(path).map_err(|e| format!("open {}: {}", path.display(), e))?;
+    reader(BufReader::with_capacity(capacity, file))
+        .map_err(|e| format!("read {}: {}", path.display(), e))
+}
+
+/// Whether the file at `path` is zstd-compressed.
+pub fn is_zstd(path: &Path) -> Result<bool, String> {
+    let mut magic = [0u8; 4];
+    let mut file = fs::File::open(path).map_err(|e| format!("open {}: {}", path.display(), e))?;
+    let n = file
+        .read(&mut magic)
+        .map_err(|e| format!("read {}: {}", path.display(), e))?;
+    Ok(n == magic.len() && magic == ZSTD_MAGIC)
+}
+
+/// `input` as is, or decompressed if it starts with the zstd magic.
+pub fn reader<R: BufRead + 'static>(mut input: R) -> io::Result<Box<dyn Read>> {
+    if input.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+        let decoder = zstd::Decoder::with_buffer(input)?;
+        return Ok(Box::new(BufReader::with_capacity(BUF_SIZE, decoder)));
+    }
+    Ok(Box::new(input))
+}
+
+/// Output of the edit, compressed as configured. Must be `finish`ed.
+pub enum Writer<W: Write> {
+    Plain(W),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(output: W, compression: &OutputCompression) -> io::Result<Self> {
+        if compression.kind == Compression::None {
+            return Ok(Writer::Plain(output));
+        }
+        let workers = match compression.workers {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get() as u32),
+            n => n,
+        };
+        let mut encoder = zstd::Encoder::new(output, compression.level)?;
+        encoder.multithread(workers)?;
+        Ok(Writer::Zstd(encoder))
+    }
+
+    /// Write the end of the zstd frame and flush.
+    pub fn finish(self) -> io::Result<W> {
+        let mut output = match self {
+            Writer::Plain(w) => w,
+            Writer::Zstd(encoder) => encoder.finish()?,
+        };
+        output.flush()?;
+        Ok(output)
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::Plain(w) => w.write(buf),
+            Writer::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Plain(w) => w.flush(),
+            Writer::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! Duplicate-address probe for new_addr on the target node.
+//!
+//! Runs `arping -D` (duplicate address detection) on the target via SSH while
+//! the archive is being streamed, so the probe adds no latency unless it is
+//! slower than the edit itself. Set EDIT_CHECKPOINT_ARPING (e.g. "sudo arping")
+//! to change the arping command.
+
+use std::process::{Child, Stdio};
+
+use crate::remote;
+
+pub struct ConflictProbe {
+    child: Child,
+    addr: String,
+    target: String,
+}
+
+/// Start probing `addr` on `iface`, locally or on `host`.
+pub fn start(host: Option<&str>, iface: &str, addr: &str) -> Result<ConflictProbe, String> {
+    let argv = remote::argv(
+        "EDIT_CHECKPOINT_ARPING",
+        "arping",
+        &["-D", "-c", "2", "-w", "2", "-I", iface, addr],
+    );
+    let child = remote::command(host, &argv)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("run {}: {}", argv.join(" "), e))?;
+    Ok(ConflictProbe {
+        child,
+        addr: addr.to_string(),
+        target: format!("{} on {}", iface, host.unwrap_or("localhost")),
+    })
+}
+
+impl ConflictProbe {
+    /// Wait for the probe. Fails if something answered for the address, and
+    /// also if the probe itself could not run: an unverified address is not
+    /// treated as free.
+    pub fn finish(self) -> Result<(), String> {
+        let out = self
+            .child
+            .wait_with_output()
+            .map_err(|e| format!("arping: {}", e))?;
+        match out.status.code() {
+            Some(0) => Ok(()),
+            // iputils arping -D exits 1 when a reply was received
+            Some(1) => {
+                let stdout = String::from_utf8_lossy(&out.stdout);
+                let reply = stdout
+                    .lines()
+                    .find(|l| l.contains("reply from"))
+                    .unwrap_or("")
+                    .trim();
+                Err(format!(
+                    "new_addr {} is already live on the target ({}): {}",
+                    self.addr, self.target, reply
+                ))
+            }
+            _ => Err(format!(
+                "conflict probe for {} ({}) failed: {}",
+                self.addr,
+                self.target,
+                String::from_utf8_lossy(&out.stderr).trim()
+            )),
+        }
+    }
+}
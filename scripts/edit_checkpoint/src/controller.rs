@@ -0,0 +1,53 @@
+//! Client for the p4containerflow controller's HTTP API (controller/controller.py).
+
+use std::time::Duration;
+
+use crate::trace;
+
+pub struct Controller {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl Controller {
+    pub fn new(url: &str) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(2))
+            .timeout(Duration::from_secs(4))
+            .build();
+        Controller {
+            url: url.trim_end_matches('/').to_string(),
+            agent,
+        }
+    }
+
+    /// POST /migrateNode: repoint the load balancer's node entry from old to new.
+    pub fn migrate_node(&self, old_ipv4: &str, new_ipv4: &str) -> Result<(), String> {
+        let _span = trace::span("controller migrateNode");
+        self.post(
+            "/migrateNode",
+            serde_json::json!({ "old_ipv4": old_ipv4, "new_ipv4": new_ipv4 }),
+        )
+    }
+
+    fn post(&self, path: &str, body: serde_json::Value) -> Result<(), String> {
+        let url = format!("{}{}", self.url, path);
+        let mut request = self
+            .agent
+            .post(&url)
+            .set("Content-Type", "application/json");
+        if let Some(tp) = trace::traceparent() {
+            request = request.set("traceparent", &tp);
+        }
+        match request.send_string(&body.to_string()) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, resp)) => Err(format!(
+                "{} returned HTTP {}: {}",
+                url,
+                code,
+                resp.into_string().unwrap_or_default().trim()
+            )),
+            Err(e) => Err(format!("{}: {}", url, e)),
+        }
+    }
+}
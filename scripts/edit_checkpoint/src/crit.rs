@@ -0,0 +1,377 @@
+//! Running crit: CRIU images ⇄ JSON. crit reads the image or JSON from a
+//! memfd it inherits and writes the result to a pipe, so there is nothing on
+//! disk to clean up however the run ends (without the `edit` feature, off
+//! Linux, the input is a temp file instead). With CRIU's protobuf
+//! descriptors at hand, the images they cover are decoded without crit
+//! instead (see proto.rs).
+//!
+//! crit gets no stdin and --crit-timeout to finish; one that hangs is
+//! killed, and the error says how much it had written. What crit prints to
+//! stderr is passed on, and is in the error when it fails.
+//!
+//! With --crit-host USER@HOST, crit runs there instead, over SSH: the image
+//! or JSON goes to its stdin and the result comes back on stdout, for nodes
+//! that cannot have crit and its Python installed. The timeout covers the
+//! whole run, connection included.
+//!
+//! With --decode-cache DIR, what crit decodes is kept in DIR as JSON named
+//! by the SHA-256 of the image and the crit installed, for runs on the same
+//! checkpoint to skip the decode.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::interrupt;
+use crate::proto::Descriptors;
+use crate::remote;
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 120;
+
+static TIMEOUT: OnceLock<Option<Duration>> = OnceLock::new();
+
+/// How long each crit run may take (--crit-timeout); None for as long as
+/// it takes.
+pub fn set_timeout(timeout: Option<Duration>) {
+    let _ = TIMEOUT.set(timeout);
+}
+
+fn timeout() -> Option<Duration> {
+    *TIMEOUT.get_or_init(|| Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS)))
+}
+
+static HOST: OnceLock<Option<String>> = OnceLock::new();
+
+/// Run crit on `host` over SSH (--crit-host).
+pub fn set_host(host: Option<String>) {
+    let _ = HOST.set(host);
+}
+
+fn host() -> Option<&'static str> {
+    HOST.get()?.as_deref()
+}
+
+static CACHE: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Keep crit's decodes in `dir` (--decode-cache).
+pub fn set_cache(dir: Option<PathBuf>) {
+    let _ = CACHE.set(dir);
+}
+
+/// The crit on PATH, by path, size and mtime: a new install decodes anew.
+/// A remote one is known by its host only.
+fn crit_identity() -> &'static str {
+    static IDENTITY: OnceLock<String> = OnceLock::new();
+    IDENTITY.get_or_init(|| {
+        if let Some(host) = host() {
+            return format!("ssh {}", host);
+        }
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        std::env::split_paths(&path)
+            .map(|dir| dir.join("crit"))
+            .find_map(|crit| {
+                let meta = fs::metadata(&crit).ok()?;
+                let mtime = meta.modified().ok()?;
+                Some(format!("{} {} {:?}", crit.display(), meta.len(), mtime))
+            })
+            .unwrap_or_default()
+    })
+}
+
+/// Where the decode of `content` is cached, if it is.
+fn cache_path(content: &[u8]) -> Option<PathBuf> {
+    let dir = CACHE.get()?.as_deref()?;
+    let mut hasher = Sha256::new();
+    hasher.update(crit_identity());
+    hasher.update([0]);
+    hasher.update(content);
+    Some(dir.join(format!("{:x}.json", hasher.finalize())))
+}
+
+/// Keep `decoded` at `path`; a cache that cannot be written is no cache.
+fn cache_store(path: &Path, decoded: &[u8]) {
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    let stored = fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))
+        .and_then(|()| fs::write(&tmp, decoded))
+        .and_then(|()| fs::rename(&tmp, path));
+    if let Err(e) = stored {
+        let _ = fs::remove_file(&tmp);
+        eprintln!("Warning: decode cache {}: {}", path.display(), e);
+    }
+}
+
+/// The descriptors, loaded on first use.
+fn descriptors() -> Result<Option<&'static Descriptors>, String> {
+    static DESCRIPTORS: OnceLock<Result<Option<Descriptors>, String>> = OnceLock::new();
+    DESCRIPTORS
+        .get_or_init(Descriptors::load)
+        .as_ref()
+        .map(Option::as_ref)
+        .map_err(Clone::clone)
+}
+
+#[cfg(feature = "edit")]
+mod input {
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    /// What crit reads, in a memfd.
+    pub struct Input(OwnedFd);
+
+    impl Input {
+        pub fn new(content: &[u8]) -> Result<Input, String> {
+            // SAFETY: the name is NUL-terminated, and a descriptor returned
+            // by memfd_create is ours to own
+            let fd = unsafe {
+                let fd = libc::memfd_create(c"crit-input".as_ptr(), libc::MFD_CLOEXEC);
+                if fd < 0 {
+                    return Err(format!("memfd: {}", std::io::Error::last_os_error()));
+                }
+                OwnedFd::from_raw_fd(fd)
+            };
+            let mut file = File::from(fd);
+            file.write_all(content)
+                .map_err(|e| format!("memfd: {}", e))?;
+            Ok(Input(file.into()))
+        }
+
+        /// `command` with the memfd inherited and its path as the argument.
+        pub fn pass<'a>(&self, command: &'a mut Command) -> &'a mut Command {
+            let fd = self.0.as_raw_fd();
+            // SAFETY: fcntl is async-signal-safe and only touches the
+            // child's copy of the descriptor
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            command.arg(format!("/proc/self/fd/{}", fd))
+        }
+    }
+}
+
+#[cfg(not(feature = "edit"))]
+mod input {
+    use std::path::Path;
+    use std::process::Command;
+
+    /// What crit reads, in a temp file, in RAM when /dev/shm exists.
+    pub struct Input(tempfile::NamedTempFile);
+
+    impl Input {
+        pub fn new(content: &[u8]) -> Result<Input, String> {
+            let shm = Path::new("/dev/shm");
+            let file = if shm.is_dir() {
+                tempfile::NamedTempFile::new_in(shm)
+            } else {
+                tempfile::NamedTempFile::new()
+            };
+            let file = file.map_err(|e| e.to_string())?;
+            std::fs::write(file.path(), content).map_err(|e| e.to_string())?;
+            Ok(Input(file))
+        }
+
+        /// `command` with the temp file's path as the argument.
+        pub fn pass<'a>(&self, command: &'a mut Command) -> &'a mut Command {
+            command.arg(self.0.path())
+        }
+    }
+}
+
+/// Wait for `child` until `deadline`; None if it is still running then.
+fn wait_until(child: &mut Child, deadline: Instant) -> Result<Option<ExitStatus>, String> {
+    let mut pause = Duration::from_millis(1);
+    loop {
+        if let Some(status) = child.try_wait().map_err(|e| format!("run crit: {}", e))? {
+            return Ok(Some(status));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        std::thread::sleep(pause.min(deadline - now));
+        pause = (pause * 2).min(Duration::from_millis(50));
+    }
+}
+
+/// Collect what `pipe` yields on a thread, so a crit that hangs does not
+/// hang the read: what was read so far, and the thread.
+fn collect(
+    mut pipe: impl Read + Send + 'static,
+) -> (Arc<Mutex<Vec<u8>>>, JoinHandle<std::io::Result<()>>) {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let reader = std::thread::spawn({
+        let output = Arc::clone(&output);
+        move || {
+            let mut buf = [0; 64 << 10];
+            loop {
+                match pipe.read(&mut buf) {
+                    Ok(0) => return Ok(()),
+                    Ok(n) => output.lock().unwrap().extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    });
+    (output, reader)
+}
+
+/// The end of what crit printed to stderr, for an error.
+fn stderr_tail(stderr: &[u8]) -> String {
+    let text = String::from_utf8_lossy(stderr);
+    let lines: Vec<&str> = text.trim_end().lines().collect();
+    let tail = lines[lines.len().saturating_sub(20)..].join("\n");
+    if tail.is_empty() {
+        String::new()
+    } else {
+        format!(":\n{}", tail)
+    }
+}
+
+/// What a crit that hung had written, for the error.
+fn partial(output: &[u8]) -> String {
+    if output.is_empty() {
+        return "it had written nothing".to_string();
+    }
+    let tail = &output[output.len().saturating_sub(200)..];
+    format!(
+        "it had written {} bytes, ending {:?}",
+        output.len(),
+        String::from_utf8_lossy(tail)
+    )
+}
+
+/// Run crit with `args` on `content`, returning what it writes to stdout;
+/// killed if the run is interrupted or takes longer than the timeout.
+fn run(args: &[&str], content: &[u8]) -> Result<Vec<u8>, String> {
+    let input;
+    let mut command = match host() {
+        Some(host) => {
+            let mut argv = vec!["crit".to_string()];
+            argv.extend(args.iter().map(|a| a.to_string()));
+            argv.extend(["-i".to_string(), "/dev/stdin".to_string()]);
+            let mut command = remote::command(Some(host), &argv);
+            command.stdin(Stdio::piped());
+            command
+        }
+        None => {
+            input = input::Input::new(content)?;
+            let mut command = Command::new("crit");
+            command.args(args).arg("-i");
+            input.pass(&mut command).stdin(Stdio::null());
+            command
+        }
+    };
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("run crit: {}", e))?;
+    let _child = interrupt::kill(&child);
+    // Fed on a thread, while crit's output is read; a crit killed midway
+    // ends the write with EPIPE
+    let writer = child.stdin.take().map(|mut stdin| {
+        let content = content.to_vec();
+        std::thread::spawn(move || stdin.write_all(&content))
+    });
+    let (output, reader) = collect(child.stdout.take().ok_or("run crit: no stdout")?);
+    let (stderr, stderr_reader) = collect(child.stderr.take().ok_or("run crit: no stderr")?);
+    let status = match timeout() {
+        Some(timeout) => wait_until(&mut child, Instant::now() + timeout)?,
+        None => Some(child.wait().map_err(|e| format!("run crit: {}", e))?),
+    };
+    let on = host()
+        .map(|host| format!(" on {}", host))
+        .unwrap_or_default();
+    let Some(status) = status else {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(format!(
+            "crit {}{} did not finish in {} s (--crit-timeout), killed it; {}{}",
+            args[0],
+            on,
+            timeout().unwrap_or_default().as_secs(),
+            partial(&output.lock().unwrap()),
+            stderr_tail(&stderr.lock().unwrap())
+        ));
+    };
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    let read = reader
+        .join()
+        .map_err(|_| "read crit output: the reader panicked")?;
+    let _ = stderr_reader.join();
+    let stderr = std::mem::take(&mut *stderr.lock().unwrap());
+    if !status.success() {
+        return Err(format!(
+            "crit {}{} failed ({}){}",
+            args[0],
+            on,
+            status,
+            stderr_tail(&stderr)
+        ));
+    }
+    // Warnings, as if crit had printed them itself
+    let _ = std::io::stderr().write_all(&stderr);
+    read.map_err(|e| format!("read crit output: {}", e))?;
+    let output = std::mem::take(&mut *output.lock().unwrap());
+    Ok(output)
+}
+
+/// A number as crit renders it: plain, or a hex string.
+pub fn number(value: &Value) -> Option<u64> {
+    match value {
+        Value::String(s) => u64::from_str_radix(s.trim_start_matches("0x"), 16).ok(),
+        other => other.as_u64(),
+    }
+}
+
+/// Decode one image to JSON.
+pub fn decode(content: &[u8]) -> Result<Value, String> {
+    if let Some(descriptors) = descriptors()? {
+        if let Some(data) = descriptors.decode_image(content)? {
+            return Ok(data);
+        }
+    }
+    let cache = cache_path(content);
+    if let Some(data) = cache
+        .as_ref()
+        .and_then(|path| fs::read(path).ok())
+        .and_then(|cached| serde_json::from_slice(&cached).ok())
+    {
+        return Ok(data);
+    }
+    let decoded = run(&["decode"], content)?;
+    let data = serde_json::from_slice(&decoded).map_err(|e| format!("parse crit output: {}", e))?;
+    if let Some(path) = &cache {
+        cache_store(path, &decoded);
+    }
+    Ok(data)
+}
+
+/// Encode JSON as produced by `decode` back into an image.
+pub fn encode(data: &Value) -> Result<Vec<u8>, String> {
+    if let Some(descriptors) = descriptors()? {
+        if let Some(image) = descriptors.encode_image(data)? {
+            return Ok(image);
+        }
+    }
+    // Compact JSON is smaller and faster for crit encode to read
+    let json = serde_json::to_vec(data).map_err(|e| e.to_string())?;
+    run(&["encode"], &json)
+}
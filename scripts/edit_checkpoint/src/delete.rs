@@ -0,0 +1,241 @@
+//! `delete`: drop the entries of a checkpoint archive matching --glob in one
+//! streaming pass, e.g. oversized log files captured in the rootfs diff.
+//!
+//! The files of rootfs-diff.tar are matched as rootfs-diff.tar/PATH (`*`
+//! matches across slashes, so `*.log` takes logs anywhere). The entries the
+//! restore cannot do without (the CRIU images, config.dump, spec.dump,
+//! network.status and rootfs-diff.tar itself) are never deleted: a glob
+//! matching one is refused.
+
+use std::fs;
+use std::io::{self, Seek};
+use std::path::PathBuf;
+
+use clap::Args;
+
+use crate::compress;
+use crate::edit::RESTORE_INDEX_PATH;
+use crate::inject::entry_name;
+use crate::lock;
+use crate::metadata::{CONFIG_DUMP_PATH, NETWORK_STATUS_PATH, SPEC_DUMP_PATH};
+use crate::rewrite::Rewrite;
+use crate::sign::{self, MANIFEST_PATH, SIGNATURE_PATH};
+use crate::userns::ROOTFS_DIFF_PATH;
+use crate::watch::glob_match;
+
+#[derive(Args)]
+pub struct DeleteArgs {
+    /// Checkpoint archive, changed in place
+    archive: PathBuf,
+    /// Delete the entries matching PATTERN (repeatable); `*` and `?` match
+    /// any characters, slashes included
+    #[arg(long, value_name = "PATTERN", required = true)]
+    glob: Vec<String>,
+    /// List what would be deleted and leave the archive alone
+    #[arg(long, short = 'n')]
+    dry_run: bool,
+}
+
+/// Never deleted, matched as globs themselves.
+const PROTECTED: [&str; 6] = [
+    "checkpoint",
+    "checkpoint/*",
+    CONFIG_DUMP_PATH,
+    SPEC_DUMP_PATH,
+    NETWORK_STATUS_PATH,
+    ROOTFS_DIFF_PATH,
+];
+
+struct Globs<'a> {
+    patterns: &'a [String],
+    deleted: Vec<(String, u64)>,
+}
+
+impl Globs<'_> {
+    /// Whether the entry at `path` of `size` bytes is to be deleted.
+    fn matches(&mut self, path: &str, size: u64) -> Result<bool, String> {
+        let Some(glob) = self.patterns.iter().find(|g| glob_match(g, path)) else {
+            return Ok(false);
+        };
+        if PROTECTED.iter().any(|p| glob_match(p, path)) {
+            return Err(format!(
+                "{} matches {}, which the restore cannot do without; not deleting anything",
+                glob, path
+            ));
+        }
+        self.deleted.push((path.to_string(), size));
+        Ok(true)
+    }
+}
+
+/// Copy the files of the rootfs diff at `input` to `output` as a tar,
+/// leaving out the ones `globs` match.
+fn filter_rootfs_diff(
+    input: &fs::File,
+    output: &fs::File,
+    globs: &mut Globs,
+) -> Result<(), String> {
+    let mut archive = tar::Archive::new(input);
+    let mut builder = tar::Builder::new(output);
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let path = entry.path().map_err(|e| e.to_string())?.into_owned();
+        let size = entry.header().size().unwrap_or(0);
+        let name = format!(
+            "{}/{}",
+            ROOTFS_DIFF_PATH,
+            entry_name(&path.to_string_lossy())
+        );
+        if globs.matches(&name, size)? {
+            continue;
+        }
+        // Keep xattrs and the like; names are set anew
+        let pax: Vec<(String, Vec<u8>)> = match entry.pax_extensions().map_err(|e| e.to_string())? {
+            Some(exts) => exts
+                .filter_map(|e| e.ok())
+                .filter_map(|e| Some((e.key().ok()?.to_string(), e.value_bytes().to_vec())))
+                .filter(|(k, _)| !matches!(k.as_str(), "path" | "linkpath"))
+                .collect(),
+            None => Vec::new(),
+        };
+        if !pax.is_empty() {
+            builder
+                .append_pax_extensions(pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())))
+                .map_err(|e| e.to_string())?;
+        }
+        let mut header = entry.header().clone();
+        let link = entry
+            .link_name()
+            .map_err(|e| e.to_string())?
+            .map(|l| l.into_owned());
+        let result = match link {
+            Some(link) => builder.append_link(&mut header, &path, &link),
+            None => builder.append_data(&mut header, &path, &mut entry),
+        };
+        result.map_err(|e| format!("{}/{}: {}", ROOTFS_DIFF_PATH, path.display(), e))?;
+    }
+    builder.finish().map_err(|e| e.to_string())
+}
+
+/// Whether any file of the rootfs diff at `input` matches `globs`.
+fn rootfs_diff_matches(mut input: &fs::File, globs: &Globs) -> Result<bool, String> {
+    let mut archive = tar::Archive::new(input);
+    let mut matches = false;
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let path = entry.path().map_err(|e| e.to_string())?;
+        let name = format!(
+            "{}/{}",
+            ROOTFS_DIFF_PATH,
+            entry_name(&path.to_string_lossy())
+        );
+        if globs.patterns.iter().any(|g| glob_match(g, &name)) {
+            matches = true;
+            break;
+        }
+    }
+    input.rewind().map_err(|e| e.to_string())?;
+    Ok(matches)
+}
+
+pub fn run(args: &DeleteArgs) -> Result<(), String> {
+    let path = args.archive.as_path();
+    let _input_lock = lock::input(path)?;
+    let mut rewrite = if args.dry_run {
+        None
+    } else {
+        Some(Rewrite::start(path, path)?)
+    };
+    let mut globs = Globs {
+        patterns: &args.glob,
+        deleted: Vec::new(),
+    };
+    let mut archive = tar::Archive::new(compress::open(path)?);
+    let mut dropped_index = false;
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let name = entry_name(&entry.path().map_err(|e| e.to_string())?.to_string_lossy());
+        let mut header = entry.header().clone();
+        let size = header.size().unwrap_or(0);
+        if name == MANIFEST_PATH || name == SIGNATURE_PATH {
+            return Err(sign::refuse(&path.display().to_string()));
+        }
+        if globs.matches(&name, size)? {
+            continue;
+        }
+        if name == RESTORE_INDEX_PATH {
+            dropped_index = true;
+            continue;
+        }
+        if name == ROOTFS_DIFF_PATH {
+            let spill = || tempfile::tempfile().map_err(|e| format!("spill: {}", e));
+            let mut original = spill()?;
+            io::copy(&mut entry, &mut original)
+                .and_then(|_| original.rewind())
+                .map_err(|e| format!("spill {}: {}", name, e))?;
+            let mut content = original;
+            if rootfs_diff_matches(&content, &globs)? {
+                let filtered = spill()?;
+                filter_rootfs_diff(&content, &filtered, &mut globs)?;
+                content = filtered;
+                content.rewind().map_err(|e| e.to_string())?;
+            }
+            let len = content.metadata().map_err(|e| e.to_string())?.len();
+            if let Some(rewrite) = &mut rewrite {
+                header.set_size(len);
+                header.set_cksum();
+                rewrite
+                    .builder()
+                    .append(&header, content)
+                    .map_err(|e| format!("write {}: {}", name, e))?;
+            }
+            continue;
+        }
+        if let Some(rewrite) = &mut rewrite {
+            rewrite
+                .builder()
+                .append(&header, &mut entry)
+                .map_err(|e| format!("write {}: {}", name, e))?;
+        }
+    }
+
+    for (name, size) in &globs.deleted {
+        eprintln!(
+            "{}{} ({} bytes)",
+            if args.dry_run {
+                "Would delete "
+            } else {
+                "Deleted "
+            },
+            name,
+            size
+        );
+    }
+    let freed: u64 = globs.deleted.iter().map(|(_, size)| size).sum();
+    match rewrite {
+        Some(_) if globs.deleted.is_empty() => {
+            // Leaves the archive as it was
+            eprintln!("Nothing matches {}", args.glob.join(", "));
+            return Ok(());
+        }
+        Some(rewrite) => rewrite.finish(Some(path))?,
+        None => {}
+    }
+    eprintln!(
+        "{} {} entries, {} MiB",
+        if args.dry_run {
+            "Would delete"
+        } else {
+            "Deleted"
+        },
+        globs.deleted.len(),
+        freed >> 20
+    );
+    if dropped_index && !args.dry_run {
+        eprintln!(
+            "Left out {}, which lists the old entries; edit with --restore-order to write it again",
+            RESTORE_INDEX_PATH
+        );
+    }
+    Ok(())
+}
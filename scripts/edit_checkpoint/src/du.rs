@@ -0,0 +1,173 @@
+//! `du`: where the size of a checkpoint goes. Lists the largest entries of
+//! an archive (or files of an unpacked checkpoint) and the totals of each
+//! category: the memory pages, ghost files (deleted files still open),
+//! the other CRIU images, the rootfs diff and podman's metadata.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use serde_json::json;
+
+use crate::compress;
+use crate::report;
+
+#[derive(Args)]
+pub struct DuArgs {
+    /// Checkpoint archive or unpacked checkpoint directory
+    checkpoint: PathBuf,
+    /// List the N largest entries
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    top: usize,
+    /// Write JSON instead of the table
+    #[arg(long)]
+    json: bool,
+}
+
+const CATEGORIES: [&str; 6] = [
+    "pages",
+    "ghost files",
+    "images",
+    "rootfs-diff",
+    "metadata",
+    "other",
+];
+
+fn category(name: &str) -> &'static str {
+    let file = name.rsplit('/').next().unwrap_or(name);
+    if file.starts_with("pages-") && file.ends_with(".img") {
+        "pages"
+    } else if file.starts_with("ghost-file-") {
+        "ghost files"
+    } else if name == "rootfs-diff.tar" || name == "deleted.files" {
+        "rootfs-diff"
+    } else if file.ends_with(".img") || name.starts_with("checkpoint/") {
+        "images"
+    } else if !name.contains('/') {
+        "metadata"
+    } else {
+        "other"
+    }
+}
+
+/// The regular files of an archive, with their sizes.
+fn archive_files(path: &Path) -> Result<Vec<(String, u64)>, String> {
+    let mut archive = tar::Archive::new(compress::open(path)?);
+    let mut files = Vec::new();
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path().map_err(|e| e.to_string())?;
+        let name = name.to_string_lossy();
+        let name = name.trim_start_matches("./").trim_start_matches('/');
+        files.push((name.to_string(), entry.size()));
+    }
+    Ok(files)
+}
+
+/// The regular files under `dir`, named relative to it.
+fn dir_files(dir: &Path, prefix: &str, files: &mut Vec<(String, u64)>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("read {}: {}", dir.display(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("read {}: {}", dir.display(), e))?;
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let kind = entry.file_type().map_err(|e| e.to_string())?;
+        if kind.is_dir() {
+            dir_files(&entry.path(), &format!("{}/", name), files)?;
+        } else if kind.is_file() {
+            let meta = entry.metadata().map_err(|e| e.to_string())?;
+            files.push((name, meta.len()));
+        }
+    }
+    Ok(())
+}
+
+pub fn run(args: &DuArgs) -> Result<(), String> {
+    let path = args.checkpoint.as_path();
+    let mut files = Vec::new();
+    let on_disk = if path.is_dir() {
+        dir_files(path, "", &mut files)?;
+        None
+    } else {
+        files = archive_files(path)?;
+        let meta = fs::metadata(path).map_err(|e| format!("stat {}: {}", path.display(), e))?;
+        Some(meta.len())
+    };
+    let total: u64 = files.iter().map(|(_, size)| size).sum();
+    let mut totals: BTreeMap<&str, (u64, usize)> = BTreeMap::new();
+    for (name, size) in &files {
+        let sum = totals.entry(category(name)).or_default();
+        sum.0 += size;
+        sum.1 += 1;
+    }
+    files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let largest = &files[..args.top.min(files.len())];
+    let percent = |size: u64| {
+        if total == 0 {
+            0.0
+        } else {
+            size as f64 * 100.0 / total as f64
+        }
+    };
+
+    let out = if args.json {
+        let categories: serde_json::Map<String, serde_json::Value> = CATEGORIES
+            .iter()
+            .map(|c| {
+                let (bytes, entries) = totals.get(c).copied().unwrap_or_default();
+                (c.to_string(), json!({"bytes": bytes, "entries": entries}))
+            })
+            .collect();
+        let largest: Vec<_> = largest
+            .iter()
+            .map(|(name, size)| json!({"path": name, "bytes": size, "category": category(name)}))
+            .collect();
+        let doc = json!({
+            "total_bytes": total,
+            "entries": files.len(),
+            "archive_bytes": on_disk,
+            "categories": categories,
+            "largest": largest,
+        });
+        serde_json::to_string_pretty(&doc).map_err(|e| e.to_string())? + "\n"
+    } else {
+        let mut out = String::new();
+        for (name, size) in largest {
+            out += &format!(
+                "{:>6}  {:>5.1}%  {}\n",
+                report::human(*size),
+                percent(*size),
+                name
+            );
+        }
+        out += "\n";
+        for c in CATEGORIES {
+            let Some((bytes, entries)) = totals.get(c) else {
+                continue;
+            };
+            out += &format!(
+                "{:>6}  {:>5.1}%  {} ({} entries)\n",
+                report::human(*bytes),
+                percent(*bytes),
+                c,
+                entries
+            );
+        }
+        out += &format!(
+            "{:>6}          total ({} entries)",
+            report::human(total),
+            files.len()
+        );
+        if let Some(on_disk) = on_disk {
+            out += &format!(", {} archive", report::human(on_disk));
+        }
+        out + "\n"
+    };
+    std::io::stdout()
+        .write_all(out.as_bytes())
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,1506 @@
+//! The streaming edit pass: reads a checkpoint tar entry by entry, patches
+//! checkpoint/files.img (via crit), network.status and config.dump, and writes
+//! every entry to the output as it goes. Input and output can be files or pipes.
+//! Checkpoints unpacked on disk are patched in place instead (patch_dir).
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::action::{self, ActionPatch};
+use crate::applied::{self, APPLIED_PATH};
+use crate::arch::{self, PageAlignment, TargetPlatform};
+use crate::audit::{Audit, AUDIT_PATH};
+use crate::buffers::BufferConfig;
+use crate::cgroup;
+use crate::compress::OutputCompression;
+use crate::crit;
+use crate::filelocks;
+use crate::ghosts::{self, GhostPatch};
+use crate::images::{self, FILES_IMG_PATH};
+use crate::inaddr;
+use crate::integrity;
+use crate::inventory::{self, InventoryPatch, INVENTORY_PATH};
+use crate::metadata::{
+    self, AddrPatch, NetworkPatch, CONFIG_DUMP_PATH, NETWORK_STATUS_PATH, SPEC_DUMP_PATH,
+};
+use crate::nat64::{self, Nat64};
+use crate::policy::{self, Action, Rule};
+use crate::ports::{self, PortPatch};
+use crate::proto;
+use crate::redact::{self, Redaction};
+use crate::report::Report;
+use crate::resources::Estimate;
+use crate::resume::Progress;
+use crate::rootfs;
+use crate::secrets;
+use crate::security::{self, SecurityPatch};
+use crate::sign::{self, Signer, MANIFEST_PATH, SIGNATURE_PATH};
+use crate::sockets;
+use crate::spec::{self, CRIU_CONFIG_PATH};
+use crate::summary;
+use crate::timeline::{Timeline, TIMELINE_PATH};
+use crate::timens::{self, TimensPatch};
+use crate::tmpfs::{self, TmpfsPatch};
+use crate::trace;
+use crate::userns::{self, IdMaps, ROOTFS_DIFF_PATH};
+
+/// With --restore-order: the entries before it and after it.
+pub const RESTORE_INDEX_PATH: &str = "restore-index.json";
+
+/// The entries whose changes the summary lists value by value.
+const SUMMARIZED: [&str; 4] = [
+    FILES_IMG_PATH,
+    NETWORK_STATUS_PATH,
+    CONFIG_DUMP_PATH,
+    SPEC_DUMP_PATH,
+];
+
+/// Selects established TCP connections by a port on either end, or by the
+/// peer's address and optionally port.
+#[derive(Clone)]
+pub enum TcpClose {
+    Port(u16),
+    Peer(IpAddr, Option<u16>),
+}
+
+impl TcpClose {
+    fn matches(&self, isk: &serde_json::Value) -> bool {
+        let port = |key: &str| isk.get(key).and_then(|p| p.as_u64());
+        match self {
+            TcpClose::Port(p) => {
+                port("src_port") == Some(u64::from(*p)) || port("dst_port") == Some(u64::from(*p))
+            }
+            TcpClose::Peer(addr, p) => {
+                // A dual-stack socket has IPv4 peers as ::ffff:a.b.c.d
+                let peer = isk.get("dst_addr").and_then(inaddr::decode);
+                peer.map(|a| a.to_canonical()) == Some(addr.to_canonical())
+                    && p.is_none_or(|p| port("dst_port") == Some(u64::from(p)))
+            }
+        }
+    }
+}
+
+/// Options of one edit pass beyond the address pair.
+#[derive(Default)]
+pub struct EditOptions {
+    pub secondary: bool,
+    pub dns_servers: Option<Vec<String>>,
+    pub dns_search: Option<Vec<String>>,
+    pub aliases: Option<Vec<String>>,
+    /// CRIU restore options written to criu-restore.conf.
+    pub criu_opts: Option<Vec<String>>,
+    /// CRIU action scripts to add (and run from criu-restore.conf).
+    pub action: ActionPatch,
+    /// Established TCP connections to close at restore instead of repairing.
+    pub tcp_close: Vec<TcpClose>,
+    /// Per-socket actions, overriding the wildcarding of old_addr.
+    pub socket_rules: Vec<Rule>,
+    /// Prefix to translate connected sockets' peers through, and the
+    /// container's IPv6 address to connect them from.
+    pub nat64: Option<Nat64>,
+    pub nat64_src: Option<Ipv6Addr>,
+    /// Ports taken on the target, to move listeners off.
+    pub ports: PortPatch,
+    pub security: SecurityPatch,
+    /// New user namespace mappings (empty: none, as rootful podman).
+    pub idmap: Option<IdMaps>,
+    pub timens: TimensPatch,
+    /// Path and content rules for the tmpfs images.
+    pub tmpfs: TmpfsPatch,
+    /// Fields of inventory.img to set or clear.
+    pub inventory: InventoryPatch,
+    /// OLD=NEW prefixes of the host paths secrets are mounted from.
+    pub secrets_map: Vec<(String, String)>,
+    /// OLD=NEW prefixes of the paths of the files held open (and locked).
+    pub lock_path_map: Vec<(String, String)>,
+    /// OLD=NEW prefixes of the paths ghost files are recreated at.
+    pub ghosts: GhostPatch,
+    /// OLD=NEW names of the slices and cgroups the container runs under.
+    pub cgroup_map: Vec<(String, String)>,
+    /// OLD=NEW image references (names or IDs).
+    pub image_map: Vec<(String, String)>,
+    /// Interface and host for the duplicate-address probe of new_addr.
+    pub conflict_iface: Option<String>,
+    pub conflict_host: Option<String>,
+    /// Podman API of the target to check the image on.
+    pub image_check: Option<String>,
+    pub compression: OutputCompression,
+    /// Read and write the archive through io_uring.
+    pub io_uring: bool,
+    pub buffers: BufferConfig,
+    /// Move the pages images to the end, behind RESTORE_INDEX_PATH.
+    pub restore_order: bool,
+    /// Write the sidecar index of the edited archive.
+    pub index: bool,
+    /// Where to write the edited archive instead of over the input.
+    pub output: Option<String>,
+    /// Keep a journal to resume an interrupted edit from (see resume.rs).
+    pub resumable: bool,
+    /// Hash the entries copied unchanged, for integrity::verify.
+    pub verify_copy: bool,
+    /// Hash of the requested edit, recorded in APPLIED_PATH.
+    pub fingerprint: Option<String>,
+    /// Key to sign the edited archive with (and to edit a signed one).
+    pub signing_key: Option<ed25519_dalek::SigningKey>,
+    /// The archive is a pre-checkpoint (criu pre-dump): memory only, no
+    /// files.img.
+    pub pre_dump: bool,
+    /// The platform the checkpoint is to be restored on.
+    pub target: TargetPlatform,
+    /// Memory the target has for the restore, in bytes.
+    pub target_memory: Option<u64>,
+    /// Patterns to overwrite in the pages images.
+    pub redact: Redaction,
+}
+
+impl EditOptions {
+    pub fn network_patch<'a>(
+        &'a self,
+        old_addr: Ipv4Addr,
+        old_prefix: u8,
+        addr: AddrPatch<'a>,
+    ) -> NetworkPatch<'a> {
+        NetworkPatch {
+            old_addr,
+            old_prefix,
+            addr,
+            dns_servers: self.dns_servers.as_deref(),
+            dns_search: self.dns_search.as_deref(),
+            aliases: self.aliases.as_deref(),
+        }
+    }
+}
+
+/// Copy the archive from `input` to `output`, applying `net` on the way.
+/// Marks edit_start/edit_end on `timeline` and appends it as TIMELINE_PATH.
+/// Returns `output`, flushed.
+pub fn stream<W: Write>(
+    input: impl Read,
+    output: W,
+    net: &NetworkPatch,
+    opts: &EditOptions,
+    timeline: &mut Timeline,
+    report: &mut Report,
+) -> Result<W, String> {
+    let mut archive = tar::Archive::new(input);
+    let entries = archive.entries().map_err(|e| e.to_string())?;
+    stream_entries(entries, output, None, None, net, opts, timeline, report)
+}
+
+/// Like `stream`, for an uncompressed archive in a regular file: entries
+/// that are not edited are copied from file to file in the kernel
+/// (copy_file_range) rather than through user-space buffers, which matters
+/// when the pages images are most of the archive. `output` should be a
+/// (buffered) File for that to happen. The tar headers and the edited
+/// entries are read in `read_buffer` chunks. With `progress` (and `output`
+/// its Tracked writer), the edit resumes or can be resumed, copying through
+/// user space.
+#[allow(clippy::too_many_arguments)]
+pub fn stream_file<W: Write>(
+    input: &Path,
+    read_buffer: usize,
+    output: W,
+    progress: Option<&Progress>,
+    net: &NetworkPatch,
+    opts: &EditOptions,
+    timeline: &mut Timeline,
+    report: &mut Report,
+) -> Result<W, String> {
+    let open = || fs::File::open(input).map_err(|e| format!("open {}: {}", input.display(), e));
+    let mut archive = tar::Archive::new(std::io::BufReader::with_capacity(read_buffer, open()?));
+    let entries = archive.entries_with_seek().map_err(|e| e.to_string())?;
+    // Its own file offset, apart from the tar reader's
+    let source = open()?;
+    stream_entries(
+        entries,
+        output,
+        Some(&source),
+        progress,
+        net,
+        opts,
+        timeline,
+        report,
+    )
+}
+
+/// Whether the entry at `path` is looked at (and maybe patched) by
+/// stream_entries; the rest are copied as they are. Keep in step with the
+/// chain there.
+fn edits(path: &str, opts: &EditOptions) -> bool {
+    let metadata = [
+        TIMELINE_PATH,
+        RESTORE_INDEX_PATH,
+        APPLIED_PATH,
+        AUDIT_PATH,
+        MANIFEST_PATH,
+        SIGNATURE_PATH,
+        CRIU_CONFIG_PATH,
+        FILES_IMG_PATH,
+        NETWORK_STATUS_PATH,
+        CONFIG_DUMP_PATH,
+        SPEC_DUMP_PATH,
+    ];
+    metadata.contains(&path)
+        || (opts.security.strips_seccomp() && is_core_img(path))
+        || (!opts.timens.is_empty() && timens::is_timens_img(path))
+        || (path == ROOTFS_DIFF_PATH && opts.idmap.is_some())
+        || (!opts.action.is_empty() && action::is_action_script(path))
+        || (opts.target.arch.is_some() && is_core_img(path))
+        || (opts.target.page_size.is_some() && arch::is_pagemap_img(path))
+        || (!opts.redact.is_empty() && is_pages_img(path))
+        || (!opts.lock_path_map.is_empty() && path == filelocks::LOCKS_PATH)
+        || (!opts.tmpfs.is_empty() && tmpfs::is_tmpfs_img(path))
+        || (!opts.inventory.is_empty() && path == INVENTORY_PATH)
+}
+
+/// The edit pass over `entries`. With `passthrough` (the archive file),
+/// entries that are not edited are copied straight from it; with `progress`,
+/// unless a resumed run has them already (see resume.rs).
+#[allow(clippy::too_many_arguments)]
+fn stream_entries<R: Read, W: Write>(
+    entries: tar::Entries<R>,
+    output: W,
+    passthrough: Option<&fs::File>,
+    progress: Option<&Progress>,
+    net: &NetworkPatch,
+    opts: &EditOptions,
+    timeline: &mut Timeline,
+    report: &mut Report,
+) -> Result<W, String> {
+    let show_timing = env::var("EDIT_CHECKPOINT_TIMING").is_ok();
+    let t0 = Instant::now();
+    timeline.mark("edit_start");
+    let _span = trace::span("tar stream");
+
+    let mut builder = tar::Builder::new(Signer::new(output, opts.signing_key.is_some()));
+    let mut passthrough_bytes = 0u64;
+    let mut found_files_img = false;
+    let mut found_timens = false;
+    let mut found_spec = false;
+    let mut found_core = false;
+    let mut found_locks = false;
+    let mut found_inventory = false;
+    let mut pages = PageAlignment::default();
+    let mut estimate = Estimate::default();
+    let mut redacted = vec![0; opts.redact.patterns.len()];
+    let mut redacted_images = 0;
+    let mut old_idmap = None;
+    // Where podman unpacks the action scripts on the target
+    let mut userdata = None;
+    let mut patched_entries: Vec<&str> = Vec::new();
+    let mut stripped_cores: Vec<String> = Vec::new();
+    // Whether any entry's content changes; the edit is a noop if none does
+    let mut changed = false;
+    // The entries rewritten at the end, as they were
+    let mut rewritten: Vec<(String, Vec<u8>)> = Vec::new();
+    // --restore-order: the entries written so far and those held back
+    let mut head: Vec<(String, u64)> = Vec::new();
+    let mut tail = Deferred::default();
+    let mut audit = Audit::default();
+    // First, so it is seen before the metadata the next edit reads
+    if let Some(fingerprint) = &opts.fingerprint {
+        let marker = applied::marker(fingerprint);
+        match progress {
+            // Stamped as by the run resumed, which wrote it
+            Some(progress) => append_stamped(
+                &mut builder,
+                APPLIED_PATH,
+                &marker,
+                0o644,
+                progress.started(),
+            )?,
+            None => append_new(&mut builder, APPLIED_PATH, &marker)?,
+        }
+        head.push((APPLIED_PATH.to_string(), marker.len() as u64));
+    }
+
+    for (index, entry) in entries.enumerate() {
+        if let Some(progress) = progress {
+            progress.checkpoint(index as u64, builder.get_mut())?;
+        }
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let path = entry
+            .path()
+            .map_err(|e| e.to_string())?
+            .display()
+            .to_string()
+            .replace('\\', "/");
+        estimate.add(
+            &path,
+            entry.header().entry_size().map_err(|e| e.to_string())?,
+        );
+        if opts.restore_order && is_pages_img(&path) {
+            tail.push(&mut entry, path, passthrough.is_some())?;
+            continue;
+        }
+        if !opts.redact.is_empty() && is_pages_img(&path) {
+            // Streamed: pages images are the bulk of the archive
+            let header = entry.header().clone();
+            let mut redactor = opts.redact.reader(&mut entry);
+            builder
+                .append(&header, &mut redactor)
+                .map_err(|e| format!("redact {}: {}", path, e))?;
+            redact::add(&mut redacted, &redactor.matches);
+            changed |= redactor.matches.iter().any(|m| *m > 0);
+            redacted_images += 1;
+            let size = header.entry_size().map_err(|e| e.to_string())?;
+            head.push((path, size));
+            continue;
+        }
+        if let Some(source) = passthrough.filter(|_| !edits(&path, opts)) {
+            let size = entry.header().entry_size().map_err(|e| e.to_string())?;
+            head.push((path.clone(), size));
+            if !progress.is_some_and(|p| p.skip(size)) {
+                copy_raw(
+                    &mut builder,
+                    entry.header(),
+                    source,
+                    entry.raw_file_position(),
+                    size,
+                )
+                .map_err(|e| format!("copy {}: {}", path, e))?;
+            }
+            if opts.verify_copy {
+                let sha256 =
+                    integrity::digest_at(entry.header(), source, entry.raw_file_position(), size)
+                        .map_err(|e| format!("hash {}: {}", path, e))?;
+                integrity::record(report, &path, sha256);
+            }
+            passthrough_bytes += size;
+            continue;
+        }
+        let size_hint = entry.header().size().unwrap_or(0) as usize;
+        let mut content = Vec::with_capacity(size_hint.clamp(4096, 64 * 1024 * 1024));
+        entry.read_to_end(&mut content).map_err(|e| e.to_string())?;
+
+        // One task's core image tells the architecture of all
+        if is_core_img(&path) && !found_core {
+            found_core = true;
+            opts.target.check_core(&path, &content, report)?;
+        }
+        if opts.target.page_size.is_some() && arch::is_pagemap_img(&path) {
+            pages.add_pagemap(&content)?;
+        }
+        let patched = if path == TIMELINE_PATH {
+            // Rewritten at the end with this run's marks
+            timeline.merge_entry(&content);
+            continue;
+        } else if path == RESTORE_INDEX_PATH {
+            // Stale once anything is edited; rewritten with --restore-order
+            continue;
+        } else if path == APPLIED_PATH {
+            // Written first above, for this edit
+            continue;
+        } else if path == MANIFEST_PATH || path == SIGNATURE_PATH {
+            // Signed again at the end, if at all
+            if opts.signing_key.is_none() {
+                return Err(sign::refuse("the checkpoint"));
+            }
+            continue;
+        } else if path == AUDIT_PATH {
+            // Rewritten at the end with this edit's entry added
+            audit.merge_entry(&content);
+            continue;
+        } else if path == CRIU_CONFIG_PATH && opts.criu_opts.is_some() {
+            // Rewritten at the end with the new options
+            rewritten.push((path, content));
+            continue;
+        } else if !opts.action.is_empty() && action::is_action_script(&path) {
+            // Rewritten at the end
+            rewritten.push((path, content));
+            continue;
+        } else if path == FILES_IMG_PATH {
+            found_files_img = true;
+            if show_timing {
+                eprintln!("  tar stream:    {:>6} ms (read)", t0.elapsed().as_millis());
+            }
+            patched_entries.push(FILES_IMG_PATH);
+            Some(patch_files_img(&content, net, opts, report)?)
+        } else if path == NETWORK_STATUS_PATH {
+            // Patch network.status: set the IP to new_addr (or add/strip it)
+            let span = trace::span("patch network.status");
+            let patched = metadata::patch_network_status(&content, net)?;
+            drop(span);
+            changed |= audit.record(NETWORK_STATUS_PATH, &content, &patched);
+            patched_entries.push(NETWORK_STATUS_PATH);
+            Some(patched)
+        } else if path == CONFIG_DUMP_PATH {
+            if !opts.ports.is_empty() && !found_files_img {
+                return Err(format!(
+                    "{} precedes {} in the archive; unpack it to move ports",
+                    CONFIG_DUMP_PATH, FILES_IMG_PATH
+                ));
+            }
+            // Patch config.dump: set staticIP to new_addr (or add/remove it)
+            let span = trace::span("patch config.dump");
+            let patched = patch_config(&content, net, opts, report)?;
+            drop(span);
+            changed |= audit.record(CONFIG_DUMP_PATH, &content, &patched);
+            patched_entries.push(CONFIG_DUMP_PATH);
+            Some(patched)
+        } else if path == SPEC_DUMP_PATH {
+            found_spec = true;
+            if opts.idmap.is_some() {
+                old_idmap = Some(userns::spec_idmaps(&content)?);
+            }
+            if !opts.action.is_empty() {
+                userdata = spec::userdata(&content);
+            }
+            let patched = patch_spec(&content, net, opts, None, report)?;
+            if let Some(patched) = &patched {
+                changed |= audit.record(SPEC_DUMP_PATH, &content, patched);
+                patched_entries.push(SPEC_DUMP_PATH);
+            }
+            patched
+        } else if opts.security.strips_seccomp() && is_core_img(&path) {
+            let _span = trace::span("strip seccomp");
+            images::check_version(&path, &content)?;
+            stripped_cores.push(path.clone());
+            Some(security::strip_core_seccomp(&content)?)
+        } else if !opts.timens.is_empty() && timens::is_timens_img(&path) {
+            found_timens = true;
+            patched_entries.push("timens");
+            images::check_version(&path, &content)?;
+            Some(timens::patch(&content, &opts.timens, report)?)
+        } else if !opts.tmpfs.is_empty() && tmpfs::is_tmpfs_img(&path) {
+            let _span = trace::span("patch tmpfs");
+            tmpfs::patch(&path, &content, &opts.tmpfs, net, report)?
+        } else if !opts.inventory.is_empty() && path == INVENTORY_PATH {
+            found_inventory = true;
+            patched_entries.push(INVENTORY_PATH);
+            images::check_version(&path, &content)?;
+            Some(inventory::patch(&content, &opts.inventory, report)?)
+        } else if !opts.lock_path_map.is_empty() && path == filelocks::LOCKS_PATH {
+            found_locks = true;
+            filelocks::count(&content, report)?;
+            None
+        } else if let (ROOTFS_DIFF_PATH, Some(idmap)) = (path.as_str(), &opts.idmap) {
+            let old = old_idmap.as_ref().ok_or_else(|| {
+                format!(
+                    "{} precedes {} in the archive; unpack it to remap IDs",
+                    ROOTFS_DIFF_PATH, SPEC_DUMP_PATH
+                )
+            })?;
+            let _span = trace::span("remap rootfs-diff.tar");
+            patched_entries.push(ROOTFS_DIFF_PATH);
+            Some(userns::remap_rootfs_diff(&content, old, idmap)?)
+        } else {
+            None
+        };
+        let mut header = entry.header().clone();
+        if let Some(patched) = &patched {
+            // The metadata entries' changes are the audit's, not their bytes
+            let metadata = [NETWORK_STATUS_PATH, CONFIG_DUMP_PATH, SPEC_DUMP_PATH];
+            changed |= !metadata.contains(&path.as_str()) && *patched != content;
+            header.set_size(patched.len() as u64);
+            content = patched.clone();
+        } else if opts.verify_copy {
+            integrity::record(report, &path, integrity::digest(&header, &content));
+        }
+        header.set_cksum();
+        builder
+            .append(&header, content.as_slice())
+            .map_err(|e| e.to_string())?;
+        head.push((path, content.len() as u64));
+    }
+
+    if !found_files_img && !opts.pre_dump {
+        return Err(format!("{} not found in archive", FILES_IMG_PATH));
+    }
+    if opts.target.arch.is_some() && !found_core && !opts.pre_dump {
+        report.warn("no core image: the checkpoint's architecture is not checked");
+    }
+    pages.check(&opts.target, report)?;
+    estimate.check(opts.target_memory, report)?;
+    security::record(&opts.security, report);
+    if !opts.timens.is_empty() && !found_timens {
+        report.warn("no timens image: the container has no time namespace to patch");
+    }
+    if !opts.lock_path_map.is_empty() && !found_locks && !opts.pre_dump {
+        filelocks::missing(report);
+    }
+    if !opts.inventory.is_empty() && !found_inventory {
+        report.warn("no inventory.img in the checkpoint to patch");
+    }
+    if let Some(criu_opts) = &opts.criu_opts {
+        if !found_spec {
+            eprintln!(
+                "Warning: no spec.dump; pass {} to CRIU at restore yourself",
+                CRIU_CONFIG_PATH
+            );
+        }
+        let mut criu_opts = criu_opts.clone();
+        if !opts.action.is_empty() {
+            let userdata = userdata.ok_or(
+                "cannot tell from spec.dump where the action scripts are unpacked at restore",
+            )?;
+            criu_opts.extend(opts.action.criu_opts(&userdata));
+            for (path, script) in opts.action.scripts(net) {
+                changed |= rewrites(&rewritten, path, &script);
+                append_entry(&mut builder, path, &script, 0o755)?;
+                head.push((path.to_string(), script.len() as u64));
+            }
+            eprintln!("Added the CRIU action scripts to {}", CRIU_CONFIG_PATH);
+        }
+        let config = spec::criu_config(&criu_opts);
+        changed |= rewrites(&rewritten, CRIU_CONFIG_PATH, &config);
+        append_new(&mut builder, CRIU_CONFIG_PATH, &config)?;
+        patched_entries.push(CRIU_CONFIG_PATH);
+        head.push((CRIU_CONFIG_PATH.to_string(), config.len() as u64));
+    }
+    if opts.restore_order {
+        let (matches, images) = tail.write(
+            &mut builder,
+            &head,
+            passthrough,
+            &opts.redact,
+            opts.verify_copy,
+            report,
+        )?;
+        redact::add(&mut redacted, &matches);
+        changed |= matches.iter().any(|m| *m > 0);
+        redacted_images += images;
+    }
+    opts.redact.record(&redacted, redacted_images, report);
+
+    append_new(&mut builder, AUDIT_PATH, &audit.to_json(net, opts))?;
+    timeline.mark("edit_end");
+    let stamps = serde_json::to_vec_pretty(&timeline.to_json()).map_err(|e| e.to_string())?;
+    append_new(&mut builder, TIMELINE_PATH, &stamps)?;
+    if let Some(key) = &opts.signing_key {
+        let (manifest, signature) = builder.get_mut().sign(key)?;
+        append_new(&mut builder, MANIFEST_PATH, &manifest)?;
+        append_new(&mut builder, SIGNATURE_PATH, &signature)?;
+        let key_id = sign::key_id(key);
+        eprintln!("Signed the edited archive with key {}", &key_id[..16]);
+        report.set("signed", key_id);
+    }
+
+    let mut output = builder
+        .into_inner()
+        .map_err(|e| e.to_string())?
+        .into_inner();
+    output.flush().map_err(|e| e.to_string())?;
+    summary::metadata(report, audit.changes());
+    patched_entries.extend(stripped_cores.iter().map(String::as_str));
+    summary::entries(report, &patched_entries, &SUMMARIZED);
+    summary::print(report);
+    report.set("patched_entries", patched_entries);
+    report.set("changed", changed);
+    if passthrough.is_some() {
+        report.set("passthrough_bytes", passthrough_bytes);
+    }
+    Ok(output)
+}
+
+/// Write `header` and `size` bytes of data from `source` at `offset`, padded
+/// to the tar block size. io::copy turns a File-to-File copy into
+/// copy_file_range (or sendfile/splice where that is not supported).
+fn copy_entry(
+    output: &mut impl Write,
+    header: &tar::Header,
+    mut source: &fs::File,
+    offset: u64,
+    size: u64,
+) -> std::io::Result<()> {
+    use std::io::{Seek, SeekFrom};
+    output.write_all(header.as_bytes())?;
+    source.seek(SeekFrom::Start(offset))?;
+    let copied = std::io::copy(&mut source.take(size), output)?;
+    if copied != size {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let padding = (512 - size % 512) % 512;
+    output.write_all(&[0; 512][..padding as usize])
+}
+
+/// copy_entry into the archive being built, straight to the output unless
+/// it is hashed for signing.
+fn copy_raw<W: Write>(
+    builder: &mut tar::Builder<Signer<W>>,
+    header: &tar::Header,
+    source: &fs::File,
+    offset: u64,
+    size: u64,
+) -> std::io::Result<()> {
+    let signer = builder.get_mut();
+    if signer.is_signing() {
+        copy_entry(signer, header, source, offset, size)
+    } else {
+        copy_entry(signer.get_mut(), header, source, offset, size)
+    }
+}
+
+/// checkpoint/pages-N.img, memory pages: most of the archive, and read by
+/// CRIU only once the tasks are restored.
+fn is_pages_img(path: &str) -> bool {
+    path.strip_prefix("checkpoint/pages-")
+        .and_then(|n| n.strip_suffix(".img"))
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Entries held back to the end of the archive by --restore-order: where
+/// their data is in the input archive or, when that is a stream, in a spill
+/// file under TMPDIR.
+#[derive(Default)]
+struct Deferred {
+    entries: Vec<(tar::Header, String, u64)>,
+    spill: Option<fs::File>,
+    spill_len: u64,
+}
+
+impl Deferred {
+    fn push<R: Read>(
+        &mut self,
+        entry: &mut tar::Entry<R>,
+        path: String,
+        in_archive: bool,
+    ) -> Result<(), String> {
+        let header = entry.header().clone();
+        let offset = if in_archive {
+            entry.raw_file_position()
+        } else {
+            let spill = match &mut self.spill {
+                Some(spill) => spill,
+                spill => spill.insert(tempfile::tempfile().map_err(|e| format!("spill: {}", e))?),
+            };
+            let size = std::io::copy(entry, spill).map_err(|e| format!("spill {}: {}", path, e))?;
+            self.spill_len += size;
+            self.spill_len - size
+        };
+        self.entries.push((header, path, offset));
+        Ok(())
+    }
+
+    /// Append RESTORE_INDEX_PATH, listing the `head` entries already written
+    /// and the held back ones, then the held back entries.
+    fn write<W: Write>(
+        self,
+        builder: &mut tar::Builder<Signer<W>>,
+        head: &[(String, u64)],
+        passthrough: Option<&fs::File>,
+        redaction: &Redaction,
+        verify_copy: bool,
+        report: &mut Report,
+    ) -> Result<(Vec<u64>, usize), String> {
+        let mut sizes = Vec::with_capacity(self.entries.len());
+        for (header, _, _) in &self.entries {
+            sizes.push(header.entry_size().map_err(|e| e.to_string())?);
+        }
+        let entry = |path: &str, size: u64| serde_json::json!({"path": path, "size": size});
+        let index = serde_json::json!({
+            "head": head.iter().map(|(p, s)| entry(p, *s)).collect::<Vec<_>>(),
+            "tail": self
+                .entries
+                .iter()
+                .zip(&sizes)
+                .map(|((_, p, _), s)| entry(p, *s))
+                .collect::<Vec<_>>(),
+        });
+        let index = serde_json::to_vec_pretty(&index).map_err(|e| e.to_string())?;
+        append_new(builder, RESTORE_INDEX_PATH, &index)?;
+        let mut redacted = vec![0; redaction.patterns.len()];
+        if let Some(source) = self.spill.as_ref().or(passthrough) {
+            for ((header, path, offset), size) in self.entries.iter().zip(&sizes) {
+                if redaction.is_empty() {
+                    copy_raw(builder, header, source, *offset, *size)
+                        .map_err(|e| format!("copy {}: {}", path, e))?;
+                    if verify_copy {
+                        let sha256 = integrity::digest_at(header, source, *offset, *size)
+                            .map_err(|e| format!("hash {}: {}", path, e))?;
+                        integrity::record(report, path, sha256);
+                    }
+                    continue;
+                }
+                let matches = redaction
+                    .copy(source, *offset, *size, |data| builder.append(header, data))
+                    .map_err(|e| format!("redact {}: {}", path, e))?;
+                redact::add(&mut redacted, &matches);
+            }
+        }
+        let bytes: u64 = sizes.iter().sum();
+        eprintln!(
+            "Moved {} pages images ({} MiB) behind {}",
+            self.entries.len(),
+            bytes >> 20,
+            RESTORE_INDEX_PATH
+        );
+        report.set(
+            "restore_order",
+            serde_json::json!({"deferred": self.entries.len(), "deferred_bytes": bytes}),
+        );
+        let images = if redaction.is_empty() {
+            0
+        } else {
+            self.entries.len()
+        };
+        Ok((redacted, images))
+    }
+}
+
+/// checkpoint/core-PID.img, a task's core image.
+fn is_core_img(path: &str) -> bool {
+    path.strip_prefix("checkpoint/core-")
+        .and_then(|n| n.strip_suffix(".img"))
+        .is_some_and(|pid| !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Append an entry this edit adds to the archive.
+pub fn append_new(
+    builder: &mut tar::Builder<impl Write>,
+    path: &str,
+    content: &[u8],
+) -> Result<(), String> {
+    append_entry(builder, path, content, 0o644)
+}
+
+/// Like append_new, with file mode `mode`.
+fn append_entry(
+    builder: &mut tar::Builder<impl Write>,
+    path: &str,
+    content: &[u8],
+    mode: u32,
+) -> Result<(), String> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    append_stamped(builder, path, content, mode, now)
+}
+
+/// Like append_entry, modified at `mtime` rather than now.
+fn append_stamped(
+    builder: &mut tar::Builder<impl Write>,
+    path: &str,
+    content: &[u8],
+    mode: u32,
+    mtime: u64,
+) -> Result<(), String> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(mode);
+    header.set_mtime(mtime);
+    builder
+        .append_data(&mut header, path, content)
+        .map_err(|e| e.to_string())
+}
+
+/// Patch config.dump: the address and DNS settings, the security edits and
+/// the mount sources.
+fn patch_config(
+    content: &[u8],
+    net: &NetworkPatch,
+    opts: &EditOptions,
+    report: &mut Report,
+) -> Result<Vec<u8>, String> {
+    let mut patched = metadata::patch_config_dump(content, net)?;
+    if !opts.security.is_empty() {
+        patched = security::patch_config_dump(&patched, &opts.security)?;
+    }
+    if let Some(idmap) = &opts.idmap {
+        patched = userns::patch_config_dump(&patched, idmap)?;
+    }
+    if !opts.secrets_map.is_empty() {
+        let remapped;
+        (patched, remapped) = secrets::patch_config_dump(&patched, &opts.secrets_map)?;
+        secrets::record(CONFIG_DUMP_PATH, remapped, report);
+    }
+    if !opts.cgroup_map.is_empty() {
+        let remapped;
+        (patched, remapped) = cgroup::patch_config_dump(&patched, &opts.cgroup_map)?;
+        cgroup::record(CONFIG_DUMP_PATH, remapped, report);
+    }
+    if !opts.image_map.is_empty() {
+        patched = rootfs::patch_config_dump(&patched, &opts.image_map, report)?;
+    }
+    if !opts.ports.is_empty() {
+        patched = ports::patch_config_dump(&patched, report)?;
+    }
+    Ok(patched)
+}
+
+/// Patch spec.dump: the CRI-O pod addresses, and the runtime spec edits in
+/// `opts`. `userdata` is where the checkpoint is unpacked, if known. Returns
+/// None when nothing applies.
+fn patch_spec(
+    content: &[u8],
+    net: &NetworkPatch,
+    opts: &EditOptions,
+    userdata: Option<&Path>,
+    report: &mut Report,
+) -> Result<Option<Vec<u8>>, String> {
+    let _span = trace::span("patch spec.dump");
+    let mut patched = None;
+    if metadata::is_crio_spec(content) {
+        // CRI-O checkpoint: the pod address lives in spec.dump annotations
+        report.set("layout", "cri-o");
+        patched = Some(metadata::patch_spec_dump(content, net)?);
+    }
+    if !opts.security.is_empty() {
+        let current = patched.as_deref().unwrap_or(content);
+        patched = Some(security::patch_spec_dump(current, &opts.security)?);
+    }
+    if let Some(idmap) = &opts.idmap {
+        let current = patched.as_deref().unwrap_or(content);
+        patched = Some(userns::patch_spec_dump(current, idmap)?);
+        eprintln!("Replaced the user namespace mappings in spec.dump");
+    }
+    if !opts.secrets_map.is_empty() {
+        let current = patched.as_deref().unwrap_or(content);
+        let (content, remapped) = secrets::patch_spec_dump(current, &opts.secrets_map)?;
+        secrets::record(SPEC_DUMP_PATH, remapped, report);
+        patched = Some(content);
+    }
+    if !opts.cgroup_map.is_empty() {
+        let current = patched.as_deref().unwrap_or(content);
+        let (content, remapped) = cgroup::patch_spec_dump(current, &opts.cgroup_map)?;
+        cgroup::record(SPEC_DUMP_PATH, remapped, report);
+        patched = Some(content);
+    }
+    if !opts.image_map.is_empty() && metadata::is_crio_spec(content) {
+        let current = patched.as_deref().unwrap_or(content);
+        patched = Some(rootfs::patch_spec_dump(current, &opts.image_map, report)?);
+    }
+    if opts.criu_opts.is_some() {
+        let current = patched.as_deref().unwrap_or(content);
+        patched = Some(spec::set_criu_config(current, userdata)?);
+        eprintln!("Pointed spec.dump org.criu.config at {}", CRIU_CONFIG_PATH);
+    }
+    Ok(patched)
+}
+
+/// Check the core and pagemap images in `images` against `target`, before
+/// anything is patched in place.
+fn check_platform_dir(
+    images: &Path,
+    target: &TargetPlatform,
+    report: &mut Report,
+) -> Result<(), String> {
+    let entries = fs::read_dir(images).map_err(|e| e.to_string())?;
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| format!("checkpoint/{}", e.file_name().to_string_lossy()))
+        .collect();
+    names.sort();
+    let read = |name: &str| {
+        let path = images.join(name.trim_start_matches("checkpoint/"));
+        fs::read(&path).map_err(|e| format!("read {}: {}", path.display(), e))
+    };
+    if let Some(core) = names.iter().find(|n| is_core_img(n)) {
+        target.check_core(core, &read(core)?, report)?;
+    }
+    let mut pages = PageAlignment::default();
+    if target.page_size.is_some() {
+        for pagemap in names.iter().filter(|n| arch::is_pagemap_img(n)) {
+            pages.add_pagemap(&read(pagemap)?)?;
+        }
+    }
+    pages.check(target, report)
+}
+
+/// Patch an unpacked checkpoint in place: files.img in `images`, and
+/// network.status, config.dump and a CRI-O spec.dump in `root` if given.
+pub fn patch_dir(
+    images: &Path,
+    root: Option<&Path>,
+    net: &NetworkPatch,
+    opts: &EditOptions,
+    report: &mut Report,
+) -> Result<(), String> {
+    if root.is_none() && opts.criu_opts.is_some() {
+        return Err("CRIU options need the checkpoint's metadata directory".to_string());
+    }
+    if root.is_some_and(|root| root.join(SIGNATURE_PATH).exists()) {
+        return Err(format!(
+            "{} is signed; edit the packed archive with --sign-key to re-sign it",
+            images.display()
+        ));
+    }
+    if !opts.target.is_empty() {
+        check_platform_dir(images, &opts.target, report)?;
+    }
+    // Patched first, for the open files the estimate counts
+    let files_img = images.join("files.img");
+    let content =
+        fs::read(&files_img).map_err(|e| format!("read {}: {}", files_img.display(), e))?;
+    let patched_files_img = patch_files_img(&content, net, opts, report)?;
+    // Whether any file's content changes; the edit is a noop if none does
+    let mut changed = patched_files_img != content;
+    if !opts.lock_path_map.is_empty() {
+        match fs::read(images.join("file-locks.img")) {
+            Ok(content) => filelocks::count(&content, report)?,
+            Err(_) => filelocks::missing(report),
+        }
+    }
+    let mut estimate = Estimate::default();
+    estimate.add_dir(images, root)?;
+    estimate.check(opts.target_memory, report)?;
+    let mut patched_entries: Vec<&str> = Vec::new();
+    let mut stripped_cores: Vec<String> = Vec::new();
+    let mut redacted = vec![0; opts.redact.patterns.len()];
+    let mut redacted_images = 0;
+    if !opts.inventory.is_empty() {
+        let path = images.join("inventory.img");
+        match fs::read(&path) {
+            Ok(content) => {
+                images::check_version(INVENTORY_PATH, &content)?;
+                let patched = inventory::patch(&content, &opts.inventory, report)?;
+                changed |= patched != content;
+                replace(&path, &patched)?;
+                patched_entries.push(INVENTORY_PATH);
+            }
+            Err(_) => report.warn("no inventory.img in the checkpoint to patch"),
+        }
+    }
+    if opts.security.strips_seccomp()
+        || !opts.timens.is_empty()
+        || !opts.redact.is_empty()
+        || !opts.tmpfs.is_empty()
+    {
+        let entries = fs::read_dir(images).map_err(|e| e.to_string())?;
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let name = format!("checkpoint/{}", name);
+            if opts.security.strips_seccomp() && is_core_img(&name) {
+                let content = fs::read(&path).map_err(|e| e.to_string())?;
+                images::check_version(&name, &content)?;
+                let patched = security::strip_core_seccomp(&content)?;
+                changed |= patched != content;
+                replace(&path, &patched)?;
+                stripped_cores.push(name);
+            } else if !opts.timens.is_empty() && timens::is_timens_img(&name) {
+                let content = fs::read(&path).map_err(|e| e.to_string())?;
+                images::check_version(&name, &content)?;
+                let patched = timens::patch(&content, &opts.timens, report)?;
+                changed |= patched != content;
+                replace(&path, &patched)?;
+                patched_entries.push("timens");
+            } else if !opts.tmpfs.is_empty() && tmpfs::is_tmpfs_img(&name) {
+                let content = fs::read(&path).map_err(|e| e.to_string())?;
+                if let Some(patched) = tmpfs::patch(&name, &content, &opts.tmpfs, net, report)? {
+                    changed |= patched != content;
+                    replace(&path, &patched)?;
+                }
+            } else if !opts.redact.is_empty() && is_pages_img(&name) {
+                let matches = opts.redact.in_place(&path)?;
+                changed |= matches.iter().any(|m| *m > 0);
+                redact::add(&mut redacted, &matches);
+                redacted_images += 1;
+            }
+        }
+        if !opts.timens.is_empty() && !patched_entries.contains(&"timens") {
+            report.warn("no timens image: the container has no time namespace to patch");
+        }
+    }
+    security::record(&opts.security, report);
+    opts.redact.record(&redacted, redacted_images, report);
+    replace(&files_img, &patched_files_img)?;
+    patched_entries.push(FILES_IMG_PATH);
+
+    if let Some(root) = root {
+        let mut audit = Audit::default();
+        if let Ok(content) = fs::read(root.join(AUDIT_PATH)) {
+            audit.merge_entry(&content);
+        }
+        let status = root.join(NETWORK_STATUS_PATH);
+        if let Ok(content) = fs::read(&status) {
+            let patched = metadata::patch_network_status(&content, net)?;
+            changed |= audit.record(NETWORK_STATUS_PATH, &content, &patched);
+            replace(&status, &patched)?;
+            patched_entries.push(NETWORK_STATUS_PATH);
+        }
+        let config = root.join(CONFIG_DUMP_PATH);
+        if let Ok(content) = fs::read(&config) {
+            let patched = patch_config(&content, net, opts, report)?;
+            changed |= audit.record(CONFIG_DUMP_PATH, &content, &patched);
+            replace(&config, &patched)?;
+            patched_entries.push(CONFIG_DUMP_PATH);
+        }
+        let spec = root.join(SPEC_DUMP_PATH);
+        let rootfs_diff = root.join(ROOTFS_DIFF_PATH);
+        if let (Some(idmap), Ok(content)) = (&opts.idmap, fs::read(&rootfs_diff)) {
+            let spec = fs::read(&spec).map_err(|e| format!("read {}: {}", spec.display(), e))?;
+            let old = userns::spec_idmaps(&spec)?;
+            let patched = userns::remap_rootfs_diff(&content, &old, idmap)?;
+            changed |= patched != content;
+            replace(&rootfs_diff, &patched)?;
+            patched_entries.push(ROOTFS_DIFF_PATH);
+        }
+        if let Ok(content) = fs::read(&spec) {
+            let root = fs::canonicalize(root).map_err(|e| e.to_string())?;
+            if let Some(patched) = patch_spec(&content, net, opts, Some(&root), report)? {
+                changed |= audit.record(SPEC_DUMP_PATH, &content, &patched);
+                replace(&spec, &patched)?;
+                patched_entries.push(SPEC_DUMP_PATH);
+            }
+        }
+        if let Some(criu_opts) = &opts.criu_opts {
+            let mut criu_opts = criu_opts.clone();
+            if !opts.action.is_empty() {
+                use std::os::unix::fs::PermissionsExt;
+                let root = fs::canonicalize(root).map_err(|e| e.to_string())?;
+                criu_opts.extend(opts.action.criu_opts(&root));
+                for (path, script) in opts.action.scripts(net) {
+                    changed |= fs::read(root.join(path)).map_or(true, |old| old != script);
+                    replace(&root.join(path), &script)?;
+                    fs::set_permissions(root.join(path), fs::Permissions::from_mode(0o755))
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+            let config = spec::criu_config(&criu_opts);
+            let path = root.join(CRIU_CONFIG_PATH);
+            changed |= fs::read(&path).map_or(true, |old| old != config);
+            replace(&path, &config)?;
+            patched_entries.push(CRIU_CONFIG_PATH);
+        }
+        replace(&root.join(AUDIT_PATH), &audit.to_json(net, opts))?;
+        summary::metadata(report, audit.changes());
+        let marker = root.join(APPLIED_PATH);
+        match &opts.fingerprint {
+            Some(fingerprint) => replace(&marker, &applied::marker(fingerprint))?,
+            None => {
+                let _ = fs::remove_file(&marker);
+            }
+        }
+    }
+    patched_entries.extend(stripped_cores.iter().map(String::as_str));
+    summary::entries(report, &patched_entries, &SUMMARIZED);
+    summary::print(report);
+    report.set("patched_entries", patched_entries);
+    report.set("changed", changed);
+    Ok(())
+}
+
+/// Whether writing `content` at `path` changes the entry `old` had there.
+fn rewrites(old: &[(String, Vec<u8>)], path: &str, content: &[u8]) -> bool {
+    !old.iter().any(|(p, c)| p == path && c == content)
+}
+
+/// Write `content` next to `path` and rename it over, so a failed edit never
+/// leaves a truncated file behind.
+pub fn replace(path: &Path, content: &[u8]) -> Result<(), String> {
+    let tmp = path.with_extension("new");
+    fs::write(&tmp, content).map_err(|e| format!("write {}: {}", tmp.display(), e))?;
+    if path.exists() {
+        copy_owner(path, &tmp)?;
+    }
+    fs::rename(&tmp, path).map_err(|e| format!("rename {}: {}", tmp.display(), e))
+}
+
+/// Give `to` the mode and (when running as root) the owner of `from`, so a
+/// rewritten file stays usable by rootless podman when edited as root.
+pub fn copy_owner(from: &Path, to: &Path) -> Result<(), String> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = fs::metadata(from).map_err(|e| format!("stat {}: {}", from.display(), e))?;
+    fs::set_permissions(to, meta.permissions()).map_err(|e| e.to_string())?;
+    let owned = fs::metadata(to).map_err(|e| e.to_string())?;
+    if (owned.uid(), owned.gid()) != (meta.uid(), meta.gid()) {
+        if let Err(e) = std::os::unix::fs::chown(to, Some(meta.uid()), Some(meta.gid())) {
+            eprintln!(
+                "Warning: cannot keep the owner of {}: {}",
+                from.display(),
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Patch sockets bound to old_addr in a files.img as the socket rules say,
+/// mark the `tcp_close` connections closed, move listeners off the target's
+/// taken ports and translate the connected sockets through `nat64`,
+/// returning the new image.
+pub fn patch_files_img(
+    content: &[u8],
+    net: &NetworkPatch,
+    opts: &EditOptions,
+    report: &mut Report,
+) -> Result<Vec<u8>, String> {
+    let show_timing = env::var("EDIT_CHECKPOINT_TIMING").is_ok();
+    let t1 = Instant::now();
+    images::check_version(FILES_IMG_PATH, content)?;
+    let mut span = trace::span("crit decode");
+    span.attr("size", content.len());
+    // Only the inet sockets (and regular files, to remap) are decoded, the
+    // other entries are kept as they are; all of them if the image does not
+    // split
+    let types: &[u64] = if opts.lock_path_map.is_empty() && opts.ghosts.is_empty() {
+        &[proto::FD_TYPE_INETSK]
+    } else {
+        &[proto::FD_TYPE_INETSK, proto::FD_TYPE_REG]
+    };
+    let split = proto::Split::new(content, proto::FILE_ENTRY_TYPE, types);
+    let mut data = match &split {
+        Some(split) => {
+            let picked = split.picked();
+            span.attr("decoded_size", picked.len());
+            crit::decode(&picked)?
+        }
+        None => crit::decode(content)?,
+    };
+    drop(span);
+    policy::check_resolved(&opts.socket_rules)?;
+    if show_timing {
+        eprintln!("  crit decode:   {:>6} ms", t1.elapsed().as_millis());
+    }
+    let t2 = Instant::now();
+    let mut span = trace::span("patch files.img");
+    let before = socket_rows(&data);
+    let old_addr = net.old_addr;
+    let new_addr = match net.addr {
+        AddrPatch::Replace(addr) | AddrPatch::Add(addr) => addr.parse().ok(),
+        AddrPatch::Clear => None,
+    };
+    let sockets = patch_files_img_json(&mut data, old_addr, new_addr, &opts.socket_rules)?;
+    let patched = sockets.wildcarded + sockets.rewritten;
+    span.attr("sockets_patched", patched);
+    report.set("sockets_patched", patched);
+    if !opts.socket_rules.is_empty() {
+        report.set("sockets_rewritten", sockets.rewritten);
+        report.set("sockets_skipped", sockets.skipped.clone());
+    }
+    if sockets.mapped > 0 {
+        report.set("sockets_v4_mapped", sockets.mapped);
+    }
+    if patched as usize + sockets.skipped.len() == 0 {
+        eprintln!(
+            "Note: no INETSK entries bound to {} found in files.img (server likely uses 0.0.0.0 — OK)",
+            old_addr
+        );
+    }
+    let rules_close = opts
+        .socket_rules
+        .iter()
+        .any(|r| r.action() == Action::Close);
+    if !opts.tcp_close.is_empty() || rules_close {
+        let closed = close_tcp(&mut data, &opts.tcp_close, &opts.socket_rules);
+        eprintln!("Marked {} TCP connections to close at restore", closed);
+        span.attr("tcp_closed", closed);
+        report.set("tcp_closed", closed);
+    }
+    if !opts.ports.is_empty() {
+        ports::remap(&mut data, &opts.ports, report)?;
+    }
+    if let Some(prefix) = &opts.nat64 {
+        let translated = nat64::translate(&mut data, prefix, opts.nat64_src)?;
+        span.attr("nat64_translated", translated);
+        report.set("nat64_translated", translated);
+    }
+    if !opts.lock_path_map.is_empty() {
+        filelocks::remap(&mut data, &opts.lock_path_map, report);
+    }
+    if !opts.ghosts.is_empty() {
+        ghosts::remap(&mut data, &opts.ghosts, report)?;
+    }
+    summary::sockets(report, &before, &socket_rows(&data));
+    // What is left to repair needs `podman container restore --tcp-established`
+    report.set("tcp_established", count_established(&data));
+    let open_files = match &split {
+        Some(split) => split.entries(),
+        None => data
+            .get("entries")
+            .and_then(|e| e.as_array())
+            .map_or(0, Vec::len),
+    };
+    report.set("open_files", open_files);
+    drop(span);
+    if show_timing {
+        eprintln!("  json patch:    {:>6} ms", t2.elapsed().as_millis());
+    }
+    let t3 = Instant::now();
+    let span = trace::span("crit encode");
+    let encoded = match &split {
+        Some(split) => split.join(&crit::encode(&data)?)?,
+        None => crit::encode(&data)?,
+    };
+    drop(span);
+    if show_timing {
+        eprintln!("  crit encode:   {:>6} ms", t3.elapsed().as_millis());
+    }
+    Ok(encoded)
+}
+
+/// What patch_files_img_json did with the sockets bound to old_addr.
+#[derive(Default)]
+struct SocketsPatched {
+    wildcarded: u32,
+    rewritten: u32,
+    /// Inodes of the sockets a rule left bound to old_addr.
+    skipped: Vec<u64>,
+    /// How many of the sockets patched are AF_INET6 ones bound to old_addr
+    /// as ::ffff:old_addr.
+    mapped: u32,
+}
+
+/// Patch INETSK entries' src_addr in the decoded files.img JSON.
+/// Sockets bound to old_addr are rewritten to 0.0.0.0 (wildcard)
+/// so CRIU can bind them on any interface, avoiding "Cannot assign requested
+/// address" when the restored container's IPAM-assigned IP differs.
+/// Sockets bound to other specific addresses (e.g. 127.0.0.1) are left alone.
+/// Dual-stack (AF_INET6) sockets bound to ::ffff:old_addr are patched the
+/// same way, to ::ffff:0.0.0.0 or ::ffff:new_addr, so they stay IPv4 only.
+/// A matching rule in `rules` binds the socket to `new_addr` instead, or
+/// leaves it as it is.
+fn patch_files_img_json(
+    data: &mut serde_json::Value,
+    old_addr: Ipv4Addr,
+    new_addr: Option<Ipv4Addr>,
+    rules: &[Rule],
+) -> Result<SocketsPatched, String> {
+    let mut patched = SocketsPatched::default();
+    let entries = match data.get_mut("entries").and_then(|e| e.as_array_mut()) {
+        Some(e) => e,
+        None => return Ok(patched),
+    };
+    for entry in entries.iter_mut() {
+        if entry.get("type").and_then(|t| t.as_str()) != Some("INETSK") {
+            continue;
+        }
+        let isk = match entry.get_mut("isk") {
+            Some(i) => i,
+            None => continue,
+        };
+        // Check family: AF_INET = 2, AF_INET6 = 10, crit may output as
+        // string "AF_INET" or integer 2
+        let family_str = isk.get("family").and_then(|f| f.as_str()).unwrap_or("");
+        let family_num = isk.get("family").and_then(|f| f.as_u64()).unwrap_or(0);
+        let is_inet4 = family_str == "AF_INET" || family_str == "INET" || family_num == 2;
+        let is_inet6 = family_str == "AF_INET6" || family_str == "INET6" || family_num == 10;
+        // crit decode outputs src_addr as in_addr words or a dotted quad
+        let mapped = match isk.get("src_addr").and_then(inaddr::decode) {
+            Some(IpAddr::V4(addr)) if is_inet4 && addr == old_addr => false,
+            Some(IpAddr::V6(addr)) if is_inet6 && addr.to_ipv4_mapped() == Some(old_addr) => true,
+            _ => continue,
+        };
+        let addr = match policy::action(rules, isk) {
+            Some(Action::Skip) => {
+                patched
+                    .skipped
+                    .extend(isk.get("ino").and_then(|i| i.as_u64()));
+                continue;
+            }
+            Some(Action::Rewrite) => {
+                patched.rewritten += 1;
+                new_addr.ok_or("a socket rule rewrites to new_addr, but there is none")?
+            }
+            _ => {
+                patched.wildcarded += 1;
+                Ipv4Addr::UNSPECIFIED
+            }
+        };
+        let addr = if mapped {
+            patched.mapped += 1;
+            IpAddr::V6(addr.to_ipv6_mapped())
+        } else {
+            IpAddr::V4(addr)
+        };
+        // Keep the original format: integer or dotted quad
+        isk["src_addr"] = inaddr::encode(addr, &isk["src_addr"]);
+    }
+    Ok(patched)
+}
+
+/// sockets::row of each INETSK in the decoded files.img JSON.
+fn socket_rows(data: &serde_json::Value) -> Vec<serde_json::Value> {
+    let entries = data.get("entries").and_then(|e| e.as_array());
+    entries
+        .into_iter()
+        .flatten()
+        .filter(|e| e.get("type").and_then(|t| t.as_str()) == Some("INETSK"))
+        .filter_map(|e| e.get("isk"))
+        .map(sockets::row)
+        .collect()
+}
+
+fn is_established_tcp(isk: &serde_json::Value) -> bool {
+    let tcp = matches!(isk.get("proto"), Some(p) if p == "TCP" || p == 6);
+    tcp && matches!(isk.get("state"), Some(s) if s == "ESTABLISHED" || s == 1)
+}
+
+/// The number of established TCP sockets in the decoded files.img JSON.
+fn count_established(data: &serde_json::Value) -> usize {
+    let entries = data.get("entries").and_then(|e| e.as_array());
+    entries
+        .into_iter()
+        .flatten()
+        .filter(|e| e.get("type").and_then(|t| t.as_str()) == Some("INETSK"))
+        .filter(|e| e.get("isk").is_some_and(is_established_tcp))
+        .count()
+}
+
+/// Turn established TCP sockets matching `select`, or a close rule first of
+/// `rules`, into fresh unconnected, unbound sockets, the way CRIU's
+/// --tcp-close restores every connection: the application sees the
+/// connection fail on its next read or write instead of it being repaired.
+/// Their tcp-stream images are left unused. Returns the number of sockets
+/// changed.
+fn close_tcp(data: &mut serde_json::Value, select: &[TcpClose], rules: &[Rule]) -> u32 {
+    let Some(entries) = data.get_mut("entries").and_then(|e| e.as_array_mut()) else {
+        return 0;
+    };
+    let mut count = 0u32;
+    for entry in entries.iter_mut() {
+        if entry.get("type").and_then(|t| t.as_str()) != Some("INETSK") {
+            continue;
+        }
+        let Some(isk) = entry.get_mut("isk") else {
+            continue;
+        };
+        let selected = select.iter().any(|s| s.matches(isk))
+            || policy::action(rules, isk) == Some(Action::Close);
+        if !is_established_tcp(isk) || !selected {
+            continue;
+        }
+        // Keep the rendering crit used: enum names or numbers
+        isk["state"] = if isk["state"].is_string() {
+            "CLOSE".into()
+        } else {
+            7.into()
+        };
+        // Unbound, so it cannot clash with a listener on the same port
+        isk["src_port"] = 0.into();
+        isk["dst_port"] = 0.into();
+        count += 1;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const OLD: Ipv4Addr = Ipv4Addr::new(10, 88, 0, 5);
+    const NEW: Ipv4Addr = Ipv4Addr::new(10, 88, 0, 9);
+
+    fn inetsk(isk: serde_json::Value) -> serde_json::Value {
+        json!({"type": "INETSK", "isk": isk})
+    }
+
+    fn src_addrs(data: &serde_json::Value) -> Vec<serde_json::Value> {
+        data["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["isk"]["src_addr"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn wildcards_sockets_bound_to_old_addr() {
+        let mut data = json!({"entries": [
+            inetsk(json!({"family": "INET", "proto": "TCP", "src_port": 80, "src_addr": ["10.88.0.5"]})),
+            inetsk(json!({"family": 2, "proto": 6, "src_port": 81, "src_addr": [0x0500_580a]})),
+            inetsk(json!({"family": "INET", "proto": "TCP", "src_port": 82, "src_addr": ["127.0.0.1"]})),
+            inetsk(json!({"family": "INET", "proto": "TCP", "src_port": 83, "src_addr": ["10.88.0.6"]})),
+        ]});
+        let patched = patch_files_img_json(&mut data, OLD, Some(NEW), &[]).unwrap();
+        assert_eq!(
+            (patched.wildcarded, patched.rewritten, patched.mapped),
+            (2, 0, 0)
+        );
+        assert_eq!(
+            src_addrs(&data),
+            [
+                json!(["0.0.0.0"]),
+                json!([0]),
+                json!(["127.0.0.1"]),
+                json!(["10.88.0.6"])
+            ]
+        );
+    }
+
+    #[test]
+    fn patches_mapped_dual_stack_sockets() {
+        let mut data = json!({"entries": [
+            inetsk(json!({"family": "INET6", "proto": "TCP", "src_port": 80, "src_addr": ["::ffff:10.88.0.5"]})),
+            inetsk(json!({"family": 10, "proto": 6, "src_port": 81, "src_addr": [0, 0, 0xffff_0000u32, 0x0500_580a]})),
+            inetsk(json!({"family": "INET6", "proto": "TCP", "src_port": 82, "src_addr": ["fd00::5"]})),
+            // Only AF_INET6 sockets are bound to ::ffff:old_addr
+            inetsk(json!({"family": "INET", "proto": "TCP", "src_port": 83, "src_addr": ["::ffff:10.88.0.5"]})),
+        ]});
+        let rules = policy::parse_rules("tcp/81 rewrite").unwrap().0;
+        let patched = patch_files_img_json(&mut data, OLD, Some(NEW), &rules).unwrap();
+        assert_eq!(
+            (patched.wildcarded, patched.rewritten, patched.mapped),
+            (1, 1, 2)
+        );
+        assert_eq!(
+            src_addrs(&data),
+            [
+                json!(["::ffff:0.0.0.0"]),
+                json!([0, 0, 0xffff_0000u32, 0x0900_580a]),
+                json!(["fd00::5"]),
+                json!(["::ffff:10.88.0.5"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_skip_and_rewrite() {
+        let mut data = json!({"entries": [
+            inetsk(json!({"family": "INET", "proto": "TCP", "src_port": 8080, "src_addr": ["10.88.0.5"], "ino": 11})),
+            inetsk(json!({"family": "INET", "proto": "TCP", "src_port": 9090, "src_addr": ["10.88.0.5"], "ino": 12})),
+            inetsk(json!({"family": "INET", "proto": "UDP", "src_port": 53, "src_addr": ["10.88.0.5"], "ino": 13})),
+        ]});
+        let rules = policy::parse_rules("tcp/8080 rewrite, tcp skip").unwrap().0;
+        let patched = patch_files_img_json(&mut data, OLD, Some(NEW), &rules).unwrap();
+        assert_eq!((patched.wildcarded, patched.rewritten), (1, 1));
+        assert_eq!(patched.skipped, [12]);
+        assert_eq!(
+            src_addrs(&data),
+            [
+                json!(["10.88.0.9"]),
+                json!(["10.88.0.5"]),
+                json!(["0.0.0.0"])
+            ]
+        );
+        // Rewriting needs new_addr
+        let mut data = json!({"entries": [
+            inetsk(json!({"family": "INET", "proto": "TCP", "src_port": 8080, "src_addr": ["10.88.0.5"]})),
+        ]});
+        assert!(patch_files_img_json(&mut data, OLD, None, &rules).is_err());
+    }
+
+    #[test]
+    fn closes_established_connections() {
+        let mut data = json!({"entries": [
+            inetsk(json!({"proto": "TCP", "state": "ESTABLISHED", "src_port": 8080, "dst_port": 40000, "dst_addr": ["10.0.0.1"]})),
+            inetsk(json!({"proto": 6, "state": 1, "src_port": 45000, "dst_port": 5432, "dst_addr": ["10.0.0.2"]})),
+            inetsk(json!({"proto": "TCP", "state": "ESTABLISHED", "src_port": 9090, "dst_port": 40001, "dst_addr": ["10.0.0.3"]})),
+            inetsk(json!({"proto": "TCP", "state": "LISTEN", "src_port": 8080, "dst_port": 0})),
+        ]});
+        let select = [TcpClose::Port(8080)];
+        let rules = policy::parse_rules("tcp/45000 close").unwrap().0;
+        assert_eq!(close_tcp(&mut data, &select, &rules), 2);
+        let isks: Vec<_> = data["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["isk"]["state"].clone(), e["isk"]["src_port"].clone()))
+            .collect();
+        assert_eq!(
+            isks,
+            [
+                (json!("CLOSE"), json!(0)),
+                (json!(7), json!(0)),
+                (json!("ESTABLISHED"), json!(9090)),
+                (json!("LISTEN"), json!(8080)),
+            ]
+        );
+        assert_eq!(count_established(&data), 1);
+    }
+
+    #[test]
+    fn closes_by_peer() {
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let dual = json!({"proto": "TCP", "state": "ESTABLISHED", "dst_port": 5432, "dst_addr": ["::ffff:10.0.0.2"]});
+        assert!(TcpClose::Peer(peer, None).matches(&dual));
+        assert!(TcpClose::Peer(peer, Some(5432)).matches(&dual));
+        assert!(!TcpClose::Peer(peer, Some(5433)).matches(&dual));
+    }
+}
@@ -0,0 +1,75 @@
+//! Newline-delimited JSON events for an orchestrator (--event-fd).
+//!
+//! stderr is for people; this is the same run as it happens, one JSON object
+//! per line, with `time_us` (Unix time in microseconds) and `event`:
+//!
+//! - progress: a phase (the trace span names) with `state` "start" or "end";
+//!   an end carries `duration_ms` and, if the phase failed, `error`
+//! - warning: the `message` of a warning
+//! - error: the `message` the run fails with
+//! - done: the run is over, with `status` "ok", "noop" (see EXIT_NOOP) or
+//!   "error"
+//!
+//! --event-fd takes a descriptor the caller left open (`3>events.ndjson ...
+//! --event-fd 3`) or a path, e.g. a named pipe the caller reads. Events that
+//! cannot be written are dropped; they never fail the run.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+static SINK: Mutex<Option<File>> = Mutex::new(None);
+
+/// Write events to `target`: a descriptor number or a path.
+pub fn init(target: &str) -> Result<(), String> {
+    let file = match target.parse::<u32>() {
+        Ok(fd) => OpenOptions::new()
+            .append(true)
+            .open(format!("/proc/self/fd/{}", fd))
+            .map_err(|e| {
+                format!(
+                    "--event-fd: descriptor {} is not open for writing: {}",
+                    fd, e
+                )
+            })?,
+        Err(_) => OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(target)
+            .map_err(|e| format!("--event-fd {}: {}", target, e))?,
+    };
+    *SINK.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+/// Write the event `event` with the fields of the object `fields`.
+pub fn emit(event: &str, fields: Value) {
+    let mut guard = SINK.lock().unwrap();
+    let Some(sink) = guard.as_mut() else { return };
+    let time_us = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0);
+    let mut line = json!({ "time_us": time_us, "event": event });
+    if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), fields) {
+        line.extend(fields);
+    }
+    let _ = writeln!(sink, "{}", line);
+}
+
+pub fn warning(message: &str) {
+    emit("warning", json!({ "message": message }));
+}
+
+/// The run failed with `message`.
+pub fn error(message: &str) {
+    emit("error", json!({ "message": message }));
+    done("error");
+}
+
+pub fn done(status: &str) {
+    emit("done", json!({ "status": status }));
+}
@@ -0,0 +1,191 @@
+//! https:// (and http://) checkpoint sources.
+//!
+//! The archive must have been published with its --index sidecar next to it
+//! (URL.index.json). The metadata entries are range-requested first, so
+//! old_addr detection, IPAM and the image check run before the archive is
+//! downloaded; the edit pass then reads the archive in CHUNK ranges that a
+//! thread fetches ahead of it, each retried on its own, so it starts on the
+//! first chunk rather than after the download.
+
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::applied::APPLIED_PATH;
+use crate::index;
+use crate::metadata::{MetadataFiles, CONFIG_DUMP_PATH, NETWORK_STATUS_PATH, SPEC_DUMP_PATH};
+use crate::report::Report;
+
+const CHUNK: u64 = 8 * 1024 * 1024;
+/// Chunks fetched ahead of the edit.
+const PREFETCH: usize = 2;
+const ATTEMPTS: u32 = 3;
+
+pub fn is_url(checkpoint: &str) -> bool {
+    checkpoint.starts_with("https://") || checkpoint.starts_with("http://")
+}
+
+/// `len` bytes of `url` from `start`, retrying failed and short transfers.
+fn get_range(agent: &ureq::Agent, url: &str, start: u64, len: u64) -> Result<Vec<u8>, String> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    let range = format!("bytes={}-{}", start, start + len - 1);
+    let mut last_error = String::new();
+    for attempt in 0..ATTEMPTS {
+        if attempt > 0 {
+            thread::sleep(Duration::from_millis(500 << attempt));
+        }
+        let response = match agent.get(url).set("Range", &range).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(code, _)) if code < 500 => {
+                return Err(format!("{}: HTTP {}", url, code));
+            }
+            Err(e) => {
+                last_error = e.to_string();
+                continue;
+            }
+        };
+        if response.status() != 206 {
+            return Err(format!(
+                "{}: the server does not support range requests (HTTP {})",
+                url,
+                response.status()
+            ));
+        }
+        let mut body = Vec::with_capacity(len as usize);
+        match response.into_reader().take(len).read_to_end(&mut body) {
+            Ok(n) if n as u64 == len => return Ok(body),
+            Ok(n) => last_error = format!("short read ({} of {} bytes)", n, len),
+            Err(e) => last_error = e.to_string(),
+        }
+    }
+    Err(format!("{} {}: {}", url, range, last_error))
+}
+
+/// A published checkpoint archive and its index.
+pub struct Source {
+    url: String,
+    agent: ureq::Agent,
+    index: Value,
+    size: u64,
+}
+
+impl Source {
+    /// Fetch the index of the archive at `url`.
+    pub fn open(url: &str, report: &mut Report) -> Result<Source, String> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(10))
+            .timeout_read(Duration::from_secs(60))
+            .build();
+        let index_url = format!("{}{}", url, index::SUFFIX);
+        let index: Value = match agent.get(&index_url).call() {
+            Ok(response) => serde_json::from_reader(response.into_reader())
+                .map_err(|e| format!("{}: {}", index_url, e))?,
+            Err(ureq::Error::Status(404, _)) => {
+                return Err(format!(
+                    "no index at {}; publish the archive with the sidecar from --index",
+                    index_url
+                ));
+            }
+            Err(e) => return Err(format!("{}: {}", index_url, e)),
+        };
+        let size = index
+            .get("size")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| format!("{}: no archive size", index_url))?;
+        let entries = index["entries"].as_array().map_or(0, Vec::len);
+        eprintln!("Fetched the index of {} ({} entries)", url, entries);
+        report.set(
+            "source",
+            serde_json::json!({"url": url, "size": size, "entries": entries}),
+        );
+        Ok(Source {
+            url: url.to_string(),
+            agent,
+            index,
+            size,
+        })
+    }
+
+    /// The data of the entry at `path`, if the index lists it.
+    fn entry(&self, path: &str) -> Result<Option<Vec<u8>>, String> {
+        let entry = self.index["entries"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|e| e["path"] == path);
+        let Some(entry) = entry else {
+            return Ok(None);
+        };
+        let (Some(offset), Some(length)) = (entry["offset"].as_u64(), entry["length"].as_u64())
+        else {
+            return Err(format!("index entry {} has no offset or length", path));
+        };
+        get_range(&self.agent, &self.url, offset, length).map(Some)
+    }
+
+    /// network.status, config.dump, spec.dump and the marker, range-requested.
+    pub fn metadata(&self) -> Result<MetadataFiles, String> {
+        Ok(MetadataFiles {
+            status: self.entry(NETWORK_STATUS_PATH)?,
+            config: self.entry(CONFIG_DUMP_PATH)?,
+            spec: self.entry(SPEC_DUMP_PATH)?,
+            applied: self.entry(APPLIED_PATH)?,
+        })
+    }
+
+    /// The whole archive, fetched ahead of the reader.
+    pub fn reader(&self) -> RangeReader {
+        let (tx, rx) = mpsc::sync_channel(PREFETCH);
+        let (agent, url, size) = (self.agent.clone(), self.url.clone(), self.size);
+        thread::spawn(move || {
+            let mut start = 0;
+            while start < size {
+                let len = CHUNK.min(size - start);
+                let chunk = get_range(&agent, &url, start, len);
+                let failed = chunk.is_err();
+                // Stop once the reader is gone or after an error
+                if tx.send(chunk).is_err() || failed {
+                    return;
+                }
+                start += len;
+            }
+        });
+        RangeReader {
+            chunks: rx,
+            current: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+/// Sequential reader over the chunks of a Source.
+pub struct RangeReader {
+    chunks: Receiver<Result<Vec<u8>, String>>,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.current.len() {
+            match self.chunks.recv() {
+                Ok(Ok(chunk)) => {
+                    self.current = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => return Err(io::Error::other(e)),
+                // All chunks read
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = out.len().min(self.current.len() - self.pos);
+        out[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
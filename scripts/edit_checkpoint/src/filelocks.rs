@@ -0,0 +1,67 @@
+//! Remap the paths of the files a checkpoint taken with --file-locks holds
+//! locks on (--lock-path-map).
+//!
+//! file-locks.img records each lock by the PID and descriptor holding it,
+//! not by path; CRIU takes the lock again at restore on whatever file that
+//! descriptor reopens, which is the REG entry's name in files.img. So the
+//! remapping is of those names: every regular file open under OLD is opened
+//! under NEW on the target (whole path components only), locked or not,
+//! since a file moved for its locks has moved for the other descriptors on
+//! it too.
+
+use serde_json::Value;
+
+use crate::crit;
+use crate::images::FILES_IMG_PATH;
+use crate::report::Report;
+use crate::secrets;
+use crate::summary;
+
+pub const LOCKS_PATH: &str = "checkpoint/file-locks.img";
+
+/// Count the locks in a file-locks.img, for the report.
+pub fn count(content: &[u8], report: &mut Report) -> Result<(), String> {
+    let data = crit::decode(content)?;
+    let locks = data
+        .get("entries")
+        .and_then(|e| e.as_array())
+        .map_or(0, Vec::len);
+    eprintln!("{} file locks in the checkpoint", locks);
+    report.set("file_locks", locks);
+    Ok(())
+}
+
+/// Replace the OLD prefixes of `map` in the names of the REG entries of the
+/// decoded files.img JSON.
+pub fn remap(data: &mut Value, map: &[(String, String)], report: &mut Report) {
+    let entries = data.get_mut("entries").and_then(|e| e.as_array_mut());
+    let mut remapped = 0;
+    for entry in entries.into_iter().flatten() {
+        if entry.get("type").and_then(|t| t.as_str()) != Some("REG") {
+            continue;
+        }
+        let id = entry.get("id").and_then(|i| i.as_u64()).unwrap_or(0);
+        let Some(reg) = entry.get_mut("reg") else {
+            continue;
+        };
+        let Some(old) = reg.get("name").and_then(|n| n.as_str()) else {
+            continue;
+        };
+        let Some(new) = secrets::remap_path(old, map) else {
+            continue;
+        };
+        let field = format!("id {} reg name", id);
+        summary::add(report, FILES_IMG_PATH, &field, old, &new, "remapped");
+        reg["name"] = new.into();
+        remapped += 1;
+    }
+    report.set("lock_paths_remapped", remapped);
+    if remapped == 0 {
+        report.warn("--lock-path-map matched none of the files the checkpoint has open");
+    }
+}
+
+/// Warn that there are no locks to remap paths for.
+pub fn missing(report: &mut Report) {
+    report.warn("no file-locks.img: the checkpoint was taken without --file-locks");
+}
@@ -0,0 +1,118 @@
+//! Remap where deleted files still held open are recreated at restore
+//! (--ghost-path-map).
+//!
+//! CRIU keeps the content of such a file in a ghost-file-N.img, and its
+//! remap-fpath.img entry ties it to the file's REG entry in files.img. At
+//! restore the ghost is created next to the path in that entry, opened and
+//! unlinked again, so the path's directory has to exist on the target. When
+//! the volume or bind mount the file was in is mounted elsewhere there, the
+//! OLD=NEW prefixes move the REG entries of the ghost files (and only
+//! those) along with it. Which entries those are is read from
+//! remap-fpath.img before the edit, so the checkpoint has to be on disk.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::crit::{self, number};
+use crate::images::{self, FILES_IMG_PATH};
+use crate::report::Report;
+use crate::secrets;
+use crate::summary;
+
+/// The remap_id flag of ghost files, in images older than remap_type.
+const REMAP_GHOST: u64 = 1 << 31;
+
+#[derive(Default)]
+pub struct GhostPatch {
+    /// OLD=NEW path prefixes.
+    pub map: Vec<(String, String)>,
+    /// The file ids of the ghost files, once resolved.
+    pub ids: Option<BTreeSet<u64>>,
+}
+
+impl GhostPatch {
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+/// The file id and ghost file id of a ghost remap-fpath entry.
+pub fn ghost_remap(entry: &Value) -> Option<(u64, u64)> {
+    let orig = entry.get("orig_id").and_then(number)?;
+    let remap = entry.get("remap_id").and_then(number)?;
+    match entry.get("remap_type") {
+        Some(Value::String(kind)) => (kind == "GHOST").then_some((orig, remap)),
+        Some(kind) => (kind.as_u64() == Some(1)).then_some((orig, remap)),
+        None => (remap & REMAP_GHOST != 0).then_some((orig, remap & !REMAP_GHOST)),
+    }
+}
+
+/// Find the ghost files of `checkpoint` in its remap-fpath.img.
+pub fn resolve(patch: &mut GhostPatch, checkpoint: &Path) -> Result<(), String> {
+    if patch.is_empty() {
+        return Ok(());
+    }
+    let images = images::read(checkpoint, |name| name == "remap-fpath.img")?;
+    let mut ids = BTreeSet::new();
+    if let Some(content) = images.get("remap-fpath.img") {
+        let remap = crit::decode(content)?;
+        let entries = remap.get("entries").and_then(|e| e.as_array());
+        ids.extend(
+            entries
+                .into_iter()
+                .flatten()
+                .filter_map(ghost_remap)
+                .map(|(orig, _)| orig),
+        );
+    }
+    patch.ids = Some(ids);
+    Ok(())
+}
+
+/// Replace the OLD prefixes of `patch` in the names of the REG entries of
+/// the ghost files in the decoded files.img JSON.
+pub fn remap(data: &mut Value, patch: &GhostPatch, report: &mut Report) -> Result<(), String> {
+    let ids = patch
+        .ids
+        .as_ref()
+        .ok_or("--ghost-path-map needs the checkpoint on disk, to find its ghost files")?;
+    let entries = data.get_mut("entries").and_then(|e| e.as_array_mut());
+    let mut remapped = 0;
+    for entry in entries.into_iter().flatten() {
+        if entry.get("type").and_then(|t| t.as_str()) != Some("REG") {
+            continue;
+        }
+        let Some(id) = entry
+            .get("id")
+            .and_then(number)
+            .filter(|id| ids.contains(id))
+        else {
+            continue;
+        };
+        let Some(reg) = entry.get_mut("reg") else {
+            continue;
+        };
+        let Some(old) = reg.get("name").and_then(|n| n.as_str()) else {
+            continue;
+        };
+        let Some(new) = secrets::remap_path(old, &patch.map) else {
+            continue;
+        };
+        let field = format!("id {} ghost name", id);
+        summary::add(report, FILES_IMG_PATH, &field, old, &new, "remapped");
+        reg["name"] = new.into();
+        remapped += 1;
+    }
+    report.set("ghost_paths_remapped", remapped);
+    if ids.is_empty() {
+        report.warn("--ghost-path-map: the checkpoint has no ghost files");
+    } else if remapped == 0 {
+        report.warn(format!(
+            "--ghost-path-map matched none of the {} ghost files",
+            ids.len()
+        ));
+    }
+    Ok(())
+}
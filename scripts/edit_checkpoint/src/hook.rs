@@ -0,0 +1,165 @@
+//! `hook`: run as an OCI hook so the checkpoint is patched at restore time.
+//!
+//! Reads the OCI state JSON from stdin, and if the bundle holds a checkpoint
+//! being restored (podman extracts `--import` archives into the container's
+//! userdata directory, which is the bundle), patches the checkpoint images
+//! there in place: the sockets of files.img and whatever else the options
+//! change under checkpoint/. Containers that are not being restored, or have
+//! no new_addr configured, are left alone.
+//!
+//! By prestart, podman or CRI-O has already read network.status, config.dump,
+//! spec.dump, rootfs-diff.tar and criu-restore.conf, so the hook leaves them
+//! as they are and refuses the options that only patch those (--dns-server,
+//! --cap-add, --uidmap, --criu-opt and the like): edit the checkpoint before
+//! restoring for those.
+//!
+//! new_addr and old_addr come from the io.p4containerflow.new-addr and
+//! io.p4containerflow.old-addr annotations or from the hook's arguments;
+//! old_addr is detected from the bundle when neither sets it. Example
+//! /usr/share/containers/oci/hooks.d/edit-checkpoint.json:
+//!
+//! ```json
+//! {
+//!   "version": "1.0.0",
+//!   "hook": {"path": "/usr/local/bin/edit_checkpoint", "args": ["edit_checkpoint", "hook"]},
+//!   "when": {"annotations": {"^io\\.p4containerflow\\.new-addr$": ".*"}},
+//!   "stages": ["prestart"]
+//! }
+//! ```
+
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use clap::Args;
+
+use crate::edit;
+use crate::ghosts;
+use crate::images::FILES_IMG_PATH;
+use crate::metadata::{self, AddrPatch};
+use crate::policy;
+use crate::report::Report;
+use crate::security::SeccompPatch;
+use crate::PatchArgs;
+
+const NEW_ADDR_ANNOTATION: &str = "io.p4containerflow.new-addr";
+const OLD_ADDR_ANNOTATION: &str = "io.p4containerflow.old-addr";
+
+#[derive(Args)]
+pub struct HookArgs {
+    /// Address to restore with, unless the container's annotation sets one
+    #[arg(long, value_name = "ADDR")]
+    new_addr: Option<String>,
+    /// Address the checkpoint was taken with (default: annotation, or detected)
+    #[arg(long, value_name = "ADDR")]
+    old_addr: Option<String>,
+    #[command(flatten)]
+    patch: PatchArgs,
+}
+
+/// The options in `patch` that only change the metadata files the runtime
+/// has read before the hook runs.
+fn metadata_options(patch: &PatchArgs) -> Vec<&'static str> {
+    [
+        ("--add-addr", patch.add_addr),
+        ("--dns-server", !patch.dns_server.is_empty()),
+        ("--dns-search", !patch.dns_search.is_empty()),
+        ("--alias", !patch.alias.is_empty()),
+        ("--criu-opt", !patch.criu_opt.is_empty()),
+        ("--restore-fixup", patch.restore_fixup),
+        ("--restore-route", !patch.restore_route.is_empty()),
+        ("--action-script", patch.action_script.is_some()),
+        ("--selinux-map", !patch.selinux_map.is_empty()),
+        ("--selinux-disable", patch.selinux_disable),
+        ("--apparmor-profile", patch.apparmor_profile.is_some()),
+        ("--apparmor-clear", patch.apparmor_clear),
+        (
+            "--seccomp replace=",
+            matches!(patch.seccomp, Some(SeccompPatch::Replace(_))),
+        ),
+        ("--cap-add", !patch.cap_add.is_empty()),
+        ("--cap-drop", !patch.cap_drop.is_empty()),
+        ("--uidmap", !patch.uidmap.is_empty()),
+        ("--no-userns", patch.no_userns),
+        ("--secrets-map", !patch.secrets_map.is_empty()),
+        ("--cgroup-map", !patch.cgroup_map.is_empty()),
+        ("--image-map", !patch.image_map.is_empty()),
+    ]
+    .into_iter()
+    .filter(|(_, given)| *given)
+    .map(|(option, _)| option)
+    .collect()
+}
+
+pub fn run(args: &HookArgs) -> Result<(), String> {
+    let refused = metadata_options(&args.patch);
+    if !refused.is_empty() {
+        return Err(format!(
+            "{} only patch the container's metadata, which the runtime has read before the \
+             hook runs; edit the checkpoint before restoring instead",
+            refused.join(", ")
+        ));
+    }
+    let mut state = String::new();
+    std::io::stdin()
+        .read_to_string(&mut state)
+        .map_err(|e| format!("read hook state: {}", e))?;
+    let state: serde_json::Value =
+        serde_json::from_str(&state).map_err(|e| format!("parse hook state: {}", e))?;
+    let bundle = state
+        .get("bundle")
+        .and_then(|b| b.as_str())
+        .ok_or("hook state has no bundle")?;
+    let bundle = Path::new(bundle);
+    let files_img = bundle.join(FILES_IMG_PATH);
+    if !files_img.is_file() {
+        // Plain start, not a restore
+        return Ok(());
+    }
+    let annotation = |key: &str| {
+        state
+            .get("annotations")
+            .and_then(|a| a.get(key))
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+    };
+    let Some(new_addr) = annotation(NEW_ADDR_ANNOTATION).or(args.new_addr.clone()) else {
+        return Ok(());
+    };
+
+    let old_addr = match annotation(OLD_ADDR_ANNOTATION).or(args.old_addr.clone()) {
+        Some(addr) => addr,
+        None => {
+            metadata::detect_old_addr_in_dir(bundle)
+                .ok_or_else(|| {
+                    format!(
+                        "could not detect old_addr in {}; set {}",
+                        bundle.display(),
+                        OLD_ADDR_ANNOTATION
+                    )
+                })?
+                .0
+        }
+    };
+    if old_addr == new_addr {
+        return Ok(());
+    }
+    let old_ip: Ipv4Addr = old_addr
+        .parse()
+        .map_err(|_| format!("old_addr {} is not an IPv4 address", old_addr))?;
+    eprintln!(
+        "Patching checkpoint in {}: {} → {}",
+        bundle.display(),
+        old_addr,
+        new_addr
+    );
+
+    let mut opts = args.patch.options();
+    policy::resolve(&mut opts.socket_rules, &bundle.join("checkpoint"))?;
+    ghosts::resolve(&mut opts.ghosts, &bundle.join("checkpoint"))?;
+    let old_prefix = metadata::MetadataFiles::from_dir(bundle).old_prefix(old_ip);
+    let net = opts.network_patch(old_ip, old_prefix, AddrPatch::Replace(&new_addr));
+    let mut report = Report::default();
+    // The images only: see the module doc
+    edit::patch_dir(&bundle.join("checkpoint"), None, &net, &opts, &mut report)
+}
@@ -0,0 +1,324 @@
+//! Edit checkpoints stored as images: local ones (`podman container
+//! checkpoint --create-image`, with --image) and OCI image layouts on disk.
+//!
+//! A local image is saved as an uncompressed OCI directory first. The layer
+//! holding checkpoint/files.img is edited like an exported archive, into a
+//! file next to the blobs that is renamed to its digest once hashed; then
+//! the config's diff ID and the manifest/config digests are rewritten. A
+//! local image is loaded back under the same name, a layout is left updated
+//! where it is, without the blobs only the old manifest referenced. An edit
+//! that changes nothing leaves the image as it is. Layers may be
+//! uncompressed or zstd (written as
+//! --compress says); gzip layers are not edited. Set EDIT_CHECKPOINT_PODMAN
+//! (e.g. "sudo podman") to change the podman command.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::compress;
+use crate::edit;
+use crate::images::FILES_IMG_PATH;
+use crate::remote;
+use crate::report::Report;
+
+const LAYER_TAR: &str = "application/vnd.oci.image.layer.v1.tar";
+const LAYER_TAR_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+
+/// An image in an OCI directory, with the layer to edit located.
+pub struct SavedImage {
+    /// The local image to load back, or None for a layout on disk.
+    name: Option<String>,
+    oci: PathBuf,
+    /// Keeps a saved image's OCI directory alive until it is loaded back.
+    _dir: Option<tempfile::TempDir>,
+    manifest_digest: String,
+    manifest: Value,
+    config: Value,
+    layer: usize,
+}
+
+/// Whether `path` is an OCI image layout directory.
+pub fn is_layout(path: &Path) -> bool {
+    path.join("oci-layout").is_file() && path.join("index.json").is_file()
+}
+
+/// Save `name` and find its checkpoint layer.
+pub fn save(name: &str) -> Result<SavedImage, String> {
+    // Layers can be large: keep them off /dev/shm
+    let dir = tempfile::tempdir_in("/var/tmp")
+        .or_else(|_| tempfile::tempdir())
+        .map_err(|e| e.to_string())?;
+    let out = dir.path().join("oci");
+    let out_str = out.to_string_lossy();
+    podman(&[
+        "image",
+        "save",
+        "--format",
+        "oci-dir",
+        "--uncompressed",
+        "-o",
+        &out_str,
+        name,
+    ])?;
+    let mut image = find(&out, name)?;
+    image.name = Some(name.to_string());
+    image._dir = Some(dir);
+    Ok(image)
+}
+
+/// Find the checkpoint layer of the OCI layout at `dir`, to edit in place.
+pub fn open(dir: &Path) -> Result<SavedImage, String> {
+    find(dir, &dir.display().to_string())
+}
+
+/// Find the first image of the index.json of `oci` with a layer holding
+/// checkpoint/files.img.
+fn find(oci: &Path, what: &str) -> Result<SavedImage, String> {
+    let index = read_json(&oci.join("index.json"))?;
+    let manifests = index
+        .get("manifests")
+        .and_then(|m| m.as_array())
+        .ok_or("index.json has no manifests")?;
+    let mut gzip = false;
+    for manifest_digest in manifests.iter().filter_map(|m| m["digest"].as_str()) {
+        let manifest = read_json(&blob_path(oci, manifest_digest)?)?;
+        // A nested index (multi-platform image) has no layers
+        let Some(layers) = manifest.get("layers").and_then(|l| l.as_array()) else {
+            continue;
+        };
+        for (i, layer) in layers.iter().enumerate() {
+            let media_type = layer.get("mediaType").and_then(|m| m.as_str());
+            let digest = layer.get("digest").and_then(|d| d.as_str()).unwrap_or("");
+            if !matches!(media_type, Some(LAYER_TAR | LAYER_TAR_ZSTD)) {
+                gzip |= media_type.is_some_and(|m| m.ends_with("+gzip"));
+                continue;
+            }
+            if !layer_has_files_img(&blob_path(oci, digest)?)? {
+                continue;
+            }
+            // Edited in place, the blob would change under the other image
+            if referenced(oci, &index, manifest_digest)?.contains(digest) {
+                return Err(format!(
+                    "the checkpoint layer {} is shared with another image in {}; \
+                     copy the image to a layout of its own",
+                    digest, what
+                ));
+            }
+            let config_digest = manifest
+                .pointer("/config/digest")
+                .and_then(|d| d.as_str())
+                .ok_or("manifest has no config")?;
+            let config = read_json(&blob_path(oci, config_digest)?)?;
+            return Ok(SavedImage {
+                name: None,
+                oci: oci.to_path_buf(),
+                _dir: None,
+                manifest_digest: manifest_digest.to_string(),
+                manifest,
+                config,
+                layer: i,
+            });
+        }
+    }
+    let gzip = if gzip {
+        " (gzip layers cannot be edited in place)"
+    } else {
+        ""
+    };
+    Err(format!(
+        "{} has no uncompressed or zstd layer with {}{}; not a checkpoint image?",
+        what, FILES_IMG_PATH, gzip
+    ))
+}
+
+impl SavedImage {
+    fn layer_digest(&self) -> &str {
+        self.manifest["layers"][self.layer]["digest"]
+            .as_str()
+            .unwrap_or_default()
+    }
+
+    /// The checkpoint layer, an archive in the export layout to edit in place.
+    pub fn layer_path(&self) -> Result<PathBuf, String> {
+        blob_path(&self.oci, self.layer_digest())
+    }
+
+    /// Where the edit writes the checkpoint layer, for commit to take it
+    /// from: next to the blobs, under no digest.
+    pub fn edited_path(&self) -> Result<PathBuf, String> {
+        Ok(self.layer_path()?.with_extension("edited"))
+    }
+
+    /// Digest the layer edited into `edited` and store it as a blob, update
+    /// config, manifest and index, and load a local image back under its
+    /// name.
+    pub fn commit(mut self, edited: &Path, report: &mut Report) -> Result<(), String> {
+        let oci = self.oci.clone();
+        let old_layer = self.layer_digest().to_string();
+        let (new_layer, size) = hash_file(edited)?;
+        let zstd = compress::is_zstd(edited)?;
+        // The diff ID is the digest of the uncompressed layer
+        let diff_id = if zstd {
+            hash(compress::open(edited)?)?.0
+        } else {
+            new_layer.clone()
+        };
+        fs::rename(edited, blob_path(&oci, &new_layer)?).map_err(|e| e.to_string())?;
+
+        let layer = &mut self.manifest["layers"][self.layer];
+        layer["digest"] = new_layer.clone().into();
+        layer["size"] = size.into();
+        layer["mediaType"] = if zstd { LAYER_TAR_ZSTD } else { LAYER_TAR }.into();
+        let slot = self
+            .config
+            .pointer_mut("/rootfs/diff_ids")
+            .and_then(|d| d.as_array_mut())
+            .and_then(|d| d.get_mut(self.layer))
+            .ok_or("image config has no diff ID for the checkpoint layer")?;
+        *slot = diff_id.into();
+
+        let old_config = self.manifest["config"]["digest"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let (config_digest, config_size) = write_blob(&oci, &self.config)?;
+        self.manifest["config"]["digest"] = config_digest.into();
+        self.manifest["config"]["size"] = config_size.into();
+        let (manifest_digest, manifest_size) = write_blob(&oci, &self.manifest)?;
+        let index_path = oci.join("index.json");
+        let mut index = read_json(&index_path)?;
+        let entries = index.get_mut("manifests").and_then(|m| m.as_array_mut());
+        for entry in entries.into_iter().flatten() {
+            if entry["digest"] == self.manifest_digest.as_str() {
+                entry["digest"] = manifest_digest.clone().into();
+                entry["size"] = manifest_size.into();
+            }
+        }
+        edit::replace(&index_path, index.to_string().as_bytes())?;
+
+        match &self.name {
+            Some(name) => {
+                podman(&["image", "load", "-i", &oci.to_string_lossy()])?;
+                eprintln!("Reloaded image {} (layer {})", name, new_layer);
+            }
+            None => {
+                let keep = referenced(&oci, &index, "")?;
+                for old in [&self.manifest_digest, &old_config, &old_layer] {
+                    if !keep.contains(old.as_str()) {
+                        let _ = fs::remove_file(blob_path(&oci, old)?);
+                    }
+                }
+                eprintln!("Updated {} (layer {})", oci.display(), new_layer);
+            }
+        }
+        report.set(
+            "image",
+            serde_json::json!({
+                "name": self.name,
+                "layout": self.name.is_none().then(|| oci.display().to_string()),
+                "old_layer": old_layer,
+                "new_layer": new_layer,
+                "manifest": manifest_digest,
+            }),
+        );
+        Ok(())
+    }
+}
+
+/// The blobs the images of `index` reference, but for the one whose
+/// manifest is `except`.
+fn referenced(oci: &Path, index: &Value, except: &str) -> Result<BTreeSet<String>, String> {
+    let mut blobs = BTreeSet::new();
+    let manifests = index.get("manifests").and_then(|m| m.as_array());
+    for digest in manifests
+        .into_iter()
+        .flatten()
+        .filter_map(|m| m["digest"].as_str())
+    {
+        if digest == except {
+            continue;
+        }
+        blobs.insert(digest.to_string());
+        let manifest = read_json(&blob_path(oci, digest)?)?;
+        let config = manifest.pointer("/config/digest").and_then(|d| d.as_str());
+        let layers = manifest.get("layers").and_then(|l| l.as_array());
+        let layers = layers
+            .into_iter()
+            .flatten()
+            .filter_map(|l| l["digest"].as_str());
+        blobs.extend(config.into_iter().chain(layers).map(String::from));
+    }
+    Ok(blobs)
+}
+
+fn layer_has_files_img(path: &Path) -> Result<bool, String> {
+    let mut archive = tar::Archive::new(compress::open(path)?);
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let path = entry.path().map_err(|e| e.to_string())?;
+        if path.to_string_lossy() == FILES_IMG_PATH {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn blob_path(oci: &Path, digest: &str) -> Result<PathBuf, String> {
+    let (algo, hex) = digest
+        .split_once(':')
+        .ok_or_else(|| format!("malformed digest {}", digest))?;
+    Ok(oci.join("blobs").join(algo).join(hex))
+}
+
+fn read_json(path: &Path) -> Result<Value, String> {
+    let content = fs::read(path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+    serde_json::from_slice(&content).map_err(|e| format!("parse {}: {}", path.display(), e))
+}
+
+/// Store a JSON document as a blob; returns its digest and size.
+fn write_blob(oci: &Path, value: &Value) -> Result<(String, u64), String> {
+    let content = value.to_string();
+    let digest = format!("sha256:{:x}", Sha256::digest(content.as_bytes()));
+    edit::replace(&blob_path(oci, &digest)?, content.as_bytes())?;
+    Ok((digest, content.len() as u64))
+}
+
+fn hash_file(path: &Path) -> Result<(String, u64), String> {
+    hash(fs::File::open(path).map_err(|e| e.to_string())?)
+}
+
+/// The digest and size of what `input` reads.
+fn hash(mut input: impl Read) -> Result<(String, u64), String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 256 * 1024];
+    let mut size = 0u64;
+    loop {
+        let n = input.read(&mut buf).map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((format!("sha256:{:x}", hasher.finalize()), size))
+}
+
+fn podman(args: &[&str]) -> Result<(), String> {
+    let argv = remote::argv("EDIT_CHECKPOINT_PODMAN", "podman", args);
+    let out = remote::command(None, &argv)
+        .output()
+        .map_err(|e| format!("run {}: {}", argv.join(" "), e))?;
+    if !out.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            argv.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+//! Read CRIU images out of a checkpoint archive or an unpacked checkpoint
+//! directory, for the commands that only inspect them, and check the format
+//! of the images the edit commands patch.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::compress;
+
+pub const FILES_IMG_PATH: &str = "checkpoint/files.img";
+
+/// The first word of a CRIU image since format v2, then the image's own
+/// magic. v1 images start with their own magic.
+const IMG_COMMON_MAGIC: u32 = 0x5456_4319;
+/// The first word of the service images (inventory, stats, irmap-cache).
+const IMG_SERVICE_MAGIC: u32 = 0x5510_5940;
+/// The images patched, by file name prefix, with their magic.
+const PATCHED: &[(&str, &str, u32)] = &[
+    ("files", "FILES_MAGIC", 0x5630_3138),
+    ("core-", "CORE_MAGIC", 0x5505_3847),
+    ("timens-", "TIMENS_MAGIC", 0x4311_4433),
+    ("utsns-", "UTSNS_MAGIC", 0x5447_3203),
+    ("remap-fpath", "REMAP_FPATH_MAGIC", 0x5913_3954),
+    ("inventory", "INVENTORY_MAGIC", 0x5831_3116),
+];
+
+/// Refuse to patch the image at `path` unless `content` is in the format
+/// and of the kind its name says: a v2 image (a v2 service image for the
+/// inventory) with its kind's magic. crit given anything else fails with
+/// its own message at best, or writes an image CRIU cannot restore.
+pub fn check_version(path: &str, content: &[u8]) -> Result<(), String> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let word = |i: usize| {
+        content
+            .get(i * 4..i * 4 + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    };
+    let expected = PATCHED
+        .iter()
+        .find(|(prefix, _, _)| name.starts_with(prefix));
+    let first = if name.starts_with("inventory") {
+        IMG_SERVICE_MAGIC
+    } else {
+        IMG_COMMON_MAGIC
+    };
+    let found = match (word(0), word(1)) {
+        (Some(head), Some(magic)) if head == first => match expected {
+            Some((_, _, want)) if magic != *want => format!("v2 with magic {:#010x}", magic),
+            _ => return Ok(()),
+        },
+        (Some(IMG_SERVICE_MAGIC), _) => "a v2 service image".to_string(),
+        (Some(IMG_COMMON_MAGIC), _) => "a v2 image, not a service image".to_string(),
+        (Some(magic), _) if expected.is_some_and(|(_, _, want)| magic == *want) => "v1".to_string(),
+        (Some(magic), _) => format!("unknown, starting {:#010x}", magic),
+        (None, _) => format!("unknown, {} bytes long", content.len()),
+    };
+    let supported = match expected {
+        Some((_, magic_name, magic)) => {
+            format!("v2 ({:#010x}) with {} {:#010x}", first, magic_name, magic)
+        }
+        None => format!("v2 ({:#010x})", first),
+    };
+    Err(format!(
+        "{}: found image version {}, supported: {}; refusing to patch it",
+        path, found, supported
+    ))
+}
+
+/// Locate the CRIU images and the podman metadata of an unpacked checkpoint:
+/// an export-layout directory (DIR/checkpoint/files.img), podman's
+/// userdata/checkpoint, or a bare `criu dump` directory without metadata.
+pub fn dir_layout(dir: &Path) -> Result<(PathBuf, Option<PathBuf>), String> {
+    if dir.join(FILES_IMG_PATH).is_file() {
+        return Ok((dir.join("checkpoint"), Some(dir.to_path_buf())));
+    }
+    if !dir.join("files.img").is_file() {
+        return Err(format!("no files.img in {}", dir.display()));
+    }
+    let root = dir
+        .parent()
+        .filter(|_| dir.file_name().is_some_and(|n| n == "checkpoint"));
+    Ok((dir.to_path_buf(), root.map(Path::to_path_buf)))
+}
+
+/// Contents of the images whose file name (e.g. "fdinfo-2.img") passes
+/// `want`, keyed by file name.
+pub fn read(
+    checkpoint: &Path,
+    want: impl Fn(&str) -> bool,
+) -> Result<BTreeMap<String, Vec<u8>>, String> {
+    let mut images = BTreeMap::new();
+    if checkpoint.is_dir() {
+        let (dir, _) = dir_layout(checkpoint)?;
+        let entries = fs::read_dir(&dir).map_err(|e| format!("read {}: {}", dir.display(), e))?;
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if want(&name) {
+                let content = fs::read(entry.path())
+                    .map_err(|e| format!("read {}: {}", entry.path().display(), e))?;
+                images.insert(name, content);
+            }
+        }
+        return Ok(images);
+    }
+    let mut archive = tar::Archive::new(compress::open(checkpoint)?);
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let path = entry.path().map_err(|e| e.to_string())?;
+        let Some(name) = path
+            .to_str()
+            .and_then(|p| p.strip_prefix("checkpoint/"))
+            .filter(|n| !n.contains('/') && want(n))
+            .map(str::to_string)
+        else {
+            continue;
+        };
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).map_err(|e| e.to_string())?;
+        images.insert(name, content);
+    }
+    Ok(images)
+}
@@ -0,0 +1,122 @@
+//! Socket addresses as the decoded images hold them: crit renders an inet
+//! socket's src_addr and dst_addr as a dotted/colon string, or as the words
+//! of the in_addr (one) or in6_addr (four) CRIU copied out of the kernel.
+//! Those words are the address bytes, in network order, read as u32s on the
+//! dumping host, which for CRIU is little endian (x86_64, aarch64, ppc64le,
+//! riscv64); they are read and written as such here, not in the byte order of
+//! the host running the edit.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use serde_json::{json, Value};
+
+/// The in_addr word of `addr`.
+pub fn v4_word(addr: Ipv4Addr) -> u32 {
+    u32::from_le_bytes(addr.octets())
+}
+
+pub fn v4_from_word(word: u32) -> Ipv4Addr {
+    Ipv4Addr::from(word.to_le_bytes())
+}
+
+/// The in6_addr words of `addr`.
+pub fn v6_words(addr: Ipv6Addr) -> [u32; 4] {
+    let octets = addr.octets();
+    std::array::from_fn(|i| u32::from_le_bytes(octets[i * 4..i * 4 + 4].try_into().unwrap()))
+}
+
+pub fn v6_from_words(words: [u32; 4]) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    for (i, word) in words.iter().enumerate() {
+        octets[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    Ipv6Addr::from(octets)
+}
+
+/// The address in a src_addr or dst_addr array, in either rendering.
+pub fn decode(value: &Value) -> Option<IpAddr> {
+    let items = value.as_array()?;
+    if let [Value::String(s)] = items.as_slice() {
+        return s.parse().ok();
+    }
+    let words: Vec<u32> = items
+        .iter()
+        .map(|w| w.as_u64().and_then(|w| u32::try_from(w).ok()))
+        .collect::<Option<_>>()?;
+    match words.as_slice() {
+        [word] => Some(IpAddr::V4(v4_from_word(*word))),
+        [a, b, c, d] => Some(IpAddr::V6(v6_from_words([*a, *b, *c, *d]))),
+        _ => None,
+    }
+}
+
+/// `addr` as a src_addr or dst_addr array, rendered as `like` is: words if it
+/// holds numbers, a string otherwise.
+pub fn encode(addr: IpAddr, like: &Value) -> Value {
+    if !like.get(0).is_some_and(Value::is_number) {
+        return json!([addr.to_string()]);
+    }
+    match addr {
+        IpAddr::V4(addr) => json!([v4_word(addr)]),
+        IpAddr::V6(addr) => json!(v6_words(addr)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v4_word_is_network_order_read_little_endian() {
+        // 192.168.12.2 as crit prints the in_addr of a socket bound to it
+        assert_eq!(v4_word(Ipv4Addr::new(192, 168, 12, 2)), 0x020c_a8c0);
+        assert_eq!(v4_from_word(0x020c_a8c0), Ipv4Addr::new(192, 168, 12, 2));
+        assert_eq!(v4_word(Ipv4Addr::new(127, 0, 0, 1)), 0x0100_007f);
+        assert_eq!(v4_word(Ipv4Addr::UNSPECIFIED), 0);
+    }
+
+    #[test]
+    fn v6_words_are_network_order_read_little_endian() {
+        let addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        assert_eq!(v6_words(addr), [0xb80d_0120, 0, 0, 0x0100_0000]);
+        assert_eq!(v6_from_words([0xb80d_0120, 0, 0, 0x0100_0000]), addr);
+        assert_eq!(v6_words(Ipv6Addr::LOCALHOST), [0, 0, 0, 0x0100_0000]);
+    }
+
+    #[test]
+    fn round_trips() {
+        for addr in [
+            "10.0.0.9",
+            "255.255.255.255",
+            "0.0.0.0",
+            "fd00::2",
+            "::ffff:10.0.0.9",
+        ] {
+            let addr: IpAddr = addr.parse().unwrap();
+            for like in [json!([0]), json!(["0.0.0.0"])] {
+                assert_eq!(decode(&encode(addr, &like)), Some(addr));
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_the_rendering() {
+        let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9));
+        assert_eq!(encode(addr, &json!([0x020c_a8c0])), json!([0x0900_000a]));
+        assert_eq!(encode(addr, &json!(["192.168.12.2"])), json!(["10.0.0.9"]));
+        let addr: IpAddr = "fd00::2".parse().unwrap();
+        assert_eq!(
+            encode(addr, &json!([0, 0, 0, 0])),
+            json!([0xfd, 0, 0, 0x0200_0000])
+        );
+    }
+
+    #[test]
+    fn decodes_only_addresses() {
+        assert_eq!(decode(&json!([])), None);
+        assert_eq!(decode(&json!([1, 2])), None);
+        assert_eq!(decode(&json!([1u64 << 32])), None);
+        assert_eq!(decode(&json!(["not an address"])), None);
+        assert_eq!(decode(&json!("10.0.0.9")), None);
+    }
+}
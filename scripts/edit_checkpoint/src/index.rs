@@ -0,0 +1,58 @@
+//! The sidecar index of an edited archive (--index): where each entry's
+//! header and data are in the tar, written next to it as TAR.index.json. A
+//! fetcher on the target range-requests the entries it needs first (with
+//! --restore-order, everything before restore-index.json) instead of reading
+//! the archive front to back.
+
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::report::Report;
+
+pub const SUFFIX: &str = ".index.json";
+
+pub fn sidecar_path(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(SUFFIX);
+    PathBuf::from(path)
+}
+
+/// The index of the uncompressed tar at `archive`: for each entry its path,
+/// the offset of its header, and the offset and length of its data.
+pub fn build(archive: &Path) -> Result<Value, String> {
+    let file = fs::File::open(archive).map_err(|e| format!("open {}: {}", archive.display(), e))?;
+    let size = file.metadata().map_err(|e| e.to_string())?.len();
+    let mut tar = tar::Archive::new(BufReader::new(file));
+    let mut entries = Vec::new();
+    for entry in tar.entries_with_seek().map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| format!("{}: {}", archive.display(), e))?;
+        let path = entry.path().map_err(|e| e.to_string())?;
+        entries.push(json!({
+            "path": path.display().to_string(),
+            "header": entry.raw_header_position(),
+            "offset": entry.raw_file_position(),
+            "length": entry.size(),
+        }));
+    }
+    let name = archive
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned());
+    Ok(json!({"archive": name, "size": size, "entries": entries}))
+}
+
+/// Index `archive` and write the sidecar next to it.
+pub fn write(archive: &Path, report: &mut Report) -> Result<(), String> {
+    let index = build(archive)?;
+    let path = sidecar_path(archive);
+    let tmp = format!("{}.new", path.display());
+    let content = serde_json::to_vec_pretty(&index).map_err(|e| e.to_string())?;
+    fs::write(&tmp, content).map_err(|e| format!("write {}: {}", tmp, e))?;
+    fs::rename(&tmp, &path).map_err(|e| format!("rename {}: {}", tmp, e))?;
+    let count = index["entries"].as_array().map_or(0, Vec::len);
+    eprintln!("Wrote {} ({} entries)", path.display(), count);
+    report.set("index", path.display().to_string());
+    Ok(())
+}
@@ -0,0 +1,125 @@
+//! `inject`: put a file into a checkpoint archive as the entry ENTRY, e.g. a
+//! hand-fixed config.dump, in one streaming pass. An existing entry keeps its
+//! mode, owner and place in the archive and gets the file's size and mtime;
+//! a new one is added at the end with the file's mode.
+//!
+//! The restore index lists the entry sizes and is left out. A signed archive
+//! is refused, since its signature would no longer hold.
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use clap::Args;
+
+use crate::compress;
+use crate::edit::RESTORE_INDEX_PATH;
+use crate::lock;
+use crate::rewrite::Rewrite;
+use crate::sign::{self, MANIFEST_PATH, SIGNATURE_PATH};
+
+#[derive(Args)]
+pub struct InjectArgs {
+    /// Checkpoint archive, changed in place
+    archive: PathBuf,
+    /// Path of the entry in the archive, e.g. config.dump or
+    /// checkpoint/files.img
+    entry: String,
+    /// File to put in as ENTRY
+    file: PathBuf,
+}
+
+/// `path` without ./ and leading slashes, as entries are compared.
+pub fn entry_name(path: &str) -> String {
+    Path::new(path)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+pub fn run(args: &InjectArgs) -> Result<(), String> {
+    let path = args.archive.as_path();
+    if Path::new(&args.entry)
+        .components()
+        .any(|c| c == Component::ParentDir)
+    {
+        return Err(format!(
+            "{}: entries cannot have .. in their path",
+            args.entry
+        ));
+    }
+    let name = entry_name(&args.entry);
+    if name.is_empty() {
+        return Err(format!("{} is not an entry path", args.entry));
+    }
+    let meta =
+        fs::metadata(&args.file).map_err(|e| format!("stat {}: {}", args.file.display(), e))?;
+    if !meta.is_file() {
+        return Err(format!("{} is not a regular file", args.file.display()));
+    }
+    let open =
+        || fs::File::open(&args.file).map_err(|e| format!("open {}: {}", args.file.display(), e));
+
+    let _input_lock = lock::input(path)?;
+    let mut rewrite = Rewrite::start(path, path)?;
+    let builder = rewrite.builder();
+    let mut archive = tar::Archive::new(compress::open(path)?);
+    let mut replaced = Vec::new();
+    let mut dropped_index = false;
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let entry_path = entry.path().map_err(|e| e.to_string())?;
+        let entry_path = entry_name(&entry_path.to_string_lossy());
+        if entry_path == MANIFEST_PATH || entry_path == SIGNATURE_PATH {
+            return Err(sign::refuse(&path.display().to_string()));
+        }
+        if entry_path == RESTORE_INDEX_PATH && name != RESTORE_INDEX_PATH {
+            dropped_index = true;
+            continue;
+        }
+        let mut header = entry.header().clone();
+        let result = if entry_path == name {
+            if !header.entry_type().is_file() {
+                return Err(format!("{} in {} is not a file", name, path.display()));
+            }
+            replaced.push(header.size().unwrap_or(0));
+            header.set_size(meta.len());
+            header.set_mtime(meta.mtime().max(0) as u64);
+            header.set_cksum();
+            builder.append(&header, open()?)
+        } else {
+            builder.append(&header, &mut entry)
+        };
+        result.map_err(|e| format!("write {}: {}", entry_path, e))?;
+    }
+    if replaced.is_empty() {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(meta.len());
+        header.set_mode(meta.mode() & 0o7777);
+        header.set_mtime(meta.mtime().max(0) as u64);
+        builder
+            .append_data(&mut header, &name, open()?)
+            .map_err(|e| format!("write {}: {}", name, e))?;
+    }
+    rewrite.finish(Some(path))?;
+
+    match replaced.as_slice() {
+        [] => eprintln!("Added {} ({} bytes)", name, meta.len()),
+        [old] => eprintln!("Replaced {} ({} → {} bytes)", name, old, meta.len()),
+        _ => eprintln!(
+            "Replaced the {} entries named {} ({} bytes each)",
+            replaced.len(),
+            name,
+            meta.len()
+        ),
+    }
+    if dropped_index {
+        eprintln!(
+            "Left out {}, which lists the old sizes; edit with --restore-order to write it again",
+            RESTORE_INDEX_PATH
+        );
+    }
+    Ok(())
+}
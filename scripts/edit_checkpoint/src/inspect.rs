@@ -0,0 +1,521 @@
+//! `inspect`: what a checkpoint's processes hold besides sockets that a
+//! migration has to account for.
+//!
+//! Pipes and FIFOs: one row per pipe (by its kernel pipe id) with its read
+//! and write ends and the PID:FD pairs holding them, and the bytes queued in
+//! it (pipes-data.img, fifo-data.img). CRIU restores a pipe whole, so a pipe
+//! with only one kind of end in the checkpoint had its peer outside it,
+//! another container's process or a `podman exec` (stdin, stdout and stderr
+//! of the container's own processes excepted, which podman reconnects):
+//! that peer is cut off by a solo migration, and these rows are flagged.
+//!
+//! Event fds: eventfds with their counter, signalfds with their mask,
+//! timerfds with their clock and timer, and epoll instances with the fds
+//! they watch and what those are, inet sockets by address and port. An
+//! epoll set watches descriptors by number, and CRIU registers them again
+//! on the restored ones: sockets the edit rebinds stay in the sets.
+//!
+//! Shared memory: the SysV segments of the IPC namespace (ipcns-shm-N.img)
+//! with their key, size and mode, and the memfds (memfd.img) with their
+//! size and the descriptors open on them, and the total. It is all
+//! allocated again on the target at restore, SysV segments within the
+//! namespace's shmmax and shmall, so a node with less memory free than the
+//! total fails the restore.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::crit::{self, number};
+use crate::report::human;
+use crate::{images, proto, sockets};
+
+#[derive(Args)]
+pub struct InspectArgs {
+    /// Checkpoint archive or unpacked checkpoint directory
+    checkpoint: PathBuf,
+    /// Write JSON instead of text
+    #[arg(long)]
+    json: bool,
+    /// Write the report to FILE instead of stdout
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+/// O_ACCMODE and O_WRONLY of the flags of a pipe end.
+const O_ACCMODE: u64 = 3;
+const O_WRONLY: u64 = 1;
+
+pub fn run(args: &InspectArgs) -> Result<(), String> {
+    let wanted = |name: &str| {
+        matches!(
+            name,
+            "files.img" | "pipes-data.img" | "fifo-data.img" | "eventpoll-tfd.img" | "memfd.img"
+        ) || (name.ends_with(".img")
+            && ["fdinfo-", "ids-", "ipcns-shm-"]
+                .iter()
+                .any(|p| name.starts_with(p)))
+    };
+    let images = images::read(&args.checkpoint, wanted)?;
+    let files = images
+        .get("files.img")
+        .ok_or_else(|| format!("no files.img in {}", args.checkpoint.display()))?;
+    let types = [
+        proto::FD_TYPE_REG,
+        proto::FD_TYPE_PIPE,
+        proto::FD_TYPE_FIFO,
+        proto::FD_TYPE_INETSK,
+        proto::FD_TYPE_EVENTFD,
+        proto::FD_TYPE_EVENTPOLL,
+        proto::FD_TYPE_SIGNALFD,
+        proto::FD_TYPE_TIMERFD,
+        proto::FD_TYPE_MEMFD,
+    ];
+    let files = match proto::Split::new(files, proto::FILE_ENTRY_TYPE, &types) {
+        Some(split) => crit::decode(&split.picked())?,
+        None => crit::decode(files)?,
+    };
+    let entries = files
+        .get("entries")
+        .and_then(|e| e.as_array())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let holders = sockets::fd_holders(&images)?;
+    let pipes = pipes(&images, entries, &holders)?;
+    let event_fds = event_fds(&images, entries, &holders)?;
+    let shared = shared_memory(&images, entries, &holders)?;
+    let shared_bytes: u64 = shared.iter().filter_map(|s| s["size"].as_u64()).sum();
+
+    let flagged = pipes.iter().filter(|p| p["peer_outside"] == true).count();
+    let out = if args.json {
+        let doc = json!({
+            "pipes": pipes,
+            "event_fds": event_fds,
+            "shared_memory": shared,
+            "shared_memory_bytes": shared_bytes,
+        });
+        serde_json::to_string_pretty(&doc).map_err(|e| e.to_string())? + "\n"
+    } else {
+        let mut out = format!("Pipes and FIFOs ({}):\n", pipes.len());
+        for pipe in &pipes {
+            out += &pipe_line(pipe);
+        }
+        out += &format!("Event fds ({}):\n", event_fds.len());
+        for fd in &event_fds {
+            out += &event_line(fd);
+        }
+        out += &format!(
+            "Shared memory ({}, {} in all):\n",
+            shared.len(),
+            human(shared_bytes)
+        );
+        for shm in &shared {
+            out += &shared_line(shm);
+        }
+        out
+    };
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, out).map_err(|e| format!("write {}: {}", path.display(), e))?
+        }
+        None => std::io::stdout()
+            .write_all(out.as_bytes())
+            .map_err(|e| e.to_string())?,
+    }
+    if flagged > 0 {
+        eprintln!(
+            "Warning: {} pipes or FIFOs lead outside the checkpoint; migrate the processes \
+             on their other end as well",
+            flagged
+        );
+    }
+    Ok(())
+}
+
+/// The pipes and FIFOs of the files.img `entries`, by pipe id.
+fn pipes(
+    images: &BTreeMap<String, Vec<u8>>,
+    entries: &[Value],
+    holders: &BTreeMap<u64, Vec<String>>,
+) -> Result<Vec<Value>, String> {
+    let reg_names: BTreeMap<u64, &Value> = entries
+        .iter()
+        .filter(|e| e["type"] == "REG")
+        .filter_map(|e| Some((e["id"].as_u64()?, e.get("reg")?)))
+        .collect();
+    let queued = queued(images)?;
+    // Pipe id → kind, FIFO path and ends
+    let mut pipes: BTreeMap<u64, (&str, Option<&str>, Vec<Value>)> = BTreeMap::new();
+    for entry in entries {
+        let (kind, body) = match entry["type"].as_str() {
+            Some("PIPE") => ("pipe", &entry["pipe"]),
+            Some("FIFO") => ("fifo", &entry["fifo"]),
+            _ => continue,
+        };
+        let (Some(id), Some(pipe_id)) =
+            (entry["id"].as_u64(), body.get("pipe_id").and_then(number))
+        else {
+            continue;
+        };
+        // A FIFO's access mode and path are its REG entry's
+        let reg = body
+            .get("regf_id")
+            .and_then(number)
+            .and_then(|r| reg_names.get(&r));
+        let flags = match reg {
+            Some(reg) => reg.get("flags"),
+            None => body.get("flags"),
+        };
+        let write = flags.and_then(number).unwrap_or(0) & O_ACCMODE == O_WRONLY;
+        let slot = pipes.entry(pipe_id).or_insert((kind, None, Vec::new()));
+        if let Some(name) = reg.and_then(|r| r["name"].as_str()) {
+            slot.1 = Some(name);
+        }
+        slot.2.push(json!({
+            "id": id,
+            "end": if write { "write" } else { "read" },
+            "fds": holders.get(&id).cloned().unwrap_or_default(),
+        }));
+    }
+    let mut rows = Vec::new();
+    for (pipe_id, (kind, path, ends)) in pipes {
+        let has = |end: &str| ends.iter().any(|e| e["end"] == end);
+        let stdio = ends.iter().all(|e| {
+            let fds = e["fds"].as_array().map(Vec::as_slice).unwrap_or_default();
+            !fds.is_empty() && fds.iter().all(is_stdio)
+        });
+        let paired = has("read") && has("write");
+        let peer_outside = !paired && !stdio;
+        rows.push(json!({
+            "kind": kind,
+            "pipe_id": pipe_id,
+            "path": path,
+            "ends": ends,
+            "queued_bytes": queued.get(&pipe_id).copied().unwrap_or(0),
+            "stdio": stdio,
+            "peer_outside": peer_outside,
+        }));
+    }
+    Ok(rows)
+}
+
+/// Whether a "PID:FD" is a standard stream.
+fn is_stdio(fd: &Value) -> bool {
+    fd.as_str()
+        .and_then(|fd| fd.rsplit_once(':'))
+        .is_some_and(|(_, n)| matches!(n, "0" | "1" | "2"))
+}
+
+/// Bytes queued in each pipe, by pipe id.
+fn queued(images: &BTreeMap<String, Vec<u8>>) -> Result<BTreeMap<u64, u64>, String> {
+    let mut queued = BTreeMap::new();
+    for name in ["pipes-data.img", "fifo-data.img"] {
+        let Some(content) = images.get(name) else {
+            continue;
+        };
+        let data = crit::decode(content)?;
+        let entries = data.get("entries").and_then(|e| e.as_array());
+        for entry in entries.into_iter().flatten() {
+            if let (Some(pipe_id), Some(bytes)) = (
+                entry.get("pipe_id").and_then(number),
+                entry.get("bytes").and_then(number),
+            ) {
+                *queued.entry(pipe_id).or_default() += bytes;
+            }
+        }
+    }
+    Ok(queued)
+}
+
+fn pipe_line(pipe: &Value) -> String {
+    let ends: Vec<String> = pipe["ends"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|end| {
+            let fds: Vec<&str> = end["fds"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|f| f.as_str())
+                .collect();
+            let fds = if fds.is_empty() {
+                "-".to_string()
+            } else {
+                fds.join(",")
+            };
+            format!("{} {}", end["end"].as_str().unwrap_or_default(), fds)
+        })
+        .collect();
+    let mut line = format!(
+        "  {} {}",
+        pipe["kind"].as_str().unwrap_or_default(),
+        pipe["pipe_id"]
+    );
+    if let Some(path) = pipe["path"].as_str() {
+        line += &format!(" {}", path);
+    }
+    line += &format!("  {}", ends.join("  "));
+    if let Some(bytes) = pipe["queued_bytes"].as_u64().filter(|b| *b > 0) {
+        line += &format!("  {} bytes queued", bytes);
+    }
+    if pipe["stdio"] == true {
+        line += "  (stdio)";
+    }
+    if pipe["peer_outside"] == true {
+        line += "  PEER OUTSIDE THE CHECKPOINT";
+    }
+    line + "\n"
+}
+
+/// The files.img kinds `event_fds` lists: type, body field, name.
+const EVENT_FDS: [(&str, &str, &str); 4] = [
+    ("EVENTFD", "efd", "eventfd"),
+    ("EVENTPOLL", "epfd", "epoll"),
+    ("SIGNALFD", "sgfd", "signalfd"),
+    ("TIMERFD", "tfd", "timerfd"),
+];
+
+/// The eventfds, epoll instances, signalfds and timerfds of the files.img
+/// `entries`.
+fn event_fds(
+    images: &BTreeMap<String, Vec<u8>>,
+    entries: &[Value],
+    holders: &BTreeMap<u64, Vec<String>>,
+) -> Result<Vec<Value>, String> {
+    let by_id: BTreeMap<u64, &Value> = entries
+        .iter()
+        .filter_map(|e| Some((e["id"].as_u64()?, e)))
+        .collect();
+    // "PID:FD" → file id, to see what an epoll set's fds are
+    let by_fd: BTreeMap<&str, u64> = holders
+        .iter()
+        .flat_map(|(id, fds)| fds.iter().map(move |fd| (fd.as_str(), *id)))
+        .collect();
+    // Targets of epoll sets in images older than the tfd field, by epoll id
+    let mut old_targets: BTreeMap<u64, Vec<Value>> = BTreeMap::new();
+    if let Some(content) = images.get("eventpoll-tfd.img") {
+        let data = crit::decode(content)?;
+        let targets = data.get("entries").and_then(|e| e.as_array());
+        for target in targets.into_iter().flatten() {
+            if let Some(id) = target.get("id").and_then(number) {
+                old_targets.entry(id).or_default().push(target.clone());
+            }
+        }
+    }
+    let mut rows = Vec::new();
+    for entry in entries {
+        let Some((_, field, kind)) = EVENT_FDS.iter().find(|(t, _, _)| entry["type"] == *t) else {
+            continue;
+        };
+        let (Some(id), Some(body)) = (entry["id"].as_u64(), entry.get(*field)) else {
+            continue;
+        };
+        let fds = holders.get(&id).cloned().unwrap_or_default();
+        let mut row = json!({ "kind": kind, "id": id, "fds": fds });
+        match *kind {
+            "eventfd" => row["counter"] = body.get("counter").and_then(number).into(),
+            "signalfd" => row["sigmask"] = body.get("sigmask").and_then(number).into(),
+            "timerfd" => {
+                for key in ["clockid", "ticks", "vsec", "vnsec", "isec", "insec"] {
+                    row[key] = body.get(key).and_then(number).into();
+                }
+            }
+            _ => {
+                let mut targets: Vec<Value> = body
+                    .get("tfd")
+                    .and_then(|t| t.as_array())
+                    .cloned()
+                    .unwrap_or_default();
+                targets.extend(old_targets.remove(&id).unwrap_or_default());
+                let pids: Vec<&str> = fds
+                    .iter()
+                    .filter_map(|fd| fd.rsplit_once(':'))
+                    .map(|(pid, _)| pid)
+                    .collect();
+                let watches: Vec<Value> = targets
+                    .iter()
+                    .filter_map(|t| t.get("tfd").and_then(|n| n.as_u64()))
+                    .map(|tfd| {
+                        let target = pids
+                            .iter()
+                            .find_map(|pid| by_fd.get(format!("{}:{}", pid, tfd).as_str()))
+                            .and_then(|id| by_id.get(id));
+                        json!({ "fd": tfd, "target": target.map(|t| describe(t)) })
+                    })
+                    .collect();
+                row["watches"] = watches.into();
+            }
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// What the files.img `entry` is, in a few words.
+fn describe(entry: &Value) -> String {
+    match entry["type"].as_str().unwrap_or_default() {
+        "INETSK" => {
+            let row = sockets::row(&entry["isk"]);
+            let text = |key: &str| match &row[key] {
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
+            };
+            format!(
+                "{} {}:{} {}",
+                text("proto"),
+                text("src_addr"),
+                text("src_port"),
+                text("state")
+            )
+        }
+        "PIPE" => format!("pipe {}", entry["pipe"]["pipe_id"]),
+        "REG" => entry["reg"]["name"].as_str().unwrap_or("file").to_string(),
+        kind => kind.to_lowercase(),
+    }
+}
+
+fn clock(clockid: &Value) -> String {
+    match clockid.as_u64() {
+        Some(0) => "CLOCK_REALTIME".into(),
+        Some(1) => "CLOCK_MONOTONIC".into(),
+        Some(7) => "CLOCK_BOOTTIME".into(),
+        _ => format!("clock {}", clockid),
+    }
+}
+
+fn event_line(fd: &Value) -> String {
+    let fds: Vec<&str> = fd["fds"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|f| f.as_str())
+        .collect();
+    let fds = if fds.is_empty() {
+        "-".to_string()
+    } else {
+        fds.join(",")
+    };
+    let detail = match fd["kind"].as_str().unwrap_or_default() {
+        "eventfd" => format!("counter {}", fd["counter"]),
+        "signalfd" => format!("mask {:#x}", fd["sigmask"].as_u64().unwrap_or(0)),
+        "timerfd" => format!(
+            "{}  value {}.{:09}s  interval {}.{:09}s",
+            clock(&fd["clockid"]),
+            fd["vsec"].as_u64().unwrap_or(0),
+            fd["vnsec"].as_u64().unwrap_or(0),
+            fd["isec"].as_u64().unwrap_or(0),
+            fd["insec"].as_u64().unwrap_or(0)
+        ),
+        _ => {
+            let watches: Vec<String> = fd["watches"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|w| {
+                    let target = w["target"].as_str().unwrap_or("not open");
+                    format!("fd {} ({})", w["fd"], target)
+                })
+                .collect();
+            format!("watches {}", watches.join(", "))
+        }
+    };
+    format!(
+        "  {} {}  {}\n",
+        fd["kind"].as_str().unwrap_or_default(),
+        fds,
+        detail
+    )
+}
+
+/// The SysV shm segments and memfds of the checkpoint.
+fn shared_memory(
+    images: &BTreeMap<String, Vec<u8>>,
+    entries: &[Value],
+    holders: &BTreeMap<u64, Vec<String>>,
+) -> Result<Vec<Value>, String> {
+    let mut rows = Vec::new();
+    for (name, content) in images.iter().filter(|(n, _)| n.starts_with("ipcns-shm-")) {
+        let data = crit::decode(content)?;
+        let segments = data.get("entries").and_then(|e| e.as_array());
+        for segment in segments.into_iter().flatten() {
+            let desc = &segment["desc"];
+            rows.push(json!({
+                "kind": "sysv",
+                "image": name,
+                "key": desc.get("key").and_then(number),
+                "shmid": desc.get("id").and_then(number),
+                "mode": desc.get("mode").and_then(number),
+                "size": segment.get("size").and_then(number).unwrap_or(0),
+            }));
+        }
+    }
+    // memfd inode id → the descriptors open on it
+    let mut fds: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+    for entry in entries.iter().filter(|e| e["type"] == "MEMFD") {
+        let (Some(id), Some(inode)) = (
+            entry["id"].as_u64(),
+            entry["memfd"].get("inode_id").and_then(number),
+        ) else {
+            continue;
+        };
+        let held = holders.get(&id).cloned().unwrap_or_default();
+        fds.entry(inode).or_default().extend(held);
+    }
+    if let Some(content) = images.get("memfd.img") {
+        let data = crit::decode(content)?;
+        let inodes = data.get("entries").and_then(|e| e.as_array());
+        for inode in inodes.into_iter().flatten() {
+            let id = inode.get("inode_id").and_then(number);
+            rows.push(json!({
+                "kind": "memfd",
+                "name": inode.get("name"),
+                "inode_id": id,
+                "size": inode.get("size").and_then(number).unwrap_or(0),
+                "hugetlb": inode.get("hugetlb_flag").and_then(number).is_some_and(|f| f != 0),
+                "fds": id.and_then(|id| fds.remove(&id)).unwrap_or_default(),
+            }));
+        }
+    }
+    Ok(rows)
+}
+
+fn shared_line(shm: &Value) -> String {
+    let size = human(shm["size"].as_u64().unwrap_or(0));
+    if shm["kind"] == "sysv" {
+        let mut line = format!(
+            "  sysv key {:#010x} shmid {}  {}",
+            shm["key"].as_u64().unwrap_or(0),
+            shm["shmid"],
+            size
+        );
+        if let Some(mode) = shm["mode"].as_u64() {
+            line += &format!("  mode {:o}", mode & 0o777);
+        }
+        return line + "\n";
+    }
+    let fds: Vec<&str> = shm["fds"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|f| f.as_str())
+        .collect();
+    let fds = if fds.is_empty() {
+        "mapped only".to_string()
+    } else {
+        fds.join(",")
+    };
+    let mut line = format!(
+        "  memfd:{} {}  {}",
+        shm["name"].as_str().unwrap_or_default(),
+        fds,
+        size
+    );
+    if shm["hugetlb"] == true {
+        line += "  (hugetlb)";
+    }
+    line + "\n"
+}
@@ -0,0 +1,112 @@
+//! --verify-copy: check that the entries an edit leaves alone come out of
+//! it bit for bit before the output is trusted for restore.
+//!
+//! Each entry copied unchanged is hashed as the input is read: SHA-256 of its
+//! tar header, with the checksum field blanked since the builder recomputes
+//! it, and of its data. The hashes are kept in the report as `copied`. Once
+//! the output is written it is read back, and every one of them has to be in
+//! it with the same hash, in order among the entries of the same path, before
+//! the output replaces anything.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::compress;
+use crate::report::Report;
+
+/// Offset and length of the checksum field in a tar header.
+const CKSUM: std::ops::Range<usize> = 148..156;
+
+fn start(header: &tar::Header) -> Sha256 {
+    let mut bytes = *header.as_bytes();
+    bytes[CKSUM].fill(b' ');
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    hasher
+}
+
+/// The hash of an entry with `header` and `data`.
+pub fn digest(header: &tar::Header, data: &[u8]) -> String {
+    let mut hasher = start(header);
+    hasher.update(data);
+    format!("{:x}", hasher.finalize())
+}
+
+/// The hash of an entry with `header` whose `size` bytes of data are at
+/// `offset` in `source`.
+pub fn digest_at(
+    header: &tar::Header,
+    source: &File,
+    offset: u64,
+    size: u64,
+) -> std::io::Result<String> {
+    let mut hasher = start(header);
+    let mut buf = vec![0; (1 << 20).min(size as usize)];
+    let mut done = 0;
+    while done < size {
+        let n = buf.len().min((size - done) as usize);
+        source.read_exact_at(&mut buf[..n], offset + done)?;
+        hasher.update(&buf[..n]);
+        done += n as u64;
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Record that the entry at `path` was copied with hash `sha256`.
+pub fn record(report: &mut Report, path: &str, sha256: String) {
+    report.extend("copied", [json!({"path": path, "sha256": sha256})]);
+}
+
+/// Read the archive at `output` back and check every entry recorded as
+/// copied is in it unchanged.
+pub fn verify(output: &Path, report: &mut Report) -> Result<(), String> {
+    let copied = report.take("copied").unwrap_or_default();
+    let mut expected: BTreeMap<String, VecDeque<String>> = BTreeMap::new();
+    for entry in copied.as_array().into_iter().flatten() {
+        if let (Some(path), Some(sha256)) = (entry["path"].as_str(), entry["sha256"].as_str()) {
+            expected
+                .entry(path.to_string())
+                .or_default()
+                .push_back(sha256.to_string());
+        }
+    }
+    let total: usize = expected.values().map(VecDeque::len).sum();
+    let mut archive = tar::Archive::new(compress::open(output)?);
+    let read_error = |e: std::io::Error| format!("verify {}: {}", output.display(), e);
+    for entry in archive.entries().map_err(read_error)? {
+        let mut entry = entry.map_err(read_error)?;
+        let path = entry.path().map_err(read_error)?.display().to_string();
+        let Some(sha256) = expected.get_mut(&path).and_then(VecDeque::pop_front) else {
+            continue;
+        };
+        let mut hasher = start(entry.header());
+        std::io::copy(&mut entry, &mut hasher).map_err(read_error)?;
+        if format!("{:x}", hasher.finalize()) != sha256 {
+            return Err(format!(
+                "--verify-copy: {} in {} differs from the input's; the edit is not \
+                 trustworthy, the input is left as it was",
+                path,
+                output.display()
+            ));
+        }
+    }
+    if let Some((path, _)) = expected.iter().find(|(_, left)| !left.is_empty()) {
+        return Err(format!(
+            "--verify-copy: {} is missing from {}",
+            path,
+            output.display()
+        ));
+    }
+    eprintln!(
+        "Verified {} unchanged entries in {}",
+        total,
+        output.display()
+    );
+    report.set("copy_verified", total);
+    Ok(())
+}
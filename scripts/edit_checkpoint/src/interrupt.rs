@@ -0,0 +1,118 @@
+//! Cleaning up after SIGINT and SIGTERM.
+//!
+//! A signal ends the process without running destructors, so a half-written
+//! ARCHIVE.new and the crit children would outlive an interrupted run. They
+//! are registered here for as long as they exist; on SIGINT or SIGTERM a
+//! thread kills the children, removes the files and exits with 128 + the
+//! signal number (130 or 143).
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+enum Item {
+    File(PathBuf),
+    Child(u32),
+}
+
+static ITEMS: Mutex<BTreeMap<u64, Item>> = Mutex::new(BTreeMap::new());
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// A registration, dropped once what it names is gone or complete.
+pub struct Guard(u64);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        ITEMS.lock().unwrap().remove(&self.0);
+    }
+}
+
+fn register(item: Item) -> Guard {
+    let id = NEXT.fetch_add(1, Ordering::Relaxed);
+    ITEMS.lock().unwrap().insert(id, item);
+    Guard(id)
+}
+
+/// Remove the partial output file at `path` if the run is interrupted.
+pub fn remove_file(path: &Path) -> Guard {
+    register(Item::File(path.to_path_buf()))
+}
+
+/// An output file being written: removed when dropped before `keep`, so a
+/// failed run leaves none of it, and by the handler if the run is
+/// interrupted.
+pub struct Partial {
+    path: PathBuf,
+    kept: bool,
+    _guard: Guard,
+}
+
+impl Partial {
+    pub fn new(path: &Path) -> Partial {
+        Partial {
+            path: path.to_path_buf(),
+            kept: false,
+            _guard: remove_file(path),
+        }
+    }
+
+    /// The output is complete.
+    pub fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        if !self.kept {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Kill `child` if the run is interrupted.
+pub fn kill(child: &Child) -> Guard {
+    register(Item::Child(child.id()))
+}
+
+/// Handle SIGINT and SIGTERM for the rest of the run.
+#[cfg(feature = "edit")]
+pub fn install() -> Result<(), String> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use std::fs;
+
+    let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM])
+        .map_err(|e| format!("install signal handlers: {}", e))?;
+    std::thread::spawn(move || {
+        let Some(signal) = signals.forever().next() else {
+            return;
+        };
+        let name = if signal == SIGINT {
+            "SIGINT"
+        } else {
+            "SIGTERM"
+        };
+        // Held to the exit, so nothing registers or unregisters meanwhile
+        let items = ITEMS.lock().unwrap_or_else(|e| e.into_inner());
+        for item in items.values() {
+            if let Item::Child(pid) = item {
+                // SAFETY: kill(2) takes no pointers; a child already reaped
+                // makes it fail with ESRCH at worst
+                unsafe { libc::kill(*pid as libc::pid_t, libc::SIGKILL) };
+            }
+        }
+        let removed = items
+            .values()
+            .filter(|item| match item {
+                Item::File(path) => fs::remove_file(path).is_ok(),
+                Item::Child(_) => false,
+            })
+            .count();
+        eprintln!("Interrupted by {}; removed {} partial files", name, removed);
+        crate::events::error(&format!("interrupted by {}", name));
+        std::process::exit(128 + signal);
+    });
+    Ok(())
+}
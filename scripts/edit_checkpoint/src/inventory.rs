@@ -0,0 +1,120 @@
+//! Patch the fields of checkpoint/inventory.img that tie a checkpoint to
+//! the host it was dumped on (--inventory-set, --inventory-clear).
+//!
+//! The inventory is CRIU's header for the whole dump: the image format, the
+//! kernel object ids of the root task (root_ids), its cgroup set, the LSM of
+//! the source host (lsmtype) and the uptime at dump. CRIU refuses a restore
+//! where the target's LSM differs from lsmtype unless given --lsm-profile;
+//! with lsmtype cleared it does not compare them. Fields of root_ids are set
+//! as root_ids.FIELD. img_version is left alone: it says how the other
+//! images are encoded.
+
+use serde_json::{json, Map, Value};
+
+use crate::crit;
+use crate::report::Report;
+use crate::summary;
+
+pub const INVENTORY_PATH: &str = "checkpoint/inventory.img";
+
+/// Fields that describe the images rather than the host.
+const FORMAT_FIELDS: [&str; 2] = ["img_version", "fdinfo_per_id"];
+
+#[derive(Clone, Default)]
+pub struct InventoryPatch {
+    pub set: Vec<(String, Value)>,
+    pub clear: Vec<String>,
+}
+
+impl InventoryPatch {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.clear.is_empty()
+    }
+}
+
+/// Check a field name: FIELD or root_ids.FIELD, not a format field.
+pub fn parse_field(s: &str) -> Result<String, String> {
+    let parts: Vec<&str> = s.split('.').collect();
+    if parts.len() > 2 || parts.iter().any(|p| p.is_empty()) {
+        return Err(format!("{} is not FIELD or FIELD.SUBFIELD", s));
+    }
+    if FORMAT_FIELDS.contains(&s) {
+        return Err(format!(
+            "{} is the format of the other images; it cannot be patched",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+/// Parse --inventory-set: `FIELD=VALUE`, VALUE a number, true or false, or
+/// else a string (enum values by name, e.g. lsmtype=NO_LSM).
+pub fn parse_set(s: &str) -> Result<(String, Value), String> {
+    let (field, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{} is not FIELD=VALUE", s))?;
+    let value = match serde_json::from_str::<Value>(value) {
+        Ok(v @ (Value::Number(_) | Value::Bool(_))) => v,
+        _ => Value::String(value.to_string()),
+    };
+    Ok((parse_field(field)?, value))
+}
+
+fn text(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+pub fn patch(
+    content: &[u8],
+    patch: &InventoryPatch,
+    report: &mut Report,
+) -> Result<Vec<u8>, String> {
+    let mut data = crit::decode(content)?;
+    let entry = data
+        .pointer_mut("/entries/0")
+        .and_then(|e| e.as_object_mut())
+        .ok_or("inventory image has no entry")?;
+    let mut changes = Vec::new();
+    for (field, value) in &patch.set {
+        let (parent, key) = match field.split_once('.') {
+            Some((outer, key)) => {
+                let outer = entry
+                    .entry(outer)
+                    .or_insert_with(|| Value::Object(Map::new()));
+                let parent = outer
+                    .as_object_mut()
+                    .ok_or_else(|| format!("inventory {} is not a message", field))?;
+                (parent, key)
+            }
+            None => (&mut *entry, field.as_str()),
+        };
+        let old = parent.insert(key.to_string(), value.clone());
+        let (old, new) = (text(old.as_ref()), text(Some(value)));
+        eprintln!("Inventory {}: {} → {}", field, old, new);
+        summary::add(report, INVENTORY_PATH, field, &old, &new, "set");
+        changes.push(json!({ "field": field, "old": old, "new": new }));
+    }
+    for field in &patch.clear {
+        let old = match field.split_once('.') {
+            Some((outer, key)) => entry
+                .get_mut(outer)
+                .and_then(|o| o.as_object_mut())
+                .and_then(|o| o.remove(key)),
+            None => entry.remove(field.as_str()),
+        };
+        let Some(old) = old else {
+            report.warn(format!("inventory.img has no {} to clear", field));
+            continue;
+        };
+        let old = text(Some(&old));
+        eprintln!("Inventory {}: {} cleared", field, old);
+        summary::add(report, INVENTORY_PATH, field, &old, "-", "cleared");
+        changes.push(json!({ "field": field, "old": old, "new": null }));
+    }
+    report.set("inventory", changes);
+    crit::encode(&data)
+}
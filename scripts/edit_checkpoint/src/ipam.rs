@@ -0,0 +1,235 @@
+//! Allocate a free address on the target node's netavark network.
+//!
+//! Reads the network's subnet from `podman network inspect` and the addresses
+//! already held by containers on it from `podman container inspect`, either
+//! locally or on the target via SSH, then picks the first free host address.
+//! Set EDIT_CHECKPOINT_PODMAN (e.g. "sudo podman") to change the podman command.
+
+use std::net::Ipv4Addr;
+
+use crate::remote;
+
+pub struct IpamQuery<'a> {
+    /// Network name on the target (e.g. "switch-net").
+    pub network: &'a str,
+    /// `user@host` to query over SSH; None queries the local podman.
+    pub host: Option<&'a str>,
+}
+
+pub struct Allocation {
+    pub addr: Ipv4Addr,
+    pub subnet: String,
+}
+
+/// Pick the first free address in the network's IPv4 subnet, skipping the
+/// gateway, addresses in use on the target, and `exclude`.
+pub fn allocate(query: &IpamQuery, exclude: &[Ipv4Addr]) -> Result<Allocation, String> {
+    let inspect = podman(query.host, &["network", "inspect", query.network])?;
+    let nets: serde_json::Value = serde_json::from_str(&inspect)
+        .map_err(|e| format!("parse podman network inspect: {}", e))?;
+    let net = nets
+        .get(0)
+        .ok_or_else(|| format!("network {} not found", query.network))?;
+    let subnet = ipv4_subnet(net, query.network)?;
+    let cidr = subnet["subnet"].as_str().unwrap_or_default();
+
+    let mut used = used_addrs(query)?;
+    used.extend_from_slice(exclude);
+    first_free(subnet, &used)
+        .map(|addr| Allocation {
+            addr,
+            subnet: cidr.to_string(),
+        })
+        .ok_or_else(|| format!("no free address in {} on {}", cidr, query.network))
+}
+
+/// The IPv4 subnet of the inspected network `net` to allocate from.
+fn ipv4_subnet<'a>(
+    net: &'a serde_json::Value,
+    network: &str,
+) -> Result<&'a serde_json::Value, String> {
+    let driver = net
+        .pointer("/ipam_options/driver")
+        .and_then(|d| d.as_str())
+        .unwrap_or("host-local");
+    if driver != "host-local" {
+        return Err(format!(
+            "network {} uses {} IPAM; cannot pre-allocate an address",
+            network, driver
+        ));
+    }
+    let subnet = net
+        .get("subnets")
+        .and_then(|s| s.as_array())
+        .into_iter()
+        .flatten()
+        .find(|s| {
+            s.get("subnet")
+                .and_then(|v| v.as_str())
+                .and_then(parse_cidr)
+                .is_some()
+        })
+        .ok_or_else(|| format!("network {} has no IPv4 subnet", network))?;
+    let cidr = subnet["subnet"].as_str().unwrap_or_default();
+    // /31 and /32 have no network and broadcast address to leave out
+    if parse_cidr(cidr).is_some_and(|(_, prefix)| prefix > 30) {
+        return Err(format!(
+            "subnet {} of {} is too small to allocate from; it needs a /30 or larger",
+            cidr, network
+        ));
+    }
+    Ok(subnet)
+}
+
+/// The first host address of `subnet` (within its lease_range, if any) that
+/// is neither its gateway nor in `used`.
+fn first_free(subnet: &serde_json::Value, used: &[Ipv4Addr]) -> Option<Ipv4Addr> {
+    let cidr = subnet["subnet"].as_str().unwrap_or_default();
+    let (base, prefix) = parse_cidr(cidr)?;
+    let gateway = subnet
+        .get("gateway")
+        .and_then(|g| g.as_str())
+        .and_then(|g| g.parse::<Ipv4Addr>().ok());
+
+    let host_bits = 32 - u32::from(prefix);
+    let network = u32::from(base) & !mask(host_bits);
+    let mut first = network + 1;
+    let mut last = (network | mask(host_bits)).saturating_sub(1);
+    if let Some(range) = subnet.get("lease_range") {
+        let bound = |key: &str| {
+            range
+                .get(key)
+                .and_then(|v| v.as_str())
+                .and_then(|v| v.parse::<Ipv4Addr>().ok())
+                .map(u32::from)
+        };
+        first = bound("start_ip").unwrap_or(first);
+        last = bound("end_ip").unwrap_or(last);
+    }
+    (first..=last)
+        .map(Ipv4Addr::from)
+        .find(|a| Some(*a) != gateway && !used.contains(a))
+}
+
+/// Addresses held on the network by existing containers (running or not).
+fn used_addrs(query: &IpamQuery) -> Result<Vec<Ipv4Addr>, String> {
+    let filter = format!("network={}", query.network);
+    let ids = podman(query.host, &["ps", "-aq", "--filter", &filter])?;
+    let ids: Vec<&str> = ids.split_whitespace().collect();
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut args = vec!["container", "inspect"];
+    args.extend(ids);
+    let out = podman(query.host, &args)?;
+    let containers: serde_json::Value =
+        serde_json::from_str(&out).map_err(|e| format!("parse podman container inspect: {}", e))?;
+    let mut used = Vec::new();
+    for c in containers.as_array().into_iter().flatten() {
+        let ip = c
+            .pointer("/NetworkSettings/Networks")
+            .and_then(|n| n.get(query.network))
+            .and_then(|n| n.get("IPAddress"))
+            .and_then(|ip| ip.as_str());
+        used.extend(ip.and_then(|ip| ip.parse::<Ipv4Addr>().ok()));
+    }
+    Ok(used)
+}
+
+/// Run podman locally or on `host` via SSH and return its stdout.
+fn podman(host: Option<&str>, args: &[&str]) -> Result<String, String> {
+    let argv = remote::argv("EDIT_CHECKPOINT_PODMAN", "podman", args);
+    let out = remote::command(host, &argv)
+        .output()
+        .map_err(|e| format!("run {}: {}", argv.join(" "), e))?;
+    if !out.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            argv.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+fn parse_cidr(cidr: &str) -> Option<(Ipv4Addr, u8)> {
+    let (addr, prefix) = cidr.split_once('/')?;
+    let prefix: u8 = prefix.parse().ok().filter(|p| *p <= 32)?;
+    Some((addr.parse().ok()?, prefix))
+}
+
+fn mask(host_bits: u32) -> u32 {
+    if host_bits >= 32 {
+        u32::MAX
+    } else {
+        (1u32 << host_bits) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn addr(s: &str) -> Ipv4Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn picks_the_ipv4_subnet() {
+        let net = json!({"subnets": [
+            {"subnet": "fd00::/64", "gateway": "fd00::1"},
+            {"subnet": "10.88.0.0/16", "gateway": "10.88.0.1"},
+        ]});
+        assert_eq!(ipv4_subnet(&net, "n").unwrap()["subnet"], "10.88.0.0/16");
+        let net = json!({"subnets": [{"subnet": "fd00::/64"}]});
+        assert!(ipv4_subnet(&net, "n").is_err());
+        let net =
+            json!({"ipam_options": {"driver": "dhcp"}, "subnets": [{"subnet": "10.88.0.0/16"}]});
+        assert!(ipv4_subnet(&net, "n").is_err());
+        let net = json!({"subnets": [{"subnet": "10.88.0.4/31"}]});
+        assert!(ipv4_subnet(&net, "n").is_err());
+        let net = json!({"subnets": [{"subnet": "10.88.0.4/30"}]});
+        assert!(ipv4_subnet(&net, "n").is_ok());
+    }
+
+    #[test]
+    fn skips_network_gateway_and_used() {
+        let subnet = json!({"subnet": "10.88.0.0/24", "gateway": "10.88.0.1"});
+        assert_eq!(first_free(&subnet, &[]), Some(addr("10.88.0.2")));
+        let used = [addr("10.88.0.2"), addr("10.88.0.3"), addr("10.88.0.5")];
+        assert_eq!(first_free(&subnet, &used), Some(addr("10.88.0.4")));
+        // A subnet written with host bits set starts at its network
+        let subnet = json!({"subnet": "10.88.0.77/24"});
+        assert_eq!(first_free(&subnet, &[]), Some(addr("10.88.0.1")));
+    }
+
+    #[test]
+    fn leaves_out_broadcast() {
+        let subnet = json!({"subnet": "10.88.0.4/30", "gateway": "10.88.0.5"});
+        assert_eq!(first_free(&subnet, &[]), Some(addr("10.88.0.6")));
+        assert_eq!(first_free(&subnet, &[addr("10.88.0.6")]), None);
+    }
+
+    #[test]
+    fn stays_in_lease_range() {
+        let subnet = json!({
+            "subnet": "10.88.0.0/24",
+            "gateway": "10.88.0.1",
+            "lease_range": {"start_ip": "10.88.0.100", "end_ip": "10.88.0.101"},
+        });
+        assert_eq!(first_free(&subnet, &[]), Some(addr("10.88.0.100")));
+        assert_eq!(
+            first_free(&subnet, &[addr("10.88.0.100")]),
+            Some(addr("10.88.0.101"))
+        );
+        assert_eq!(
+            first_free(&subnet, &[addr("10.88.0.100"), addr("10.88.0.101")]),
+            None
+        );
+        // Either bound alone narrows the range from one side
+        let subnet = json!({"subnet": "10.88.0.0/24", "lease_range": {"end_ip": "10.88.0.1"}});
+        assert_eq!(first_free(&subnet, &[]), Some(addr("10.88.0.1")));
+        assert_eq!(first_free(&subnet, &[addr("10.88.0.1")]), None);
+    }
+}
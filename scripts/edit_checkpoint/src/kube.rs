@@ -0,0 +1,183 @@
+//! Restoring an edited checkpoint as a Kubernetes pod (--kube DIR).
+//!
+//! CRI-O (with enable_criu_support) restores a container instead of creating
+//! it when the pod's image is a checkpoint image: an image holding the
+//! checkpoint archive unpacked, annotated
+//! io.kubernetes.cri-o.annotations.checkpoint.name. --kube writes what it
+//! takes to get there from the edited archive:
+//!
+//! - annotations.json: the checkpoint image's annotations, the container's
+//!   name (the pod's and its namespace for CRI-O checkpoints) and the image
+//!   it runs on, which the target's nodes need;
+//! - build.sh: the buildah commands building the checkpoint image from the
+//!   archive with those annotations and pushing it as --kube-image;
+//! - pod.json: a pod running that image, for `kubectl apply -f`.
+//!
+//! The pod's address is the cluster network's to assign: new_addr holds only
+//! where the CNI plugin takes a fixed address from a pod annotation
+//! (--kube-annotation, e.g. Calico's cni.projectcalico.org/ipAddrs).
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::{json, Map, Value};
+
+use crate::metadata::MetadataFiles;
+use crate::remote::shell_quote;
+use crate::report::Report;
+use crate::rootfs;
+
+const ANNOTATION: &str = "io.kubernetes.cri-o.annotations.checkpoint.";
+
+/// What --kube writes, and for which image.
+pub struct KubeOptions<'a> {
+    pub dir: &'a Path,
+    /// The registry reference to push the checkpoint image as.
+    pub image: &'a str,
+    pub namespace: Option<&'a str>,
+    /// Annotations for the pod.
+    pub annotations: &'a [(String, String)],
+}
+
+/// `name` as a DNS label, as Kubernetes wants container and pod names:
+/// lower case alphanumerics and '-', at most 63 long.
+fn dns_label(name: &str) -> String {
+    let label: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(63)
+        .collect();
+    label.trim_matches('-').to_string()
+}
+
+/// Write the --kube files for the edited archive at `archive`, `map` the
+/// --image-map of the edit.
+pub fn write(
+    archive: &Path,
+    kube: &KubeOptions,
+    map: &[(String, String)],
+    report: &mut Report,
+) -> Result<(), String> {
+    let archive =
+        fs::canonicalize(archive).map_err(|e| format!("--kube: {}: {}", archive.display(), e))?;
+    let files = MetadataFiles::read(&archive)?;
+    let [_, config, spec] = files.parsed();
+    let spec_annotation = |key: &str| {
+        spec.as_ref()
+            .and_then(|s| s.get("annotations"))
+            .and_then(|a| a.get(key))
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+    };
+    let name = spec_annotation("io.kubernetes.container.name")
+        .or_else(|| files.name())
+        .ok_or("--kube: the checkpoint names no container in config.dump or spec.dump")?;
+    let pod = spec_annotation("io.kubernetes.pod.name");
+    let namespace = kube
+        .namespace
+        .map(str::to_string)
+        .or_else(|| spec_annotation("io.kubernetes.pod.namespace"))
+        .unwrap_or_else(|| "default".to_string());
+    let rootfs = rootfs::reference(config.as_ref(), spec.as_ref(), map)
+        .ok_or("--kube: the checkpoint names no image it runs on")?;
+
+    let mut annotations = Map::new();
+    annotations.insert(format!("{}name", ANNOTATION), name.clone().into());
+    if let Some(pod) = &pod {
+        annotations.insert(format!("{}pod", ANNOTATION), pod.clone().into());
+        annotations.insert(format!("{}namespace", ANNOTATION), namespace.clone().into());
+    }
+    annotations.insert(format!("{}rootfsImageName", ANNOTATION), rootfs.into());
+    let image_id = config
+        .as_ref()
+        .and_then(|c| c.get("rootfsImageID"))
+        .and_then(|i| i.as_str());
+    if let Some(id) = image_id.filter(|id| !id.is_empty()) {
+        annotations.insert(format!("{}rootfsImageID", ANNOTATION), id.into());
+    }
+
+    let container = dns_label(&name);
+    let pod_name = dns_label(pod.as_deref().unwrap_or(&name));
+    if container.is_empty() || pod_name.is_empty() {
+        return Err(format!("--kube: {} makes no Kubernetes name", name));
+    }
+    let pod_annotations: Map<String, Value> = kube
+        .annotations
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone().into()))
+        .collect();
+    let manifest = json!({
+        "apiVersion": "v1",
+        "kind": "Pod",
+        "metadata": {
+            "name": pod_name,
+            "namespace": namespace,
+            "annotations": pod_annotations,
+        },
+        "spec": {
+            "containers": [{ "name": container, "image": kube.image }],
+        },
+    });
+
+    let mut build = format!(
+        "#!/bin/sh\n\
+         # Builds the checkpoint image of {archive} (edited by edit_checkpoint {version})\n\
+         # and pushes it for pod.json\n\
+         set -e\n\
+         c=$(buildah from scratch)\n\
+         buildah add \"$c\" {quoted} /\n",
+        archive = archive.display(),
+        version = env!("CARGO_PKG_VERSION"),
+        quoted = shell_quote(&archive.to_string_lossy()),
+    );
+    for (key, value) in &annotations {
+        let value = value.as_str().unwrap_or_default();
+        build += &format!(
+            "buildah config --annotation={} \"$c\"\n",
+            shell_quote(&format!("{}={}", key, value))
+        );
+    }
+    build += &format!(
+        "buildah commit \"$c\" {image}\n\
+         buildah rm \"$c\"\n\
+         buildah push {image}\n",
+        image = shell_quote(kube.image)
+    );
+
+    fs::create_dir_all(kube.dir).map_err(|e| format!("{}: {}", kube.dir.display(), e))?;
+    let write = |file: &str, content: &[u8]| {
+        let path = kube.dir.join(file);
+        fs::write(&path, content).map_err(|e| format!("write {}: {}", path.display(), e))
+    };
+    let pretty = |value: &Value| serde_json::to_string_pretty(value).unwrap_or_default() + "\n";
+    write(
+        "annotations.json",
+        pretty(&Value::Object(annotations)).as_bytes(),
+    )?;
+    write("pod.json", pretty(&manifest).as_bytes())?;
+    write("build.sh", build.as_bytes())?;
+    set_executable(&kube.dir.join("build.sh"))?;
+    eprintln!(
+        "Wrote {dir}/{{annotations.json,build.sh,pod.json}}; run build.sh to push {image}, \
+         then restore the pod with:\n  kubectl apply -f {dir}/pod.json",
+        dir = kube.dir.display(),
+        image = kube.image
+    );
+    report.set(
+        "kube",
+        json!({
+            "dir": kube.dir.display().to_string(),
+            "image": kube.image,
+            "pod": pod_name,
+            "namespace": namespace,
+        }),
+    );
+    Ok(())
+}
+
+fn set_executable(path: &Path) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+        .map_err(|e| format!("chmod {}: {}", path.display(), e))
+}
@@ -0,0 +1,389 @@
+//! `lb-config`: describe the migrated container as a load balancer endpoint.
+//!
+//! Derives the container's listening ports from the (patched) checkpoint and
+//! prints a JSON fragment in the controller's configuration vocabulary: the
+//! `nodes` entry for the container on the new node (ipv4, sw_port, dst_mac,
+//! is_lb_node) and the service ports it listens on, e.g.
+//!
+//! ```json
+//! {
+//!   "node": {"ipv4": "192.168.12.9", "sw_port": 148, "dst_mac": "02:42:c0:a8:0c:09", "is_lb_node": true},
+//!   "service_ports": [{"proto": "tcp", "port": 12345}]
+//! }
+//! ```
+//!
+//! Sockets bound only to loopback are not reachable through the switch and
+//! are left out.
+//!
+//! `hash-impact`: estimate which flows a migration disturbs before committing
+//! it. Reads the controller config (controller/controller_config.json) for the
+//! selector group, then models the switch's node_selector: CRC16 (CRC-16/ARC)
+//! over src_addr, dst_addr, protocol, src_port, dst_port, and the member
+//! picked as hash % group size. The controller repoints the moved member in
+//! place (migrateNode), so the buckets of the other members are untouched
+//! and the moved member's share of flows follows the endpoint to new_addr.
+//! With --checkpoint, the service connections held in the checkpoint are
+//! hashed too: those not landing on the moved member would be steered
+//! elsewhere after restore.
+//!
+//! `flows`: export those connections for the controller to pin before the
+//! restored container is unpaused. Each is the 5-tuple the switch sees
+//! (client to VIP:service_port, the node_selector's selector fields) with the
+//! backend it has to stay on as set_rewrite_dst's new_dst, the backend's
+//! member ID, and whether the selector alone would send it elsewhere.
+
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use serde_json::json;
+
+use crate::metadata::{self, MetadataFiles};
+use crate::sockets;
+
+#[derive(Args)]
+pub struct LbConfigArgs {
+    /// Edited checkpoint archive or unpacked checkpoint directory
+    checkpoint: PathBuf,
+    /// Switch port the new node is attached to
+    #[arg(long, value_name = "PORT")]
+    sw_port: u16,
+    /// Endpoint address (default: the address in the checkpoint metadata)
+    #[arg(long, value_name = "ADDR")]
+    addr: Option<String>,
+    /// Warn unless the container listens on the controller's service port
+    #[arg(long, value_name = "PORT")]
+    service_port: Option<u16>,
+    /// Write the fragment to FILE instead of stdout
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+pub fn run(args: &LbConfigArgs) -> Result<(), String> {
+    let files = MetadataFiles::read(&args.checkpoint)?;
+    let addr = match &args.addr {
+        Some(addr) => addr.clone(),
+        None => {
+            files
+                .addr()
+                .ok_or_else(|| {
+                    format!(
+                        "no address in {} metadata; pass --addr",
+                        args.checkpoint.display()
+                    )
+                })?
+                .0
+        }
+    };
+    let mac = files.parsed()[0]
+        .as_ref()
+        .and_then(metadata::network_status_mac);
+
+    let mut ports = BTreeSet::new();
+    for socket in sockets::read_table(&args.checkpoint)? {
+        let field = |key: &str| socket[key].as_str().unwrap_or_default().to_string();
+        let listening = match field("proto").as_str() {
+            "TCP" => field("state") == "LISTEN",
+            // Bound, unconnected datagram sockets
+            "UDP" => socket["dst_port"].as_u64() == Some(0),
+            _ => false,
+        };
+        let loopback = field("src_addr")
+            .parse::<IpAddr>()
+            .is_ok_and(|a| a.is_loopback());
+        let port = socket["src_port"].as_u64().unwrap_or(0);
+        if listening && !loopback && port != 0 {
+            ports.insert((field("proto").to_lowercase(), port));
+        }
+    }
+    if let Some(service) = args.service_port {
+        if !ports.iter().any(|(_, p)| *p == u64::from(service)) {
+            eprintln!(
+                "Warning: checkpoint has no socket listening on service port {}",
+                service
+            );
+        }
+    }
+    if ports.is_empty() {
+        eprintln!("Warning: no listening sockets in the checkpoint");
+    }
+
+    let mut node = json!({"ipv4": addr, "sw_port": args.sw_port, "is_lb_node": true});
+    if let Some(mac) = mac {
+        node["dst_mac"] = mac.into();
+    }
+    let fragment = json!({
+        "node": node,
+        "service_ports": ports
+            .iter()
+            .map(|(proto, port)| json!({"proto": proto, "port": port}))
+            .collect::<Vec<_>>(),
+    });
+    let out = serde_json::to_string_pretty(&fragment).map_err(|e| e.to_string())? + "\n";
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, out).map_err(|e| format!("write {}: {}", path.display(), e))
+        }
+        None => {
+            print!("{}", out);
+            Ok(())
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct HashImpactArgs {
+    /// Controller configuration (controller_config.json)
+    #[arg(long, value_name = "FILE")]
+    config: PathBuf,
+    /// Endpoint address being migrated (an LB node in the config)
+    #[arg(long, value_name = "ADDR")]
+    old_addr: Ipv4Addr,
+    /// Address the endpoint moves to
+    #[arg(long, value_name = "ADDR")]
+    new_addr: Ipv4Addr,
+    /// Checkpoint whose established service connections are hashed
+    #[arg(long, value_name = "PATH")]
+    checkpoint: Option<PathBuf>,
+    /// Write the analysis as JSON to FILE
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+}
+
+/// The master switch's load balancer in the controller config.
+struct Balancer {
+    vip: Ipv4Addr,
+    service_port: u16,
+    /// Member ID and address of each LB node.
+    members: Vec<(usize, String)>,
+}
+
+fn read_config(path: &Path) -> Result<Balancer, String> {
+    let content = std::fs::read(path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+    let configs: serde_json::Value =
+        serde_json::from_slice(&content).map_err(|e| format!("parse {}: {}", path.display(), e))?;
+    let master = configs
+        .as_array()
+        .and_then(|c| c.iter().find(|c| c["master"].as_bool() == Some(true)))
+        .ok_or("no master switch in the controller config")?;
+    let vip: Ipv4Addr = master["load_balancer_ip"]
+        .as_str()
+        .and_then(|a| a.parse().ok())
+        .ok_or("master switch has no load_balancer_ip")?;
+    let service_port = master["service_port"]
+        .as_u64()
+        .ok_or("master switch has no service_port")? as u16;
+    // Member IDs are the node's position in the config, as in NodeManager
+    let members: Vec<(usize, String)> = master["nodes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .filter(|(_, n)| n["is_lb_node"].as_bool() == Some(true))
+        .filter_map(|(i, n)| Some((i, n["ipv4"].as_str()?.to_string())))
+        .collect();
+    Ok(Balancer {
+        vip,
+        service_port,
+        members,
+    })
+}
+
+/// The client address and port of each established connection to the
+/// service port in `checkpoint`.
+fn service_clients(checkpoint: &Path, service_port: u16) -> Result<Vec<(Ipv4Addr, u16)>, String> {
+    let mut clients = Vec::new();
+    for socket in sockets::read_table(checkpoint)? {
+        let established = socket["proto"] == "TCP" && socket["state"] == "ESTABLISHED";
+        let client = socket["dst_addr"].as_str().and_then(|a| a.parse().ok());
+        let (Some(client), Some(client_port)) = (client, socket["dst_port"].as_u64()) else {
+            continue;
+        };
+        if established && socket["src_port"].as_u64() == Some(u64::from(service_port)) {
+            clients.push((client, client_port as u16));
+        }
+    }
+    Ok(clients)
+}
+
+pub fn hash_impact(args: &HashImpactArgs) -> Result<(), String> {
+    let Balancer {
+        vip,
+        service_port,
+        members,
+    } = read_config(&args.config)?;
+    let old = args.old_addr.to_string();
+    let moved = members
+        .iter()
+        .position(|(_, addr)| *addr == old)
+        .ok_or_else(|| format!("{} is not an LB node in {}", old, args.config.display()))?;
+    if members.iter().any(|(_, a)| *a == args.new_addr.to_string()) {
+        eprintln!(
+            "Warning: {} is already an LB node; it would receive two members' flows",
+            args.new_addr
+        );
+    }
+    let share = 1.0 / members.len() as f64;
+    eprintln!(
+        "Member {} ({}) moves to {}: 1 of {} members, {:.1}% of new flows to {}:{} follow it, the rest keep their node",
+        members[moved].0,
+        old,
+        args.new_addr,
+        members.len(),
+        share * 100.0,
+        vip,
+        service_port
+    );
+
+    let mut flows = Vec::new();
+    if let Some(checkpoint) = &args.checkpoint {
+        for (client, client_port) in service_clients(checkpoint, service_port)? {
+            let hash = selector_hash(client, vip, 6, client_port, service_port);
+            let member = hash as usize % members.len();
+            flows.push(json!({
+                "client": format!("{}:{}", client, client_port),
+                "hash": hash,
+                "member": members[member].0,
+                "follows": member == moved,
+            }));
+        }
+        let stray = flows.iter().filter(|f| f["follows"] == false).count();
+        eprintln!(
+            "{} service connections in the checkpoint, {} hash to other members",
+            flows.len(),
+            stray
+        );
+    }
+
+    if let Some(path) = &args.report {
+        let report = json!({
+            "vip": vip.to_string(),
+            "service_port": service_port,
+            "members": members
+                .iter()
+                .map(|(id, addr)| json!({"member": id, "ipv4": addr}))
+                .collect::<Vec<_>>(),
+            "moved_member": members[moved].0,
+            "old_addr": old,
+            "new_addr": args.new_addr.to_string(),
+            "affected_share": share,
+            "flows": flows,
+        });
+        let json = serde_json::to_vec_pretty(&report).map_err(|e| e.to_string())?;
+        std::fs::write(path, json).map_err(|e| format!("write {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+pub struct FlowsArgs {
+    /// Edited checkpoint archive or unpacked checkpoint directory
+    checkpoint: PathBuf,
+    /// Controller configuration (controller_config.json)
+    #[arg(long, value_name = "FILE")]
+    config: PathBuf,
+    /// Backend the flows stay on (default: the address in the checkpoint
+    /// metadata)
+    #[arg(long, value_name = "ADDR")]
+    backend: Option<Ipv4Addr>,
+    /// The endpoint's address in the config, when the controller has not
+    /// moved it to the backend yet (for its member ID)
+    #[arg(long, value_name = "ADDR")]
+    old_addr: Option<Ipv4Addr>,
+    /// Write the list to FILE instead of stdout
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+pub fn flows(args: &FlowsArgs) -> Result<(), String> {
+    let balancer = read_config(&args.config)?;
+    let backend = match args.backend {
+        Some(backend) => backend,
+        None => MetadataFiles::read(&args.checkpoint)?
+            .addr()
+            .and_then(|(addr, _)| addr.parse().ok())
+            .ok_or_else(|| {
+                format!(
+                    "no IPv4 address in {} metadata; pass --backend",
+                    args.checkpoint.display()
+                )
+            })?,
+    };
+    let endpoint = args.old_addr.unwrap_or(backend).to_string();
+    let position = balancer.members.iter().position(|(_, a)| *a == endpoint);
+    if position.is_none() {
+        eprintln!(
+            "Warning: {} is not an LB node in {}; the flows carry no member ID",
+            endpoint,
+            args.config.display()
+        );
+    }
+    let member = position.map(|p| balancer.members[p].0);
+
+    let mut flows = Vec::new();
+    for (client, client_port) in service_clients(&args.checkpoint, balancer.service_port)? {
+        let hash = selector_hash(client, balancer.vip, 6, client_port, balancer.service_port);
+        let mut flow = json!({
+            "src_addr": client.to_string(),
+            "dst_addr": balancer.vip.to_string(),
+            "protocol": 6,
+            "src_port": client_port,
+            "dst_port": balancer.service_port,
+            "new_dst": backend.to_string(),
+            "member": member,
+            "hash": hash,
+        });
+        if let Some(position) = position {
+            flow["rehashed"] = (hash as usize % balancer.members.len() != position).into();
+        }
+        flows.push(flow);
+    }
+    let rehashed = flows.iter().filter(|f| f["rehashed"] == true).count();
+    eprintln!(
+        "{} service connections to pin to {}, {} of which the selector would send elsewhere",
+        flows.len(),
+        backend,
+        rehashed
+    );
+    let export = json!({
+        "vip": balancer.vip.to_string(),
+        "service_port": balancer.service_port,
+        "backend": backend.to_string(),
+        "member": member,
+        "flows": flows,
+    });
+    let out = serde_json::to_string_pretty(&export).map_err(|e| e.to_string())? + "\n";
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, out).map_err(|e| format!("write {}: {}", path.display(), e))
+        }
+        None => {
+            print!("{}", out);
+            Ok(())
+        }
+    }
+}
+
+/// The selector hash of a flow: CRC-16/ARC over the node_selector's selector
+/// fields in key order, in network byte order.
+fn selector_hash(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, src_port: u16, dst_port: u16) -> u16 {
+    let mut bytes = Vec::with_capacity(13);
+    bytes.extend(src.octets());
+    bytes.extend(dst.octets());
+    bytes.push(proto);
+    bytes.extend(src_port.to_be_bytes());
+    bytes.extend(dst_port.to_be_bytes());
+    let mut crc = 0u16;
+    for b in bytes {
+        crc ^= u16::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,46 @@
+//! Advisory locks on the archive being edited.
+//!
+//! Two migration runs editing the same archive each write ARCHIVE.new and
+//! rename it over the archive; whichever renames last wins, having truncated
+//! the other's half-written output on the way. An exclusive flock on the
+//! archive and on ARCHIVE.new, taken before either is read or truncated and
+//! held until the edit is done, makes the second run fail at once instead.
+//! The lock on ARCHIVE.new stays with the file after the rename, so a run
+//! starting then waits for nothing and fails the same way.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+
+/// An exclusive lock, released when dropped.
+pub struct Lock {
+    _file: File,
+}
+
+fn try_lock(file: File, path: &Path) -> Result<Lock, String> {
+    match file.try_lock() {
+        Ok(()) => Ok(Lock { _file: file }),
+        Err(TryLockError::WouldBlock) => Err(format!(
+            "{} is locked by another edit; is a migration of this checkpoint already running?",
+            path.display()
+        )),
+        Err(TryLockError::Error(e)) => Err(format!("lock {}: {}", path.display(), e)),
+    }
+}
+
+/// Lock the archive (or unpacked checkpoint directory) at `path`.
+pub fn input(path: &Path) -> Result<Lock, String> {
+    let file = File::open(path).map_err(|e| format!("open {}: {}", path.display(), e))?;
+    try_lock(file, path)
+}
+
+/// Lock the output at `path`, creating it but leaving what is there for the
+/// holder of the lock to truncate.
+pub fn output(path: &Path) -> Result<Lock, String> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| format!("create {}: {}", path.display(), e))?;
+    try_lock(file, path)
+}
@@ -1,306 +1,1486 @@
 //! Edit a Podman/CRIU checkpoint archive for cross-node migration:
 //! 1. Patches IP address in checkpoint/files.img (old_addr -> new_addr) using crit decode/encode.
 //!    Only patches sockets bound to old_addr specifically (NOT 0.0.0.0/:: wildcard).
+//!    old_addr may be omitted, in which case it is read from network.status/config.dump.
 //! 2. Patches network.status to set the target IP (so podman assigns it on restore).
 //! 3. Patches config.dump to set staticIP to the target IP.
-//! Streams the tar (no full extract/repack): only checkpoint/files.img is written to temp for crit.
+//!
+//! CRI-O checkpoints (no network.status) carry the pod address in the
+//! io.kubernetes.cri-o.IP.N annotations of spec.dump, which are patched instead.
+//!
+//! With --clear-static-ip, steps 2 and 3 remove the fixed address instead so the
+//! target network assigns one at restore. With --add-addr they keep old_addr and
+//! add new_addr as a secondary address (make-before-break switchover).
+//!
+//! Rootless checkpoints work the same; with slirp4netns or pasta there is no
+//! address in the metadata, so old_addr must be given (see MetadataFiles).
+//!
+//! CHECKPOINT may also be an unpacked checkpoint directory, patched in place.
+//! With --image, CHECKPOINT names a --create-image checkpoint image; an OCI
+//! image layout directory holding one has its layer edited in place (see
+//! image.rs).
+//! --criu-opt adds CRIU restore options that travel with the archive (see spec.rs).
+//! --kube writes what restoring it as a Kubernetes pod takes (see kube.rs).
+//!
+//! zstd-compressed archives are read as well; --compress zstd writes one
+//! (see compress.rs).
+//!
+//! Streams the tar (no full extract/repack): only the images patched go through crit, in memory.
+//! Edit start/end timestamps are added as migration-timeline.json (see timeline.rs).
+//! Set OTEL_EXPORTER_OTLP_ENDPOINT to export trace spans (see trace.rs).
+//! The output's filesystem is checked for room before the edit starts (see
+//! space.rs); a failed edit leaves no partial output behind, unless it is
+//! --resumable (see resume.rs).
+//! --verify-copy reads the output back to check the entries the edit left
+//! alone are unchanged (see integrity.rs).
+//! SIGINT and SIGTERM kill crit, remove the partial output and exit with 130
+//! or 143 (see interrupt.rs).
+//!
+//! An edit that changes nothing (already applied, or nothing matched) exits
+//! with EXIT_NOOP instead of 0, and its report says "status": "noop";
+//! --fail-if-noop makes it an error.
+//!
+//! Built without the default `edit` feature (for a macOS or Windows laptop),
+//! only the subcommands that inspect a checkpoint are there; everything
+//! editing, migrating or restoring one is behind it.
 
+// The inspection build uses only part of the modules shared with editing
+#![cfg_attr(not(feature = "edit"), allow(dead_code))]
+
+#[cfg(feature = "edit")]
+mod action;
+#[cfg(feature = "edit")]
+mod anonymize;
+mod applied;
+#[cfg(feature = "edit")]
+mod arch;
+#[cfg(feature = "edit")]
+mod audit;
+#[cfg(feature = "edit")]
+mod buffers;
+#[cfg(feature = "edit")]
+mod cgroup;
+mod completions;
+mod compress;
+#[cfg(feature = "edit")]
+mod conflict;
+#[cfg(feature = "edit")]
+mod controller;
+mod crit;
+#[cfg(feature = "edit")]
+mod delete;
+mod du;
+#[cfg(feature = "edit")]
+mod edit;
+mod events;
+#[cfg(feature = "edit")]
+mod fetch;
+#[cfg(feature = "edit")]
+mod filelocks;
+#[cfg(feature = "edit")]
+mod ghosts;
+#[cfg(feature = "edit")]
+mod hook;
+#[cfg(feature = "edit")]
+mod image;
+mod images;
+mod inaddr;
+#[cfg(feature = "edit")]
+mod index;
+#[cfg(feature = "edit")]
+mod inject;
+mod inspect;
+#[cfg(feature = "edit")]
+mod integrity;
+mod interrupt;
+#[cfg(feature = "edit")]
+mod inventory;
+#[cfg(feature = "edit")]
+mod ipam;
+#[cfg(feature = "edit")]
+mod kube;
+mod lb;
+#[cfg(feature = "edit")]
+mod lock;
+#[cfg(feature = "edit")]
+mod merge;
+mod metadata;
+#[cfg(feature = "edit")]
+mod migrate;
+#[cfg(feature = "edit")]
+mod nat64;
+#[cfg(feature = "edit")]
+mod normalize;
+mod pack;
+#[cfg(feature = "edit")]
+mod policy;
+#[cfg(feature = "edit")]
+mod ports;
+#[cfg(feature = "edit")]
+mod preflight;
+mod proto;
+#[cfg(feature = "edit")]
+mod prune;
+#[cfg(feature = "edit")]
+mod redact;
+mod remote;
+#[cfg(feature = "edit")]
+mod replace;
+mod report;
+#[cfg(feature = "edit")]
+mod resources;
+#[cfg(feature = "edit")]
+mod restore;
+#[cfg(feature = "edit")]
+mod resume;
+#[cfg(feature = "edit")]
+mod rewrite;
+#[cfg(feature = "edit")]
+mod rootfs;
+#[cfg(feature = "edit")]
+mod s3;
+#[cfg(feature = "edit")]
+mod secrets;
+#[cfg(feature = "edit")]
+mod security;
+#[cfg(feature = "edit")]
+mod sign;
+mod sockets;
+#[cfg(feature = "edit")]
+mod space;
+#[cfg(feature = "edit")]
+mod spec;
+#[cfg(feature = "edit")]
+mod summary;
+mod tcp_repair;
+#[cfg(feature = "edit")]
+mod timeline;
+#[cfg(feature = "edit")]
+mod timens;
+#[cfg(feature = "edit")]
+mod tmpfs;
+mod trace;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(feature = "edit")]
+mod undo;
+#[cfg(feature = "io_uring")]
+mod uring;
+#[cfg(feature = "edit")]
+mod userns;
+mod verify;
+#[cfg(feature = "edit")]
+mod watch;
+
+#[cfg(feature = "edit")]
 use std::env;
+#[cfg(feature = "edit")]
 use std::fs;
-use std::io::{BufReader, BufWriter, Read};
-use std::path::Path;
-use std::process::Command;
+#[cfg(feature = "edit")]
+use std::io::{BufReader, BufWriter, Read, Write};
+#[cfg(feature = "edit")]
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+#[cfg(feature = "edit")]
 use std::time::Instant;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 4 {
-        eprintln!("Usage: edit_checkpoint <checkpoint.tar> <old_addr> <new_addr> [image_name]");
-        std::process::exit(1);
+#[cfg(feature = "edit")]
+use clap::Args;
+use clap::{Parser, Subcommand};
+
+#[cfg(feature = "edit")]
+use edit::{EditOptions, TcpClose};
+#[cfg(feature = "edit")]
+use metadata::{AddrPatch, MetadataFiles, CONFIG_DUMP_PATH, NETWORK_STATUS_PATH};
+use report::Report;
+#[cfg(feature = "edit")]
+use security::{SecurityPatch, SelinuxPatch};
+#[cfg(feature = "edit")]
+use timeline::Timeline;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Edit a Podman/CRIU checkpoint archive for cross-node migration",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+#[cfg_attr(
+    not(feature = "edit"),
+    command(subcommand_required = true, arg_required_else_help = true)
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[cfg(feature = "edit")]
+    #[command(flatten)]
+    edit: EditArgs,
+    /// Write NDJSON progress, warning and error events to descriptor FD or
+    /// PATH (e.g. a named pipe), see events.rs
+    #[arg(long, global = true, value_name = "FD|PATH")]
+    event_fd: Option<String>,
+    /// Kill a crit decode or encode that takes longer than SECS (0: wait
+    /// as long as it takes)
+    #[arg(long, global = true, value_name = "SECS", default_value_t = crit::DEFAULT_TIMEOUT_SECS)]
+    crit_timeout: u64,
+    /// Keep what crit decodes in DIR, by content hash, for later runs on
+    /// the same checkpoint to skip the decode
+    #[arg(long, global = true, value_name = "DIR")]
+    decode_cache: Option<PathBuf>,
+    /// Run crit on this host over SSH, streaming the images to it, for nodes
+    /// without crit installed
+    #[arg(long, global = true, value_name = "USER@HOST")]
+    crit_host: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[cfg(feature = "edit")]
+    /// Checkpoint a container on one node, edit the archive in flight and
+    /// restore it on another
+    Migrate(Box<migrate::MigrateArgs>),
+    #[cfg(feature = "edit")]
+    /// Edit archives dropped into a spool directory by rules, moving the
+    /// results to an outbox
+    Watch(watch::WatchArgs),
+    #[cfg(feature = "edit")]
+    /// Run as an OCI hook: patch the checkpoint of a container being restored
+    /// in place (state JSON on stdin)
+    Hook(Box<hook::HookArgs>),
+    /// Unpack a checkpoint archive into a directory, keeping file metadata
+    Unpack(pack::UnpackArgs),
+    #[cfg(feature = "edit")]
+    /// Pack an unpacked checkpoint directory back into an archive
+    Pack(pack::PackArgs),
+    #[cfg(feature = "edit")]
+    /// Reverse the last edit of a checkpoint archive from its audit entry
+    /// (restore old_addr and the other metadata values it changed)
+    Undo(undo::UndoArgs),
+    #[cfg(feature = "edit")]
+    /// Replace an entry of a checkpoint archive with a file, or add it, with
+    /// correct tar headers
+    Inject(inject::InjectArgs),
+    #[cfg(feature = "edit")]
+    /// Delete the entries of a checkpoint archive matching a glob, e.g. large
+    /// log files in the rootfs diff
+    Delete(delete::DeleteArgs),
+    #[cfg(feature = "edit")]
+    /// Drop the entries of a checkpoint archive the restore does without:
+    /// dump statistics, unreferenced ghost files, unread pre-dump images
+    Prune(prune::PruneArgs),
+    #[cfg(feature = "edit")]
+    /// Write a byte-deterministic copy of a checkpoint archive (sorted
+    /// entries, fixed mtimes and owners, canonical JSON), for comparing
+    /// edits with sha256sum
+    Normalize(normalize::NormalizeArgs),
+    #[cfg(feature = "edit")]
+    /// Combine two checkpoints of the same container, taking the process
+    /// images, filesystem changes and metadata each from one of them
+    Merge(merge::MergeArgs),
+    #[cfg(feature = "edit")]
+    /// Write a copy of a checkpoint archive with its names, addresses, MAC
+    /// addresses and environment secrets replaced, for sharing it upstream
+    Anonymize(anonymize::AnonymizeArgs),
+    #[cfg(feature = "tui")]
+    /// Browse a checkpoint archive, decode its entries and pick what the
+    /// edit does to each socket, then write it (built with --features tui)
+    Tui(tui::TuiArgs),
+    #[cfg(feature = "edit")]
+    /// Check crit, criu, podman, the kernel and /dev/shm on this node and
+    /// others over SSH before a migration
+    Preflight(preflight::PreflightArgs),
+    /// Check a restored container's address, MAC, hostname and ports against
+    /// the edited checkpoint
+    VerifyRestore(verify::VerifyArgs),
+    /// Export the checkpoint's socket table (protocol, state, addresses,
+    /// inode, fds) as CSV or JSON
+    Sockets(sockets::SocketsArgs),
+    /// List the checkpoint's pipes and FIFOs with the processes on each end,
+    /// flagging those whose peer is outside the checkpoint, and its eventfds,
+    /// signalfds, timerfds and epoll sets with what they watch, and its SysV
+    /// shared memory segments and memfds with their sizes
+    Inspect(inspect::InspectArgs),
+    /// List the largest entries of a checkpoint and the size of its pages,
+    /// images, rootfs diff and metadata
+    Du(du::DuArgs),
+    /// Show the TCP repair state of each connection (sequence numbers,
+    /// queues, windows), for debugging flows that stall after the restore
+    TcpRepair(tcp_repair::TcpRepairArgs),
+    /// Print the load balancer config fragment (node entry and service ports)
+    /// for the container on the new node
+    LbConfig(lb::LbConfigArgs),
+    /// Estimate which load balancer buckets and flows moving an endpoint
+    /// from old_addr to new_addr disturbs
+    HashImpact(lb::HashImpactArgs),
+    /// Export the checkpoint's service connections as 5-tuples with their
+    /// backend, for the controller to pin before the restore is unpaused
+    Flows(lb::FlowsArgs),
+    /// Print shell completions for SHELL
+    Completions(completions::CompletionsArgs),
+    /// Print the man page, or write one per subcommand to a directory
+    Manpage(completions::ManpageArgs),
+}
+
+/// Default mode: edit a checkpoint archive in place.
+#[cfg(feature = "edit")]
+#[derive(Args)]
+struct EditArgs {
+    /// Checkpoint archive (podman container checkpoint --export), s3://
+    /// object, https:// URL of one published with its --index, unpacked
+    /// checkpoint directory, OCI image layout directory of a checkpoint
+    /// image, or image name with --image
+    #[arg(required = true)]
+    checkpoint: Option<String>,
+    /// CHECKPOINT is a local checkpoint image (podman container checkpoint
+    /// --create-image); its layer is edited and the image reloaded
+    #[arg(long)]
+    image: bool,
+    /// [old_addr|-] <new_addr> [image_name]; old_addr is detected when omitted or "-",
+    /// new_addr is omitted with --ipam-network and --clear-static-ip
+    #[arg(value_name = "ADDR", num_args = 0..=3)]
+    addrs: Vec<String>,
+    /// Allocate new_addr from this netavark network on the target
+    #[arg(long, value_name = "NETWORK")]
+    ipam_network: Option<String>,
+    /// Query IPAM on this host over SSH instead of the local podman
+    #[arg(long, value_name = "USER@HOST", requires = "ipam_network")]
+    ipam_host: Option<String>,
+    /// Remove the static IP from config.dump and network.status instead of
+    /// setting new_addr, letting the target network assign one
+    #[arg(long, conflicts_with_all = ["ipam_network", "add_addr"])]
+    clear_static_ip: bool,
+    #[command(flatten)]
+    patch: PatchArgs,
+    /// Before finalizing, probe new_addr with arping -D on this interface of the
+    /// target and abort if anything answers
+    #[arg(long, value_name = "IFACE")]
+    conflict_check: Option<String>,
+    /// Run the conflict probe on this host over SSH (default: --ipam-host, or local)
+    #[arg(long, value_name = "USER@HOST", requires = "conflict_check")]
+    conflict_host: Option<String>,
+    /// Check that the target's podman has the container's image, asking its
+    /// API at URL (unix:///run/podman/podman.sock or tcp://HOST:PORT)
+    #[arg(long, value_name = "URL")]
+    image_check: Option<String>,
+    /// Compress the edited archive
+    #[arg(long, value_enum, default_value = "none")]
+    compress: compress::Compression,
+    /// zstd compression level
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    zstd_level: i32,
+    /// zstd compression threads (default: one per CPU)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    zstd_workers: u32,
+    /// Read and write the archive through io_uring (needs the io_uring
+    /// build feature)
+    #[arg(long)]
+    io_uring: bool,
+    /// Read the archive SIZE bytes at a time (e.g. 64K, 1M; default 256K)
+    #[arg(long, value_name = "SIZE", value_parser = buffers::parse_size)]
+    read_buffer: Option<usize>,
+    /// Write the edited archive SIZE bytes at a time (default 256K)
+    #[arg(long, value_name = "SIZE", value_parser = buffers::parse_size)]
+    write_buffer: Option<usize>,
+    /// Pick the buffer sizes not given from the archive's filesystem (tmpfs,
+    /// NFS, ...) and size
+    #[arg(long)]
+    auto_buffers: bool,
+    /// Write CHECKPOINT.index.json: the offset and length of each entry in
+    /// the edited archive, for fetching entries by range
+    #[arg(long, conflicts_with = "image")]
+    index: bool,
+    /// Write the edited archive to FILE (or s3://BUCKET/KEY) instead of
+    /// replacing CHECKPOINT (needed for a URL)
+    #[arg(long, short, value_name = "FILE", conflicts_with = "image")]
+    output: Option<String>,
+    /// Keep the partial output of an interrupted or failed edit with a
+    /// progress journal, and resume from it when run again (local,
+    /// uncompressed archives)
+    #[arg(long, conflicts_with = "image")]
+    resumable: bool,
+    /// Read the edited archive back before it replaces anything and check
+    /// that every entry the edit did not change is in it bit for bit
+    #[arg(long, conflicts_with = "image")]
+    verify_copy: bool,
+    /// Sign the edited archive with this Ed25519 key (PKCS#8 PEM); needed to
+    /// edit a signed checkpoint, whose signature the edit would break
+    #[arg(long, value_name = "KEY")]
+    sign_key: Option<PathBuf>,
+    /// Write a systemd unit to FILE that restores the edited checkpoint with
+    /// the podman flags it needs, for `systemctl start` on the target
+    #[arg(long, value_name = "FILE")]
+    unit: Option<PathBuf>,
+    /// Where the target finds the edited archive, for the printed restore
+    /// command and --unit (default: its absolute path here)
+    #[arg(long, value_name = "PATH", conflicts_with = "image")]
+    restore_archive: Option<String>,
+    /// Extra argument for the printed `podman container restore` and --unit
+    /// (repeatable)
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    restore_arg: Vec<String>,
+    /// Write what restoring the edited checkpoint as a Kubernetes pod takes
+    /// to DIR: the checkpoint image's annotations, build.sh and pod.json
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with = "image",
+        requires = "kube_image"
+    )]
+    kube: Option<PathBuf>,
+    /// The reference build.sh pushes the checkpoint image as, e.g.
+    /// registry.example.com/checkpoints/app:v2
+    #[arg(long, value_name = "IMAGE", requires = "kube")]
+    kube_image: Option<String>,
+    /// Namespace of the pod (default: the checkpoint's, or "default")
+    #[arg(long, value_name = "NS", requires = "kube")]
+    kube_namespace: Option<String>,
+    /// Annotate the pod with KEY=VALUE (repeatable), e.g. to have the CNI
+    /// plugin assign new_addr
+    #[arg(long, value_name = "KEY=VALUE", value_parser = security::parse_map)]
+    #[arg(requires = "kube")]
+    kube_annotation: Vec<(String, String)>,
+    /// Fail instead of exiting with the no-op status when the edit changes
+    /// nothing, e.g. because it was given the wrong checkpoint
+    #[arg(long)]
+    fail_if_noop: bool,
+    /// Write a JSON report of the edit to FILE
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+}
+
+/// Metadata patch flags shared by the edit and migrate modes.
+#[cfg(feature = "edit")]
+#[derive(Args, Debug)]
+struct PatchArgs {
+    /// Add new_addr as a secondary address on the interface, keeping old_addr
+    /// (for switchovers where old_addr is tunneled to the target meanwhile)
+    #[arg(long)]
+    add_addr: bool,
+    /// Replace the captured DNS servers (repeatable). Without it, servers on
+    /// the source subnet are pruned when new_addr is on a different subnet
+    #[arg(long, value_name = "ADDR")]
+    dns_server: Vec<String>,
+    /// Replace the captured DNS search domains (repeatable)
+    #[arg(long, value_name = "DOMAIN")]
+    dns_search: Vec<String>,
+    /// Replace the container's network aliases (repeatable)
+    #[arg(long, value_name = "NAME")]
+    alias: Vec<String>,
+    /// Pass OPT to CRIU at restore (repeatable, e.g. tcp-close); written to
+    /// criu-restore.conf in the archive
+    #[arg(long, value_name = "OPT", allow_hyphen_values = true)]
+    criu_opt: Vec<String>,
+    /// At restore, have CRIU add new_addr to the container's interface if
+    /// missing and announce it with gratuitous ARP (an injected action script)
+    #[arg(long)]
+    restore_fixup: bool,
+    /// At restore, have CRIU add this route in the container, as `ip route`
+    /// takes it, e.g. "default via 10.0.0.1" (repeatable)
+    #[arg(long, value_name = "ROUTE")]
+    restore_route: Vec<String>,
+    /// Add FILE as a CRIU action script run at each restore stage
+    #[arg(long, value_name = "FILE", value_parser = action::parse_script)]
+    action_script: Option<action::Script>,
+    /// Close established TCP connections on PORT (either end) at restore
+    /// instead of repairing them (repeatable)
+    #[arg(long, value_name = "PORT")]
+    tcp_close_port: Vec<u16>,
+    /// Close established TCP connections to ADDR[:PORT] at restore instead of
+    /// repairing them (repeatable)
+    #[arg(long, value_name = "ADDR[:PORT]", value_parser = parse_peer)]
+    tcp_close_peer: Vec<(IpAddr, Option<u16>)>,
+    /// What to do with each socket instead of wildcarding those bound to
+    /// old_addr: comma-separated SELECTOR ACTION rules, first match wins, e.g.
+    /// "tcp/8080 rewrite, tcp/9090 skip, tcp:established close" (repeatable;
+    /// actions: wildcard, rewrite, skip, close)
+    #[arg(long, value_name = "RULES", value_parser = policy::parse_rules)]
+    socket_rules: Vec<policy::Rules>,
+    /// Translate connected sockets' IPv4 peers into this NAT64 prefix, e.g.
+    /// 64:ff9b::/96, for a target segment that is IPv6-only
+    #[arg(long, value_name = "PREFIX/LEN", value_parser = nat64::parse_prefix)]
+    nat64_prefix: Option<nat64::Nat64>,
+    /// The container's IPv6 address on the target, the translated sockets'
+    /// new source (required to translate established TCP connections)
+    #[arg(long, value_name = "ADDR", requires = "nat64_prefix")]
+    nat64_src: Option<Ipv6Addr>,
+    /// Move listening sockets off the ports FILE lists as taken on the
+    /// target (PROTO/PORT lines, or saved `ss -Hltun` output), e.g. ports the
+    /// pod's other containers hold
+    #[arg(long, value_name = "FILE", value_parser = ports::parse_file)]
+    port_inventory: Option<ports::PortInventory>,
+    /// Like --port-inventory, querying the ports in use with `ss` on
+    /// USER@HOST over SSH
+    #[arg(long, value_name = "USER@HOST", conflicts_with = "port_inventory")]
+    port_inventory_host: Option<String>,
+    /// Rewrite SELinux labels for the target's policy (repeatable); OLD is a
+    /// whole label or one field of it, e.g. container_t=spc_t
+    #[arg(long, value_name = "OLD=NEW", value_parser = security::parse_map)]
+    selinux_map: Vec<(String, String)>,
+    /// Drop the SELinux labels and restore unconfined (label=disable)
+    #[arg(long, conflicts_with = "selinux_map")]
+    selinux_disable: bool,
+    /// Restore under this AppArmor profile instead of the checkpointed one
+    #[arg(long, value_name = "NAME")]
+    apparmor_profile: Option<String>,
+    /// Restore without an AppArmor profile (unconfined)
+    #[arg(long, conflicts_with = "apparmor_profile")]
+    apparmor_clear: bool,
+    /// `strip` the seccomp filter, or `replace=FILE` it with an OCI
+    /// linux.seccomp object (when the target kernel rejects the source's)
+    #[arg(long, value_name = "strip|replace=FILE", value_parser = security::parse_seccomp)]
+    seccomp: Option<security::SeccompPatch>,
+    /// Add a Linux capability (e.g. NET_RAW) to the restored container
+    #[arg(long, value_name = "CAP", value_parser = security::parse_cap_add)]
+    cap_add: Vec<String>,
+    /// Drop a Linux capability from the restored container (ALL for every one)
+    #[arg(long, value_name = "CAP", value_parser = security::parse_cap)]
+    cap_drop: Vec<String>,
+    /// Restore with this UID mapping, CONTAINER:HOST:SIZE as podman --uidmap
+    /// (repeatable); rootfs-diff.tar owners are translated to it
+    #[arg(long, value_name = "MAP", value_parser = userns::parse_idmap)]
+    uidmap: Vec<userns::IdMap>,
+    /// GID mapping like --uidmap (default: the UID mapping)
+    #[arg(long, value_name = "MAP", value_parser = userns::parse_idmap, requires = "uidmap")]
+    gidmap: Vec<userns::IdMap>,
+    /// Restore without a user namespace (e.g. a rootless checkpoint as rootful)
+    #[arg(long, conflicts_with = "uidmap")]
+    no_userns: bool,
+    /// Remap the host directory secrets are mounted from, OLD=NEW path
+    /// prefixes (repeatable), e.g. a different kubelet root
+    #[arg(long, value_name = "OLD=NEW", value_parser = security::parse_map)]
+    secrets_map: Vec<(String, String)>,
+    /// Open files (and so the locks of a --file-locks checkpoint) from NEW
+    /// instead of OLD, path prefixes in the container (repeatable)
+    #[arg(long, value_name = "OLD=NEW", value_parser = security::parse_map)]
+    lock_path_map: Vec<(String, String)>,
+    /// Recreate the deleted files the container holds open (ghost files)
+    /// under NEW instead of OLD, path prefixes in the container (repeatable)
+    #[arg(long, value_name = "OLD=NEW", value_parser = security::parse_map)]
+    ghost_path_map: Vec<(String, String)>,
+    /// Rename a systemd slice or cgroup the container runs under, OLD=NEW
+    /// (repeatable), e.g. machine.slice=tenant-a.slice
+    #[arg(long, value_name = "OLD=NEW", value_parser = security::parse_map)]
+    cgroup_map: Vec<(String, String)>,
+    /// Restore on a different image reference, OLD=NEW names or IDs
+    /// (repeatable), e.g. when the target has the image under a mirror's tag
+    #[arg(long, value_name = "OLD=NEW", value_parser = security::parse_map)]
+    image_map: Vec<(String, String)>,
+    /// Move the container's monotonic and boot clocks forward by SECS (or
+    /// back, if negative), e.g. by the migration's downtime
+    #[arg(long, value_name = "SECS", value_parser = timens::parse_secs, allow_hyphen_values = true)]
+    timens_advance: Option<i64>,
+    /// Resume CLOCK (monotonic or boottime) at SECS in the restored container
+    /// (repeatable)
+    #[arg(long, value_name = "CLOCK=SECS", value_parser = timens::parse_clock)]
+    timens_clock: Vec<(timens::Clock, i64)>,
+    /// Move the entries under OLD to NEW in the container's tmpfs mounts,
+    /// paths from the tmpfs root (repeatable)
+    #[arg(long, value_name = "OLD=NEW", value_parser = security::parse_map)]
+    tmpfs_path_map: Vec<(String, String)>,
+    /// Replace the text OLD with NEW in the files on the container's tmpfs
+    /// mounts (repeatable)
+    #[arg(long, value_name = "OLD=NEW", value_parser = security::parse_map)]
+    tmpfs_replace: Vec<(String, String)>,
+    /// Replace old_addr with new_addr in the files on the container's tmpfs
+    /// mounts, e.g. runtime configs a service generated
+    #[arg(long)]
+    tmpfs_addr: bool,
+    /// Set FIELD of inventory.img to VALUE, root_ids.FIELD for the root
+    /// task's ids (repeatable), e.g. dump_uptime=0
+    #[arg(long, value_name = "FIELD=VALUE", value_parser = inventory::parse_set)]
+    inventory_set: Vec<(String, serde_json::Value)>,
+    /// Remove FIELD from inventory.img (repeatable), e.g. lsmtype to restore
+    /// on a host with another LSM without --lsm-profile
+    #[arg(long, value_name = "FIELD", value_parser = inventory::parse_field)]
+    inventory_clear: Vec<String>,
+    /// Write the pages images last, after an index of the archive, so the
+    /// target can start restoring before the pages have arrived
+    #[arg(long)]
+    restore_order: bool,
+    /// Refuse the edit unless the checkpoint was dumped on ARCH (as `uname
+    /// -m` prints it), the target's architecture
+    #[arg(long, value_name = "ARCH", value_parser = arch::parse_arch)]
+    target_arch: Option<String>,
+    /// Refuse the edit if the checkpoint's pages are not of SIZE (4K, 16K
+    /// or 64K), the target's page size
+    #[arg(long, value_name = "SIZE", value_parser = arch::parse_page_size)]
+    target_page_size: Option<u64>,
+    /// Refuse the edit if restoring the checkpoint needs more than SIZE
+    /// (e.g. 4G) of memory, the target's free memory; the estimate is in the
+    /// report either way
+    #[arg(long, value_name = "SIZE", value_parser = buffers::parse_bytes)]
+    target_memory: Option<usize>,
+    /// Overwrite PATTERN in the memory pages with zeros (repeatable), e.g. an
+    /// API key, before the checkpoint leaves this node; text (matched in
+    /// UTF-8 and UTF-16) or hex:BYTES
+    #[arg(long, value_name = "PATTERN", value_parser = redact::parse_pattern)]
+    redact: Vec<redact::Pattern>,
+    /// Like --redact, for each line of FILE, keeping the patterns out of the
+    /// process list
+    #[arg(long, value_name = "FILE", value_parser = redact::parse_file)]
+    redact_file: Option<redact::Patterns>,
+}
+
+#[cfg(feature = "edit")]
+fn parse_peer(s: &str) -> Result<(IpAddr, Option<u16>), String> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok((addr.ip(), Some(addr.port())));
     }
-    let tar_path = &args[1];
-    let old_addr = &args[2];
-    let new_addr = &args[3];
-    let _image_name = args.get(4).map(String::as_str);
+    s.parse::<IpAddr>()
+        .map(|addr| (addr, None))
+        .map_err(|_| format!("{} is not ADDR or ADDR:PORT", s))
+}
 
-    if !Path::new(tar_path).exists() {
-        eprintln!("Error: {} does not exist", tar_path);
-        std::process::exit(1);
+#[cfg(feature = "edit")]
+impl PatchArgs {
+    fn options(&self) -> EditOptions {
+        let non_empty = |v: &Vec<String>| Some(v.clone()).filter(|v| !v.is_empty());
+        let action = action::ActionPatch {
+            fixup: self.restore_fixup,
+            routes: self.restore_route.clone(),
+            script: self.action_script.clone(),
+        };
+        EditOptions {
+            secondary: self.add_addr,
+            dns_servers: non_empty(&self.dns_server),
+            dns_search: non_empty(&self.dns_search),
+            aliases: non_empty(&self.alias),
+            // The action scripts are run from criu-restore.conf
+            criu_opts: match non_empty(&self.criu_opt) {
+                None if !action.is_empty() => Some(Vec::new()),
+                criu_opts => criu_opts,
+            },
+            action,
+            tcp_close: self
+                .tcp_close_port
+                .iter()
+                .map(|p| TcpClose::Port(*p))
+                .chain(
+                    self.tcp_close_peer
+                        .iter()
+                        .map(|(addr, port)| TcpClose::Peer(*addr, *port)),
+                )
+                .collect(),
+            socket_rules: self
+                .socket_rules
+                .iter()
+                .flat_map(|rules| rules.0.clone())
+                .collect(),
+            nat64: self.nat64_prefix,
+            nat64_src: self.nat64_src,
+            ports: ports::PortPatch {
+                inventory: self.port_inventory.clone(),
+                host: self.port_inventory_host.clone(),
+            },
+            security: SecurityPatch {
+                selinux: match (self.selinux_disable, self.selinux_map.is_empty()) {
+                    (true, _) => Some(SelinuxPatch::Disable),
+                    (false, false) => Some(SelinuxPatch::Map(self.selinux_map.clone())),
+                    (false, true) => None,
+                },
+                apparmor: if self.apparmor_clear {
+                    Some("unconfined".to_string())
+                } else {
+                    self.apparmor_profile.clone()
+                },
+                seccomp: self.seccomp.clone(),
+                cap_add: self.cap_add.clone(),
+                cap_drop: self.cap_drop.clone(),
+            },
+            idmap: if self.no_userns {
+                Some(userns::IdMaps::default())
+            } else if !self.uidmap.is_empty() {
+                Some(userns::IdMaps {
+                    uid: self.uidmap.clone(),
+                    gid: if self.gidmap.is_empty() {
+                        self.uidmap.clone()
+                    } else {
+                        self.gidmap.clone()
+                    },
+                })
+            } else {
+                None
+            },
+            secrets_map: self.secrets_map.clone(),
+            lock_path_map: self.lock_path_map.clone(),
+            ghosts: ghosts::GhostPatch {
+                map: self.ghost_path_map.clone(),
+                ids: None,
+            },
+            cgroup_map: self.cgroup_map.clone(),
+            image_map: self.image_map.clone(),
+            timens: timens::TimensPatch {
+                advance: self.timens_advance,
+                set: self.timens_clock.clone(),
+            },
+            tmpfs: tmpfs::TmpfsPatch {
+                path_map: self.tmpfs_path_map.clone(),
+                replace: self.tmpfs_replace.clone(),
+                addr: self.tmpfs_addr,
+            },
+            inventory: inventory::InventoryPatch {
+                set: self.inventory_set.clone(),
+                clear: self.inventory_clear.clone(),
+            },
+            restore_order: self.restore_order,
+            target: arch::TargetPlatform {
+                arch: self.target_arch.clone(),
+                page_size: self.target_page_size,
+            },
+            target_memory: self.target_memory.map(|m| m as u64),
+            redact: redact::Redaction {
+                patterns: self
+                    .redact
+                    .iter()
+                    .chain(self.redact_file.iter().flat_map(|f| &f.0))
+                    .cloned()
+                    .collect(),
+            },
+            ..Default::default()
+        }
     }
-    if old_addr.is_empty() || new_addr.is_empty() {
-        eprintln!("Error: old_addr and new_addr must not be empty");
-        std::process::exit(1);
+}
+
+#[cfg(feature = "edit")]
+/// Where new_addr comes from: the command line or the target's IPAM.
+/// Clear drops the static address altogether.
+enum NewAddr<'a> {
+    Fixed(&'a str),
+    Ipam(ipam::IpamQuery<'a>),
+    Clear,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    trace::init();
+    if let Err(e) = cli.event_fd.as_deref().map_or(Ok(()), events::init) {
+        die(&e);
     }
-    if old_addr == new_addr {
-        eprintln!("Error: old_addr and new_addr must be different");
-        std::process::exit(1);
+    crit::set_timeout((cli.crit_timeout > 0).then(|| Duration::from_secs(cli.crit_timeout)));
+    crit::set_cache(cli.decode_cache.clone());
+    crit::set_host(cli.crit_host.clone());
+    #[cfg(feature = "edit")]
+    if let Err(e) = interrupt::install() {
+        die(&e);
     }
+    match &cli.command {
+        #[cfg(feature = "edit")]
+        Some(Command::Migrate(args)) => {
+            let mut root = trace::span("migrate");
+            root.attr("container", &args.container);
+            let mut report = Report::default();
+            let result = migrate::run(args, &mut report);
+            finish(result, report, args.report.as_deref(), root);
+        }
+        #[cfg(feature = "edit")]
+        Some(Command::Preflight(args)) => {
+            let root = trace::span("preflight");
+            let mut report = Report::default();
+            let result = preflight::run(args, &mut report);
+            finish(result, report, args.report.as_deref(), root);
+        }
+        Some(Command::VerifyRestore(args)) => {
+            let root = trace::span("verify-restore");
+            let mut report = Report::default();
+            let result = verify::run(args, &mut report);
+            finish(result, report, args.report.as_deref(), root);
+        }
+        #[cfg(feature = "edit")]
+        Some(Command::Hook(args)) => exit_on_error(hook::run(args)),
+        #[cfg(feature = "edit")]
+        Some(Command::Watch(args)) => exit_on_error(watch::run(args)),
+        Some(Command::Unpack(args)) => exit_on_error(pack::unpack(args)),
+        #[cfg(feature = "edit")]
+        Some(Command::Pack(args)) => exit_on_error(pack::pack(args)),
+        #[cfg(feature = "edit")]
+        Some(Command::Undo(args)) => exit_on_error(undo::run(args)),
+        #[cfg(feature = "edit")]
+        Some(Command::Inject(args)) => exit_on_error(inject::run(args)),
+        #[cfg(feature = "edit")]
+        Some(Command::Delete(args)) => exit_on_error(delete::run(args)),
+        #[cfg(feature = "edit")]
+        Some(Command::Prune(args)) => exit_on_error(prune::run(args)),
+        #[cfg(feature = "edit")]
+        Some(Command::Normalize(args)) => exit_on_error(normalize::run(args)),
+        #[cfg(feature = "edit")]
+        Some(Command::Merge(args)) => exit_on_error(merge::run(args)),
+        #[cfg(feature = "edit")]
+        Some(Command::Anonymize(args)) => exit_on_error(anonymize::run(args)),
+        #[cfg(feature = "tui")]
+        Some(Command::Tui(args)) => exit_on_error(tui::run(args)),
+        Some(Command::Sockets(args)) => exit_on_error(sockets::run(args)),
+        Some(Command::Inspect(args)) => exit_on_error(inspect::run(args)),
+        Some(Command::Du(args)) => exit_on_error(du::run(args)),
+        Some(Command::TcpRepair(args)) => exit_on_error(tcp_repair::run(args)),
+        Some(Command::LbConfig(args)) => exit_on_error(lb::run(args)),
+        Some(Command::HashImpact(args)) => exit_on_error(lb::hash_impact(args)),
+        Some(Command::Flows(args)) => exit_on_error(lb::flows(args)),
+        Some(Command::Completions(args)) => exit_on_error(completions::completions(args)),
+        Some(Command::Manpage(args)) => exit_on_error(completions::manpage(args)),
+        #[cfg(feature = "edit")]
+        None => edit_main(&cli.edit),
+        #[cfg(not(feature = "edit"))]
+        None => unreachable!("a subcommand is required"),
+    }
+}
 
-    if let Err(e) = run(tar_path, old_addr, new_addr) {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
+fn exit_on_error(result: Result<(), String>) {
+    match result {
+        Ok(()) => events::done("ok"),
+        Err(e) => die(&e),
     }
 }
 
-const FILES_IMG_PATH: &str = "checkpoint/files.img";
-const NETWORK_STATUS_PATH: &str = "network.status";
-const CONFIG_DUMP_PATH: &str = "config.dump";
+/// Print `message` as the error the run fails with and exit non-zero.
+fn die(message: &str) -> ! {
+    eprintln!("Error: {}", message);
+    events::error(message);
+    std::process::exit(1);
+}
 
-fn run(tar_path: &str, _old_addr: &str, new_addr: &str) -> Result<(), String> {
-    let show_timing = env::var("EDIT_CHECKPOINT_TIMING").is_ok();
-    let t0 = Instant::now();
+/// Exit status of an edit that completed without changing anything.
+const EXIT_NOOP: i32 = 3;
 
-    // Prefer RAM (e.g. /dev/shm) for crit temp files to minimize I/O latency
-    let temp_path = Path::new("/dev/shm");
-    let temp_dir = if temp_path.exists() && temp_path.is_dir() {
-        tempfile::tempdir_in(temp_path).map_err(|e| e.to_string())?
-    } else {
-        tempfile::tempdir().map_err(|e| e.to_string())?
+/// Close the root span, write the report (if requested) and exit non-zero on
+/// error, or with EXIT_NOOP if the report marks the run a no-op.
+fn finish(
+    result: Result<(), String>,
+    mut report: Report,
+    report_path: Option<&Path>,
+    mut root: trace::Span,
+) {
+    if let Err(e) = &result {
+        root.fail(e);
+    }
+    drop(root);
+    trace::flush();
+    let noop = result.is_ok() && report.get("noop").is_some_and(|n| *n == true);
+    let status = match &result {
+        Err(_) => "error",
+        Ok(()) if noop => "noop",
+        Ok(()) => "ok",
     };
-    let temp_path = temp_dir.path();
-    let files_img_in = temp_path.join("files.img.in");
-    let files_img_out = temp_path.join("files.img.out");
-    let decoded_path = temp_path.join("decoded.json");
-
-    let tar_file = fs::File::open(tar_path).map_err(|e| e.to_string())?;
-    let mut archive = tar::Archive::new(BufReader::with_capacity(256 * 1024, tar_file));
-    let new_tar_path = format!("{}.new", tar_path);
-    let out_file = fs::File::create(&new_tar_path).map_err(|e| e.to_string())?;
-    let mut builder = tar::Builder::new(BufWriter::with_capacity(256 * 1024, out_file));
-
-    let entries = archive.entries().map_err(|e| e.to_string())?;
-    let mut found_files_img = false;
-
-    for entry in entries {
-        let mut entry = entry.map_err(|e| e.to_string())?;
-        let path = entry
-            .path()
-            .map_err(|e| e.to_string())?
-            .display()
-            .to_string()
-            .replace('\\', "/");
-        let size_hint = entry.header().size().unwrap_or(0) as usize;
-        let mut content = Vec::with_capacity(size_hint.min(64 * 1024 * 1024).max(4096));
-        entry.read_to_end(&mut content).map_err(|e| e.to_string())?;
-
-        if path == FILES_IMG_PATH {
-            found_files_img = true;
-            if show_timing {
-                eprintln!("  tar stream:    {:>6} ms (read)", t0.elapsed().as_millis());
-            }
-            let t1 = Instant::now();
-            fs::write(&files_img_in, &content).map_err(|e| e.to_string())?;
-            let decode_status = Command::new("crit")
-                .args(["decode", "-i", files_img_in.to_str().unwrap()])
-                .stdout(std::process::Stdio::from(
-                    fs::File::create(&decoded_path).map_err(|e| e.to_string())?,
-                ))
-                .status()
-                .map_err(|e| e.to_string())?;
-            if !decode_status.success() {
-                return Err("crit decode failed".to_string());
-            }
-            if show_timing {
-                eprintln!("  crit decode:   {:>6} ms", t1.elapsed().as_millis());
-            }
-            let t2 = Instant::now();
-            let mut data: serde_json::Value =
-                serde_json::from_reader(fs::File::open(&decoded_path).map_err(|e| e.to_string())?)
-                    .map_err(|e| e.to_string())?;
-            let updated = patch_files_img_json(&mut data, new_addr);
-            if !updated {
-                eprintln!(
-                    "Note: no non-wildcard INETSK entries found in files.img (server likely uses 0.0.0.0 — OK)",
-                );
-            }
-            // Compact JSON is smaller and faster for crit encode to read
-            fs::write(
-                &decoded_path,
-                serde_json::to_string(&data).map_err(|e| e.to_string())?,
-            )
-            .map_err(|e| e.to_string())?;
-            if show_timing {
-                eprintln!("  json patch:    {:>6} ms", t2.elapsed().as_millis());
-            }
-            let t3 = Instant::now();
-            let encode_status = Command::new("crit")
-                .args([
-                    "encode",
-                    "-i",
-                    decoded_path.to_str().unwrap(),
-                    "-o",
-                    files_img_out.to_str().unwrap(),
-                ])
-                .status()
-                .map_err(|e| e.to_string())?;
-            if !encode_status.success() {
-                return Err("crit encode failed".to_string());
-            }
-            if show_timing {
-                eprintln!("  crit encode:   {:>6} ms", t3.elapsed().as_millis());
-            }
-            content = fs::read(&files_img_out).map_err(|e| e.to_string())?;
-            let mut new_header = entry.header().clone();
-            new_header.set_size(content.len() as u64);
-            new_header.set_cksum();
-            builder
-                .append(&new_header, content.as_slice())
-                .map_err(|e| e.to_string())?;
-        } else if path == NETWORK_STATUS_PATH {
-            // Patch network.status: set the IP to new_addr
-            let patched = patch_network_status(&content, new_addr)?;
-            let mut new_header = entry.header().clone();
-            new_header.set_size(patched.len() as u64);
-            new_header.set_cksum();
-            builder
-                .append(&new_header, patched.as_slice())
-                .map_err(|e| e.to_string())?;
-            eprintln!("Patched network.status → {}", new_addr);
-        } else if path == CONFIG_DUMP_PATH {
-            // Patch config.dump: set staticIP to new_addr
-            let patched = patch_config_dump(&content, new_addr)?;
-            let mut new_header = entry.header().clone();
-            new_header.set_size(patched.len() as u64);
-            new_header.set_cksum();
-            builder
-                .append(&new_header, patched.as_slice())
-                .map_err(|e| e.to_string())?;
-            eprintln!("Patched config.dump staticIP → {}", new_addr);
-        } else {
-            let mut h = entry.header().clone();
-            h.set_cksum();
-            builder
-                .append(&h, content.as_slice())
-                .map_err(|e| e.to_string())?;
+    if let Some(path) = report_path {
+        report.set("status", status);
+        if let Err(e) = &result {
+            report.set("error", e.as_str());
+        }
+        if let Err(e) = report.write(path) {
+            eprintln!("Warning: {}", e);
         }
     }
+    if let Err(e) = result {
+        die(&e);
+    }
+    events::done(status);
+    if noop {
+        std::process::exit(EXIT_NOOP);
+    }
+}
 
-    if !found_files_img {
-        return Err(format!("{} not found in archive", FILES_IMG_PATH));
+#[cfg(feature = "edit")]
+fn edit_main(cli: &EditArgs) {
+    // <checkpoint.tar> <new_addr>
+    // <checkpoint.tar> <old_addr|-> <new_addr> [image_name]
+    // <checkpoint.tar> [old_addr|-] --ipam-network NET
+    // <checkpoint.tar> [old_addr|-] --clear-static-ip
+    let addrs: Vec<&str> = cli.addrs.iter().map(String::as_str).collect();
+    let (old_addr, new_addr) = match (&cli.ipam_network, addrs.as_slice()) {
+        (None, [] | [_]) if cli.clear_static_ip => (addrs.first().copied(), NewAddr::Clear),
+        (Some(network), [] | [_]) => {
+            let query = ipam::IpamQuery {
+                network,
+                host: cli.ipam_host.as_deref(),
+            };
+            (addrs.first().copied(), NewAddr::Ipam(query))
+        }
+        _ if cli.clear_static_ip => {
+            die("--clear-static-ip takes no new_addr");
+        }
+        (None, [new]) => (None, NewAddr::Fixed(new)),
+        (None, [old, new] | [old, new, _]) => (Some(*old), NewAddr::Fixed(new)),
+        _ => {
+            eprintln!(
+                "Usage: edit_checkpoint <checkpoint.tar> [old_addr|-] <new_addr> [image_name]\n       \
+                 edit_checkpoint <checkpoint.tar> [old_addr|-] --ipam-network NETWORK\n       \
+                 edit_checkpoint <checkpoint.tar> [old_addr|-] --clear-static-ip"
+            );
+            events::error("wrong number of arguments");
+            std::process::exit(1);
+        }
+    };
+    let old_addr = old_addr.filter(|a| *a != "-");
+    let tar_path = cli.checkpoint.as_deref().unwrap_or_default();
+
+    if fetch::is_url(tar_path) && cli.output.is_none() {
+        die("a URL CHECKPOINT needs --output");
+    }
+    let remote = |path: &str| fetch::is_url(path) || s3::is_s3(path);
+    if cli.io_uring && (remote(tar_path) || cli.output.as_deref().is_some_and(remote)) {
+        die("--io-uring needs a local CHECKPOINT and output");
+    }
+    if !cli.image && !remote(tar_path) && !Path::new(tar_path).exists() {
+        die(&format!("{} does not exist", tar_path));
+    }
+    if let NewAddr::Fixed(new_addr) = new_addr {
+        if old_addr.is_some_and(str::is_empty) || new_addr.is_empty() {
+            die("old_addr and new_addr must not be empty");
+        }
+        if old_addr == Some(new_addr) {
+            die("old_addr and new_addr must be different");
+        }
     }
 
-    builder.finish().map_err(|e| e.to_string())?;
-    drop(builder);
-    fs::rename(&new_tar_path, tar_path).map_err(|e| e.to_string())?;
-    if show_timing {
-        eprintln!(
-            "  total:        {:>6} ms (stream, no full extract/repack)",
-            t0.elapsed().as_millis()
-        );
+    let signing_key = match cli.sign_key.as_deref().map(sign::load_key).transpose() {
+        Ok(key) => key,
+        Err(e) => {
+            die(&e);
+        }
+    };
+
+    let mut root = trace::span("edit");
+    root.attr("checkpoint", tar_path);
+    let mut report = Report::default();
+    report.set("checkpoint", tar_path);
+    let mut opts = EditOptions {
+        conflict_iface: cli.conflict_check.clone(),
+        conflict_host: cli.conflict_host.clone().or(cli.ipam_host.clone()),
+        image_check: cli.image_check.clone(),
+        compression: compress::OutputCompression {
+            kind: cli.compress,
+            level: cli.zstd_level,
+            workers: cli.zstd_workers,
+        },
+        io_uring: cli.io_uring,
+        buffers: buffers::BufferConfig {
+            read: cli.read_buffer,
+            write: cli.write_buffer,
+            auto: cli.auto_buffers,
+        },
+        index: cli.index,
+        output: cli.output.clone(),
+        resumable: cli.resumable,
+        verify_copy: cli.verify_copy,
+        fingerprint: Some(applied::fingerprint(&format!(
+            "{:?} {:?} {:?} {} {:?}",
+            cli.addrs, cli.ipam_network, cli.ipam_host, cli.clear_static_ip, cli.patch
+        ))),
+        signing_key,
+        ..cli.patch.options()
+    };
+    let layout = !cli.image && image::is_layout(Path::new(tar_path));
+    if layout && (cli.output.is_some() || cli.index) {
+        die("an OCI image layout is edited in place; --output and --index do not apply");
+    }
+    let edited = cli.output.as_deref().unwrap_or(tar_path);
+    if cli.kube.is_some() && (layout || remote(edited) || Path::new(edited).is_dir()) {
+        die("--kube: the edited checkpoint is not a local archive");
+    }
+    if !cli.image && !layout && !remote(tar_path) {
+        if let Err(e) = policy::resolve(&mut opts.socket_rules, Path::new(tar_path)) {
+            die(&e);
+        }
+        if let Err(e) = ghosts::resolve(&mut opts.ghosts, Path::new(tar_path)) {
+            die(&e);
+        }
     }
+    let result = if cli.image {
+        run_image(tar_path, old_addr, new_addr, &mut opts, &mut report)
+    } else if layout {
+        run_layout(tar_path, old_addr, new_addr, &mut opts, &mut report)
+    } else {
+        run(tar_path, old_addr, new_addr, &opts, &mut report)
+    };
+    // Already applied, or no address or socket matched: nothing changed
+    let result = result.map_err(space::explain);
+    let noop = result.is_ok() && !report.get("changed").is_some_and(|c| *c == true);
+    report.set("noop", noop);
+    let result = match result {
+        Ok(()) if noop && cli.fail_if_noop => Err(format!(
+            "--fail-if-noop: the edit changed nothing in {}; is it the right checkpoint?",
+            tar_path
+        )),
+        result => result,
+    };
+    let result = result.and_then(|()| {
+        let out_path = cli.output.as_deref().unwrap_or(tar_path);
+        let archive = match &cli.restore_archive {
+            Some(archive) => Some(archive.clone()),
+            // Nothing podman can import from
+            None if cli.image || remote(out_path) || Path::new(out_path).is_dir() => None,
+            None => Some(
+                fs::canonicalize(out_path)
+                    .map_err(|e| format!("{}: {}", out_path, e))?
+                    .display()
+                    .to_string(),
+            ),
+        };
+        if let (Some(dir), Some(image)) = (&cli.kube, &cli.kube_image) {
+            let kube = kube::KubeOptions {
+                dir,
+                image,
+                namespace: cli.kube_namespace.as_deref(),
+                annotations: &cli.kube_annotation,
+            };
+            kube::write(Path::new(out_path), &kube, &opts.image_map, &mut report)?;
+        }
+        let source =
+            match &archive {
+                _ if cli.image => restore::Source::Image(tar_path),
+                Some(archive) => restore::Source::Archive(archive),
+                None if cli.unit.is_some() => return Err(
+                    "--unit: the edited checkpoint is not a local archive; pass --restore-archive"
+                        .to_string(),
+                ),
+                None => return Ok(()),
+            };
+        let restore = restore::command(source, &cli.restore_arg, &mut report);
+        match &cli.unit {
+            Some(unit) => restore::write_unit(unit, source, &restore, &mut report),
+            None => Ok(()),
+        }
+    });
+    finish(result, report, cli.report.as_deref(), root);
+}
 
-    Ok(())
+#[cfg(feature = "edit")]
+/// --image: edit the checkpoint layer of a local image like an archive.
+fn run_image(
+    name: &str,
+    old_addr: Option<&str>,
+    new_addr: NewAddr,
+    opts: &mut EditOptions,
+    report: &mut Report,
+) -> Result<(), String> {
+    let saved = image::save(name)?;
+    run_layer(saved, name, old_addr, new_addr, opts, report)
+}
+
+#[cfg(feature = "edit")]
+/// CHECKPOINT is an OCI image layout: edit its checkpoint layer in place.
+fn run_layout(
+    dir: &str,
+    old_addr: Option<&str>,
+    new_addr: NewAddr,
+    opts: &mut EditOptions,
+    report: &mut Report,
+) -> Result<(), String> {
+    let saved = image::open(Path::new(dir))?;
+    let layer = saved.layer_path()?;
+    policy::resolve(&mut opts.socket_rules, &layer)?;
+    ghosts::resolve(&mut opts.ghosts, &layer)?;
+    run_layer(saved, dir, old_addr, new_addr, opts, report)
 }
 
-/// Check whether a src_addr array contains a specific (non-wildcard) address.
-/// crit decode outputs src_addr as an array of integers (uint32 network order)
-/// for AF_INET, but some versions may use strings.
-fn is_specific_addr(addrs: &[serde_json::Value]) -> bool {
-    addrs.iter().any(|a| {
-        if let Some(n) = a.as_u64() {
-            n != 0 // 0 = 0.0.0.0 (wildcard)
-        } else if let Some(s) = a.as_str() {
-            !s.is_empty() && s != "0.0.0.0" && s != "::" && s != "0"
-        } else {
-            false
+#[cfg(feature = "edit")]
+/// Edit the checkpoint layer of `saved` into a file of its own and commit
+/// it, unless the edit is applied already or changes nothing: the image is
+/// left as it is then, and so it is when the edit fails.
+fn run_layer(
+    saved: image::SavedImage,
+    what: &str,
+    old_addr: Option<&str>,
+    new_addr: NewAddr,
+    opts: &mut EditOptions,
+    report: &mut Report,
+) -> Result<(), String> {
+    let layer = saved.layer_path()?;
+    // run refuses an output other than its input for an applied edit
+    if opts.fingerprint.is_some() && MetadataFiles::read(&layer)?.applied() == opts.fingerprint {
+        eprintln!("{} already has this edit applied; left as it is", what);
+        report.set("already_applied", true);
+        return Ok(());
+    }
+    let edited = saved.edited_path()?;
+    opts.output = Some(edited.to_string_lossy().into_owned());
+    let result = run(&layer.to_string_lossy(), old_addr, new_addr, opts, report).and_then(|()| {
+        let changed = report.get("changed").is_some_and(|c| *c == true);
+        if report.get("already_applied").is_some() || !changed {
+            eprintln!("The edit changes nothing in {}; left as it is", what);
+            return Ok(());
         }
-    })
+        saved.commit(&edited, report)
+    });
+    // Renamed to its digest by a commit
+    let _ = fs::remove_file(&edited);
+    result
 }
 
-/// Patch INETSK entries' src_addr in the decoded files.img JSON.
-/// Sockets bound to a specific IP are rewritten to 0.0.0.0 (wildcard)
-/// so CRIU can bind them on any interface, avoiding "Cannot assign requested
-/// address" when the restored container's IPAM-assigned IP differs.
-/// Returns true if any change was made.
-fn patch_files_img_json(data: &mut serde_json::Value, new_addr: &str) -> bool {
-    let _ = new_addr; // new_addr not used; we always wildcard to 0.0.0.0
-
-    let entries = match data.get_mut("entries").and_then(|e| e.as_array_mut()) {
-        Some(e) => e,
-        None => return false,
+#[cfg(feature = "edit")]
+fn run(
+    tar_path: &str,
+    old_addr: Option<&str>,
+    new_addr: NewAddr,
+    opts: &EditOptions,
+    report: &mut Report,
+) -> Result<(), String> {
+    let show_timing = env::var("EDIT_CHECKPOINT_TIMING").is_ok();
+    let t0 = Instant::now();
+
+    let source = if fetch::is_url(tar_path) {
+        let _span = trace::span("fetch index");
+        Some(fetch::Source::open(tar_path, report)?)
+    } else {
+        None
     };
-    let mut updated = false;
-    let mut count = 0u32;
-    for entry in entries.iter_mut() {
-        if entry.get("type").and_then(|t| t.as_str()) != Some("INETSK") {
-            continue;
-        }
-        let isk = match entry.get_mut("isk") {
-            Some(i) => i,
-            None => continue,
-        };
-        // Check family: AF_INET = 2, crit may output as string "AF_INET" or integer 2
-        let family_str = isk.get("family").and_then(|f| f.as_str()).unwrap_or("");
-        let family_num = isk.get("family").and_then(|f| f.as_u64()).unwrap_or(0);
-        let is_inet4 = family_str == "AF_INET" || family_str == "INET" || family_num == 2;
-        if !is_inet4 {
-            continue;
+    let s3_input = s3::Object::parse(tar_path)?;
+    // Held until the edited archive has replaced this one
+    let _input_lock = match (&source, &s3_input) {
+        (None, None) => Some(lock::input(Path::new(tar_path))?),
+        _ => None,
+    };
+    let files = match (&source, &s3_input) {
+        (Some(source), _) => source.metadata()?,
+        (_, Some(object)) => {
+            let _span = trace::span("s3 metadata");
+            let input = compress::reader(BufReader::new(object.get()?))
+                .map_err(|e| format!("{}: {}", object, e))?;
+            MetadataFiles::from_reader(input)?
         }
-        let src_addrs = isk.get("src_addr").and_then(|a| a.as_array());
-        if src_addrs.map_or(false, |addrs| is_specific_addr(addrs)) {
-            // Determine format: if the original was integer, use integer 0; otherwise "0.0.0.0"
-            let was_integer = src_addrs
-                .unwrap()
-                .first()
-                .map_or(true, |v| v.is_number());
-            if was_integer {
-                isk["src_addr"] = serde_json::json!([0]);
-            } else {
-                isk["src_addr"] = serde_json::json!(["0.0.0.0"]);
-            }
-            count += 1;
-            updated = true;
+        _ => MetadataFiles::read(Path::new(tar_path))?,
+    };
+    let network_mode = files.network_mode();
+    report.set("network_mode", network_mode.as_deref());
+    report.set("rootless", files.rootless());
+    report.set("container", files.name());
+    if opts.fingerprint.is_some() && files.applied() == opts.fingerprint {
+        if opts.output.as_deref().is_some_and(|out| out != tar_path) {
+            return Err(format!(
+                "{} already has this edit applied; copy it to the output instead",
+                tar_path
+            ));
         }
+        eprintln!("{} already has this edit applied; left as it is", tar_path);
+        report.set("already_applied", true);
+        report.set("duration_ms", t0.elapsed().as_millis() as u64);
+        return Ok(());
     }
-    if updated {
-        eprintln!("Patched {} INETSK src_addr entries → 0.0.0.0 (wildcard)", count);
+    let local_archive = source.is_none() && s3_input.is_none() && !Path::new(tar_path).is_dir();
+    if opts.signing_key.is_none() && local_archive && sign::is_signed(Path::new(tar_path))? {
+        return Err(sign::refuse(tar_path));
+    }
+    if let Some(api) = &opts.image_check {
+        let _span = trace::span("image check");
+        let [_, config, spec] = files.parsed();
+        let image = rootfs::reference(config.as_ref(), spec.as_ref(), &opts.image_map)
+            .ok_or("the checkpoint names no image to check")?;
+        rootfs::check_image(api, &image, report)?;
+    }
+    let (old_addr, old_source) = match old_addr {
+        Some(addr) => (addr.to_string(), "argument"),
+        None => {
+            let (addr, source) = files.addr().ok_or_else(|| match network_mode.as_deref() {
+                Some("slirp4netns") => "the container used slirp4netns, whose address \
+                    (10.0.2.100 by default) is the same on every node; pass old_addr \
+                    explicitly to patch it anyway"
+                    .to_string(),
+                Some("pasta") => "the container used pasta, which copies the host's \
+                    address; pass the source host's address as old_addr"
+                    .to_string(),
+                _ => format!(
+                    "could not detect old_addr from {} or {}; pass it explicitly",
+                    NETWORK_STATUS_PATH, CONFIG_DUMP_PATH
+                ),
+            })?;
+            eprintln!("Detected old_addr {} (from {})", addr, source);
+            (addr, source)
+        }
+    };
+    let old_ip: Ipv4Addr = old_addr
+        .parse()
+        .map_err(|_| format!("old_addr {} is not an IPv4 address", old_addr))?;
+    report.set("old_addr", old_addr.as_str());
+    report.set("old_addr_source", old_source);
+    if show_timing {
+        eprintln!("  detect:        {:>6} ms", t0.elapsed().as_millis());
     }
-    updated
-}
 
-/// Patch network.status JSON: replace the IP in the "ips" array with new_addr.
-fn patch_network_status(content: &[u8], new_addr: &str) -> Result<Vec<u8>, String> {
-    let mut data: serde_json::Value =
-        serde_json::from_slice(content).map_err(|e| format!("parse network.status: {}", e))?;
-
-    if let Some(arr) = data.as_array_mut() {
-        for entry in arr.iter_mut() {
-            if let Some(ips) = entry.get_mut("ips").and_then(|v| v.as_array_mut()) {
-                for ip in ips.iter_mut() {
-                    if let Some(addr) = ip.get_mut("address") {
-                        // address is "IP/prefix", e.g. "192.168.12.2/24"
-                        let old = addr.as_str().unwrap_or("");
-                        let prefix = old.split('/').nth(1).unwrap_or("24");
-                        *addr = serde_json::json!(format!("{}/{}", new_addr, prefix));
-                    }
-                }
+    let new_addr = match new_addr {
+        NewAddr::Fixed(addr) => {
+            report.set("new_addr_source", "argument");
+            Some(addr.to_string())
+        }
+        NewAddr::Clear => {
+            report.set("new_addr_source", "cleared");
+            None
+        }
+        NewAddr::Ipam(query) => {
+            let _span = trace::span("ipam allocate");
+            let t = Instant::now();
+            let alloc = ipam::allocate(&query, &[old_ip])?;
+            eprintln!(
+                "Allocated new_addr {} from {} ({})",
+                alloc.addr, query.network, alloc.subnet
+            );
+            report.set("new_addr_source", "ipam");
+            report.set(
+                "ipam",
+                serde_json::json!({
+                    "network": query.network,
+                    "host": query.host,
+                    "subnet": alloc.subnet,
+                }),
+            );
+            if show_timing {
+                eprintln!("  ipam:          {:>6} ms", t.elapsed().as_millis());
             }
+            Some(alloc.addr.to_string())
         }
+    };
+    if new_addr.as_deref() == Some(old_addr.as_str()) {
+        return Err(format!("checkpoint already uses {}", old_addr));
     }
+    report.set("new_addr", new_addr.as_deref());
+    let addr_patch = match new_addr.as_deref() {
+        Some(addr) if opts.secondary => AddrPatch::Add(addr),
+        Some(addr) => AddrPatch::Replace(addr),
+        None => AddrPatch::Clear,
+    };
+    report.set("secondary_addr", opts.secondary);
+    let net_patch = opts.network_patch(old_ip, files.old_prefix(old_ip), addr_patch);
 
-    serde_json::to_vec_pretty(&data).map_err(|e| format!("serialize network.status: {}", e))
-}
+    let probe = match (&opts.conflict_iface, addr_patch) {
+        (Some(iface), AddrPatch::Replace(addr) | AddrPatch::Add(addr)) => {
+            Some(conflict::start(opts.conflict_host.as_deref(), iface, addr)?)
+        }
+        _ => None,
+    };
 
-/// Patch config.dump JSON: replace staticIP with new_addr.
-fn patch_config_dump(content: &[u8], new_addr: &str) -> Result<Vec<u8>, String> {
-    let mut data: serde_json::Value =
-        serde_json::from_slice(content).map_err(|e| format!("parse config.dump: {}", e))?;
+    if Path::new(tar_path).is_dir() {
+        if opts.output.is_some() {
+            return Err("--output: a checkpoint directory is patched in place".to_string());
+        }
+        if opts.verify_copy {
+            return Err("--verify-copy: a checkpoint directory is patched in place".to_string());
+        }
+        // Patched in place with no way back, so settle the probe first
+        if let Some(probe) = probe {
+            wait_probe(probe, report, show_timing)?;
+        }
+        let (images, root) = images::dir_layout(Path::new(tar_path))?;
+        edit::patch_dir(&images, root.as_deref(), &net_patch, opts, report)?;
+        report.set("duration_ms", t0.elapsed().as_millis() as u64);
+        return Ok(());
+    }
 
-    // Patch "staticIP" field
-    if data.get("staticIP").is_some() {
-        data["staticIP"] = serde_json::json!(new_addr);
+    let out_path = opts.output.as_deref().unwrap_or(tar_path);
+    let s3_output = s3::Object::parse(out_path)?;
+    if opts.index && (opts.compression.kind == compress::Compression::Zstd || s3_output.is_some()) {
+        return Err("--index needs an uncompressed local archive".to_string());
+    }
+    if opts.verify_copy && s3_output.is_some() {
+        return Err("--verify-copy needs a local output to read back".to_string());
+    }
+    let new_tar_path = format!("{}.new", out_path);
+    let mut timeline = Timeline::default();
+    let remote_input = source.is_some() || s3_input.is_some();
+    let buffers = if remote_input {
+        opts.buffers.resolve(Path::new(&new_tar_path), report)
+    } else {
+        opts.buffers.resolve(Path::new(tar_path), report)
+    };
+    if opts.compression.kind == compress::Compression::Zstd {
+        report.set("compression", "zstd");
+    }
+    // Inputs other than a local file are streamed
+    let remote: Option<Box<dyn Read>> = match (&source, &s3_input) {
+        (Some(source), _) => Some(Box::new(source.reader())),
+        (_, Some(object)) => Some(
+            compress::reader(BufReader::with_capacity(buffers.read, object.get()?))
+                .map_err(|e| format!("{}: {}", object, e))?,
+        ),
+        _ => None,
+    };
+    if let Some(object) = &s3_output {
+        let input = match remote {
+            Some(input) => input,
+            None => compress::open_buffered(Path::new(tar_path), buffers.read)?,
+        };
+        let output = compress::Writer::new(s3::Upload::start(object)?, &opts.compression)
+            .map_err(|e| format!("zstd: {}", e))?;
+        // Dropped on error, which aborts the upload
+        let upload = edit::stream(input, output, &net_patch, opts, &mut timeline, report)?
+            .finish()
+            .map_err(|e| format!("upload to {}: {}", object, e))?;
+        report.set("timestamps", timeline.to_json());
+        if let Some(probe) = probe {
+            wait_probe(probe, report, show_timing)?;
+        }
+        upload.complete(report)?;
+        report.set("duration_ms", t0.elapsed().as_millis() as u64);
+        return Ok(());
+    }
+    let plain = remote.is_none()
+        && opts.compression.kind == compress::Compression::None
+        && !opts.io_uring
+        && !compress::is_zstd(Path::new(tar_path))?;
+    if opts.resumable && (!plain || opts.signing_key.is_some()) {
+        return Err(
+            "--resumable needs a local uncompressed archive and output, \
+            without --io-uring or --sign-key"
+                .to_string(),
+        );
+    }
+    if remote.is_none() {
+        // What a resumed edit wrote before is there already
+        let written = match opts.resumable {
+            true => fs::metadata(&new_tar_path).map_or(0, |m| m.len()),
+            false => 0,
+        };
+        space::check(Path::new(tar_path), Path::new(out_path), written, report)?;
     }
+    let _output_lock = lock::output(Path::new(&new_tar_path))?;
+    // Kept for the next run to resume, with --resumable
+    let partial = (!opts.resumable).then(|| interrupt::Partial::new(Path::new(&new_tar_path)));
+    let mut progress = None;
+    if plain && opts.resumable {
+        let (resumed, out_file) = resume::Progress::open(
+            Path::new(tar_path),
+            Path::new(&new_tar_path),
+            opts.fingerprint.as_deref(),
+            report,
+        )?;
+        edit::stream_file(
+            Path::new(tar_path),
+            buffers.read,
+            resumed.writer(BufWriter::with_capacity(buffers.write, out_file)),
+            Some(&resumed),
+            &net_patch,
+            opts,
+            &mut timeline,
+            report,
+        )?;
+        progress = Some(resumed);
+    } else if plain {
+        // Unchanged entries go file to file in the kernel
+        let out_file = fs::File::create(&new_tar_path)
+            .map_err(|e| format!("create {}: {}", new_tar_path, e))?;
+        edit::stream_file(
+            Path::new(tar_path),
+            buffers.read,
+            BufWriter::with_capacity(buffers.write, out_file),
+            None,
+            &net_patch,
+            opts,
+            &mut timeline,
+            report,
+        )?;
+    } else if let Some(input) = remote {
+        let out_file = fs::File::create(&new_tar_path)
+            .map_err(|e| format!("create {}: {}", new_tar_path, e))?;
+        let output = compress::Writer::new(
+            BufWriter::with_capacity(buffers.write, out_file),
+            &opts.compression,
+        )
+        .map_err(|e| format!("zstd: {}", e))?;
+        edit::stream(input, output, &net_patch, opts, &mut timeline, report)?
+            .finish()
+            .map_err(|e| format!("write {}: {}", new_tar_path, e))?;
+    } else {
+        let (input, output) = open_io(
+            Path::new(tar_path),
+            Path::new(&new_tar_path),
+            buffers,
+            opts.io_uring,
+        )?;
+        let output =
+            compress::Writer::new(output, &opts.compression).map_err(|e| format!("zstd: {}", e))?;
+        edit::stream(input, output, &net_patch, opts, &mut timeline, report)?
+            .finish()
+            .map_err(|e| format!("write {}: {}", new_tar_path, e))?;
+    }
+    if !remote_input {
+        edit::copy_owner(Path::new(tar_path), Path::new(&new_tar_path))?;
+    }
+    if opts.verify_copy {
+        let _span = trace::span("verify copy");
+        integrity::verify(Path::new(&new_tar_path), report)?;
+    }
+    report.set("timestamps", timeline.to_json());
+    if let Some(probe) = probe {
+        wait_probe(probe, report, show_timing)?;
+    }
+    if let Some(partial) = partial {
+        partial.keep();
+    }
+    let strategy = replace::replace(Path::new(&new_tar_path), Path::new(out_path))?;
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    if strategy != replace::Strategy::Rename {
+        eprintln!(
+            "{} is on another or a network filesystem; replaced by {}",
+            out_path,
+            strategy.name()
+        );
+    }
+    report.set("replace", strategy.name());
+    if opts.index {
+        let _span = trace::span("index");
+        index::write(Path::new(out_path), report)?;
+    }
+    if show_timing {
+        eprintln!(
+            "  total:        {:>6} ms (stream, no full extract/repack)",
+            t0.elapsed().as_millis()
+        );
+    }
+    report.set("duration_ms", t0.elapsed().as_millis() as u64);
 
-    // Also patch in the "createCommand" array if "--ip" is followed by an IP
-    if let Some(cmd) = data.get_mut("createCommand").and_then(|v| v.as_array_mut()) {
-        let mut i = 0;
-        while i < cmd.len() {
-            if cmd[i].as_str() == Some("--ip") && i + 1 < cmd.len() {
-                cmd[i + 1] = serde_json::json!(new_addr);
-            }
-            i += 1;
+    Ok(())
+}
+
+#[cfg(feature = "edit")]
+/// Input and output of the edit pass.
+type EditIo = (Box<dyn Read>, Box<dyn Write>);
+
+#[cfg(feature = "edit")]
+/// The archive to edit and the file to write it to, through io_uring if asked.
+fn open_io(
+    input: &Path,
+    output: &Path,
+    buffers: buffers::BufferSizes,
+    io_uring: bool,
+) -> Result<EditIo, String> {
+    let create =
+        || fs::File::create(output).map_err(|e| format!("create {}: {}", output.display(), e));
+    if io_uring {
+        #[cfg(feature = "io_uring")]
+        {
+            let in_file =
+                fs::File::open(input).map_err(|e| format!("open {}: {}", input.display(), e))?;
+            let uring_err = |e: std::io::Error| format!("io_uring: {}", e);
+            let reader = uring::UringReader::new(in_file, buffers.read).map_err(uring_err)?;
+            let reader = compress::reader(reader).map_err(uring_err)?;
+            let writer = uring::UringWriter::new(create()?, buffers.write).map_err(uring_err)?;
+            return Ok((reader, Box::new(writer)));
         }
+        #[cfg(not(feature = "io_uring"))]
+        return Err("--io-uring: built without the io_uring feature".to_string());
     }
+    let reader = compress::open_buffered(input, buffers.read)?;
+    Ok((
+        reader,
+        Box::new(BufWriter::with_capacity(buffers.write, create()?)),
+    ))
+}
 
-    serde_json::to_vec(&data).map_err(|e| format!("serialize config.dump: {}", e))
+#[cfg(feature = "edit")]
+fn wait_probe(
+    probe: conflict::ConflictProbe,
+    report: &mut Report,
+    show_timing: bool,
+) -> Result<(), String> {
+    let _span = trace::span("conflict wait");
+    let t = Instant::now();
+    if let Err(e) = probe.finish() {
+        report.set("conflict_check", "conflict");
+        return Err(e);
+    }
+    report.set("conflict_check", "clear");
+    if show_timing {
+        eprintln!("  conflict wait: {:>6} ms", t.elapsed().as_millis());
+    }
+    Ok(())
 }
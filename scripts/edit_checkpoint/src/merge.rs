@@ -0,0 +1,256 @@
+//! `merge`: combine two checkpoints of the same container into one, taking
+//! each group of entries whole from one of them: the process images
+//! (checkpoint/), the filesystem changes (rootfs-diff.tar, deleted.files) and
+//! the metadata (config.dump, network.status and the other top-level
+//! entries). This serves workflows capturing the filesystem and the memory
+//! at different times, e.g. a pre-copied rootfs diff and a final dump.
+//!
+//! Checkpoints of different containers are refused, as are process images
+//! and metadata from checkpoints that were edited differently (the addresses
+//! in files.img would not match network.status). The idempotency marker is
+//! kept when the images and the metadata record the same edit; the restore
+//! index and the signature describe one of the inputs and are left out.
+
+use std::path::{Path, PathBuf};
+
+use clap::{Args, ValueEnum};
+
+use crate::applied::APPLIED_PATH;
+use crate::compress;
+use crate::edit::{self, RESTORE_INDEX_PATH};
+use crate::inject::entry_name;
+use crate::lock;
+use crate::metadata::MetadataFiles;
+use crate::rewrite::Rewrite;
+use crate::sign::{MANIFEST_PATH, SIGNATURE_PATH};
+use crate::userns::ROOTFS_DIFF_PATH;
+
+/// podman's list of the files deleted from the container's filesystem.
+const DELETED_FILES_PATH: &str = "deleted.files";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum Side {
+    First,
+    Second,
+}
+
+#[derive(Args)]
+pub struct MergeArgs {
+    /// First checkpoint archive
+    first: PathBuf,
+    /// Second checkpoint archive
+    second: PathBuf,
+    /// Merged archive to write (compressed like FIRST)
+    output: PathBuf,
+    /// Take the process images (checkpoint/) from this checkpoint
+    #[arg(long, value_enum, default_value = "first")]
+    images: Side,
+    /// Take the filesystem changes (rootfs-diff.tar, deleted.files) from
+    /// this checkpoint
+    #[arg(long, value_enum, default_value = "first")]
+    rootfs: Side,
+    /// Take the metadata (config.dump, network.status, spec.dump, ...) from
+    /// this checkpoint
+    #[arg(long, value_enum, default_value = "first")]
+    metadata: Side,
+    /// Merge process images and metadata from checkpoints edited differently
+    #[arg(long)]
+    allow_mixed_edits: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Group {
+    Images,
+    Rootfs,
+    Metadata,
+}
+
+impl Group {
+    fn of(name: &str) -> Group {
+        if name == "checkpoint" || name.starts_with("checkpoint/") {
+            Group::Images
+        } else if name == ROOTFS_DIFF_PATH || name == DELETED_FILES_PATH {
+            Group::Rootfs
+        } else {
+            Group::Metadata
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Group::Images => "process images",
+            Group::Rootfs => "filesystem changes",
+            Group::Metadata => "metadata",
+        }
+    }
+}
+
+/// Entries describing one of the inputs as a whole.
+const LEFT_OUT: [&str; 3] = [RESTORE_INDEX_PATH, MANIFEST_PATH, SIGNATURE_PATH];
+
+impl MergeArgs {
+    fn side(&self, group: Group) -> Side {
+        match group {
+            Group::Images => self.images,
+            Group::Rootfs => self.rootfs,
+            Group::Metadata => self.metadata,
+        }
+    }
+
+    fn path(&self, side: Side) -> &Path {
+        match side {
+            Side::First => &self.first,
+            Side::Second => &self.second,
+        }
+    }
+}
+
+/// Refuse checkpoints that are not of the same container.
+fn check_same_container(
+    args: &MergeArgs,
+    first: &MetadataFiles,
+    second: &MetadataFiles,
+) -> Result<(), String> {
+    let (what, a, b) = match (first.id(), second.id()) {
+        (Some(a), Some(b)) => ("ID", a, b),
+        _ => match (first.name(), second.name()) {
+            (Some(a), Some(b)) => ("name", a, b),
+            _ => {
+                return Err(format!(
+                    "cannot tell whether {} and {} are of the same container: no config.dump ID or name",
+                    args.first.display(),
+                    args.second.display()
+                ))
+            }
+        },
+    };
+    if a != b {
+        return Err(format!(
+            "{} and {} are checkpoints of different containers ({} {} vs {})",
+            args.first.display(),
+            args.second.display(),
+            what,
+            a,
+            b
+        ));
+    }
+    Ok(())
+}
+
+/// Write the entries of the groups taken from `side`, counting them by group.
+fn copy_side(
+    args: &MergeArgs,
+    side: Side,
+    rewrite: &mut Rewrite,
+    counts: &mut [u64; 3],
+) -> Result<(), String> {
+    let path = args.path(side);
+    let mut archive = tar::Archive::new(compress::open(path)?);
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let name = entry_name(&entry.path().map_err(|e| e.to_string())?.to_string_lossy());
+        // The marker is written first, if at all
+        if name.is_empty() || name == APPLIED_PATH || LEFT_OUT.contains(&name.as_str()) {
+            continue;
+        }
+        let group = Group::of(&name);
+        if args.side(group) != side {
+            continue;
+        }
+        counts[group as usize] += 1;
+        let header = entry.header().clone();
+        rewrite
+            .builder()
+            .append(&header, &mut entry)
+            .map_err(|e| format!("write {} from {}: {}", name, path.display(), e))?;
+    }
+    Ok(())
+}
+
+pub fn run(args: &MergeArgs) -> Result<(), String> {
+    if args.images == args.rootfs && args.rootfs == args.metadata {
+        return Err(format!(
+            "everything would come from {}: take a group from the other with --images, --rootfs or --metadata",
+            args.path(args.images).display()
+        ));
+    }
+    let _first_lock = lock::input(&args.first)?;
+    let _second_lock = lock::input(&args.second)?;
+    let first = MetadataFiles::read(&args.first)?;
+    let second = MetadataFiles::read(&args.second)?;
+    check_same_container(args, &first, &second)?;
+
+    let applied = |side| match side {
+        Side::First => first.applied(),
+        Side::Second => second.applied(),
+    };
+    if args.images != args.metadata
+        && applied(args.images) != applied(args.metadata)
+        && !args.allow_mixed_edits
+    {
+        let describe = |side| applied(side).unwrap_or_else(|| "no edit".to_string());
+        return Err(format!(
+            "the process images ({}) and the metadata ({}) were edited differently, so files.img \
+             would not match network.status; edit both the same way first, or pass --allow-mixed-edits",
+            describe(args.images),
+            describe(args.metadata)
+        ));
+    }
+    // Edits change the images and the metadata, not the filesystem
+    let keep_marker = applied(args.images) == applied(args.metadata);
+    let marker = match args.metadata {
+        Side::First => &first.applied,
+        Side::Second => &second.applied,
+    };
+
+    let mut rewrite = Rewrite::start(&args.first, &args.output)?;
+    if let (true, Some(marker)) = (keep_marker, marker) {
+        edit::append_new(rewrite.builder(), APPLIED_PATH, marker)?;
+    }
+    let mut counts = [0u64; 3];
+    for side in [Side::First, Side::Second] {
+        copy_side(args, side, &mut rewrite, &mut counts)?;
+    }
+    if counts[Group::Images as usize] == 0 {
+        return Err(format!(
+            "{} has no process images (checkpoint/)",
+            args.path(args.images).display()
+        ));
+    }
+    if counts[Group::Metadata as usize] == 0 {
+        return Err(format!(
+            "{} has no metadata entries",
+            args.path(args.metadata).display()
+        ));
+    }
+    rewrite.finish(None)?;
+
+    for group in [Group::Images, Group::Rootfs, Group::Metadata] {
+        eprintln!(
+            "{}: {} entries from {}",
+            group.label(),
+            counts[group as usize],
+            args.path(args.side(group)).display()
+        );
+    }
+    if counts[Group::Rootfs as usize] == 0 {
+        eprintln!(
+            "warning: {} has no filesystem changes; the container restores on its image's rootfs",
+            args.path(args.rootfs).display()
+        );
+    }
+    if args.rootfs != args.images {
+        eprintln!(
+            "warning: the filesystem changes and the process images come from different \
+             checkpoints; CRIU refuses to restore if a file open in the process changed size in between"
+        );
+    }
+    if !keep_marker {
+        eprintln!(
+            "Left out {}: the process images and the metadata record different edits",
+            APPLIED_PATH
+        );
+    }
+    eprintln!("Wrote {}", args.output.display());
+    Ok(())
+}
@@ -0,0 +1,649 @@
+//! Patchers for the podman metadata entries of a checkpoint archive:
+//! network.status (CNI result list or netavark status map) and config.dump,
+//! plus the pod address annotations in spec.dump of CRI-O checkpoints.
+
+use std::fs;
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use crate::applied::{self, APPLIED_PATH};
+use crate::compress;
+
+pub const NETWORK_STATUS_PATH: &str = "network.status";
+pub const CONFIG_DUMP_PATH: &str = "config.dump";
+pub const SPEC_DUMP_PATH: &str = "spec.dump";
+
+/// CRI-O records the pod's addresses in the OCI spec annotations as
+/// io.kubernetes.cri-o.IP.0, .1, ...; there is no network.status.
+const CRIO_IP_ANNOTATION: &str = "io.kubernetes.cri-o.IP.";
+
+/// How the container's static address is changed in network.status/config.dump.
+#[derive(Clone, Copy)]
+pub enum AddrPatch<'a> {
+    /// Replace old_addr with new_addr.
+    Replace(&'a str),
+    /// Keep old_addr and add new_addr as a secondary address.
+    Add(&'a str),
+    /// Drop the static address.
+    Clear,
+}
+
+/// Everything the metadata patchers change about the container's network.
+pub struct NetworkPatch<'a> {
+    pub old_addr: Ipv4Addr,
+    /// Prefix length of old_addr's network (see MetadataFiles::old_prefix):
+    /// config.dump has none of its own to prune DNS servers by.
+    pub old_prefix: u8,
+    pub addr: AddrPatch<'a>,
+    /// Replacement DNS servers; None keeps them, minus servers on the source
+    /// subnet when new_addr moves to a different subnet.
+    pub dns_servers: Option<&'a [String]>,
+    /// Replacement DNS search domains; None keeps them.
+    pub dns_search: Option<&'a [String]>,
+    /// Replacement network aliases; None keeps them.
+    pub aliases: Option<&'a [String]>,
+}
+
+impl NetworkPatch<'_> {
+    /// Whether a captured DNS server has to go: it sits on old_addr's subnet
+    /// (e.g. the source network's aardvark-dns on the gateway) and new_addr is
+    /// on a different one, so it is unreachable from the target.
+    fn is_source_dns(&self, server: &str, prefix: u8) -> bool {
+        let AddrPatch::Replace(new_addr) = self.addr else {
+            return false;
+        };
+        let (Ok(server), Ok(new_addr)) = (server.parse::<Ipv4Addr>(), new_addr.parse()) else {
+            return false;
+        };
+        same_subnet(server, self.old_addr, prefix) && !same_subnet(new_addr, self.old_addr, prefix)
+    }
+
+    /// Apply the DNS server/search and alias settings to one set of lists,
+    /// pruning source-subnet servers when no replacement was given.
+    fn apply_dns(
+        &self,
+        servers: Option<&mut serde_json::Value>,
+        search: Option<&mut serde_json::Value>,
+        aliases: Option<&mut serde_json::Value>,
+        prefix: u8,
+        entry: &str,
+    ) {
+        if let Some(servers) = servers {
+            match self.dns_servers {
+                Some(new) => *servers = serde_json::json!(new),
+                None => {
+                    if let Some(list) = servers.as_array_mut() {
+                        list.retain(|s| {
+                            let s = s.as_str().unwrap_or("");
+                            let prune = self.is_source_dns(s, prefix);
+                            if prune {
+                                eprintln!("Pruned source-network DNS server {} from {}", s, entry);
+                            }
+                            !prune
+                        });
+                    }
+                }
+            }
+        }
+        if let (Some(search), Some(new)) = (search, self.dns_search) {
+            *search = serde_json::json!(new);
+        }
+        if let (Some(aliases), Some(new)) = (aliases, self.aliases) {
+            *aliases = serde_json::json!(new);
+        }
+    }
+}
+
+fn same_subnet(a: Ipv4Addr, b: Ipv4Addr, prefix: u8) -> bool {
+    let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+    u32::from(a) & mask == u32::from(b) & mask
+}
+
+/// Prefix length of old_addr's entry in a list of "IP/prefix" strings.
+fn old_prefix<'a>(cidrs: impl IntoIterator<Item = &'a serde_json::Value>, old: Ipv4Addr) -> u8 {
+    cidrs
+        .into_iter()
+        .filter_map(|c| c.as_str()?.split_once('/'))
+        .find(|(addr, _)| addr.parse() == Ok(old))
+        .and_then(|(_, prefix)| prefix.parse().ok())
+        .filter(|p| *p <= 32)
+        .unwrap_or(24)
+}
+
+/// The raw network.status, config.dump and spec.dump of a checkpoint, and
+/// its idempotency marker.
+#[derive(Default)]
+pub struct MetadataFiles {
+    pub status: Option<Vec<u8>>,
+    pub config: Option<Vec<u8>>,
+    pub spec: Option<Vec<u8>>,
+    pub applied: Option<Vec<u8>>,
+}
+
+impl MetadataFiles {
+    /// Read them from an archive, stopping once all three have been seen
+    /// (the marker, if any, is the first entry).
+    pub fn from_tar(tar_path: &str) -> Result<MetadataFiles, String> {
+        MetadataFiles::from_reader(compress::open(Path::new(tar_path))?)
+    }
+
+    /// Read them from an (uncompressed) archive stream.
+    pub fn from_reader(input: impl Read) -> Result<MetadataFiles, String> {
+        let mut archive = tar::Archive::new(input);
+        let mut files = MetadataFiles::default();
+        for entry in archive.entries().map_err(|e| e.to_string())? {
+            let mut entry = entry.map_err(|e| e.to_string())?;
+            let path = entry
+                .path()
+                .map_err(|e| e.to_string())?
+                .display()
+                .to_string();
+            let slot = match path.as_str() {
+                APPLIED_PATH => &mut files.applied,
+                NETWORK_STATUS_PATH => &mut files.status,
+                CONFIG_DUMP_PATH => &mut files.config,
+                SPEC_DUMP_PATH => &mut files.spec,
+                _ => continue,
+            };
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).map_err(|e| e.to_string())?;
+            *slot = Some(content);
+            if files.status.is_some() && files.config.is_some() && files.spec.is_some() {
+                break;
+            }
+        }
+        Ok(files)
+    }
+
+    /// Read them from a checkpoint archive or unpacked checkpoint directory.
+    pub fn read(checkpoint: &Path) -> Result<MetadataFiles, String> {
+        if !checkpoint.is_dir() {
+            return MetadataFiles::from_tar(&checkpoint.to_string_lossy());
+        }
+        let (_, root) = crate::images::dir_layout(checkpoint)?;
+        Ok(root
+            .map(|root| MetadataFiles::from_dir(&root))
+            .unwrap_or_default())
+    }
+
+    /// Read them from an unpacked checkpoint's metadata directory.
+    pub fn from_dir(dir: &Path) -> MetadataFiles {
+        MetadataFiles {
+            status: fs::read(dir.join(NETWORK_STATUS_PATH)).ok(),
+            config: fs::read(dir.join(CONFIG_DUMP_PATH)).ok(),
+            spec: fs::read(dir.join(SPEC_DUMP_PATH)).ok(),
+            applied: fs::read(dir.join(APPLIED_PATH)).ok(),
+        }
+    }
+
+    /// Prefix length of old_addr's network in network.status, /24 when it
+    /// has none (no network of podman's own).
+    pub fn old_prefix(&self, old: Ipv4Addr) -> u8 {
+        let status = self.status.as_deref();
+        let data = status.and_then(|c| serde_json::from_slice(c).ok());
+        old_prefix(
+            status_cidrs(data.as_ref().unwrap_or(&serde_json::Value::Null)),
+            old,
+        )
+    }
+
+    /// The fingerprint of the edit last applied to the checkpoint.
+    pub fn applied(&self) -> Option<String> {
+        self.applied.as_deref().and_then(applied::recorded)
+    }
+
+    /// Parsed JSON of each file that is present; unparsable files are
+    /// reported and treated as null. Rootless containers without a network
+    /// (slirp4netns, pasta) leave network.status empty.
+    pub fn parsed(&self) -> [Option<serde_json::Value>; 3] {
+        let parse = |path: &str, content: &Option<Vec<u8>>| {
+            let content = content
+                .as_ref()
+                .filter(|c| !c.iter().all(u8::is_ascii_whitespace));
+            content.map(|c| {
+                serde_json::from_slice(c).unwrap_or_else(|e| {
+                    eprintln!("Warning: cannot parse {}: {}", path, e);
+                    serde_json::Value::Null
+                })
+            })
+        };
+        [
+            parse(NETWORK_STATUS_PATH, &self.status),
+            parse(CONFIG_DUMP_PATH, &self.config),
+            parse(SPEC_DUMP_PATH, &self.spec),
+        ]
+    }
+
+    /// podman's network mode (config.dump networkMode): "bridge", or for
+    /// rootless containers often "slirp4netns" or "pasta".
+    pub fn network_mode(&self) -> Option<String> {
+        let config: serde_json::Value = serde_json::from_slice(self.config.as_ref()?).ok()?;
+        let mode = config.get("networkMode")?.as_str()?;
+        // Options follow a colon, e.g. slirp4netns:port_handler=slirp4netns
+        Some(mode.split(':').next().unwrap_or(mode).to_string())
+    }
+
+    /// The container's name (config.dump name).
+    pub fn name(&self) -> Option<String> {
+        let config: serde_json::Value = serde_json::from_slice(self.config.as_ref()?).ok()?;
+        config.get("name")?.as_str().map(str::to_string)
+    }
+
+    /// The container's ID (config.dump id).
+    pub fn id(&self) -> Option<String> {
+        let config: serde_json::Value = serde_json::from_slice(self.config.as_ref()?).ok()?;
+        config.get("id")?.as_str().map(str::to_string)
+    }
+
+    /// Whether the container ran rootless: its root user is mapped to an
+    /// unprivileged host user.
+    pub fn rootless(&self) -> bool {
+        let Some(spec) = self
+            .spec
+            .as_ref()
+            .and_then(|s| serde_json::from_slice::<serde_json::Value>(s).ok())
+        else {
+            return false;
+        };
+        let maps = spec
+            .pointer("/linux/uidMappings")
+            .and_then(|m| m.as_array());
+        maps.into_iter().flatten().any(|m| {
+            m.get("containerID").and_then(|c| c.as_u64()) == Some(0)
+                && m.get("hostID")
+                    .and_then(|h| h.as_u64())
+                    .is_some_and(|h| h != 0)
+        })
+    }
+
+    /// The container's current IPv4 address together with the file it was
+    /// read from. network.status (the address actually assigned) wins over
+    /// config.dump; CRI-O archives only have it in spec.dump.
+    pub fn addr(&self) -> Option<(String, &'static str)> {
+        let [status, config, spec] = self.parsed();
+        let from_status = status.and_then(|data| network_status_addr(&data));
+        let from_config = config.and_then(|data| config_dump_addr(&data));
+        if let (Some(s), Some(c)) = (&from_status, &from_config) {
+            if s != c {
+                eprintln!(
+                    "Warning: {} has {} but {} has {}; using {}",
+                    NETWORK_STATUS_PATH, s, CONFIG_DUMP_PATH, c, s
+                );
+            }
+        }
+        from_status
+            .map(|a| (a, NETWORK_STATUS_PATH))
+            .or(from_config.map(|a| (a, CONFIG_DUMP_PATH)))
+            .or_else(|| {
+                spec.and_then(|data| spec_dump_addr(&data))
+                    .map(|a| (a, SPEC_DUMP_PATH))
+            })
+    }
+}
+
+/// Prefix length of old_addr's entry in a list of "IP/prefix" strings, as
+/// podman inspect prints them.
+pub fn cidrs_prefix(cidrs: &[String], old: Ipv4Addr) -> u8 {
+    let cidrs: Vec<serde_json::Value> = cidrs.iter().map(|c| c.as_str().into()).collect();
+    old_prefix(&cidrs, old)
+}
+
+/// The container's current IPv4 address in a checkpoint unpacked into `dir`,
+/// together with the file it was read from.
+pub fn detect_old_addr_in_dir(dir: &Path) -> Option<(String, &'static str)> {
+    MetadataFiles::from_dir(dir).addr()
+}
+
+/// First IPv4 address in network.status. Handles both the CNI result list
+/// (`[{"ips": [{"address": "IP/prefix"}]}]`) and the netavark status map
+/// (`{"net": {"interfaces": {"eth0": {"subnets": [{"ipnet": "IP/prefix"}]}}}}`).
+fn network_status_addr(data: &serde_json::Value) -> Option<String> {
+    status_cidrs(data)
+        .into_iter()
+        .filter_map(|c| c.as_str())
+        .map(|c| c.split('/').next().unwrap_or(c))
+        .find(|a| a.parse::<Ipv4Addr>().is_ok())
+        .map(str::to_string)
+}
+
+/// The "IP/prefix" strings of all interfaces in network.status.
+fn status_cidrs(data: &serde_json::Value) -> Vec<&serde_json::Value> {
+    let mut cidrs = Vec::new();
+    if let Some(arr) = data.as_array() {
+        for entry in arr {
+            for ip in entry
+                .get("ips")
+                .and_then(|v| v.as_array())
+                .into_iter()
+                .flatten()
+            {
+                cidrs.extend(ip.get("address"));
+            }
+        }
+    } else if let Some(map) = data.as_object() {
+        for status in map.values() {
+            let ifaces = status.get("interfaces").and_then(|v| v.as_object());
+            for iface in ifaces.into_iter().flat_map(|m| m.values()) {
+                for subnet in iface
+                    .get("subnets")
+                    .and_then(|v| v.as_array())
+                    .into_iter()
+                    .flatten()
+                {
+                    cidrs.extend(subnet.get("ipnet"));
+                }
+            }
+        }
+    }
+    cidrs
+}
+
+/// IPv4 address recorded in config.dump: staticIP, then `--ip` in createCommand.
+fn config_dump_addr(data: &serde_json::Value) -> Option<String> {
+    if let Some(ip) = data.get("staticIP").and_then(|v| v.as_str()) {
+        if ip.parse::<Ipv4Addr>().is_ok() {
+            return Some(ip.to_string());
+        }
+    }
+    let cmd = data.get("createCommand").and_then(|v| v.as_array())?;
+    cmd.windows(2)
+        .find(|w| w[0].as_str() == Some("--ip"))
+        .and_then(|w| w[1].as_str())
+        .filter(|ip| ip.parse::<Ipv4Addr>().is_ok())
+        .map(str::to_string)
+}
+
+/// CRI-O pod address annotations in spec.dump as (index, address), in index order.
+fn crio_ips(data: &serde_json::Value) -> Vec<(u32, String)> {
+    let mut ips: Vec<(u32, String)> = data
+        .get("annotations")
+        .and_then(|a| a.as_object())
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| {
+            let index = key.strip_prefix(CRIO_IP_ANNOTATION)?.parse().ok()?;
+            Some((index, value.as_str()?.to_string()))
+        })
+        .collect();
+    ips.sort();
+    ips
+}
+
+fn spec_dump_addr(data: &serde_json::Value) -> Option<String> {
+    crio_ips(data)
+        .into_iter()
+        .map(|(_, ip)| ip)
+        .find(|ip| ip.parse::<Ipv4Addr>().is_ok())
+}
+
+/// Whether spec.dump is from a CRI-O checkpoint (has pod address annotations).
+pub fn is_crio_spec(content: &[u8]) -> bool {
+    serde_json::from_slice(content).is_ok_and(|data: serde_json::Value| !crio_ips(&data).is_empty())
+}
+
+/// Patch the CRI-O pod address annotations in spec.dump the same way as
+/// network.status. Only called for CRI-O checkpoints (see is_crio_spec).
+pub fn patch_spec_dump(content: &[u8], net: &NetworkPatch) -> Result<Vec<u8>, String> {
+    let mut data: serde_json::Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse spec.dump: {}", e))?;
+    let ips = crio_ips(&data);
+    let Some(annotations) = data.get_mut("annotations").and_then(|a| a.as_object_mut()) else {
+        return Ok(content.to_vec());
+    };
+    let is_v4 = |ip: &str| ip.parse::<Ipv4Addr>().is_ok();
+    match net.addr {
+        AddrPatch::Replace(new_addr) => {
+            for (index, ip) in &ips {
+                if is_v4(ip) {
+                    annotations.insert(
+                        format!("{}{}", CRIO_IP_ANNOTATION, index),
+                        serde_json::json!(new_addr),
+                    );
+                }
+            }
+        }
+        AddrPatch::Add(new_addr) => {
+            let next = ips.last().map_or(0, |(index, _)| index + 1);
+            annotations.insert(
+                format!("{}{}", CRIO_IP_ANNOTATION, next),
+                serde_json::json!(new_addr),
+            );
+        }
+        AddrPatch::Clear => {
+            // Renumber the remaining (IPv6) addresses from 0
+            for (index, _) in &ips {
+                annotations.remove(&format!("{}{}", CRIO_IP_ANNOTATION, index));
+            }
+            for (index, (_, ip)) in ips.iter().filter(|(_, ip)| !is_v4(ip)).enumerate() {
+                annotations.insert(
+                    format!("{}{}", CRIO_IP_ANNOTATION, index),
+                    serde_json::json!(ip),
+                );
+            }
+        }
+    }
+    serde_json::to_vec(&data).map_err(|e| format!("serialize spec.dump: {}", e))
+}
+
+/// Patch network.status JSON: replace the container's address with new_addr,
+/// add new_addr next to it, or remove it entirely. Handles the CNI result list
+/// ("ips" array, "dns" object) and the netavark status map ("subnets" per
+/// interface, dns_server_ips/dns_search_domains/aliases per network).
+pub fn patch_network_status(content: &[u8], net: &NetworkPatch) -> Result<Vec<u8>, String> {
+    let patch = net.addr;
+    if content.iter().all(u8::is_ascii_whitespace) {
+        // No network of podman's own (rootless slirp4netns/pasta)
+        return Ok(content.to_vec());
+    }
+    let mut data: serde_json::Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse network.status: {}", e))?;
+
+    // Apply the patch to a list of address objects whose `key` is "IP/prefix",
+    // e.g. "192.168.12.2/24"
+    let apply = |list: &mut Vec<serde_json::Value>, key: &str| {
+        let with_addr = |cidr: Option<&serde_json::Value>, new_addr: &str| {
+            let old = cidr.and_then(|c| c.as_str()).unwrap_or("");
+            let prefix = old.split('/').nth(1).unwrap_or("24");
+            serde_json::json!(format!("{}/{}", new_addr, prefix))
+        };
+        // IPv6 entries (dual stack) are left as they are
+        let is_v4 = |entry: &serde_json::Value| {
+            let cidr = entry.get(key).and_then(|c| c.as_str()).unwrap_or("");
+            cidr.split('/')
+                .next()
+                .unwrap_or("")
+                .parse::<Ipv4Addr>()
+                .is_ok()
+        };
+        match patch {
+            AddrPatch::Replace(new_addr) => {
+                for cidr in list
+                    .iter_mut()
+                    .filter(|e| is_v4(e))
+                    .filter_map(|e| e.get_mut(key))
+                {
+                    *cidr = with_addr(Some(cidr), new_addr);
+                }
+            }
+            AddrPatch::Add(new_addr) => {
+                if let Some(mut extra) = list.iter().find(|e| is_v4(e)).cloned() {
+                    extra[key] = with_addr(extra.get(key), new_addr);
+                    list.push(extra);
+                }
+            }
+            AddrPatch::Clear => list.retain(|e| !is_v4(e)),
+        }
+    };
+
+    if let Some(arr) = data.as_array_mut() {
+        for entry in arr.iter_mut() {
+            let ips = entry.get("ips").and_then(|v| v.as_array());
+            let cidrs = ips.into_iter().flatten().filter_map(|ip| ip.get("address"));
+            let prefix = old_prefix(cidrs, net.old_addr);
+            if let Some(ips) = entry.get_mut("ips").and_then(|v| v.as_array_mut()) {
+                apply(ips, "address");
+            }
+            if let Some(dns) = entry.get_mut("dns").and_then(|v| v.as_object_mut()) {
+                // CNI only carries keys that are set; add them when replacing
+                if net.dns_servers.is_some() {
+                    dns.entry("nameservers").or_insert(serde_json::json!([]));
+                }
+                if net.dns_search.is_some() {
+                    dns.entry("search").or_insert(serde_json::json!([]));
+                }
+                let (mut servers, mut search) = (None, None);
+                for (key, value) in dns.iter_mut() {
+                    match key.as_str() {
+                        "nameservers" => servers = Some(value),
+                        "search" => search = Some(value),
+                        _ => {}
+                    }
+                }
+                net.apply_dns(servers, search, None, prefix, NETWORK_STATUS_PATH);
+            }
+        }
+    } else if let Some(map) = data.as_object_mut() {
+        for status in map.values_mut() {
+            let ifaces = status.get("interfaces").and_then(|v| v.as_object());
+            let subnets = ifaces
+                .into_iter()
+                .flat_map(|m| m.values())
+                .filter_map(|i| i.get("subnets")?.as_array())
+                .flatten();
+            let prefix = old_prefix(subnets.filter_map(|s| s.get("ipnet")), net.old_addr);
+            let ifaces = status.get_mut("interfaces").and_then(|v| v.as_object_mut());
+            for iface in ifaces.into_iter().flat_map(|m| m.values_mut()) {
+                if let Some(subnets) = iface.get_mut("subnets").and_then(|v| v.as_array_mut()) {
+                    apply(subnets, "ipnet");
+                }
+            }
+            if let Some(status) = status.as_object_mut() {
+                let (mut servers, mut search, mut aliases) = (None, None, None);
+                for (key, value) in status.iter_mut() {
+                    match key.as_str() {
+                        "dns_server_ips" => servers = Some(value),
+                        "dns_search_domains" => search = Some(value),
+                        "aliases" => aliases = Some(value),
+                        _ => {}
+                    }
+                }
+                net.apply_dns(servers, search, aliases, prefix, NETWORK_STATUS_PATH);
+            }
+        }
+    }
+
+    serde_json::to_vec_pretty(&data).map_err(|e| format!("serialize network.status: {}", e))
+}
+
+/// Patch config.dump JSON: replace staticIP with new_addr, or remove staticIP,
+/// `--ip` and per-network static_ips when clearing. A secondary address only
+/// goes into the per-network static_ips; staticIP keeps old_addr.
+/// DNS servers/search domains and per-network aliases follow `net` as well.
+pub fn patch_config_dump(content: &[u8], net: &NetworkPatch) -> Result<Vec<u8>, String> {
+    let patch = net.addr;
+    let mut data: serde_json::Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse config.dump: {}", e))?;
+
+    // Patch "staticIP" field
+    if data.get("staticIP").is_some() {
+        match patch {
+            AddrPatch::Replace(new_addr) => data["staticIP"] = serde_json::json!(new_addr),
+            AddrPatch::Clear => {
+                data.as_object_mut().map(|o| o.remove("staticIP"));
+            }
+            AddrPatch::Add(_) => {}
+        }
+    }
+
+    // Also patch in the "createCommand" array if "--ip" is followed by an IP
+    if let Some(cmd) = data.get_mut("createCommand").and_then(|v| v.as_array_mut()) {
+        let mut i = 0;
+        while i < cmd.len() {
+            let arg = cmd[i].as_str().unwrap_or("");
+            match patch {
+                AddrPatch::Replace(new_addr) if arg == "--ip" && i + 1 < cmd.len() => {
+                    cmd[i + 1] = serde_json::json!(new_addr);
+                }
+                AddrPatch::Replace(new_addr) if arg.starts_with("--ip=") => {
+                    cmd[i] = serde_json::json!(format!("--ip={}", new_addr));
+                }
+                AddrPatch::Clear if arg == "--ip" => {
+                    cmd.drain(i..(i + 2).min(cmd.len()));
+                    continue;
+                }
+                AddrPatch::Clear if arg.starts_with("--ip=") => {
+                    cmd.remove(i);
+                    continue;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    if let Some(config) = data.as_object_mut() {
+        if net.dns_servers.is_some() {
+            config.entry("dnsServer").or_insert(serde_json::json!([]));
+        }
+        if net.dns_search.is_some() {
+            config.entry("dnsSearch").or_insert(serde_json::json!([]));
+        }
+        let (mut servers, mut search) = (None, None);
+        for (key, value) in config.iter_mut() {
+            match key.as_str() {
+                "dnsServer" => servers = Some(value),
+                "dnsSearch" => search = Some(value),
+                _ => {}
+            }
+        }
+        net.apply_dns(servers, search, None, net.old_prefix, CONFIG_DUMP_PATH);
+    }
+
+    // Podman 4+ keeps per-network static addresses and aliases in newNetworks
+    if let Some(nets) = data.get_mut("newNetworks").and_then(|v| v.as_object_mut()) {
+        for opts in nets.values_mut().filter_map(|n| n.as_object_mut()) {
+            if let (Some(aliases), Some(new)) = (opts.get_mut("aliases"), net.aliases) {
+                *aliases = serde_json::json!(new);
+            }
+            let Some(ips) = opts.get_mut("static_ips").and_then(|v| v.as_array_mut()) else {
+                continue;
+            };
+            match patch {
+                AddrPatch::Replace(new_addr) => {
+                    for ip in ips.iter_mut() {
+                        if ip.as_str().is_some_and(|a| a.parse::<Ipv4Addr>().is_ok()) {
+                            *ip = serde_json::json!(new_addr);
+                        }
+                    }
+                }
+                AddrPatch::Add(new_addr) => ips.push(serde_json::json!(new_addr)),
+                AddrPatch::Clear => {
+                    opts.remove("static_ips");
+                }
+            }
+        }
+    }
+
+    serde_json::to_vec(&data).map_err(|e| format!("serialize config.dump: {}", e))
+}
+
+/// MAC of the container's interface: netavark's `mac_address` per interface,
+/// or the CNI result's interface that lives in the container's sandbox.
+pub fn network_status_mac(data: &serde_json::Value) -> Option<String> {
+    let mac = if let Some(results) = data.as_array() {
+        results
+            .iter()
+            .filter_map(|r| r.get("interfaces").and_then(|i| i.as_array()))
+            .flatten()
+            .find(|i| i.get("sandbox").is_some_and(|s| s.as_str() != Some("")))
+            .and_then(|i| i.get("mac"))
+    } else {
+        data.as_object()?
+            .values()
+            .filter_map(|n| n.get("interfaces").and_then(|i| i.as_object()))
+            .flat_map(|i| i.values())
+            .find_map(|i| i.get("mac_address"))
+    };
+    mac.and_then(|m| m.as_str()).map(str::to_lowercase)
+}
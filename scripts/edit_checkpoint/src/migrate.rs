@@ -0,0 +1,616 @@
+//! `migrate`: checkpoint a container on the source node, stream the archive
+//! through the edit pass straight to the target node, and restore it there.
+//!
+//! Replaces the checkpoint/transfer/edit/restore steps of experiments/cr_hw.sh.
+//! The archive never touches the local disk: `cat` on the source feeds the
+//! edit, whose output is piped into `cat > PATH` on the target.
+//!
+//! If anything fails after the checkpoint, the migration is rolled back: the
+//! half-restored container on the target is removed and the container is
+//! restored on the source from the checkpoint kept there (--keep).
+//!
+//! With --controller the switchover is two-phase: prepare restores the
+//! container on the target and pauses it; commit then updates the Tofino
+//! tables through the controller and unpauses the container concurrently.
+//! If either half of the commit fails, both are undone.
+//!
+//! With --pre-copy N the container keeps running through N pre-checkpoint
+//! rounds (criu pre-dump), each edited on its way to the target as it is
+//! taken, so the target always holds an edited pre-checkpoint ready to
+//! restore on; the final checkpoint is then taken --with-previous and holds
+//! only the pages dirtied since the last round, which is all the downtime
+//! window has to move.
+//!
+//! Phase timestamps (checkpoint_start, edit_start/end, restore_start/end,
+//! commit_start/end and, with --probe-port, first_packet) go into the
+//! report's "timestamps"; those known at edit time also go into the archive.
+
+use std::io::{BufReader, BufWriter};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Stdio};
+use std::time::{Duration, Instant};
+
+use clap::Args;
+
+use crate::controller::Controller;
+use crate::edit;
+use crate::metadata::{self, AddrPatch};
+use crate::remote;
+use crate::report::Report;
+use crate::timeline::Timeline;
+use crate::trace;
+use crate::PatchArgs;
+
+#[derive(Args)]
+pub struct MigrateArgs {
+    /// Source node running the container
+    #[arg(long, value_name = "USER@HOST")]
+    from: String,
+    /// Target node to restore on
+    #[arg(long, value_name = "USER@HOST")]
+    to: String,
+    /// Container name on the source
+    #[arg(long, value_name = "NAME")]
+    pub container: String,
+    /// Address of the container on the target
+    #[arg(long, value_name = "ADDR")]
+    new_addr: String,
+    /// Address of the container on the source (default: from podman inspect)
+    #[arg(long, value_name = "ADDR")]
+    old_addr: Option<String>,
+    /// Directory holding the checkpoint archive on both nodes
+    #[arg(long, value_name = "DIR", default_value = "/tmp/checkpoints")]
+    checkpoint_dir: String,
+    /// Checkpoint and restore established TCP connections
+    #[arg(long)]
+    tcp_established: bool,
+    /// Extra argument for `podman container restore` on the target (repeatable)
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    restore_arg: Vec<String>,
+    /// Run podman and file access on both nodes through `sudo -n`
+    #[arg(long)]
+    sudo: bool,
+    /// p4containerflow controller URL (e.g. http://tofino:5000); enables the
+    /// two-phase switchover with a /migrateNode table update at commit
+    #[arg(long, value_name = "URL")]
+    controller: Option<String>,
+    /// After the restore, poll new_addr:PORT with TCP connects and record the
+    /// first answer as the first_packet timestamp
+    #[arg(long, value_name = "PORT")]
+    probe_port: Option<u16>,
+    /// Leave the container stopped on the source if the migration fails
+    #[arg(long)]
+    no_rollback: bool,
+    /// Pre-checkpoint rounds to run and ship before the final checkpoint,
+    /// while the container keeps running
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pre_copy: u32,
+    /// Wait this long between pre-copy rounds
+    #[arg(long, value_name = "MS", default_value_t = 0, requires = "pre_copy")]
+    pre_copy_interval: u64,
+    #[command(flatten)]
+    patch: PatchArgs,
+    /// Write a JSON report of the migration to FILE
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+}
+
+impl MigrateArgs {
+    /// argv for a command on a node, with the sudo prefix if requested.
+    fn argv(&self, args: &[&str]) -> Vec<String> {
+        let mut argv = Vec::new();
+        if self.sudo {
+            argv.extend(["sudo".to_string(), "-n".to_string()]);
+        }
+        argv.extend(args.iter().map(|a| a.to_string()));
+        argv
+    }
+
+    fn podman_argv(&self, args: &[&str]) -> Vec<String> {
+        let podman = remote::argv("EDIT_CHECKPOINT_PODMAN", "podman", args);
+        let podman: Vec<&str> = podman.iter().map(String::as_str).collect();
+        self.argv(&podman)
+    }
+
+    fn archive_path(&self) -> String {
+        format!(
+            "{}/{}.tar",
+            self.checkpoint_dir.trim_end_matches('/'),
+            self.container
+        )
+    }
+
+    /// The pre-checkpoint archive of --pre-copy, on both nodes.
+    fn pre_archive_path(&self) -> String {
+        format!(
+            "{}/{}-pre.tar",
+            self.checkpoint_dir.trim_end_matches('/'),
+            self.container
+        )
+    }
+}
+
+pub fn run(args: &MigrateArgs, report: &mut Report) -> Result<(), String> {
+    let archive = args.archive_path();
+    report.set("container", args.container.as_str());
+    report.set("from", args.from.as_str());
+    report.set("to", args.to.as_str());
+    report.set("archive", archive.as_str());
+
+    // Also for the prefix of the source network, which --old-addr lacks
+    let cidrs = match &args.old_addr {
+        Some(_) => source_cidrs(args).unwrap_or_default(),
+        None => source_cidrs(args)?,
+    };
+    let old_addr = match &args.old_addr {
+        Some(addr) => addr.clone(),
+        None => source_addr(args, &cidrs)?,
+    };
+    let old_ip: Ipv4Addr = old_addr
+        .parse()
+        .map_err(|_| format!("old_addr {} is not an IPv4 address", old_addr))?;
+    let old_prefix = metadata::cidrs_prefix(&cidrs, old_ip);
+    if old_addr == args.new_addr {
+        return Err(format!("{} already uses {}", args.container, old_addr));
+    }
+    report.set("old_addr", old_addr.as_str());
+    report.set("new_addr", args.new_addr.as_str());
+    eprintln!(
+        "Migrating {} {} ({}) → {} ({})",
+        args.container, args.from, old_addr, args.to, args.new_addr
+    );
+
+    if args.pre_copy > 0 {
+        let _span = trace::span("pre-copy");
+        // The container is still running on the source; only the pre-dump
+        // archives on both hosts need removing
+        if let Err(e) = pre_copy(args, old_ip, old_prefix, report) {
+            let rm = args.argv(&["rm", "-f", &args.pre_archive_path()]);
+            let _ = run_on(&args.from, &rm);
+            let _ = run_on(&args.to, &rm);
+            return Err(format!("pre-copy: {}", e));
+        }
+    }
+
+    // Downtime starts when the checkpoint freezes the container
+    let mut timeline = Timeline::default();
+    timeline.mark("checkpoint_start");
+    let t0 = Instant::now();
+    let mut checkpoint = vec![
+        "container",
+        "checkpoint",
+        "--export",
+        &archive,
+        "--compress",
+        "none",
+        "--keep",
+    ];
+    if args.tcp_established {
+        checkpoint.push("--tcp-established");
+    }
+    if args.pre_copy > 0 {
+        checkpoint.push("--with-previous");
+    }
+    checkpoint.push(&args.container);
+    let span = trace::span("checkpoint");
+    run_on(&args.from, &args.podman_argv(&checkpoint))?;
+    drop(span);
+    let checkpoint_ms = t0.elapsed().as_millis() as u64;
+    eprintln!("Checkpoint:       {:>6} ms", checkpoint_ms);
+
+    let mut undo = vec![Undo::RestoreSource];
+    let result = transfer_and_restore(
+        args,
+        &archive,
+        old_ip,
+        old_prefix,
+        &mut undo,
+        &mut timeline,
+        report,
+    );
+    report.set("timestamps", timeline.to_json());
+    let phases = match result {
+        Ok(phases) => phases,
+        Err(e) => {
+            if args.no_rollback {
+                report.set("rollback", "skipped");
+            } else {
+                eprintln!("Migration failed: {}; rolling back", e);
+                let _span = trace::span("rollback");
+                match rollback(args, &archive, &undo) {
+                    Ok(()) => {
+                        eprintln!("Rolled back: {} running on {}", args.container, args.from);
+                        report.set("rollback", "ok");
+                    }
+                    Err(re) => {
+                        report.set("rollback", "failed");
+                        return Err(format!("{}; rollback failed: {}", e, re));
+                    }
+                }
+            }
+            return Err(e);
+        }
+    };
+    let downtime_ms = t0.elapsed().as_millis() as u64;
+    eprintln!("Total downtime:   {:>6} ms", downtime_ms);
+    if let Some(port) = args.probe_port {
+        let addr = SocketAddr::new(
+            args.new_addr
+                .parse()
+                .map_err(|_| format!("new_addr {} is not an IP address", args.new_addr))?,
+            port,
+        );
+        let span = trace::span("first packet");
+        let probe = first_packet(addr);
+        drop(span);
+        match probe {
+            Ok(ms) => {
+                timeline.mark("first_packet");
+                eprintln!("First packet:     {:>6} ms after restore", ms);
+            }
+            Err(e) => eprintln!("Warning: {}", e),
+        }
+        report.set("timestamps", timeline.to_json());
+    }
+
+    report.set(
+        "timings_ms",
+        serde_json::json!({
+            "checkpoint": checkpoint_ms,
+            "edit_transfer": phases.transfer_ms,
+            "restore": phases.restore_ms,
+            "commit": phases.commit_ms,
+            "downtime": downtime_ms,
+        }),
+    );
+    Ok(())
+}
+
+/// The --pre-copy rounds: pre-checkpoint the running container and stream
+/// the dump through the edit to the target, each round replacing the last.
+fn pre_copy(
+    args: &MigrateArgs,
+    old_ip: Ipv4Addr,
+    old_prefix: u8,
+    report: &mut Report,
+) -> Result<(), String> {
+    let pre_archive = args.pre_archive_path();
+    let opts = edit::EditOptions {
+        pre_dump: true,
+        // Restored from the final archive's metadata and images
+        criu_opts: None,
+        timens: Default::default(),
+        inventory: Default::default(),
+        ..args.patch.options()
+    };
+    let addr_patch = if opts.secondary {
+        AddrPatch::Add(&args.new_addr)
+    } else {
+        AddrPatch::Replace(&args.new_addr)
+    };
+    let net_patch = opts.network_patch(old_ip, old_prefix, addr_patch);
+    let mut rounds = Vec::new();
+    for round in 1..=args.pre_copy {
+        if round > 1 {
+            std::thread::sleep(Duration::from_millis(args.pre_copy_interval));
+        }
+        let t = Instant::now();
+        let checkpoint = [
+            "container",
+            "checkpoint",
+            "--pre-checkpoint",
+            "--export",
+            &pre_archive,
+            "--compress",
+            "none",
+            &args.container,
+        ];
+        run_on(&args.from, &args.podman_argv(&checkpoint))?;
+        let checkpoint_ms = t.elapsed().as_millis() as u64;
+        // The round's own report and timeline; the migration's are the final dump's
+        let t = Instant::now();
+        stream_edit(
+            args,
+            &pre_archive,
+            &net_patch,
+            &opts,
+            &mut Timeline::default(),
+            &mut Report::default(),
+        )?;
+        let transfer_ms = t.elapsed().as_millis() as u64;
+        eprintln!(
+            "Pre-copy round {}: {:>6} ms pre-checkpoint, {:>6} ms edit + transfer",
+            round, checkpoint_ms, transfer_ms
+        );
+        rounds.push(serde_json::json!({
+            "checkpoint_ms": checkpoint_ms,
+            "edit_transfer_ms": transfer_ms,
+        }));
+    }
+    report.set("pre_copy", rounds);
+    Ok(())
+}
+
+/// Durations of the phases after the checkpoint.
+struct Phases {
+    transfer_ms: u64,
+    restore_ms: u64,
+    commit_ms: Option<u64>,
+}
+
+/// Everything after the checkpoint. Pushes an undo step before each action
+/// that leaves state behind.
+fn transfer_and_restore(
+    args: &MigrateArgs,
+    archive: &str,
+    old_ip: Ipv4Addr,
+    old_prefix: u8,
+    undo: &mut Vec<Undo>,
+    timeline: &mut Timeline,
+    report: &mut Report,
+) -> Result<Phases, String> {
+    let t1 = Instant::now();
+    let opts = args.patch.options();
+    let addr_patch = if opts.secondary {
+        AddrPatch::Add(&args.new_addr)
+    } else {
+        AddrPatch::Replace(&args.new_addr)
+    };
+    let net_patch = opts.network_patch(old_ip, old_prefix, addr_patch);
+    undo.push(Undo::CleanTarget);
+    let span = trace::span("transfer");
+    stream_edit(args, archive, &net_patch, &opts, timeline, report)?;
+    drop(span);
+    let transfer_ms = t1.elapsed().as_millis() as u64;
+    eprintln!("Edit + transfer:  {:>6} ms", transfer_ms);
+
+    timeline.mark("restore_start");
+    let t2 = Instant::now();
+    let mut restore = vec!["container", "restore", "--import", archive];
+    if args.tcp_established {
+        restore.push("--tcp-established");
+    }
+    let pre_archive = args.pre_archive_path();
+    if args.pre_copy > 0 {
+        restore.extend(["--import-previous", pre_archive.as_str()]);
+    }
+    restore.extend(args.restore_arg.iter().map(String::as_str));
+    let span = trace::span("restore");
+    run_on(&args.to, &args.podman_argv(&restore))?;
+    let Some(url) = &args.controller else {
+        timeline.mark("restore_end");
+        let restore_ms = t2.elapsed().as_millis() as u64;
+        eprintln!("Restore:          {:>6} ms", restore_ms);
+        return Ok(Phases {
+            transfer_ms,
+            restore_ms,
+            commit_ms: None,
+        });
+    };
+
+    // Prepare: hold the restored container until the switch points at it
+    run_on(
+        &args.to,
+        &args.podman_argv(&["pause", args.container.as_str()]),
+    )?;
+    drop(span);
+    timeline.mark("restore_end");
+    let restore_ms = t2.elapsed().as_millis() as u64;
+    eprintln!("Restore (paused): {:>6} ms", restore_ms);
+
+    // Commit: table update and unpause together
+    timeline.mark("commit_start");
+    let span = trace::span("commit");
+    let t3 = Instant::now();
+    let controller = Controller::new(url);
+    let old_addr = old_ip.to_string();
+    let (switch, unpause) = std::thread::scope(|s| {
+        let unpause = s.spawn(|| {
+            run_on(
+                &args.to,
+                &args.podman_argv(&["unpause", args.container.as_str()]),
+            )
+        });
+        let switch = controller.migrate_node(&old_addr, &args.new_addr);
+        let unpause = unpause
+            .join()
+            .unwrap_or_else(|_| Err("unpause thread panicked".to_string()));
+        (switch, unpause)
+    });
+    drop(span);
+    if switch.is_ok() {
+        undo.push(Undo::SwitchUpdate(controller, old_addr));
+    }
+    switch.map_err(|e| format!("commit: switch update failed: {}", e))?;
+    unpause.map_err(|e| format!("commit: unpause failed: {}", e))?;
+    timeline.mark("commit_end");
+    let commit_ms = t3.elapsed().as_millis() as u64;
+    eprintln!("Commit:           {:>6} ms", commit_ms);
+    Ok(Phases {
+        transfer_ms,
+        restore_ms,
+        commit_ms: Some(commit_ms),
+    })
+}
+
+/// State left behind by a migration step, undone in reverse order on failure.
+enum Undo {
+    /// The container is checkpointed (stopped) on the source.
+    RestoreSource,
+    /// The target may hold a partial archive or a half-restored container.
+    CleanTarget,
+    /// The controller points the node entry at new_addr; holds old_addr.
+    SwitchUpdate(Controller, String),
+}
+
+/// Run the undo steps in reverse. Keeps going after a failed step so the
+/// source restore is attempted even if the target is unreachable.
+fn rollback(args: &MigrateArgs, archive: &str, undo: &[Undo]) -> Result<(), String> {
+    let mut errors = Vec::new();
+    for step in undo.iter().rev() {
+        let result = match step {
+            Undo::CleanTarget => {
+                let rm = args.podman_argv(&["rm", "-f", "--ignore", &args.container]);
+                let pre_archive = args.pre_archive_path();
+                run_on(&args.to, &rm)
+                    .and_then(|_| {
+                        run_on(&args.to, &args.argv(&["rm", "-f", archive, &pre_archive]))
+                    })
+                    .map(drop)
+            }
+            Undo::RestoreSource => restore_source(args, archive),
+            Undo::SwitchUpdate(controller, old_addr) => {
+                controller.migrate_node(&args.new_addr, old_addr)
+            }
+        };
+        if let Err(e) = result {
+            errors.push(e);
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; "))
+    }
+}
+
+/// Restore the container on the source from the checkpoint kept in place,
+/// falling back to re-importing the unedited archive still on the source.
+fn restore_source(args: &MigrateArgs, archive: &str) -> Result<(), String> {
+    let mut restore = vec!["container", "restore"];
+    if args.tcp_established {
+        restore.push("--tcp-established");
+    }
+    let mut in_place = restore.clone();
+    in_place.push(&args.container);
+    if run_on(&args.from, &args.podman_argv(&in_place)).is_ok() {
+        return Ok(());
+    }
+    eprintln!(
+        "In-place restore failed; re-importing {} on {}",
+        archive, args.from
+    );
+    run_on(
+        &args.from,
+        &args.podman_argv(&["rm", "-f", "--ignore", &args.container]),
+    )?;
+    restore.extend(["--import", archive]);
+    run_on(&args.from, &args.podman_argv(&restore)).map(drop)
+}
+
+/// Poll `addr` with TCP connects until one is answered (accepted or
+/// refused: either way the restored stack sent a packet). Gives up after 10 s.
+fn first_packet(addr: SocketAddr) -> Result<u64, String> {
+    let t = Instant::now();
+    while t.elapsed() < Duration::from_secs(10) {
+        match TcpStream::connect_timeout(&addr, Duration::from_millis(100)) {
+            Ok(_) => return Ok(t.elapsed().as_millis() as u64),
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                return Ok(t.elapsed().as_millis() as u64)
+            }
+            Err(_) => std::thread::sleep(Duration::from_millis(1)),
+        }
+    }
+    Err(format!("no answer from {} within 10 s", addr))
+}
+
+/// The container's IPv4 address among its `cidrs` on the source.
+fn source_addr(args: &MigrateArgs, cidrs: &[String]) -> Result<String, String> {
+    cidrs
+        .iter()
+        .map(|c| c.split('/').next().unwrap_or(c))
+        .find(|a| a.parse::<Ipv4Addr>().is_ok())
+        .map(str::to_string)
+        .ok_or_else(|| {
+            format!(
+                "{} has no IPv4 address on {}; pass --old-addr",
+                args.container, args.from
+            )
+        })
+}
+
+/// The container's addresses on the source, as "IP/prefix".
+fn source_cidrs(args: &MigrateArgs) -> Result<Vec<String>, String> {
+    let format = "{{range .NetworkSettings.Networks}}{{.IPAddress}}/{{.IPPrefixLen}} {{end}}";
+    let out = run_on(
+        &args.from,
+        &args.podman_argv(&["inspect", "--format", format, &args.container]),
+    )?;
+    Ok(out.split_whitespace().map(str::to_string).collect())
+}
+
+/// Pipe `cat ARCHIVE` on the source through the edit into `cat > ARCHIVE` on
+/// the target.
+fn stream_edit(
+    args: &MigrateArgs,
+    archive: &str,
+    net: &crate::metadata::NetworkPatch,
+    opts: &edit::EditOptions,
+    timeline: &mut Timeline,
+    report: &mut Report,
+) -> Result<(), String> {
+    let mut reader = spawn(
+        &args.from,
+        &args.argv(&["cat", archive]),
+        Stdio::null(),
+        Stdio::piped(),
+    )?;
+    let write_cmd = format!(
+        "mkdir -p {} && cat > {}",
+        remote::shell_quote(&args.checkpoint_dir),
+        remote::shell_quote(archive)
+    );
+    let mut writer = spawn(
+        &args.to,
+        &args.argv(&["sh", "-c", &write_cmd]),
+        Stdio::piped(),
+        Stdio::null(),
+    )?;
+
+    let input = reader.stdout.take().expect("piped stdout");
+    let output = writer.stdin.take().expect("piped stdin");
+    let result = edit::stream(
+        BufReader::with_capacity(256 * 1024, input),
+        BufWriter::with_capacity(256 * 1024, output),
+        net,
+        opts,
+        timeline,
+        report,
+    )
+    .map(drop);
+    if result.is_err() {
+        let _ = reader.kill();
+        let _ = writer.kill();
+    }
+    let read_ok = reader.wait().map(|s| s.success()).unwrap_or(false);
+    let write_ok = writer.wait().map(|s| s.success()).unwrap_or(false);
+    result?;
+    if !read_ok {
+        return Err(format!("reading {} on {} failed", archive, args.from));
+    }
+    if !write_ok {
+        return Err(format!("writing {} on {} failed", archive, args.to));
+    }
+    Ok(())
+}
+
+fn spawn(host: &str, argv: &[String], stdin: Stdio, stdout: Stdio) -> Result<Child, String> {
+    remote::command(Some(host), argv)
+        .stdin(stdin)
+        .stdout(stdout)
+        .spawn()
+        .map_err(|e| format!("run {} on {}: {}", argv.join(" "), host, e))
+}
+
+/// Run a command on `host` and return its stdout; stderr is passed through.
+fn run_on(host: &str, argv: &[String]) -> Result<String, String> {
+    let out = remote::command(Some(host), argv)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("run {} on {}: {}", argv.join(" "), host, e))?;
+    if !out.status.success() {
+        return Err(format!("{} failed on {}", argv.join(" "), host));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
@@ -0,0 +1,214 @@
+//! Translate connected IPv4 sockets to IPv6 through a NAT64 prefix
+//! (--nat64-prefix), for restoring into an IPv6-only segment behind the
+//! switch's translation stage.
+//!
+//! Each AF_INET socket with a peer outside the container becomes an AF_INET6
+//! socket to the peer's address embedded in the prefix as RFC 6052 lays it
+//! out (64:ff9b::/96 turns 192.0.2.1 into 64:ff9b::c000:201), from
+//! --nat64-src, the container's address on the target. Loopback peers and
+//! listening sockets are left alone: listeners bound to old_addr are
+//! wildcarded as usual, and serve whichever family the target reaches them
+//! over.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use serde_json::Value;
+
+use crate::{inaddr, sockets};
+
+/// Prefix lengths RFC 6052 defines an embedding for.
+const LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Nat64 {
+    pub prefix: Ipv6Addr,
+    pub len: u8,
+}
+
+/// Parse --nat64-prefix: `PREFIX/LEN`, e.g. 64:ff9b::/96.
+pub fn parse_prefix(s: &str) -> Result<Nat64, String> {
+    let (prefix, len) = s
+        .split_once('/')
+        .ok_or_else(|| format!("{} is not PREFIX/LEN", s))?;
+    let prefix: Ipv6Addr = prefix
+        .parse()
+        .map_err(|_| format!("{} is not an IPv6 prefix", prefix))?;
+    let len: u8 = len
+        .parse()
+        .ok()
+        .filter(|len| LENGTHS.contains(len))
+        .ok_or_else(|| {
+            format!(
+                "NAT64 prefix length {} is not 32, 40, 48, 56, 64 or 96",
+                len
+            )
+        })?;
+    Ok(Nat64 { prefix, len })
+}
+
+impl Nat64 {
+    /// `addr` embedded in the prefix, skipping the reserved bits 64..72.
+    pub fn embed(&self, addr: Ipv4Addr) -> Ipv6Addr {
+        let mut bytes = self.prefix.octets();
+        bytes[usize::from(self.len / 8)..].fill(0);
+        let mut at = usize::from(self.len / 8);
+        for octet in addr.octets() {
+            if at == 8 {
+                at += 1;
+            }
+            bytes[at] = octet;
+            at += 1;
+        }
+        Ipv6Addr::from(bytes)
+    }
+}
+
+/// Rewrite the connected AF_INET sockets of the decoded files.img JSON to
+/// AF_INET6 ones from `src` to their peer under `nat64`. Refuses established
+/// TCP connections without `src`: their repair needs the exact local address.
+/// Returns the number of sockets changed.
+pub fn translate(data: &mut Value, nat64: &Nat64, src: Option<Ipv6Addr>) -> Result<u32, String> {
+    let Some(entries) = data.get_mut("entries").and_then(|e| e.as_array_mut()) else {
+        return Ok(0);
+    };
+    let mut count = 0u32;
+    for entry in entries.iter_mut() {
+        if entry.get("type").and_then(|t| t.as_str()) != Some("INETSK") {
+            continue;
+        }
+        let Some(isk) = entry.get_mut("isk") else {
+            continue;
+        };
+        if !matches!(isk.get("family"), Some(f) if f == "INET" || f == "AF_INET" || f == 2) {
+            continue;
+        }
+        if matches!(isk.get("state"), Some(s) if s == "LISTEN" || s == 10) {
+            continue;
+        }
+        let Ok(peer) = sockets::addr(isk.get("dst_addr"), false).parse::<Ipv4Addr>() else {
+            continue;
+        };
+        if peer.is_unspecified() || peer.is_loopback() {
+            continue;
+        }
+        let established = matches!(isk.get("proto"), Some(p) if p == "TCP" || p == 6)
+            && matches!(isk.get("state"), Some(s) if s == "ESTABLISHED" || s == 1);
+        if established && src.is_none() {
+            return Err(format!(
+                "--nat64-prefix: the TCP connection to {}:{} needs --nat64-src, the \
+                 container's IPv6 address on the target",
+                peer, isk["dst_port"]
+            ));
+        }
+        // Keep the rendering crit used: strings or in6_addr words
+        let like = isk["dst_addr"].clone();
+        let render = |addr: Ipv6Addr| inaddr::encode(IpAddr::V6(addr), &like);
+        isk["family"] = match &isk["family"] {
+            Value::String(family) => format!("{}6", family).into(),
+            _ => 10.into(),
+        };
+        isk["dst_addr"] = render(nat64.embed(peer));
+        isk["src_addr"] = render(src.unwrap_or(Ipv6Addr::UNSPECIFIED));
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn embed(prefix: &str, addr: &str) -> String {
+        parse_prefix(prefix)
+            .unwrap()
+            .embed(addr.parse().unwrap())
+            .to_string()
+    }
+
+    #[test]
+    fn embeds_as_rfc_6052_lays_out() {
+        // The examples of RFC 6052 section 2.4, for 192.0.2.33
+        assert_eq!(embed("2001:db8::/32", "192.0.2.33"), "2001:db8:c000:221::");
+        assert_eq!(
+            embed("2001:db8:100::/40", "192.0.2.33"),
+            "2001:db8:1c0:2:21::"
+        );
+        assert_eq!(
+            embed("2001:db8:122::/48", "192.0.2.33"),
+            "2001:db8:122:c000:2:2100::"
+        );
+        assert_eq!(
+            embed("2001:db8:122:300::/56", "192.0.2.33"),
+            "2001:db8:122:3c0:0:221::"
+        );
+        assert_eq!(
+            embed("2001:db8:122:344::/64", "192.0.2.33"),
+            "2001:db8:122:344:c0:2:2100:0"
+        );
+        assert_eq!(
+            embed("2001:db8:122:344::/96", "192.0.2.33"),
+            "2001:db8:122:344::c000:221"
+        );
+        assert_eq!(embed("64:ff9b::/96", "192.0.2.1"), "64:ff9b::c000:201");
+    }
+
+    #[test]
+    fn clears_bits_past_the_prefix() {
+        // Bits 64..72 stay zero whatever the prefix had there
+        assert_eq!(
+            embed("2001:db8:ffff:ffff:ffff::/32", "192.0.2.33"),
+            "2001:db8:c000:221::"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_prefixes() {
+        assert!(parse_prefix("64:ff9b::").is_err());
+        assert!(parse_prefix("64:ff9b::/80").is_err());
+        assert!(parse_prefix("192.0.2.0/96").is_err());
+    }
+
+    #[test]
+    fn translates_connected_sockets() {
+        let nat64 = parse_prefix("64:ff9b::/96").unwrap();
+        let src: Ipv6Addr = "fd00::5".parse().unwrap();
+        let mut data = json!({"entries": [
+            {"type": "INETSK", "isk": {"family": "INET", "proto": "TCP", "state": "ESTABLISHED",
+             "src_addr": ["10.88.0.5"], "dst_addr": ["192.0.2.1"], "dst_port": 443}},
+            {"type": "INETSK", "isk": {"family": 2, "proto": 17,
+             "src_addr": [0x0500_580a], "dst_addr": [0x0102_00c0], "dst_port": 53}},
+            {"type": "INETSK", "isk": {"family": "INET", "proto": "TCP", "state": "LISTEN",
+             "src_addr": ["0.0.0.0"], "dst_addr": ["0.0.0.0"]}},
+            {"type": "INETSK", "isk": {"family": "INET", "proto": "TCP", "state": "ESTABLISHED",
+             "src_addr": ["127.0.0.1"], "dst_addr": ["127.0.0.1"]}},
+        ]});
+        assert_eq!(translate(&mut data, &nat64, Some(src)).unwrap(), 2);
+        let isks: Vec<&Value> = data["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| &e["isk"])
+            .collect();
+        assert_eq!(isks[0]["family"], "INET6");
+        assert_eq!(isks[0]["dst_addr"], json!(["64:ff9b::c000:201"]));
+        assert_eq!(isks[0]["src_addr"], json!(["fd00::5"]));
+        assert_eq!(isks[1]["family"], 10);
+        assert_eq!(
+            isks[1]["dst_addr"],
+            json!([0x9bff_6400u32, 0, 0, 0x0102_00c0])
+        );
+        assert_eq!(isks[2]["family"], "INET");
+        assert_eq!(isks[3]["dst_addr"], json!(["127.0.0.1"]));
+    }
+
+    #[test]
+    fn established_tcp_needs_a_source() {
+        let nat64 = parse_prefix("64:ff9b::/96").unwrap();
+        let mut data = json!({"entries": [
+            {"type": "INETSK", "isk": {"family": "INET", "proto": "TCP", "state": "ESTABLISHED",
+             "dst_addr": ["192.0.2.1"], "dst_port": 443}},
+        ]});
+        assert!(translate(&mut data, &nat64, None).is_err());
+    }
+}
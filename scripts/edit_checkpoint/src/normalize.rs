@@ -0,0 +1,187 @@
+//! `normalize`: write a byte-deterministic copy of a checkpoint archive, so
+//! two edits of the same checkpoint compare equal with a plain sha256sum.
+//!
+//! The entries are sorted by path, with fresh headers: mtime 0 (or
+//! SOURCE_DATE_EPOCH), owner 0:0 without names, the mode kept and no
+//! extended attributes. JSON metadata is written canonically, with sorted
+//! keys (network.status indented, as podman writes it, the rest compact).
+//! What records a particular run rather than the checkpoint is left out:
+//! the audit log, the migration timeline, the restore index and the
+//! signature; the copy cannot be undone or restored in --restore-order.
+//! Nested archives (rootfs-diff.tar) and the CRIU images are kept as they are.
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use serde_json::Value;
+
+use crate::audit::AUDIT_PATH;
+use crate::compress;
+use crate::edit::RESTORE_INDEX_PATH;
+use crate::inject::entry_name;
+use crate::lock;
+use crate::metadata::NETWORK_STATUS_PATH;
+use crate::rewrite::Rewrite;
+use crate::sign::{MANIFEST_PATH, SIGNATURE_PATH};
+use crate::timeline::TIMELINE_PATH;
+
+#[derive(Args)]
+pub struct NormalizeArgs {
+    /// Checkpoint archive (left as it is)
+    archive: PathBuf,
+    /// Normalized copy to write
+    output: PathBuf,
+}
+
+/// Entries that differ between runs over the same checkpoint.
+const LEFT_OUT: [&str; 5] = [
+    AUDIT_PATH,
+    TIMELINE_PATH,
+    RESTORE_INDEX_PATH,
+    MANIFEST_PATH,
+    SIGNATURE_PATH,
+];
+/// Metadata entries larger than this are not parsed as JSON.
+const MAX_JSON_SIZE: u64 = 16 << 20;
+
+/// An entry of the archive, and where its data is in the (uncompressed)
+/// archive file.
+struct Indexed {
+    name: String,
+    header: tar::Header,
+    link: Option<PathBuf>,
+    offset: u64,
+    size: u64,
+}
+
+/// `content` as canonical JSON, if it is JSON.
+fn canonical_json(name: &str, content: &[u8]) -> Option<Vec<u8>> {
+    let doc: Value = serde_json::from_slice(content).ok()?;
+    // serde_json's maps are sorted by key
+    let serialized = if name == NETWORK_STATUS_PATH {
+        serde_json::to_vec_pretty(&doc)
+    } else {
+        serde_json::to_vec(&doc)
+    };
+    serialized.ok()
+}
+
+/// The uncompressed archive at `path`: the file itself, or a temporary copy
+/// decompressed.
+fn uncompressed(path: &Path) -> Result<fs::File, String> {
+    if !compress::is_zstd(path)? {
+        return fs::File::open(path).map_err(|e| format!("open {}: {}", path.display(), e));
+    }
+    let mut file = tempfile::tempfile().map_err(|e| format!("spill: {}", e))?;
+    io::copy(&mut compress::open(path)?, &mut file)
+        .and_then(|_| file.rewind())
+        .map_err(|e| format!("decompress {}: {}", path.display(), e))?;
+    Ok(file)
+}
+
+fn index(file: &fs::File) -> Result<Vec<Indexed>, String> {
+    let mut archive = tar::Archive::new(file);
+    let mut entries = Vec::new();
+    for entry in archive.entries_with_seek().map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let name = entry_name(&entry.path().map_err(|e| e.to_string())?.to_string_lossy());
+        if name.is_empty() || LEFT_OUT.contains(&name.as_str()) {
+            continue;
+        }
+        entries.push(Indexed {
+            name,
+            header: entry.header().clone(),
+            link: entry
+                .link_name()
+                .map_err(|e| e.to_string())?
+                .map(|l| l.into_owned()),
+            offset: entry.raw_file_position(),
+            size: entry.size(),
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    if let Some(pair) = entries.windows(2).find(|pair| pair[0].name == pair[1].name) {
+        return Err(format!("{} is in the archive twice", pair[0].name));
+    }
+    Ok(entries)
+}
+
+pub fn run(args: &NormalizeArgs) -> Result<(), String> {
+    let path = args.archive.as_path();
+    let mtime = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch
+            .parse::<u64>()
+            .map_err(|_| format!("SOURCE_DATE_EPOCH={} is not a number of seconds", epoch))?,
+        Err(_) => 0,
+    };
+    let _input_lock = lock::input(path)?;
+    let mut file = uncompressed(path)?;
+    let entries = index(&file)?;
+
+    let mut rewrite = Rewrite::start(path, &args.output)?;
+    let builder = rewrite.builder();
+    let mut canonical = 0;
+    for entry in &entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry.header.entry_type());
+        header.set_mode(entry.header.mode().map_err(|e| e.to_string())? & 0o7777);
+        header.set_mtime(mtime);
+        header.set_uid(0);
+        header.set_gid(0);
+        if let Some(device) = entry.header.device_major().ok().flatten() {
+            header.set_device_major(device).map_err(|e| e.to_string())?;
+            let minor = entry.header.device_minor().ok().flatten().unwrap_or(0);
+            header.set_device_minor(minor).map_err(|e| e.to_string())?;
+        }
+        let result = if let Some(link) = &entry.link {
+            header.set_size(0);
+            builder.append_link(&mut header, &entry.name, link)
+        } else {
+            file.seek(SeekFrom::Start(entry.offset))
+                .map_err(|e| e.to_string())?;
+            let mut data = (&file).take(entry.size);
+            let is_metadata = !entry.name.contains('/') && entry.size <= MAX_JSON_SIZE;
+            let json = if is_metadata && header.entry_type().is_file() {
+                let mut content = Vec::new();
+                data.read_to_end(&mut content).map_err(|e| e.to_string())?;
+                match canonical_json(&entry.name, &content) {
+                    Some(json) => {
+                        canonical += 1;
+                        Some(json)
+                    }
+                    None => Some(content),
+                }
+            } else {
+                None
+            };
+            match json {
+                Some(content) => {
+                    header.set_size(content.len() as u64);
+                    builder.append_data(&mut header, &entry.name, content.as_slice())
+                }
+                // Directories are written dir/, as tar does
+                None if header.entry_type().is_dir() => {
+                    header.set_size(0);
+                    builder.append_data(&mut header, format!("{}/", entry.name), io::empty())
+                }
+                None => {
+                    header.set_size(entry.size);
+                    builder.append_data(&mut header, &entry.name, data)
+                }
+            }
+        };
+        result.map_err(|e| format!("write {}: {}", entry.name, e))?;
+    }
+    rewrite.finish(None)?;
+
+    eprintln!(
+        "Wrote {}: {} entries in order, {} JSON entries canonical",
+        args.output.display(),
+        entries.len(),
+        canonical
+    );
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! `unpack` / `pack`: convert between the checkpoint archive and an unpacked
+//! directory, e.g. to inspect or hand-edit a checkpoint and seal it again.
+//!
+//! Unpacking keeps modes, mtimes, xattrs and (as root) ownership; packing
+//! records them from the directory and writes entries in sorted order so the
+//! same directory always packs to the same archive.
+
+use std::fs;
+#[cfg(feature = "edit")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+use clap::Args;
+
+use crate::compress;
+use crate::images::FILES_IMG_PATH;
+#[cfg(feature = "edit")]
+use crate::replace;
+
+#[derive(Args)]
+pub struct UnpackArgs {
+    /// Checkpoint archive to unpack
+    archive: PathBuf,
+    /// Directory to unpack into (created; must be empty if it exists)
+    dir: PathBuf,
+}
+
+#[cfg(feature = "edit")]
+#[derive(Args)]
+pub struct PackArgs {
+    /// Unpacked checkpoint directory (with checkpoint/files.img)
+    dir: PathBuf,
+    /// Archive to write
+    archive: PathBuf,
+}
+
+pub fn unpack(args: &UnpackArgs) -> Result<(), String> {
+    let not_empty = fs::read_dir(&args.dir).is_ok_and(|mut d| d.next().is_some());
+    if not_empty {
+        return Err(format!("{} is not empty", args.dir.display()));
+    }
+    fs::create_dir_all(&args.dir).map_err(|e| format!("create {}: {}", args.dir.display(), e))?;
+    let mut archive = tar::Archive::new(compress::open(&args.archive)?);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_unpack_xattrs(true);
+    archive.set_preserve_ownerships(is_root());
+    // Directory mtimes change as their contents are written; set them last
+    let mut dir_mtimes = Vec::new();
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let path = entry.path().map_err(|e| e.to_string())?.into_owned();
+        if entry.header().entry_type().is_dir() {
+            if let Ok(mtime) = entry.header().mtime() {
+                dir_mtimes.push((path.clone(), mtime));
+            }
+        }
+        entry
+            .unpack_in(&args.dir)
+            .map_err(|e| format!("unpack {}: {}", path.display(), e))?;
+    }
+    for (path, mtime) in dir_mtimes.iter().rev() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(*mtime);
+        let _ = fs::File::open(args.dir.join(path)).and_then(|d| d.set_modified(mtime));
+    }
+    if !args.dir.join(FILES_IMG_PATH).is_file() {
+        eprintln!(
+            "Warning: {} has no {}; not a checkpoint archive?",
+            args.archive.display(),
+            FILES_IMG_PATH
+        );
+    }
+    eprintln!(
+        "Unpacked {} → {}",
+        args.archive.display(),
+        args.dir.display()
+    );
+    Ok(())
+}
+
+#[cfg(feature = "edit")]
+pub fn pack(args: &PackArgs) -> Result<(), String> {
+    if !args.dir.join(FILES_IMG_PATH).is_file() {
+        return Err(format!(
+            "{} has no {}; not an unpacked checkpoint",
+            args.dir.display(),
+            FILES_IMG_PATH
+        ));
+    }
+    let mut paths = Vec::new();
+    walk(&args.dir, Path::new(""), &mut paths)?;
+
+    let tmp = PathBuf::from(format!("{}.new", args.archive.display()));
+    let file = fs::File::create(&tmp).map_err(|e| format!("create {}: {}", tmp.display(), e))?;
+    let mut builder = tar::Builder::new(std::io::BufWriter::with_capacity(256 * 1024, file));
+    builder.mode(tar::HeaderMode::Complete);
+    builder.follow_symlinks(false);
+    let result = paths
+        .iter()
+        .try_for_each(|rel| builder.append_path_with_name(args.dir.join(rel), rel))
+        .and_then(|_| builder.into_inner())
+        .and_then(|w| w.into_inner().map_err(|e| e.into_error()))
+        .and_then(|f| f.sync_all());
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(format!("pack {}: {}", args.dir.display(), e));
+    }
+    replace::replace(&tmp, &args.archive)?;
+    eprintln!(
+        "Packed {} ({} entries) → {}",
+        args.dir.display(),
+        paths.len(),
+        args.archive.display()
+    );
+    Ok(())
+}
+
+#[cfg(feature = "edit")]
+/// Relative paths under `root`, sorted, each directory before its contents.
+fn walk(root: &Path, rel: &Path, out: &mut Vec<PathBuf>) -> Result<(), String> {
+    let dir = root.join(rel);
+    let mut names: Vec<_> = fs::read_dir(&dir)
+        .map_err(|e| format!("read {}: {}", dir.display(), e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name())
+        .collect();
+    names.sort();
+    for name in names {
+        let path = rel.join(&name);
+        let meta = fs::symlink_metadata(root.join(&path)).map_err(|e| e.to_string())?;
+        out.push(path.clone());
+        if meta.is_dir() {
+            walk(root, &path, out)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata("/proc/self").is_ok_and(|m| m.uid() == 0)
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
@@ -0,0 +1,276 @@
+//! Per-socket patch policy (--socket-rules).
+//!
+//! Without rules every socket bound to old_addr is wildcarded. A rule picks
+//! sockets by protocol, local port and state and says what to do with them
+//! instead, e.g. "tcp/8080 rewrite, tcp/9090 skip, tcp:established close":
+//!
+//! - wildcard: bind to 0.0.0.0 (what an unmatched socket gets)
+//! - rewrite: bind to new_addr, for sockets that must stay on one address
+//! - skip: leave the socket as it was dumped
+//! - close: have CRIU restore an established TCP connection closed, like
+//!   --tcp-close-port
+//!
+//! The first rule a socket matches applies. wildcard and rewrite only change
+//! sockets bound to old_addr.
+//!
+//! `ino=INODE` and `fd=PID:FD` (or `fd=/proc/PID/fd/FD`) pick a single
+//! socket, as `sockets` lists them, e.g. one of several bound alike. fd rules
+//! are resolved to inodes from the checkpoint's socket table before the edit,
+//! so they need the checkpoint on disk.
+
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::sockets;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Wildcard,
+    Rewrite,
+    Skip,
+    Close,
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    ino: Option<u64>,
+    /// "PID:FD" until resolved to `ino`.
+    fd: Option<String>,
+    proto: Option<String>,
+    port: Option<u16>,
+    state: Option<String>,
+    action: Action,
+}
+
+/// The rules of one --socket-rules.
+#[derive(Clone, Debug)]
+pub struct Rules(pub Vec<Rule>);
+
+/// Parse --socket-rules: comma-separated `SELECTOR ACTION` pairs, where
+/// SELECTOR is `PROTO[/PORT][:STATE]` with `*` for any protocol, `ino=INODE`
+/// or `fd=PID:FD`.
+pub fn parse_rules(s: &str) -> Result<Rules, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(parse_rule)
+        .collect::<Result<Vec<_>, _>>()
+        .map(Rules)
+}
+
+fn parse_rule(s: &str) -> Result<Rule, String> {
+    let (selector, action) = s
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| format!("{} is not SELECTOR ACTION", s))?;
+    let action = match action.trim() {
+        "wildcard" => Action::Wildcard,
+        "rewrite" => Action::Rewrite,
+        "skip" => Action::Skip,
+        "close" => Action::Close,
+        other => {
+            return Err(format!(
+                "unknown action {}; use wildcard, rewrite, skip or close",
+                other
+            ))
+        }
+    };
+    let mut rule = Rule {
+        ino: None,
+        fd: None,
+        proto: None,
+        port: None,
+        state: None,
+        action,
+    };
+    if let Some(ino) = selector.strip_prefix("ino=") {
+        rule.ino = Some(
+            ino.parse()
+                .map_err(|_| format!("{}: {} is not an inode number", s, ino))?,
+        );
+        return Ok(rule);
+    }
+    if let Some(fd) = selector.strip_prefix("fd=") {
+        let fd = match fd.strip_prefix("/proc/").and_then(|p| p.split_once("/fd/")) {
+            Some((pid, fd)) => format!("{}:{}", pid, fd),
+            None => fd.to_string(),
+        };
+        match fd.split_once(':') {
+            Some((pid, num)) if pid.parse::<u32>().is_ok() && num.parse::<u32>().is_ok() => {}
+            _ => return Err(format!("{}: {} is not PID:FD", s, fd)),
+        }
+        rule.fd = Some(fd);
+        return Ok(rule);
+    }
+    let (selector, state) = match selector.split_once(':') {
+        Some((selector, state)) => (selector, Some(state.to_ascii_uppercase())),
+        None => (selector, None),
+    };
+    let (proto, port) = match selector.split_once('/') {
+        Some((proto, port)) => {
+            let port = port
+                .parse()
+                .map_err(|_| format!("{}: {} is not a port", s, port))?;
+            (proto, Some(port))
+        }
+        None => (selector, None),
+    };
+    rule.proto = match proto.to_ascii_lowercase().as_str() {
+        "*" => None,
+        "tcp" | "udp" => Some(proto.to_ascii_uppercase()),
+        _ => return Err(format!("{}: protocol {} is not tcp, udp or *", s, proto)),
+    };
+    if action == Action::Close && rule.proto.as_deref() == Some("UDP") {
+        return Err(format!("{}: only TCP connections can be closed", s));
+    }
+    rule.port = port;
+    rule.state = state;
+    Ok(rule)
+}
+
+/// Resolve the fd rules of `rules` to the inodes of the sockets the
+/// descriptors refer to in `checkpoint`.
+pub fn resolve(rules: &mut [Rule], checkpoint: &Path) -> Result<(), String> {
+    if rules.iter().all(|r| r.fd.is_none()) {
+        return Ok(());
+    }
+    let table = sockets::read_table(checkpoint)?;
+    for rule in rules.iter_mut() {
+        let Some(fd) = rule.fd.take() else {
+            continue;
+        };
+        let socket = table
+            .iter()
+            .find(|row| {
+                row["fds"]
+                    .as_array()
+                    .is_some_and(|fds| fds.iter().any(|f| *f == fd))
+            })
+            .ok_or_else(|| format!("fd {} is not an INET socket in the checkpoint", fd))?;
+        rule.ino = socket["ino"].as_u64();
+    }
+    Ok(())
+}
+
+/// Refuse rules that still select by fd: the checkpoint was not on disk to
+/// resolve them.
+pub fn check_resolved(rules: &[Rule]) -> Result<(), String> {
+    match rules.iter().find_map(|r| r.fd.as_ref()) {
+        Some(fd) => Err(format!(
+            "fd={} selects a socket only in a checkpoint on disk; use ino=",
+            fd
+        )),
+        None => Ok(()),
+    }
+}
+
+impl Rule {
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    fn matches(&self, isk: &Value) -> bool {
+        let row = sockets::row(isk);
+        if self.fd.is_some() {
+            return false;
+        }
+        self.ino.is_none_or(|i| row["ino"] == i)
+            && self.proto.as_ref().is_none_or(|p| row["proto"] == *p)
+            && self.port.is_none_or(|p| row["src_port"] == p)
+            && self.state.as_ref().is_none_or(|s| row["state"] == *s)
+    }
+}
+
+/// What the first of `rules` matching `isk` says to do with it.
+pub fn action(rules: &[Rule], isk: &Value) -> Option<Action> {
+    rules.iter().find(|r| r.matches(isk)).map(|r| r.action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn isk(proto: &str, port: u16, state: &str, ino: u64) -> Value {
+        json!({"family": "INET", "proto": proto, "src_port": port, "state": state, "ino": ino})
+    }
+
+    #[test]
+    fn parses_selectors() {
+        let rules = parse_rules("tcp/8080 rewrite, udp skip,, *:established close").unwrap();
+        assert_eq!(rules.0.len(), 3);
+        let r = &rules.0[0];
+        assert_eq!((r.proto.as_deref(), r.port), (Some("TCP"), Some(8080)));
+        assert_eq!(r.action(), Action::Rewrite);
+        assert_eq!(rules.0[1].proto.as_deref(), Some("UDP"));
+        assert_eq!(rules.0[1].port, None);
+        let r = &rules.0[2];
+        assert_eq!(
+            (r.proto.as_deref(), r.state.as_deref()),
+            (None, Some("ESTABLISHED"))
+        );
+        assert_eq!(r.action(), Action::Close);
+    }
+
+    #[test]
+    fn parses_single_socket_selectors() {
+        let rules =
+            parse_rules("ino=4242 skip, fd=/proc/17/fd/5 rewrite, fd=18:6 wildcard").unwrap();
+        assert_eq!(rules.0[0].ino, Some(4242));
+        assert_eq!(rules.0[1].fd.as_deref(), Some("17:5"));
+        assert_eq!(rules.0[2].fd.as_deref(), Some("18:6"));
+        assert!(check_resolved(&rules.0[..1]).is_ok());
+        assert!(check_resolved(&rules.0).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        for rule in [
+            "tcp/8080",
+            "tcp/8080 drop",
+            "tcp/http skip",
+            "tcp/70000 skip",
+            "sctp skip",
+            "udp/53 close",
+            "ino=x skip",
+            "fd=17 skip",
+            "fd=/proc/self/fd/3 skip",
+        ] {
+            assert!(parse_rules(rule).is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn first_match_applies() {
+        let rules = parse_rules("tcp/8080 skip, tcp:listen rewrite, * wildcard").unwrap();
+        assert_eq!(
+            action(&rules.0, &isk("TCP", 8080, "LISTEN", 1)),
+            Some(Action::Skip)
+        );
+        assert_eq!(
+            action(&rules.0, &isk("TCP", 9090, "LISTEN", 2)),
+            Some(Action::Rewrite)
+        );
+        assert_eq!(
+            action(&rules.0, &isk("TCP", 9090, "ESTABLISHED", 3)),
+            Some(Action::Wildcard)
+        );
+        // crit's numeric rendering matches by name too
+        let numeric = json!({"family": 2, "proto": 6, "src_port": 8080, "state": 10, "ino": 4});
+        assert_eq!(action(&rules.0, &numeric), Some(Action::Skip));
+    }
+
+    #[test]
+    fn unmatched_sockets_get_no_action() {
+        let rules = parse_rules("ino=7 close, udp/53 skip").unwrap();
+        assert_eq!(
+            action(&rules.0, &isk("TCP", 53, "ESTABLISHED", 7)),
+            Some(Action::Close)
+        );
+        assert_eq!(action(&rules.0, &isk("TCP", 53, "ESTABLISHED", 8)), None);
+        assert_eq!(action(&rules.0, &isk("UDP", 5353, "CLOSE", 9)), None);
+        // Unresolved fd rules match nothing
+        let rules = parse_rules("fd=1:3 skip").unwrap();
+        assert_eq!(action(&rules.0, &isk("TCP", 80, "LISTEN", 1)), None);
+    }
+}
@@ -0,0 +1,364 @@
+//! Move listening ports that are taken on the target (--port-inventory).
+//!
+//! The inventory lists the ports in use where the restored sockets will bind:
+//! the target's host network namespace, or the pod's shared one when the
+//! container joins a pod whose other containers hold ports. It is a file of
+//! PROTO/PORT lines or saved `ss -Hltun` output, or queried with `ss` on the
+//! target over SSH (--port-inventory-host).
+//!
+//! Each listening socket (TCP LISTEN, or UDP bound and unconnected) on a
+//! taken port moves to the next port above it that is neither taken nor used
+//! by another socket of the checkpoint. The mapping is recorded as the
+//! `port_remap` report key, and config.dump's port mappings publish the
+//! moved ports on their old host ports. Connections already accepted on a
+//! moved port keep it, and clash with the target's listener at restore.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+use serde_json::{json, Value};
+
+use crate::remote;
+use crate::report::Report;
+
+/// Ports in use on the target, by protocol ("tcp" or "udp").
+#[derive(Clone, Debug, Default)]
+pub struct PortInventory(BTreeSet<(String, u16)>);
+
+/// Where the inventory comes from.
+#[derive(Clone, Debug, Default)]
+pub struct PortPatch {
+    pub inventory: Option<PortInventory>,
+    /// Query the inventory on this host with `ss`.
+    pub host: Option<String>,
+}
+
+impl PortPatch {
+    pub fn is_empty(&self) -> bool {
+        self.inventory.is_none() && self.host.is_none()
+    }
+
+    /// The inventory, queried now if it comes from a host.
+    fn inventory(&self) -> Result<Option<PortInventory>, String> {
+        match (&self.inventory, &self.host) {
+            (Some(inventory), _) => Ok(Some(inventory.clone())),
+            (None, Some(host)) => query(host).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Parse --port-inventory: a file of PROTO/PORT lines or `ss -Hltun`
+/// output.
+pub fn parse_file(path: &str) -> Result<PortInventory, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("read {}: {}", path, e))?;
+    parse(&content).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn parse(content: &str) -> Result<PortInventory, String> {
+    let mut ports = BTreeSet::new();
+    let lines = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    for (i, line) in lines.enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // PROTO/PORT, or ss: Netid State Recv-Q Send-Q Local:Port Peer:Port
+        let (proto, port) = match fields.as_slice() {
+            [single] => single.split_once('/').unwrap_or(("", single)),
+            [proto, _, _, _, local, ..] => (*proto, local.rsplit(':').next().unwrap_or("")),
+            _ => ("", ""),
+        };
+        let proto = proto.to_ascii_lowercase();
+        match (proto.as_str(), port.parse::<u16>()) {
+            ("tcp" | "udp", Ok(port)) => {
+                ports.insert((proto, port));
+            }
+            _ => {
+                return Err(format!(
+                    "line {}: {} is not PROTO/PORT or an ss line",
+                    i + 1,
+                    line
+                ))
+            }
+        }
+    }
+    Ok(PortInventory(ports))
+}
+
+/// The listening ports of `host`, from `ss -Hltun` run over SSH.
+fn query(host: &str) -> Result<PortInventory, String> {
+    let argv = remote::argv("EDIT_CHECKPOINT_SS", "ss", &["-Hltun"]);
+    let out = remote::command(Some(host), &argv)
+        .output()
+        .map_err(|e| format!("run {}: {}", argv.join(" "), e))?;
+    if !out.status.success() {
+        return Err(format!(
+            "port inventory of {}: {}",
+            host,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    let inventory = parse(&String::from_utf8_lossy(&out.stdout))
+        .map_err(|e| format!("port inventory of {}: {}", host, e))?;
+    eprintln!("{} ports in use on {}", inventory.0.len(), host);
+    Ok(inventory)
+}
+
+/// "tcp" or "udp" for the INETSK `isk`.
+fn proto(isk: &Value) -> Option<&'static str> {
+    match isk.get("proto") {
+        Some(p) if p == "TCP" || p == 6 => Some("tcp"),
+        Some(p) if p == "UDP" || p == 17 => Some("udp"),
+        _ => None,
+    }
+}
+
+fn is_listener(isk: &Value, proto: &str) -> bool {
+    match proto {
+        "tcp" => matches!(isk.get("state"), Some(s) if s == "LISTEN" || s == 10),
+        _ => isk.get("dst_port").and_then(|p| p.as_u64()) == Some(0),
+    }
+}
+
+/// Move the listening sockets of the decoded files.img JSON off the ports
+/// `ports` finds taken, and record the mapping as `port_remap`.
+pub fn remap(data: &mut Value, ports: &PortPatch, report: &mut Report) -> Result<(), String> {
+    let Some(inventory) = ports.inventory()? else {
+        return Ok(());
+    };
+    let Some(entries) = data.get_mut("entries").and_then(|e| e.as_array_mut()) else {
+        return Ok(());
+    };
+    let sockets = || {
+        entries
+            .iter()
+            .filter(|e| e.get("type").and_then(|t| t.as_str()) == Some("INETSK"))
+            .filter_map(|e| e.get("isk"))
+            .filter_map(|isk| {
+                let port = isk.get("src_port").and_then(|p| p.as_u64())?;
+                Some((isk, proto(isk)?, u16::try_from(port).ok()?))
+            })
+    };
+    let mut used: BTreeSet<(String, u16)> = sockets()
+        .map(|(_, proto, port)| (proto.to_string(), port))
+        .collect();
+    let taken: BTreeSet<(String, u16)> = sockets()
+        .filter(|(isk, proto, port)| is_listener(isk, proto) && *port != 0)
+        .map(|(_, proto, port)| (proto.to_string(), port))
+        .filter(|key| inventory.0.contains(key))
+        .collect();
+    let mut mapping: BTreeMap<(String, u16), u16> = BTreeMap::new();
+    for (proto, old) in taken {
+        let new = (old.saturating_add(1)..=u16::MAX)
+            .find(|p| {
+                let key = (proto.clone(), *p);
+                !inventory.0.contains(&key) && !used.contains(&key)
+            })
+            .ok_or_else(|| format!("no free {} port above {} on the target", proto, old))?;
+        used.insert((proto.clone(), new));
+        mapping.insert((proto, old), new);
+    }
+
+    let mut accepted: BTreeMap<(String, u16), usize> = BTreeMap::new();
+    for entry in entries.iter_mut() {
+        if entry.get("type").and_then(|t| t.as_str()) != Some("INETSK") {
+            continue;
+        }
+        let Some(isk) = entry.get_mut("isk") else {
+            continue;
+        };
+        let (Some(proto), Some(port)) = (proto(isk), isk.get("src_port").and_then(|p| p.as_u64()))
+        else {
+            continue;
+        };
+        let key = (proto.to_string(), port as u16);
+        let Some(new) = mapping.get(&key) else {
+            continue;
+        };
+        if is_listener(isk, proto) {
+            isk["src_port"] = (*new).into();
+        } else {
+            *accepted.entry(key).or_default() += 1;
+        }
+    }
+    for ((proto, port), count) in &accepted {
+        report.warn(format!(
+            "{} connections on {}/{} keep the port and clash with the target's listener \
+             (close them with --tcp-close-port {})",
+            count, proto, port, port
+        ));
+    }
+    let remap: Vec<Value> = mapping
+        .iter()
+        .map(|((proto, old), new)| json!({"proto": proto, "old": old, "new": new}))
+        .collect();
+    report.set("port_remap", remap);
+    Ok(())
+}
+
+/// Point config.dump's port mappings at the moved ports of `port_remap`.
+pub fn patch_config_dump(content: &[u8], report: &mut Report) -> Result<Vec<u8>, String> {
+    let remap: Vec<(String, u64, u64)> = report
+        .get("port_remap")
+        .and_then(|r| r.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|m| {
+            Some((
+                m["proto"].as_str()?.to_string(),
+                m["old"].as_u64()?,
+                m["new"].as_u64()?,
+            ))
+        })
+        .collect();
+    if remap.is_empty() {
+        return Ok(content.to_vec());
+    }
+    let mut config: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse config.dump: {}", e))?;
+    let mut unmoved = Vec::new();
+    if let Some(mappings) = config
+        .get_mut("newPortMappings")
+        .and_then(|m| m.as_array_mut())
+    {
+        for mapping in mappings.iter_mut() {
+            let protocols = mapping["protocol"].as_str().unwrap_or("tcp").to_string();
+            let Some(port) = mapping["container_port"].as_u64() else {
+                continue;
+            };
+            let range = mapping["range"].as_u64().unwrap_or(1).max(1);
+            for (proto, old, new) in &remap {
+                if !protocols.split(',').any(|p| p == proto) {
+                    continue;
+                }
+                if *old == port && range == 1 {
+                    mapping["container_port"] = (*new).into();
+                } else if (port..port + range).contains(old) {
+                    unmoved.push(format!("{}/{}", proto, old));
+                }
+            }
+        }
+    }
+    for port in unmoved {
+        report.warn(format!(
+            "{} is published as part of a port range; config.dump still maps it to the old port",
+            port
+        ));
+    }
+    serde_json::to_vec(&config).map_err(|e| format!("serialize config.dump: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch(inventory: &str) -> PortPatch {
+        PortPatch {
+            inventory: Some(parse(inventory).unwrap()),
+            host: None,
+        }
+    }
+
+    fn src_ports(data: &Value) -> Vec<u64> {
+        data["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["isk"]["src_port"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parses_port_lines_and_ss_output() {
+        let inventory = parse(
+            "# taken\ntcp/80\nUDP/53\n\n\
+             tcp   LISTEN 0      4096         0.0.0.0:8080      0.0.0.0:*\n\
+             udp   UNCONN 0      0               [::]:5353         [::]:*\n",
+        )
+        .unwrap();
+        let ports: Vec<(&str, u16)> = inventory.0.iter().map(|(p, n)| (p.as_str(), *n)).collect();
+        assert_eq!(
+            ports,
+            [("tcp", 80), ("tcp", 8080), ("udp", 53), ("udp", 5353)]
+        );
+        assert!(parse("sctp/80").is_err());
+        assert!(parse("tcp/http").is_err());
+        assert!(parse("tcp 80").is_err());
+    }
+
+    #[test]
+    fn moves_listeners_to_the_next_free_port() {
+        let mut data = json!({"entries": [
+            {"type": "INETSK", "isk": {"proto": "TCP", "state": "LISTEN", "src_port": 8080, "dst_port": 0}},
+            // 8082 is another socket of the checkpoint, 8081 taken on the target
+            {"type": "INETSK", "isk": {"proto": "TCP", "state": "LISTEN", "src_port": 8082, "dst_port": 0}},
+            {"type": "INETSK", "isk": {"proto": 17, "src_port": 53, "dst_port": 0}},
+            {"type": "INETSK", "isk": {"proto": "TCP", "state": "LISTEN", "src_port": 9000, "dst_port": 0}},
+        ]});
+        let mut report = Report::default();
+        remap(
+            &mut data,
+            &patch("tcp/8080\ntcp/8081\nudp/53\ntcp/53"),
+            &mut report,
+        )
+        .unwrap();
+        assert_eq!(src_ports(&data), [8083, 8082, 54, 9000]);
+        assert_eq!(
+            report.get("port_remap"),
+            Some(&json!([
+                {"proto": "tcp", "old": 8080, "new": 8083},
+                {"proto": "udp", "old": 53, "new": 54},
+            ]))
+        );
+    }
+
+    #[test]
+    fn keeps_accepted_connections() {
+        let mut data = json!({"entries": [
+            {"type": "INETSK", "isk": {"proto": "TCP", "state": "LISTEN", "src_port": 80, "dst_port": 0}},
+            {"type": "INETSK", "isk": {"proto": "TCP", "state": "ESTABLISHED", "src_port": 80, "dst_port": 40000}},
+            // A connected UDP socket is not a listener
+            {"type": "INETSK", "isk": {"proto": "UDP", "src_port": 5000, "dst_port": 53}},
+        ]});
+        let mut report = Report::default();
+        remap(&mut data, &patch("tcp/80\nudp/5000"), &mut report).unwrap();
+        assert_eq!(src_ports(&data), [81, 80, 5000]);
+        assert_eq!(
+            report.get("port_remap"),
+            Some(&json!([{"proto": "tcp", "old": 80, "new": 81}]))
+        );
+    }
+
+    #[test]
+    fn fails_without_a_free_port() {
+        let mut data = json!({"entries": [
+            {"type": "INETSK", "isk": {"proto": "TCP", "state": "LISTEN", "src_port": 65535, "dst_port": 0}},
+        ]});
+        let mut report = Report::default();
+        assert!(remap(&mut data, &patch("tcp/65535"), &mut report).is_err());
+    }
+
+    #[test]
+    fn publishes_moved_ports_on_their_host_ports() {
+        let mut report = Report::default();
+        report.set(
+            "port_remap",
+            json!([{"proto": "tcp", "old": 80, "new": 81}, {"proto": "udp", "old": 53, "new": 54}]),
+        );
+        let config = json!({"newPortMappings": [
+            {"host_port": 8080, "container_port": 80, "protocol": "tcp"},
+            {"host_port": 5353, "container_port": 53, "protocol": "tcp"},
+            {"host_port": 1000, "container_port": 50, "protocol": "udp", "range": 10},
+        ]});
+        let out = patch_config_dump(&serde_json::to_vec(&config).unwrap(), &mut report).unwrap();
+        let out: Value = serde_json::from_slice(&out).unwrap();
+        let ports: Vec<&Value> = out["newPortMappings"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| &m["container_port"])
+            .collect();
+        assert_eq!(ports, [&json!(81), &json!(53), &json!(50)]);
+    }
+}
@@ -0,0 +1,259 @@
+//! `preflight`: check that a node is ready for a migration before the window
+//! opens.
+//!
+//! Runs on this node and on each --host over SSH: crit encodes a small image
+//! (so its Python dependencies are there too), criu and podman answer
+//! --version, the kernel has what the restore uses, and /dev/shm has at least
+//! --shm-min free. Prints one line per check, PASS, WARN (the migration can
+//! do without it) or FAIL, and writes them per host to --report; exits
+//! non-zero if any check fails.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Output, Stdio};
+
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::buffers;
+use crate::remote;
+use crate::report::{self, Report};
+
+#[derive(Args)]
+pub struct PreflightArgs {
+    /// Also check this host over SSH (repeatable)
+    #[arg(long, value_name = "USER@HOST")]
+    host: Vec<String>,
+    /// Only check the hosts given with --host, not this node
+    #[arg(long, requires = "host")]
+    no_local: bool,
+    /// Fail when /dev/shm has less than SIZE free (e.g. 512M)
+    #[arg(long, value_name = "SIZE", default_value = "256M")]
+    #[arg(value_parser = buffers::parse_bytes)]
+    shm_min: usize,
+    /// Write a JSON report of the checks to FILE
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+}
+
+/// Paths whose presence says the kernel has a feature, and what it is for.
+const KERNEL_FEATURES: [(&str, &str); 4] = [
+    ("/proc/sys/kernel/ns_last_pid", "checkpoint/restore (PIDs)"),
+    ("/proc/self/ns/time", "time namespaces"),
+    ("/sys/fs/cgroup/cgroup.controllers", "cgroup v2"),
+    (
+        "/proc/sys/vm/unprivileged_userfaultfd",
+        "userfaultfd (lazy pages)",
+    ),
+];
+
+/// An image crit has to be able to encode.
+const SMOKE_IMAGE: &str = r#"{"magic": "INVENTORY", "entries": []}"#;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Level {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Pass => "PASS",
+            Level::Warn => "WARN",
+            Level::Fail => "FAIL",
+        }
+    }
+}
+
+/// The checks of one host.
+struct Checks {
+    host: String,
+    checks: Vec<Value>,
+    failed: usize,
+}
+
+impl Checks {
+    fn add(&mut self, level: Level, name: &str, detail: impl Into<String>) {
+        let detail = detail.into();
+        eprintln!("{} {:<12} {}", level.as_str(), name, detail);
+        if level == Level::Fail {
+            self.failed += 1;
+        }
+        self.checks.push(json!({
+            "check": name,
+            "result": level.as_str().to_lowercase(),
+            "detail": detail,
+        }));
+    }
+}
+
+pub fn run(args: &PreflightArgs, report: &mut Report) -> Result<(), String> {
+    let mut hosts: Vec<Option<&str>> = Vec::new();
+    if !args.no_local {
+        hosts.push(None);
+    }
+    hosts.extend(args.host.iter().map(|h| Some(h.as_str())));
+
+    let mut results = Vec::new();
+    let mut failed = 0;
+    for host in hosts {
+        let checks = check_host(host, args.shm_min as u64);
+        failed += checks.failed;
+        results.push(json!({
+            "host": checks.host,
+            "ready": checks.failed == 0,
+            "checks": checks.checks,
+        }));
+    }
+    report.set("hosts", results);
+    report.set("verdict", if failed == 0 { "ready" } else { "not ready" });
+    if failed > 0 {
+        return Err(format!("{} check(s) failed", failed));
+    }
+    eprintln!("Ready");
+    Ok(())
+}
+
+fn check_host(host: Option<&str>, shm_min: u64) -> Checks {
+    let mut checks = Checks {
+        host: host.unwrap_or("local").to_string(),
+        checks: Vec::new(),
+        failed: 0,
+    };
+    eprintln!("{}:", checks.host);
+    check_crit(host, &mut checks);
+    check_version(
+        host,
+        &mut checks,
+        "criu",
+        &["criu".into(), "--version".into()],
+    );
+    check_version(
+        host,
+        &mut checks,
+        "podman",
+        &remote::argv("EDIT_CHECKPOINT_PODMAN", "podman", &["--version"]),
+    );
+    check_kernel(host, &mut checks);
+    check_shm(host, shm_min, &mut checks);
+    checks
+}
+
+/// Run `argv` on `host`, feeding it `stdin`.
+fn output(host: Option<&str>, argv: &[String], stdin: Option<&[u8]>) -> Result<Output, String> {
+    let mut command = remote::command(host, argv);
+    command.stdin(if stdin.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    });
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = command.spawn().map_err(|e| format!("{}: {}", argv[0], e))?;
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        // A command that exits without reading says why on stderr
+        let _ = pipe.write_all(input);
+    }
+    child
+        .wait_with_output()
+        .map_err(|e| format!("{}: {}", argv[0], e))
+}
+
+/// The last line of what a failed command printed, or its exit status.
+fn why(out: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    match stderr.trim().lines().last() {
+        Some(line) => line.to_string(),
+        None => out.status.to_string(),
+    }
+}
+
+fn check_crit(host: Option<&str>, checks: &mut Checks) {
+    let argv: Vec<String> = ["crit", "encode", "-i", "/dev/stdin"]
+        .map(String::from)
+        .to_vec();
+    match output(host, &argv, Some(SMOKE_IMAGE.as_bytes())) {
+        Ok(out) if out.status.success() && !out.stdout.is_empty() => {
+            checks.add(Level::Pass, "crit", "encodes images")
+        }
+        Ok(out) if out.status.success() => checks.add(Level::Fail, "crit", "encode wrote nothing"),
+        Ok(out) => checks.add(Level::Fail, "crit", why(&out)),
+        Err(e) => checks.add(Level::Fail, "crit", e),
+    }
+}
+
+/// Check that `argv` (a --version) runs, showing the version it prints.
+fn check_version(host: Option<&str>, checks: &mut Checks, name: &str, argv: &[String]) {
+    match output(host, argv, None) {
+        Ok(out) if out.status.success() => {
+            let stdout = String::from_utf8_lossy(&out.stdout);
+            let version = stdout.lines().next().unwrap_or_default().trim();
+            checks.add(Level::Pass, name, version)
+        }
+        Ok(out) => checks.add(Level::Fail, name, why(&out)),
+        Err(e) => checks.add(Level::Fail, name, e),
+    }
+}
+
+fn check_kernel(host: Option<&str>, checks: &mut Checks) {
+    let script = format!(
+        "uname -r; for f in {}; do test -e \"$f\" && echo \"$f\"; done; true",
+        KERNEL_FEATURES.map(|(path, _)| path).join(" ")
+    );
+    let argv: Vec<String> = ["sh", "-c", &script].map(String::from).to_vec();
+    let out = match output(host, &argv, None) {
+        Ok(out) if out.status.success() => out,
+        Ok(out) => return checks.add(Level::Fail, "kernel", why(&out)),
+        Err(e) => return checks.add(Level::Fail, "kernel", e),
+    };
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let mut lines = stdout.lines();
+    checks.add(Level::Pass, "kernel", lines.next().unwrap_or_default());
+    let present: Vec<&str> = lines.collect();
+    for (path, feature) in KERNEL_FEATURES {
+        if present.contains(&path) {
+            checks.add(Level::Pass, "kernel", feature);
+        } else {
+            checks.add(
+                Level::Warn,
+                "kernel",
+                format!("no {} ({} is missing)", feature, path),
+            );
+        }
+    }
+}
+
+fn check_shm(host: Option<&str>, shm_min: u64, checks: &mut Checks) {
+    let argv: Vec<String> = ["df", "-Pk", "/dev/shm"].map(String::from).to_vec();
+    let out = match output(host, &argv, None) {
+        Ok(out) if out.status.success() => out,
+        Ok(out) => return checks.add(Level::Fail, "/dev/shm", why(&out)),
+        Err(e) => return checks.add(Level::Fail, "/dev/shm", e),
+    };
+    // Filesystem 1024-blocks Used Available Capacity Mounted-on
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let free = stdout
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024);
+    match free {
+        Some(free) if free >= shm_min => checks.add(
+            Level::Pass,
+            "/dev/shm",
+            format!("{} free", report::human(free)),
+        ),
+        Some(free) => checks.add(
+            Level::Fail,
+            "/dev/shm",
+            format!(
+                "{} free, less than --shm-min {}",
+                report::human(free),
+                report::human(shm_min)
+            ),
+        ),
+        None => checks.add(Level::Fail, "/dev/shm", "cannot read df's output"),
+    }
+}
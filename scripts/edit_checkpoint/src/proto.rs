@@ -0,0 +1,805 @@
+//! CRIU images decoded with protobuf descriptors loaded at runtime instead of
+//! by crit, whose compiled-in protos may be older than the CRIU that wrote
+//! the checkpoint: crit drops the fields it does not know, so a decode and
+//! encode through it loses them. Decoded here, fields missing from the
+//! descriptors are kept as raw wire bytes (`_unknown`, hex) and written back
+//! as they were, and the descriptors can be swapped for the CRIU in use.
+//!
+//! The descriptors are FileDescriptorSets, as from
+//!
+//!     protoc --include_imports --descriptor_set_out=criu.desc -I images images/*.proto
+//!
+//! in a CRIU source tree: EDIT_CHECKPOINT_CRIU_DESCRIPTORS names one or a
+//! directory of them (*.desc, *.pb), by default /usr/share/criu. The JSON
+//! has the shape of crit's with enum values by name and other numbers as
+//! such (no address or flag renderings), bytes as hex, and "magic" as the
+//! numbers read. Images of a kind not listed in IMAGES still go to crit.
+//!
+//! Split picks entries out of an image undecoded, for the socket patches to
+//! decode (here or with crit) the inet sockets of files.img alone.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+pub const DESCRIPTORS_ENV: &str = "EDIT_CHECKPOINT_CRIU_DESCRIPTORS";
+pub const DEFAULT_DIR: &str = "/usr/share/criu";
+
+const IMG_COMMON_MAGIC: u32 = 0x5456_4319;
+/// The image magics decoded here and their entry messages.
+const IMAGES: &[(u32, &str)] = &[
+    (0x5630_3138, ".file_entry"),
+    (0x5505_3847, ".core_entry"),
+    (0x5443_2030, ".task_kobj_ids_entry"),
+    (0x5621_3732, ".fdinfo_entry"),
+    (0x4311_4433, ".timens_entry"),
+];
+
+const LABEL_REPEATED: u64 = 3;
+const TYPE_MESSAGE: u64 = 11;
+const TYPE_ENUM: u64 = 14;
+const TYPE_STRING: u64 = 9;
+const TYPE_BYTES: u64 = 12;
+
+struct Field {
+    name: String,
+    number: u64,
+    kind: u64,
+    repeated: bool,
+    packed: bool,
+    /// The message or enum of a TYPE_MESSAGE or TYPE_ENUM field.
+    type_name: String,
+}
+
+/// Messages and enums by full name (".file_entry").
+#[derive(Default)]
+pub struct Descriptors {
+    messages: HashMap<String, Vec<Field>>,
+    enums: HashMap<String, Vec<(String, i64)>>,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.buf.get(self.pos).ok_or("truncated varint")?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("overlong varint".to_string())
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(n).filter(|e| *e <= self.buf.len());
+        let end = end.ok_or("truncated field")?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u32_le(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap_or_default()))
+    }
+
+    /// The payload of a field of wire type `wire`: the varint or the bytes.
+    fn value(&mut self, wire: u64) -> Result<WireValue<'a>, String> {
+        Ok(match wire {
+            0 => WireValue::Varint(self.varint()?),
+            1 => WireValue::Bytes(self.take(8)?),
+            2 => {
+                let len = self.varint()? as usize;
+                WireValue::Bytes(self.take(len)?)
+            }
+            5 => WireValue::Bytes(self.take(4)?),
+            _ => return Err(format!("unsupported wire type {}", wire)),
+        })
+    }
+}
+
+enum WireValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// The fields of a message, by number, undecoded.
+fn fields(buf: &[u8]) -> Result<Vec<(u64, WireValue<'_>)>, String> {
+    let mut reader = Reader::new(buf);
+    let mut fields = Vec::new();
+    while !reader.done() {
+        let tag = reader.varint()?;
+        fields.push((tag >> 3, reader.value(tag & 7)?));
+    }
+    Ok(fields)
+}
+
+fn text(value: &WireValue) -> String {
+    match value {
+        WireValue::Bytes(b) => String::from_utf8_lossy(b).into_owned(),
+        WireValue::Varint(_) => String::new(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) {
+        return Err(format!("odd-length hex {}", s));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| format!("bad hex {}", s)))
+        .collect()
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// The wire type of fields of type `kind`.
+fn wire_type(kind: u64) -> u64 {
+    match kind {
+        1 | 6 | 16 => 1,
+        2 | 7 | 15 => 5,
+        9 | 11 | 12 => 2,
+        _ => 0,
+    }
+}
+
+impl Descriptors {
+    /// Load the descriptor sets named by DESCRIPTORS_ENV, or those in
+    /// DEFAULT_DIR if it exists; None if neither is there.
+    pub fn load() -> Result<Option<Descriptors>, String> {
+        let (path, explicit) = match std::env::var(DESCRIPTORS_ENV) {
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_DIR.to_string(), false),
+        };
+        let path = Path::new(&path);
+        let files = if path.is_dir() {
+            let entries =
+                fs::read_dir(path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+            let mut files: Vec<_> = entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|x| x == "desc" || x == "pb"))
+                .collect();
+            files.sort();
+            files
+        } else if path.exists() || explicit {
+            vec![path.to_path_buf()]
+        } else {
+            Vec::new()
+        };
+        if files.is_empty() {
+            if explicit {
+                return Err(format!(
+                    "{}: no descriptor sets in {}",
+                    DESCRIPTORS_ENV,
+                    path.display()
+                ));
+            }
+            return Ok(None);
+        }
+        let mut descriptors = Descriptors::default();
+        for file in files {
+            let content = fs::read(&file).map_err(|e| format!("read {}: {}", file.display(), e))?;
+            descriptors
+                .add_set(&content)
+                .map_err(|e| format!("{}: not a FileDescriptorSet: {}", file.display(), e))?;
+        }
+        Ok(Some(descriptors))
+    }
+
+    fn add_set(&mut self, content: &[u8]) -> Result<(), String> {
+        for (number, file) in fields(content)? {
+            let WireValue::Bytes(file) = file else {
+                continue;
+            };
+            if number != 1 {
+                continue;
+            }
+            let file = fields(file)?;
+            let package = file
+                .iter()
+                .find(|(n, _)| *n == 2)
+                .map(|(_, v)| text(v))
+                .filter(|p| !p.is_empty())
+                .map(|p| format!(".{}", p))
+                .unwrap_or_default();
+            for (number, value) in &file {
+                match (number, value) {
+                    (4, WireValue::Bytes(message)) => self.add_message(&package, message)?,
+                    (5, WireValue::Bytes(en)) => self.add_enum(&package, en)?,
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_message(&mut self, scope: &str, content: &[u8]) -> Result<(), String> {
+        let message = fields(content)?;
+        let name = message
+            .iter()
+            .find(|(n, _)| *n == 1)
+            .map(|(_, v)| format!("{}.{}", scope, text(v)))
+            .ok_or("a message without a name")?;
+        let mut own = Vec::new();
+        for (number, value) in &message {
+            let WireValue::Bytes(value) = value else {
+                continue;
+            };
+            match number {
+                2 => own.push(field(value)?),
+                3 => self.add_message(&name, value)?,
+                4 => self.add_enum(&name, value)?,
+                _ => {}
+            }
+        }
+        own.sort_by_key(|f| f.number);
+        self.messages.insert(name, own);
+        Ok(())
+    }
+
+    fn add_enum(&mut self, scope: &str, content: &[u8]) -> Result<(), String> {
+        let en = fields(content)?;
+        let mut name = None;
+        let mut values = Vec::new();
+        for (number, value) in &en {
+            match (number, value) {
+                (1, v) => name = Some(format!("{}.{}", scope, text(v))),
+                (2, WireValue::Bytes(v)) => {
+                    let mut value_name = String::new();
+                    let mut value_number = 0;
+                    for (n, v) in fields(v)? {
+                        match (n, v) {
+                            (1, v) => value_name = text(&v),
+                            (2, WireValue::Varint(x)) => value_number = x as i32 as i64,
+                            _ => {}
+                        }
+                    }
+                    values.push((value_name, value_number));
+                }
+                _ => {}
+            }
+        }
+        self.enums
+            .insert(name.ok_or("an enum without a name")?, values);
+        Ok(())
+    }
+
+    /// Decode the image `content` if it is of a kind in IMAGES whose
+    /// message the descriptors have; None to leave it to crit.
+    pub fn decode_image(&self, content: &[u8]) -> Result<Option<Value>, String> {
+        let mut reader = Reader::new(content);
+        let mut magic = vec![reader.u32_le()?];
+        if magic[0] == IMG_COMMON_MAGIC {
+            magic.push(reader.u32_le()?);
+        }
+        let Some(message) = image_message(&magic).filter(|m| self.messages.contains_key(*m)) else {
+            return Ok(None);
+        };
+        let mut entries = Vec::new();
+        while !reader.done() {
+            let size = reader.u32_le()? as usize;
+            let entry = reader.take(size)?;
+            entries.push(self.decode(entry, message)?);
+        }
+        let mut image = Map::new();
+        image.insert("magic".to_string(), magic.into());
+        image.insert("entries".to_string(), entries.into());
+        Ok(Some(Value::Object(image)))
+    }
+
+    /// Encode an image decoded by decode_image; None if `data` was decoded
+    /// by crit.
+    pub fn encode_image(&self, data: &Value) -> Result<Option<Vec<u8>>, String> {
+        let Some(magic) = data.get("magic").and_then(|m| m.as_array()) else {
+            return Ok(None);
+        };
+        let magic: Vec<u32> = magic
+            .iter()
+            .map(|m| m.as_u64().and_then(|m| u32::try_from(m).ok()))
+            .collect::<Option<_>>()
+            .ok_or("the image magic is not a list of numbers")?;
+        let message = image_message(&magic).ok_or("unknown image magic")?;
+        let mut out: Vec<u8> = magic.iter().flat_map(|m| m.to_le_bytes()).collect();
+        let entries = data.get("entries").and_then(|e| e.as_array());
+        for entry in entries.into_iter().flatten() {
+            let encoded = self.encode(entry, message)?;
+            out.extend((encoded.len() as u32).to_le_bytes());
+            out.extend(encoded);
+        }
+        Ok(Some(out))
+    }
+
+    fn decode(&self, content: &[u8], message: &str) -> Result<Value, String> {
+        let fields = self
+            .messages
+            .get(message)
+            .ok_or_else(|| format!("no message {} in the descriptors", message))?;
+        let mut object = Map::new();
+        let mut unknown = Vec::new();
+        let mut reader = Reader::new(content);
+        while !reader.done() {
+            let start = reader.pos;
+            let tag = reader.varint()?;
+            let wire = tag & 7;
+            let value = reader.value(wire)?;
+            let field = fields.iter().find(|f| f.number == tag >> 3);
+            let values = match field {
+                Some(field) => self.decode_field(field, wire, &value)?,
+                None => None,
+            };
+            let (Some(field), Some(values)) = (field, values) else {
+                unknown.extend(&reader.buf[start..reader.pos]);
+                continue;
+            };
+            if field.repeated {
+                let list = object
+                    .entry(field.name.clone())
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                if let Value::Array(list) = list {
+                    list.extend(values);
+                }
+            } else if let Some(value) = values.into_iter().last() {
+                object.insert(field.name.clone(), value);
+            }
+        }
+        if !unknown.is_empty() {
+            object.insert("_unknown".to_string(), hex(&unknown).into());
+        }
+        Ok(Value::Object(object))
+    }
+
+    /// The values of one occurrence of `field` (several if packed); None if
+    /// the wire type does not fit the field, which is then kept unknown.
+    fn decode_field(
+        &self,
+        field: &Field,
+        wire: u64,
+        value: &WireValue,
+    ) -> Result<Option<Vec<Value>>, String> {
+        let expected = wire_type(field.kind);
+        if wire == expected {
+            return Ok(self.scalar(field, value)?.map(|v| vec![v]));
+        }
+        // A packed repeated scalar
+        let WireValue::Bytes(bytes) = value else {
+            return Ok(None);
+        };
+        if wire != 2 || !field.repeated || expected == 2 {
+            return Ok(None);
+        }
+        let mut reader = Reader::new(bytes);
+        let mut values = Vec::new();
+        while !reader.done() {
+            match self.scalar(field, &reader.value(expected)?)? {
+                Some(v) => values.push(v),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(values))
+    }
+
+    fn scalar(&self, field: &Field, value: &WireValue) -> Result<Option<Value>, String> {
+        let le = |b: &[u8]| {
+            let mut word = [0u8; 8];
+            word[..b.len()].copy_from_slice(b);
+            u64::from_le_bytes(word)
+        };
+        Ok(Some(match (field.kind, value) {
+            (3, WireValue::Varint(v)) => (*v as i64).into(),
+            (4, WireValue::Varint(v)) => (*v).into(),
+            (5, WireValue::Varint(v)) => (*v as i32).into(),
+            (8, WireValue::Varint(v)) => (*v != 0).into(),
+            (13, WireValue::Varint(v)) => (*v as u32).into(),
+            (17, WireValue::Varint(v)) => ((*v >> 1) as i32 ^ -((*v & 1) as i32)).into(),
+            (18, WireValue::Varint(v)) => ((*v >> 1) as i64 ^ -((*v & 1) as i64)).into(),
+            (TYPE_ENUM, WireValue::Varint(v)) => {
+                let number = *v as i32 as i64;
+                let name = self
+                    .enums
+                    .get(&field.type_name)
+                    .and_then(|values| values.iter().find(|(_, n)| *n == number));
+                match name {
+                    Some((name, _)) => name.as_str().into(),
+                    None => number.into(),
+                }
+            }
+            (1, WireValue::Bytes(b)) => f64::from_bits(le(b)).into(),
+            (2, WireValue::Bytes(b)) => f64::from(f32::from_bits(le(b) as u32)).into(),
+            (6, WireValue::Bytes(b)) => le(b).into(),
+            (7, WireValue::Bytes(b)) => (le(b) as u32).into(),
+            (15, WireValue::Bytes(b)) => (le(b) as u32 as i32).into(),
+            (16, WireValue::Bytes(b)) => (le(b) as i64).into(),
+            (TYPE_STRING, WireValue::Bytes(b)) => match std::str::from_utf8(b) {
+                Ok(s) => s.into(),
+                Err(_) => return Ok(None),
+            },
+            (TYPE_BYTES, WireValue::Bytes(b)) => hex(b).into(),
+            (TYPE_MESSAGE, WireValue::Bytes(b)) if self.messages.contains_key(&field.type_name) => {
+                self.decode(b, &field.type_name)?
+            }
+            _ => return Ok(None),
+        }))
+    }
+
+    fn encode(&self, value: &Value, message: &str) -> Result<Vec<u8>, String> {
+        let fields = self
+            .messages
+            .get(message)
+            .ok_or_else(|| format!("no message {} in the descriptors", message))?;
+        let object = value
+            .as_object()
+            .ok_or_else(|| format!("{} is not an object", message))?;
+        let mut out = Vec::new();
+        for field in fields {
+            let Some(value) = object.get(&field.name) else {
+                continue;
+            };
+            let values = match (field.repeated, value) {
+                (true, Value::Array(values)) => values.as_slice(),
+                (true, _) => return Err(format!("{}.{} is not a list", message, field.name)),
+                (false, value) => std::slice::from_ref(value),
+            };
+            let wire = wire_type(field.kind);
+            if field.packed && wire != 2 {
+                if values.is_empty() {
+                    continue;
+                }
+                let mut packed = Vec::new();
+                for value in values {
+                    self.put_scalar(&mut packed, field, value)?;
+                }
+                put_varint(&mut out, field.number << 3 | 2);
+                put_varint(&mut out, packed.len() as u64);
+                out.extend(packed);
+                continue;
+            }
+            for value in values {
+                put_varint(&mut out, field.number << 3 | wire);
+                self.put_scalar(&mut out, field, value)?;
+            }
+        }
+        if let Some(unknown) = object.get("_unknown").and_then(|u| u.as_str()) {
+            out.extend(unhex(unknown)?);
+        }
+        Ok(out)
+    }
+
+    fn put_scalar(&self, out: &mut Vec<u8>, field: &Field, value: &Value) -> Result<(), String> {
+        let bad = || format!("{}: unexpected value {}", field.name, value);
+        let int = || value.as_i64().or_else(|| value.as_u64().map(|v| v as i64));
+        let float = || value.as_f64().ok_or_else(bad);
+        let bytes = |b: &[u8], out: &mut Vec<u8>| {
+            put_varint(out, b.len() as u64);
+            out.extend(b);
+        };
+        match field.kind {
+            1 => out.extend(float()?.to_bits().to_le_bytes()),
+            2 => out.extend((float()? as f32).to_bits().to_le_bytes()),
+            6 | 16 => out.extend((int().ok_or_else(bad)? as u64).to_le_bytes()),
+            7 | 15 => out.extend((int().ok_or_else(bad)? as u32).to_le_bytes()),
+            8 => put_varint(out, u64::from(value.as_bool().ok_or_else(bad)?)),
+            17 => {
+                let v = int().ok_or_else(bad)? as i32;
+                put_varint(out, u64::from(((v << 1) ^ (v >> 31)) as u32));
+            }
+            18 => {
+                let v = int().ok_or_else(bad)?;
+                put_varint(out, ((v << 1) ^ (v >> 63)) as u64);
+            }
+            TYPE_ENUM => {
+                let number = match value.as_str() {
+                    Some(name) => self
+                        .enums
+                        .get(&field.type_name)
+                        .and_then(|values| values.iter().find(|(n, _)| n == name))
+                        .map(|(_, number)| *number)
+                        .ok_or_else(bad)?,
+                    None => int().ok_or_else(bad)?,
+                };
+                put_varint(out, number as u64);
+            }
+            TYPE_STRING => bytes(value.as_str().ok_or_else(bad)?.as_bytes(), out),
+            TYPE_BYTES => bytes(&unhex(value.as_str().ok_or_else(bad)?)?, out),
+            TYPE_MESSAGE => bytes(&self.encode(value, &field.type_name)?, out),
+            // int32 and int64 negatives take all ten bytes
+            _ => put_varint(out, int().ok_or_else(bad)? as u64),
+        }
+        Ok(())
+    }
+}
+
+fn field(content: &[u8]) -> Result<Field, String> {
+    let mut field = Field {
+        name: String::new(),
+        number: 0,
+        kind: 0,
+        repeated: false,
+        packed: false,
+        type_name: String::new(),
+    };
+    for (number, value) in fields(content)? {
+        match (number, &value) {
+            (1, v) => field.name = text(v),
+            (3, WireValue::Varint(n)) => field.number = *n,
+            (4, WireValue::Varint(l)) => field.repeated = *l == LABEL_REPEATED,
+            (5, WireValue::Varint(t)) => field.kind = *t,
+            (6, v) => field.type_name = text(v),
+            (8, WireValue::Bytes(options)) => {
+                field.packed = fields(options)?
+                    .iter()
+                    .any(|(n, v)| *n == 2 && matches!(v, WireValue::Varint(1)));
+            }
+            _ => {}
+        }
+    }
+    if field.kind == 10 {
+        return Err(format!("{}: groups are not supported", field.name));
+    }
+    Ok(field)
+}
+
+/// The entry message of an image with `magic` (after IMG_COMMON_MAGIC).
+fn image_message(magic: &[u32]) -> Option<&'static str> {
+    let magic = magic.last()?;
+    IMAGES
+        .iter()
+        .find(|(m, _)| m == magic)
+        .map(|(_, name)| *name)
+}
+
+/// file_entry's `type` field and the values of it picked (fdinfo.proto).
+pub const FILE_ENTRY_TYPE: u64 = 1;
+pub const FD_TYPE_REG: u64 = 1;
+pub const FD_TYPE_PIPE: u64 = 2;
+pub const FD_TYPE_FIFO: u64 = 3;
+pub const FD_TYPE_INETSK: u64 = 4;
+pub const FD_TYPE_EVENTFD: u64 = 6;
+pub const FD_TYPE_EVENTPOLL: u64 = 7;
+pub const FD_TYPE_SIGNALFD: u64 = 9;
+pub const FD_TYPE_TIMERFD: u64 = 17;
+pub const FD_TYPE_MEMFD: u64 = 18;
+
+/// A v2 image split into its entries at the wire level, for only some of
+/// them to be decoded: most of a files.img is files and pipes the socket
+/// patches never look at, and their JSON round trip through crit is most of
+/// the time the image takes.
+pub struct Split<'a> {
+    /// The magics the image starts with.
+    header: &'a [u8],
+    entries: Vec<&'a [u8]>,
+    /// Indexes of the entries picked.
+    picked: Vec<usize>,
+}
+
+impl<'a> Split<'a> {
+    /// Split `content`, picking the entries whose varint field `number` is
+    /// one of `values`; None if it is not a v2 image of length-prefixed
+    /// entries.
+    pub fn new(content: &'a [u8], number: u64, values: &[u64]) -> Option<Split<'a>> {
+        let mut reader = Reader::new(content);
+        if reader.u32_le().ok()? != IMG_COMMON_MAGIC {
+            return None;
+        }
+        reader.u32_le().ok()?;
+        let header = &content[..reader.pos];
+        let mut entries = Vec::new();
+        let mut picked = Vec::new();
+        while !reader.done() {
+            let size = reader.u32_le().ok()? as usize;
+            let entry = reader.take(size).ok()?;
+            let is_picked = fields(entry).ok()?.iter().any(|(n, v)| {
+                *n == number && matches!(v, WireValue::Varint(v) if values.contains(v))
+            });
+            if is_picked {
+                picked.push(entries.len());
+            }
+            entries.push(entry);
+        }
+        Some(Split {
+            header,
+            entries,
+            picked,
+        })
+    }
+
+    /// How many entries the image has, picked or not.
+    pub fn entries(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// An image of the picked entries alone.
+    pub fn picked(&self) -> Vec<u8> {
+        image(self.header, self.picked.iter().map(|&i| self.entries[i]))
+    }
+
+    /// The whole image again, with the picked entries as in `picked`, an
+    /// image of as many entries.
+    pub fn join(&self, picked: &[u8]) -> Result<Vec<u8>, String> {
+        let mut reader = Reader::new(picked);
+        reader.take(self.header.len())?;
+        let mut patched = Vec::with_capacity(self.picked.len());
+        while !reader.done() {
+            let size = reader.u32_le()? as usize;
+            patched.push(reader.take(size)?);
+        }
+        if patched.len() != self.picked.len() {
+            return Err(format!(
+                "{} entries came back from the decode of {}",
+                patched.len(),
+                self.picked.len()
+            ));
+        }
+        let mut entries = self.entries.clone();
+        for (&i, entry) in self.picked.iter().zip(patched) {
+            entries[i] = entry;
+        }
+        Ok(image(self.header, entries.into_iter()))
+    }
+}
+
+/// An image of `header` and the length-prefixed `entries`.
+fn image<'a>(header: &[u8], entries: impl Iterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut out = header.to_vec();
+    for entry in entries {
+        out.extend((entry.len() as u32).to_le_bytes());
+        out.extend(entry);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn scalar(name: &str, number: u64, kind: u64) -> Field {
+        Field {
+            name: name.to_string(),
+            number,
+            kind,
+            repeated: false,
+            packed: false,
+            type_name: String::new(),
+        }
+    }
+
+    fn repeated(name: &str, number: u64, kind: u64, packed: bool) -> Field {
+        Field {
+            repeated: true,
+            packed,
+            ..scalar(name, number, kind)
+        }
+    }
+
+    /// A .file_entry of an enum, a uint32, packed and unpacked repeated
+    /// uint32s, an int32 and a sint32.
+    fn descriptors() -> Descriptors {
+        let mut descriptors = Descriptors::default();
+        let kind = Field {
+            type_name: ".fd_types".to_string(),
+            ..scalar("type", 1, TYPE_ENUM)
+        };
+        descriptors.messages.insert(
+            ".file_entry".to_string(),
+            vec![
+                kind,
+                scalar("id", 2, 13),
+                repeated("packed", 3, 13, true),
+                repeated("unpacked", 4, 13, false),
+                scalar("int", 5, 5),
+                scalar("sint", 6, 17),
+            ],
+        );
+        descriptors
+            .enums
+            .insert(".fd_types".to_string(), vec![("REG".to_string(), 1)]);
+        descriptors
+    }
+
+    /// A v2 files.img of `entries`.
+    fn files_img(entries: &[&[u8]]) -> Vec<u8> {
+        let header = [0x5456_4319u32, 0x5630_3138]
+            .iter()
+            .flat_map(|m| m.to_le_bytes())
+            .collect::<Vec<u8>>();
+        image(&header, entries.iter().copied())
+    }
+
+    const ENTRY: &[u8] = &[
+        0x08, 0x01, // type = REG
+        0x10, 0x07, // id = 7
+        0x1a, 0x03, 0x01, 0xac, 0x02, // packed = [1, 300]
+        0x20, 0x05, 0x20, 0x06, // unpacked = [5, 6]
+        0x28, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, // int = -2
+        0x30, 0x05, // sint = -3
+        0x48, 0x2a, // field 9, not in the descriptors
+    ];
+
+    #[test]
+    fn decodes_fields_and_keeps_unknown_ones() {
+        let data = descriptors()
+            .decode_image(&files_img(&[ENTRY]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            data["entries"],
+            json!([{
+                "type": "REG",
+                "id": 7,
+                "packed": [1, 300],
+                "unpacked": [5, 6],
+                "int": -2,
+                "sint": -3,
+                "_unknown": "482a",
+            }])
+        );
+        assert_eq!(data["magic"], json!([0x5456_4319, 0x5630_3138]));
+    }
+
+    #[test]
+    fn round_trips_byte_for_byte() {
+        let descriptors = descriptors();
+        let content = files_img(&[ENTRY, &[0x08, 0x01, 0x10, 0x08]]);
+        let data = descriptors.decode_image(&content).unwrap().unwrap();
+        assert_eq!(descriptors.encode_image(&data).unwrap().unwrap(), content);
+    }
+
+    #[test]
+    fn leaves_other_images_to_crit() {
+        let descriptors = descriptors();
+        let mut content = files_img(&[ENTRY]);
+        content[4..8].copy_from_slice(&0x5505_3847u32.to_le_bytes());
+        assert_eq!(descriptors.decode_image(&content).unwrap(), None);
+        assert_eq!(
+            descriptors.encode_image(&json!({"entries": []})).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn split_picks_entries_and_joins_them_back() {
+        let reg: &[u8] = &[0x08, 0x01, 0x10, 0x01];
+        let inet: &[u8] = &[0x08, 0x04, 0x10, 0x02];
+        let pipe: &[u8] = &[0x08, 0x02, 0x10, 0x03];
+        let content = files_img(&[reg, inet, pipe]);
+        let split = Split::new(&content, FILE_ENTRY_TYPE, &[FD_TYPE_INETSK]).unwrap();
+        assert_eq!(split.entries(), 3);
+        assert_eq!(split.picked(), files_img(&[inet]));
+        assert_eq!(split.join(&split.picked()).unwrap(), content);
+
+        let patched: &[u8] = &[0x08, 0x04, 0x10, 0x09];
+        assert_eq!(
+            split.join(&files_img(&[patched])).unwrap(),
+            files_img(&[reg, patched, pipe])
+        );
+        assert!(split.join(&files_img(&[])).is_err());
+        assert!(Split::new(&content[4..], FILE_ENTRY_TYPE, &[FD_TYPE_INETSK]).is_none());
+    }
+}
@@ -0,0 +1,406 @@
+//! `prune`: drop the entries of a checkpoint archive that the restore
+//! provably does without, shrinking what crosses the WAN:
+//!
+//! - the statistics and the log of the dump (stats-dump, dump.log);
+//! - with --ghost-cap, the ghost files (contents of deleted files, kept for
+//!   the processes holding them) larger than SIZE that no descriptor,
+//!   mapping, executable, working directory or root refers to, along with
+//!   their remap-fpath.img entries;
+//! - in a flattened pre-dump chain (podman's --with-previous export, where
+//!   checkpoint/parent links to ../pre-checkpoint), all images of the
+//!   parents but the pagemaps and pages the child's pagemaps mark as in the
+//!   parent: the restore reads nothing else from there.
+//!
+//! Every candidate is checked before it goes; a ghost file still held is
+//! kept and listed with its holders. --dry-run prints the analysis only.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use clap::Args;
+use serde_json::Value;
+
+use crate::buffers;
+use crate::compress;
+use crate::crit::{self, number};
+use crate::edit::RESTORE_INDEX_PATH;
+use crate::ghosts::ghost_remap;
+use crate::images;
+use crate::inject::entry_name;
+use crate::lock;
+use crate::rewrite::Rewrite;
+use crate::sign::{self, MANIFEST_PATH, SIGNATURE_PATH};
+use crate::sockets;
+
+#[derive(Args)]
+pub struct PruneArgs {
+    /// Checkpoint archive, changed in place
+    archive: PathBuf,
+    /// Also drop the ghost files larger than SIZE (e.g. 64M) that nothing
+    /// refers to any more
+    #[arg(long, value_name = "SIZE", value_parser = buffers::parse_bytes)]
+    ghost_cap: Option<usize>,
+    /// Print what would be dropped and why, and leave the archive alone
+    #[arg(long, short = 'n')]
+    dry_run: bool,
+}
+
+/// Statistics and log of the dump, which the restore does not read.
+const STATS: [&str; 3] = ["stats-dump", "checkpoint/stats-dump", "checkpoint/dump.log"];
+const REMAP_PATH: &str = "checkpoint/remap-fpath.img";
+/// Pagemap entry flag: the pages are in the parent's images.
+const PE_PARENT: u64 = 1;
+
+/// What the first pass over the archive gathers.
+#[derive(Default)]
+struct Scan {
+    /// Every entry, with its size.
+    entries: Vec<(String, u64)>,
+    /// Symlink targets, by entry.
+    links: BTreeMap<String, PathBuf>,
+    /// The pagemap images of every directory, by entry.
+    pagemaps: BTreeMap<String, Vec<u8>>,
+    /// The checkpoint/ images the ghost file analysis reads, by file name.
+    images: BTreeMap<String, Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Plan {
+    /// Entries to drop, with the reason.
+    drop: BTreeMap<String, &'static str>,
+    /// remap-fpath.img without the entries of the dropped ghost files.
+    remap: Option<Vec<u8>>,
+    /// Ghost files over the cap still held, with their holders.
+    held: Vec<(String, u64, Vec<String>)>,
+}
+
+fn scan(path: &Path, ghosts: bool) -> Result<Scan, String> {
+    let mut scan = Scan::default();
+    let mut archive = tar::Archive::new(compress::open(path)?);
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let name = entry_name(&entry.path().map_err(|e| e.to_string())?.to_string_lossy());
+        if name == MANIFEST_PATH || name == SIGNATURE_PATH {
+            return Err(sign::refuse(&path.display().to_string()));
+        }
+        let kind = entry.header().entry_type();
+        if kind.is_symlink() {
+            if let Some(link) = entry.link_name().map_err(|e| e.to_string())? {
+                scan.links.insert(name.clone(), link.into_owned());
+            }
+        }
+        let file = name.rsplit_once('/').map(|(_, file)| file).unwrap_or("");
+        let image = |prefix: &str| file.starts_with(prefix) && file.ends_with(".img");
+        let slot = if image("pagemap-") {
+            Some(name.clone())
+        } else if ghosts
+            && name.starts_with("checkpoint/")
+            && (name == REMAP_PATH || ["fdinfo-", "ids-", "mm-", "fs-"].iter().any(|p| image(p)))
+        {
+            Some(file.to_string())
+        } else {
+            None
+        };
+        if let Some(slot) = slot.filter(|_| kind.is_file()) {
+            let mut content = Vec::new();
+            entry
+                .read_to_end(&mut content)
+                .map_err(|e| format!("read {}: {}", name, e))?;
+            if slot == name {
+                scan.pagemaps.insert(slot, content);
+            } else {
+                scan.images.insert(slot, content);
+            }
+        }
+        scan.entries.push((name, entry.size()));
+    }
+    Ok(scan)
+}
+
+/// The directories of the pre-dump chain in the archive, from checkpoint/
+/// to the oldest parent.
+fn chain(links: &BTreeMap<String, PathBuf>) -> Vec<String> {
+    let mut dirs = vec!["checkpoint".to_string()];
+    while let Some(target) = links.get(&format!("{}/parent", dirs[dirs.len() - 1])) {
+        let mut parts: Vec<String> = dirs[dirs.len() - 1]
+            .split('/')
+            .map(str::to_string)
+            .collect();
+        let mut inside = true;
+        for component in target.components() {
+            match component {
+                Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+                Component::ParentDir => inside &= parts.pop().is_some(),
+                Component::CurDir => {}
+                // An absolute link leaves the archive
+                _ => inside = false,
+            }
+        }
+        let dir = parts.join("/");
+        if !inside || dir.is_empty() || dirs.contains(&dir) {
+            break;
+        }
+        dirs.push(dir);
+    }
+    dirs
+}
+
+/// The pages_id of a pagemap image, and whether it has pages in the parent.
+fn pagemap(content: &[u8]) -> Result<(Option<u64>, bool), String> {
+    let data = crit::decode(content)?;
+    let entries = data
+        .get("entries")
+        .and_then(|e| e.as_array())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let pages_id = entries
+        .first()
+        .and_then(|head| head.get("pages_id"))
+        .and_then(number);
+    let in_parent = entries.iter().skip(1).any(|e| {
+        e.get("in_parent").and_then(Value::as_bool) == Some(true)
+            || match e.get("flags") {
+                Some(Value::String(flags)) => flags.split('|').any(|f| f.trim() == "PE_PARENT"),
+                Some(flags) => flags.as_u64().is_some_and(|f| f & PE_PARENT != 0),
+                None => false,
+            }
+    });
+    Ok((pages_id, in_parent))
+}
+
+/// Drop what the restore does not read from the parents of the chain.
+fn plan_parents(scan: &Scan, plan: &mut Plan) -> Result<(), String> {
+    let dirs = chain(&scan.links);
+    // The pagemaps (by PID or shmem-ID) with pages in the level below
+    let mut needed = BTreeSet::new();
+    for (level, dir) in dirs.iter().enumerate() {
+        let prefix = format!("{}/", dir);
+        let mut keep = BTreeSet::new();
+        let mut next = BTreeSet::new();
+        for (name, content) in &scan.pagemaps {
+            let Some(key) = name
+                .strip_prefix(&prefix)
+                .and_then(|n| n.strip_prefix("pagemap-"))
+                .and_then(|n| n.strip_suffix(".img"))
+            else {
+                continue;
+            };
+            if level > 0 && !needed.contains(key) {
+                continue;
+            }
+            let (pages_id, in_parent) = pagemap(content)?;
+            keep.insert(name.clone());
+            if let Some(id) = pages_id {
+                keep.insert(format!("{}pages-{}.img", prefix, id));
+            }
+            if in_parent {
+                next.insert(key.to_string());
+            }
+        }
+        let link = format!("{}parent", prefix);
+        if next.is_empty() {
+            if scan.links.contains_key(&link) {
+                plan.drop
+                    .insert(link, "link to a parent no pages are read from");
+            }
+        } else {
+            keep.insert(link);
+        }
+        if level > 0 {
+            if !keep.is_empty() {
+                keep.insert(dir.clone());
+            }
+            for (name, _) in &scan.entries {
+                if (*name == *dir || name.starts_with(&prefix)) && !keep.contains(name) {
+                    let reason = if name.contains("/pagemap-") || name.contains("/pages-") {
+                        "pre-dump pages no pagemap of the child refers to"
+                    } else {
+                        "pre-dump image the restore does not read"
+                    };
+                    plan.drop.entry(name.clone()).or_insert(reason);
+                }
+            }
+        }
+        needed = next;
+    }
+    Ok(())
+}
+
+/// File id → what refers to it: the PID:FD descriptors, and the mappings,
+/// executable, working directory and root of each process.
+fn file_users(images: &BTreeMap<String, Vec<u8>>) -> Result<BTreeMap<u64, Vec<String>>, String> {
+    let mut users = sockets::fd_holders(images)?;
+    for (image, content) in images {
+        let Some(stem) = image.strip_suffix(".img") else {
+            continue;
+        };
+        let mut add = |id: Option<&Value>, user: String| {
+            if let Some(id) = id.and_then(number).filter(|id| *id != 0) {
+                users.entry(id).or_default().push(user);
+            }
+        };
+        if let Some(pid) = stem.strip_prefix("mm-") {
+            let mm = crit::decode(content)?;
+            let Some(mm) = mm.pointer("/entries/0") else {
+                continue;
+            };
+            add(mm.get("exe_file_id"), format!("executable of {}", pid));
+            let vmas = mm.get("vmas").and_then(|v| v.as_array());
+            for vma in vmas.into_iter().flatten() {
+                add(vma.get("shmid"), format!("mapping of {}", pid));
+            }
+        } else if let Some(pid) = stem.strip_prefix("fs-") {
+            let fs = crit::decode(content)?;
+            for (key, what) in [("cwd_id", "working directory"), ("root_id", "root")] {
+                let id = fs.pointer(&format!("/entries/0/{}", key));
+                add(id, format!("{} of {}", what, pid));
+            }
+        }
+    }
+    for list in users.values_mut() {
+        list.dedup();
+    }
+    Ok(users)
+}
+
+/// Drop the ghost files over `cap` that nothing refers to.
+fn plan_ghosts(scan: &Scan, cap: u64, plan: &mut Plan) -> Result<(), String> {
+    let ghosts: Vec<(&String, u64, u64)> = scan
+        .entries
+        .iter()
+        .filter(|(_, size)| *size > cap)
+        .filter_map(|(name, size)| {
+            let id = name
+                .strip_prefix("checkpoint/ghost-file-")?
+                .strip_suffix(".img")?;
+            Some((name, *size, u64::from_str_radix(id, 16).ok()?))
+        })
+        .collect();
+    if ghosts.is_empty() {
+        return Ok(());
+    }
+    let mut remap = match scan.images.get("remap-fpath.img") {
+        Some(content) => {
+            images::check_version("remap-fpath.img", content)?;
+            Some(crit::decode(content)?)
+        }
+        None => None,
+    };
+    // Ghost file id → the ids of the files it stands in for
+    let mut originals: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    let remaps = remap.as_ref().and_then(|r| r.get("entries")?.as_array());
+    for (orig, ghost) in remaps.into_iter().flatten().filter_map(ghost_remap) {
+        originals.entry(ghost).or_default().push(orig);
+    }
+    let users = file_users(&scan.images)?;
+    let mut dropped = BTreeSet::new();
+    for (name, size, id) in ghosts {
+        let held: Vec<String> = originals
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .flat_map(|orig| users.get(orig).into_iter().flatten().cloned())
+            .collect();
+        if held.is_empty() {
+            plan.drop.insert(
+                name.clone(),
+                "ghost file nothing in the checkpoint refers to",
+            );
+            dropped.insert(id);
+        } else {
+            plan.held.push((name.clone(), size, held));
+        }
+    }
+    let entries = remap
+        .as_mut()
+        .and_then(|r| r.get_mut("entries")?.as_array_mut());
+    if let Some(entries) = entries.filter(|_| !dropped.is_empty()) {
+        entries.retain(|e| !ghost_remap(e).is_some_and(|(_, ghost)| dropped.contains(&ghost)));
+        plan.remap = remap.as_ref().map(crit::encode).transpose()?;
+    }
+    Ok(())
+}
+
+/// Rewrite the archive without the entries `plan` drops. Returns whether the
+/// restore index was left out.
+fn write(path: &Path, plan: &Plan) -> Result<bool, String> {
+    let mut rewrite = Rewrite::start(path, path)?;
+    let mut archive = tar::Archive::new(compress::open(path)?);
+    let mut dropped_index = false;
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let name = entry_name(&entry.path().map_err(|e| e.to_string())?.to_string_lossy());
+        if plan.drop.contains_key(&name) {
+            continue;
+        }
+        if name == RESTORE_INDEX_PATH {
+            dropped_index = true;
+            continue;
+        }
+        let mut header = entry.header().clone();
+        let result = match &plan.remap {
+            Some(remap) if name == REMAP_PATH => {
+                header.set_size(remap.len() as u64);
+                header.set_cksum();
+                rewrite.builder().append(&header, remap.as_slice())
+            }
+            _ => rewrite.builder().append(&header, &mut entry),
+        };
+        result.map_err(|e| format!("write {}: {}", name, e))?;
+    }
+    rewrite.finish(Some(path))?;
+    Ok(dropped_index)
+}
+
+pub fn run(args: &PruneArgs) -> Result<(), String> {
+    let path = args.archive.as_path();
+    let _input_lock = lock::input(path)?;
+    let scan = scan(path, args.ghost_cap.is_some())?;
+    let mut plan = Plan::default();
+    for (name, _) in &scan.entries {
+        if STATS.contains(&name.as_str()) {
+            plan.drop
+                .insert(name.clone(), "statistics or log of the dump");
+        }
+    }
+    plan_parents(&scan, &mut plan)?;
+    if let Some(cap) = args.ghost_cap {
+        plan_ghosts(&scan, cap as u64, &mut plan)?;
+    }
+
+    let verb = if args.dry_run {
+        "Would drop"
+    } else {
+        "Dropped"
+    };
+    let mut freed = 0;
+    for (name, size) in &scan.entries {
+        if let Some(reason) = plan.drop.get(name) {
+            eprintln!("{} {} ({} bytes): {}", verb, name, size, reason);
+            freed += size;
+        }
+    }
+    for (name, size, held) in &plan.held {
+        eprintln!(
+            "Kept {} ({} bytes): held by {}",
+            name,
+            size,
+            held.join(", ")
+        );
+    }
+    if plan.drop.is_empty() {
+        // Leaves the archive as it was
+        eprintln!("Nothing to prune");
+        return Ok(());
+    }
+    let dropped_index = !args.dry_run && write(path, &plan)?;
+    eprintln!("{} {} entries, {} MiB", verb, plan.drop.len(), freed >> 20);
+    if dropped_index {
+        eprintln!(
+            "Left out {}, which lists the old entries; edit with --restore-order to write it again",
+            RESTORE_INDEX_PATH
+        );
+    }
+    Ok(())
+}
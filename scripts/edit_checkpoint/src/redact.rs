@@ -0,0 +1,249 @@
+//! Redact secrets from the memory pages (--redact, --redact-file): every
+//! occurrence of a pattern in the pages images is overwritten with zeros
+//! before the checkpoint leaves this node, keeping the images' sizes.
+//!
+//! A text pattern is also matched in UTF-16 (little endian), as JVM and .NET
+//! processes keep their strings; hex:BYTES matches the bytes only. The pages
+//! images are streamed through a window of the longest pattern, so a match
+//! across two pages is found as well, even though those pages need not be
+//! adjacent in the process. The patterns are kept out of the audit log and
+//! the report, which count the matches of each by its position.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde_json::json;
+
+use crate::report::Report;
+
+/// Bytes read from a pages image at a time.
+const CHUNK: usize = 1 << 20;
+
+#[derive(Clone)]
+pub struct Pattern {
+    /// The byte strings matched: the pattern, and its UTF-16 form for text.
+    forms: Vec<Vec<u8>>,
+}
+
+/// Only the length, so the pattern does not end up in the fingerprint.
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pattern({} bytes)", self.forms[0].len())
+    }
+}
+
+/// The patterns of a --redact-file.
+#[derive(Clone, Debug)]
+pub struct Patterns(pub Vec<Pattern>);
+
+/// Parse --redact: text, or hex:BYTES.
+pub fn parse_pattern(s: &str) -> Result<Pattern, String> {
+    if let Some(hex) = s.strip_prefix("hex:") {
+        let hex: String = hex.split_whitespace().collect();
+        if hex.is_empty() || !hex.len().is_multiple_of(2) {
+            return Err("hex: needs an even number of hex digits".to_string());
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| format!("hex:{} is not hex", hex))?;
+        return Ok(Pattern { forms: vec![bytes] });
+    }
+    // Shorter ones match by chance in any sizable memory dump
+    if s.len() < 4 {
+        return Err("a pattern needs at least 4 bytes".to_string());
+    }
+    let utf16 = s.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    Ok(Pattern {
+        forms: vec![s.as_bytes().to_vec(), utf16],
+    })
+}
+
+/// Parse --redact-file: a pattern per line, blank lines and # comments
+/// skipped.
+pub fn parse_file(path: &str) -> Result<Patterns, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("read {}: {}", path, e))?;
+    let patterns = content
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .enumerate()
+        .map(|(i, line)| {
+            parse_pattern(line).map_err(|e| format!("{}: pattern {}: {}", path, i + 1, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if patterns.is_empty() {
+        return Err(format!("{} has no patterns", path));
+    }
+    Ok(Patterns(patterns))
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Redaction {
+    pub patterns: Vec<Pattern>,
+}
+
+impl Redaction {
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// `input` with the patterns overwritten.
+    pub fn reader<R: Read>(&self, input: R) -> Redactor<'_, R> {
+        let window = self
+            .patterns
+            .iter()
+            .flat_map(|p| &p.forms)
+            .map(|f| f.len())
+            .max()
+            .unwrap_or(1);
+        Redactor {
+            input,
+            patterns: &self.patterns,
+            window,
+            buf: Vec::new(),
+            scanned: 0,
+            done: 0,
+            eof: false,
+            matches: vec![0; self.patterns.len()],
+        }
+    }
+
+    /// Redact the pages image at `path` in place. Returns the matches of
+    /// each pattern.
+    pub fn in_place(&self, path: &Path) -> Result<Vec<u64>, String> {
+        let open = |write| {
+            fs::OpenOptions::new()
+                .read(true)
+                .write(write)
+                .open(path)
+                .map_err(|e| format!("open {}: {}", path.display(), e))
+        };
+        let mut redactor = self.reader(open(false)?);
+        // The reader stays ahead of the writer by the window
+        let mut output = open(true)?;
+        io::copy(&mut redactor, &mut output)
+            .and_then(|_| output.flush())
+            .map_err(|e| format!("redact {}: {}", path.display(), e))?;
+        Ok(redactor.matches)
+    }
+
+    /// Redact `size` bytes of `source` at `offset` (an entry of the archive)
+    /// into `output`.
+    pub fn copy(
+        &self,
+        mut source: &fs::File,
+        offset: u64,
+        size: u64,
+        output: impl FnOnce(&mut dyn Read) -> io::Result<()>,
+    ) -> io::Result<Vec<u64>> {
+        source.seek(SeekFrom::Start(offset))?;
+        let mut redactor = self.reader(source.take(size));
+        output(&mut redactor)?;
+        Ok(redactor.matches)
+    }
+
+    /// Report the matches of each pattern over `images` pages images.
+    pub fn record(&self, matches: &[u64], images: usize, report: &mut Report) {
+        if self.is_empty() {
+            return;
+        }
+        let total: u64 = matches.iter().sum();
+        eprintln!(
+            "Redacted {} matches of {} patterns in {} pages images",
+            total,
+            self.patterns.len(),
+            images
+        );
+        if images == 0 {
+            report.warn("no pages images: nothing was redacted");
+        }
+        report.set(
+            "redacted",
+            json!({"matches": total, "per_pattern": matches, "images": images}),
+        );
+    }
+}
+
+/// Adds the matches of `other` to `matches`.
+pub fn add(matches: &mut [u64], other: &[u64]) {
+    for (m, o) in matches.iter_mut().zip(other) {
+        *m += o;
+    }
+}
+
+/// A reader overwriting the patterns in what it reads.
+pub struct Redactor<'a, R> {
+    input: R,
+    patterns: &'a [Pattern],
+    /// The length of the longest pattern form.
+    window: usize,
+    buf: Vec<u8>,
+    /// Matches starting before this offset of `buf` have been redacted.
+    scanned: usize,
+    /// The bytes of `buf` already returned.
+    done: usize,
+    eof: bool,
+    pub matches: Vec<u64>,
+}
+
+impl<R: Read> Redactor<'_, R> {
+    /// Redact the matches starting in buf[scanned..end].
+    fn scan(&mut self, end: usize) {
+        for (i, pattern) in self.patterns.iter().enumerate() {
+            for form in &pattern.forms {
+                let mut at = self.scanned;
+                while at < end && at + form.len() <= self.buf.len() {
+                    let Some(found) = self.buf[at..]
+                        .windows(form.len())
+                        .take(end - at)
+                        .position(|w| w == form.as_slice())
+                    else {
+                        break;
+                    };
+                    let start = at + found;
+                    self.buf[start..start + form.len()].fill(0);
+                    self.matches[i] += 1;
+                    at = start + form.len();
+                }
+            }
+        }
+        self.scanned = end;
+    }
+
+    /// Read more, scanning all that can no longer be the start of a match
+    /// continuing past the end of the buffer.
+    fn fill(&mut self) -> io::Result<()> {
+        self.buf.drain(..self.done);
+        self.scanned -= self.done;
+        self.done = 0;
+        let len = self.buf.len();
+        self.buf.resize(len + CHUNK, 0);
+        let n = self.input.read(&mut self.buf[len..])?;
+        self.buf.truncate(len + n);
+        self.eof = n == 0;
+        let end = if self.eof {
+            self.buf.len()
+        } else {
+            (self.buf.len() + 1).saturating_sub(self.window)
+        };
+        if end > self.scanned {
+            self.scan(end);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Redactor<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.done == self.scanned && !self.eof {
+            self.fill()?;
+        }
+        let n = out.len().min(self.scanned - self.done);
+        out[..n].copy_from_slice(&self.buf[self.done..self.done + n]);
+        self.done += n;
+        Ok(n)
+    }
+}
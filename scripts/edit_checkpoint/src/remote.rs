@@ -0,0 +1,42 @@
+//! Run helper commands locally or on another node over SSH.
+
+use std::process::Command;
+
+/// Build a command for `argv`, wrapped in `ssh user@host` when `host` is set.
+/// Remote arguments are shell-quoted so they survive the remote shell.
+pub fn command(host: Option<&str>, argv: &[String]) -> Command {
+    match host {
+        Some(host) => {
+            let mut c = Command::new("ssh");
+            c.args(["-o", "BatchMode=yes", host, "--"]);
+            c.arg(
+                argv.iter()
+                    .map(|a| shell_quote(a))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+            c
+        }
+        None => {
+            let mut c = Command::new(&argv[0]);
+            c.args(&argv[1..]);
+            c
+        }
+    }
+}
+
+/// Split a command override such as EDIT_CHECKPOINT_PODMAN="sudo podman"
+/// and append `args`. An override that is empty or blank is ignored.
+pub fn argv(env_var: &str, default: &str, args: &[&str]) -> Vec<String> {
+    let cmd = std::env::var(env_var)
+        .ok()
+        .filter(|cmd| !cmd.trim().is_empty())
+        .unwrap_or_else(|| default.to_string());
+    let mut argv: Vec<String> = cmd.split_whitespace().map(str::to_string).collect();
+    argv.extend(args.iter().map(|a| a.to_string()));
+    argv
+}
+
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
@@ -0,0 +1,154 @@
+//! Putting an edited archive in place of the original.
+//!
+//! A rename is atomic only within one local filesystem. When the archive is
+//! a symlink into another filesystem (a checkpoint store on NFS, say) the
+//! temp file next to the link is on the wrong side and rename fails with
+//! EXDEV; on NFS a rename the server carried out can still come back as an
+//! error when the reply is lost. So the new file is fsynced first; across
+//! filesystems it is copied next to the target and fsynced there; and on a
+//! network filesystem the original is moved aside and removed only once the
+//! new one is in place, so one of them is always there under a known name.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::buffers;
+
+/// How the archive was put in place.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Strategy {
+    Rename,
+    /// Copied to the target's filesystem, then renamed.
+    Copy,
+    /// Renamed over on a network filesystem with the original moved aside.
+    Swap,
+    /// Copied, then swapped.
+    CopySwap,
+}
+
+impl Strategy {
+    pub fn name(self) -> &'static str {
+        match self {
+            Strategy::Rename => "rename",
+            Strategy::Copy => "copy",
+            Strategy::Swap => "swap",
+            Strategy::CopySwap => "copy+swap",
+        }
+    }
+}
+
+fn sync_file(path: &Path) -> Result<(), String> {
+    fs::File::open(path)
+        .and_then(|f| f.sync_all())
+        .map_err(|e| format!("fsync {}: {}", path.display(), e))
+}
+
+/// fsync the directory holding `path`, so a rename in it is durable.
+fn sync_dir(path: &Path) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    // Not every filesystem lets directories be fsynced
+    let _ = fs::File::open(dir).and_then(|d| d.sync_all());
+}
+
+/// `path` with `suffix` appended to the file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Rename `from` over `to`, moving the file at `to` aside first and putting
+/// it back if the rename fails. A rename reported as failed that did happen
+/// (a lost NFS reply) is recognized by `from` being gone and `to` having its
+/// size.
+fn swap(from: &Path, to: &Path) -> Result<(), String> {
+    let size = fs::metadata(from).map_err(|e| e.to_string())?.len();
+    let aside = with_suffix(to, ".old");
+    let moved_aside = match fs::rename(to, &aside) {
+        Ok(()) => true,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => return Err(format!("move {} aside: {}", to.display(), e)),
+    };
+    if let Err(e) = fs::rename(from, to) {
+        let done = !from.exists() && fs::metadata(to).is_ok_and(|m| m.len() == size);
+        if !done {
+            if moved_aside {
+                let _ = fs::rename(&aside, to);
+            }
+            return Err(format!("rename {}: {}", from.display(), e));
+        }
+    }
+    sync_dir(to);
+    if moved_aside {
+        let _ = fs::remove_file(&aside);
+    }
+    Ok(())
+}
+
+/// Replace `dest` (or the file it links to) with `new`, which is removed.
+pub fn replace(new: &Path, dest: &Path) -> Result<Strategy, String> {
+    let target = fs::canonicalize(dest).unwrap_or_else(|_| dest.to_path_buf());
+    let target_dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let network = buffers::fs_type(&target_dir).is_some_and(|t| buffers::is_network_fs(&t));
+    sync_file(new)?;
+    let new_dev = fs::metadata(new).map_err(|e| e.to_string())?.dev();
+    let same_fs = fs::metadata(&target_dir).is_ok_and(|m| m.dev() == new_dev);
+
+    if same_fs {
+        if network {
+            swap(new, &target)?;
+            return Ok(Strategy::Swap);
+        }
+        match fs::rename(new, &target) {
+            Ok(()) => {
+                sync_dir(&target);
+                return Ok(Strategy::Rename);
+            }
+            // Told apart by device above, but bind mounts share one
+            Err(e) if e.raw_os_error() != Some(18) => {
+                return Err(format!("rename {}: {}", new.display(), e));
+            }
+            Err(_) => {}
+        }
+    }
+
+    // EXDEV: stage a copy on the target's filesystem first
+    let staged = with_suffix(&target, ".new");
+    let copied = fs::copy(new, &staged)
+        .and_then(|_| {
+            // fs::copy keeps the mode; keep the owner copy_owner gave `new`
+            let meta = fs::metadata(new)?;
+            std::os::unix::fs::chown(&staged, Some(meta.uid()), Some(meta.gid()))
+        })
+        .map_err(|e| e.to_string())
+        .and_then(|_| sync_file(&staged));
+    if let Err(e) = copied {
+        let _ = fs::remove_file(&staged);
+        return Err(format!(
+            "copy {} to {}: {}",
+            new.display(),
+            staged.display(),
+            e
+        ));
+    }
+    if network {
+        swap(&staged, &target)?;
+    } else {
+        fs::rename(&staged, &target).map_err(|e| format!("rename {}: {}", staged.display(), e))?;
+        sync_dir(&target);
+    }
+    let _ = fs::remove_file(new);
+    Ok(if network {
+        Strategy::CopySwap
+    } else {
+        Strategy::Copy
+    })
+}
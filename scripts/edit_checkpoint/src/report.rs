@@ -0,0 +1,75 @@
+//! Machine-readable JSON report of an edit (`--report FILE`), consumed by the
+//! migration scripts for the steps that follow the edit.
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+use crate::events;
+
+#[derive(Default)]
+pub struct Report {
+    fields: Map<String, Value>,
+}
+
+impl Report {
+    pub fn set(&mut self, key: &str, value: impl Into<Value>) {
+        self.fields.insert(key.to_string(), value.into());
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.fields.get(key)
+    }
+
+    /// Remove `key`, returning its value.
+    pub fn take(&mut self, key: &str) -> Option<Value> {
+        self.fields.remove(key)
+    }
+
+    /// Append `items` to the list under `key`.
+    pub fn extend(&mut self, key: &str, items: impl IntoIterator<Item = Value>) {
+        let list = self
+            .fields
+            .entry(key)
+            .or_insert_with(|| Value::Array(Vec::new()));
+        if let Value::Array(list) = list {
+            list.extend(items);
+        }
+    }
+
+    /// Print a warning and record it under "warnings".
+    pub fn warn(&mut self, message: impl Into<String>) {
+        let message = message.into();
+        eprintln!("Warning: {}", message);
+        events::warning(&message);
+        self.extend("warnings", [message.into()]);
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_vec_pretty(&self.fields).map_err(|e| e.to_string())?;
+        fs::write(path, json).map_err(|e| format!("write report {}: {}", path.display(), e))
+    }
+}
+
+/// `bytes` as du -h prints it: 512, 4.0K, 13M, 9.2G.
+pub fn human(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    if bytes < 1024 {
+        return bytes.to_string();
+    }
+    let mut size = bytes as f64;
+    let mut unit = "";
+    for next in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    if size < 10.0 {
+        format!("{:.1}{}", size, unit)
+    } else {
+        format!("{:.0}{}", size, unit)
+    }
+}
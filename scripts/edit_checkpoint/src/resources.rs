@@ -0,0 +1,103 @@
+//! What the target needs to restore the checkpoint, estimated during the
+//! edit and reported as `resources` (--target-memory refuses the edit when
+//! the memory would not fit).
+//!
+//! Memory: the dumped pages, which are resident again once restored, and the
+//! kernel objects of each open file in files.img. Disk: the unpacked archive
+//! podman keeps in the container's userdata, the rootfs diff applied to its
+//! layer, and the ghost files CRIU recreates. The page cache, what the
+//! processes allocate after the restore and CRIU's own memory are not
+//! counted, so both are lower bounds.
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::json;
+
+use crate::report::{human, Report};
+use crate::userns::ROOTFS_DIFF_PATH;
+
+/// Kernel memory per open file (struct file, dentry and inode), socket
+/// buffers aside.
+const PER_FILE: u64 = 1 << 10;
+
+#[derive(Default)]
+pub struct Estimate {
+    pages: u64,
+    ghost_files: u64,
+    rootfs_diff: u64,
+    unpacked: u64,
+}
+
+impl Estimate {
+    /// Count the entry at `path` of `size` bytes.
+    pub fn add(&mut self, path: &str, size: u64) {
+        self.unpacked += size;
+        let file = path.rsplit('/').next().unwrap_or(path);
+        if file.starts_with("pages-") && file.ends_with(".img") {
+            self.pages += size;
+        } else if file.starts_with("ghost-file-") {
+            self.ghost_files += size;
+        } else if path == ROOTFS_DIFF_PATH {
+            self.rootfs_diff += size;
+        }
+    }
+
+    /// Count the images of an unpacked checkpoint, and its metadata
+    /// directory's entries if given.
+    pub fn add_dir(&mut self, images: &Path, root: Option<&Path>) -> Result<(), String> {
+        let dirs = [Some((images, "checkpoint/")), root.map(|root| (root, ""))];
+        for (dir, prefix) in dirs.into_iter().flatten() {
+            let entries =
+                fs::read_dir(dir).map_err(|e| format!("read {}: {}", dir.display(), e))?;
+            for entry in entries.filter_map(|e| e.ok()) {
+                let Some(meta) = entry.metadata().ok().filter(|m| m.is_file()) else {
+                    continue;
+                };
+                let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+                self.add(&name, meta.len());
+            }
+        }
+        Ok(())
+    }
+
+    /// Report the estimate, with the open files patch_files_img counted, and
+    /// refuse it over `target_memory`.
+    pub fn check(&self, target_memory: Option<u64>, report: &mut Report) -> Result<(), String> {
+        let open_files = report
+            .get("open_files")
+            .and_then(|n| n.as_u64())
+            .unwrap_or(0);
+        let memory = self.pages + open_files * PER_FILE;
+        let disk = self.unpacked + self.rootfs_diff + self.ghost_files;
+        report.set(
+            "resources",
+            json!({
+                "memory_bytes": memory,
+                "disk_bytes": disk,
+                "pages_bytes": self.pages,
+                "open_files": open_files,
+            }),
+        );
+        let Some(limit) = target_memory else {
+            return Ok(());
+        };
+        if memory > limit {
+            return Err(format!(
+                "restoring needs at least {} of memory ({} of dumped pages, {} open files), \
+                 more than the target's {}",
+                human(memory),
+                human(self.pages),
+                open_files,
+                human(limit)
+            ));
+        }
+        eprintln!(
+            "Restore needs at least {} of the target's {} of memory, and {} of disk",
+            human(memory),
+            human(limit),
+            human(disk)
+        );
+        Ok(())
+    }
+}
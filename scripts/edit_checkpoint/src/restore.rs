@@ -0,0 +1,180 @@
+//! Restoring an edited checkpoint on the target node: the `podman container
+//! restore` command it needs, printed and reported as restore_command after
+//! every edit, and a systemd unit running it (--unit), so activating the
+//! container there is a `systemctl start`.
+//!
+//! The flags follow from what the edit did: --tcp-established when files.img
+//! still has established connections to repair (podman refuses the archive
+//! without it), --ignore-static-ip when the static IP was cleared for the
+//! target network to assign one, and whatever --restore-arg adds. The unit is
+//! a plain service rather than a Quadlet .container, which always creates the
+//! container with `podman run` and has no way to restore one.
+
+use std::fs;
+use std::path::Path;
+
+use crate::remote::shell_quote;
+use crate::report::Report;
+
+pub const PODMAN: &str = "/usr/bin/podman";
+
+/// Where the target restores the edited checkpoint from.
+#[derive(Clone, Copy)]
+pub enum Source<'a> {
+    Archive(&'a str),
+    /// A checkpoint image (--image).
+    Image(&'a str),
+}
+
+/// The arguments of `podman container restore` for `source`, given the
+/// report of its edit.
+fn args(source: Source, extra: &[String], report: &Report) -> Vec<String> {
+    let mut args = vec!["container".to_string(), "restore".to_string()];
+    if let Source::Archive(archive) = source {
+        args.push(format!("--import={}", archive));
+    }
+    let established = report.get("tcp_established").and_then(|n| n.as_u64());
+    if established.is_some_and(|n| n > 0) {
+        args.push("--tcp-established".to_string());
+    }
+    if report.get("new_addr_source").and_then(|s| s.as_str()) == Some("cleared") {
+        args.push("--ignore-static-ip".to_string());
+    }
+    args.extend(extra.iter().cloned());
+    if let Source::Image(image) = source {
+        args.push(image.to_string());
+    }
+    args
+}
+
+/// Print the restore command for `source` and record it in the report as
+/// restore_command. Returns its podman arguments.
+pub fn command(source: Source, extra: &[String], report: &mut Report) -> Vec<String> {
+    // An edit found already applied reads no files.img
+    if report.get("already_applied").is_some() {
+        report.warn(
+            "the edit was already applied, so the restore command lacks the flags it \
+             derives from the edit (--tcp-established, --ignore-static-ip); add them \
+             with --restore-arg if needed",
+        );
+    }
+    let args = args(source, extra, report);
+    let argv = [vec!["podman".to_string()], args.clone()].concat();
+    let plain = |a: &str| {
+        a.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_=./:,@".contains(c))
+    };
+    let line: Vec<String> = argv
+        .iter()
+        .map(|a| if plain(a) { a.clone() } else { shell_quote(a) })
+        .collect();
+    eprintln!("Restore on the target with:\n  {}", line.join(" "));
+    report.set("restore_command", argv);
+    args
+}
+
+/// Quote `arg` for an Exec line of a unit file: specifiers and variables
+/// are escaped, and arguments with spaces or quotes double-quoted.
+fn unit_quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty() && !escaped.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c))
+    {
+        return escaped;
+    }
+    format!(
+        "\"{}\"",
+        escaped
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace(';', "\\;")
+    )
+}
+
+fn exec_line(args: &[String]) -> String {
+    let mut line = PODMAN.to_string();
+    for arg in args {
+        line.push(' ');
+        line.push_str(&unit_quote(arg));
+    }
+    line
+}
+
+/// The service restoring the container `name` with `restore` (the podman
+/// arguments).
+fn unit(name: &str, source: Source, restore: &[String], rootless: bool) -> String {
+    let podman = |args: &[&str]| exec_line(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>());
+    let remove = podman(&["rm", "--ignore", "--force", name]);
+    let (from, mounts) = match source {
+        Source::Archive(archive) => (
+            archive,
+            format!("RequiresMountsFor={}\n", unit_quote(archive)),
+        ),
+        Source::Image(image) => (image, String::new()),
+    };
+    format!(
+        "# Restores the checkpoint {from} edited by edit_checkpoint {version}\n\
+         [Unit]\n\
+         Description=Restore container {name} from its checkpoint\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         {mounts}\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         RemainAfterExit=yes\n\
+         TimeoutStartSec=600\n\
+         ExecStartPre=-{remove}\n\
+         ExecStart={restore}\n\
+         ExecStop={stop}\n\
+         ExecStopPost=-{remove}\n\
+         \n\
+         [Install]\n\
+         WantedBy={target}\n",
+        version = env!("CARGO_PKG_VERSION"),
+        restore = exec_line(restore),
+        stop = podman(&["stop", "--ignore", name]),
+        target = if rootless {
+            "default.target"
+        } else {
+            "multi-user.target"
+        },
+    )
+}
+
+/// Write the unit running `restore` (from `command`) to `path`.
+pub fn write_unit(
+    path: &Path,
+    source: Source,
+    restore: &[String],
+    report: &mut Report,
+) -> Result<(), String> {
+    if let Source::Archive(archive) = source {
+        if !archive.starts_with('/') {
+            return Err(format!(
+                "--unit: the archive path {} is not absolute; pass --restore-archive",
+                archive
+            ));
+        }
+    }
+    let name = report
+        .get("container")
+        .and_then(|n| n.as_str())
+        .ok_or("--unit: the checkpoint has no container name in config.dump")?
+        .to_string();
+    let rootless = report.get("rootless").and_then(|r| r.as_bool()) == Some(true);
+    let content = unit(&name, source, restore, rootless);
+    fs::write(path, content).map_err(|e| format!("write {}: {}", path.display(), e))?;
+    let dir = if rootless {
+        "~/.config/systemd/user"
+    } else {
+        "/etc/systemd/system"
+    };
+    eprintln!(
+        "Wrote {}; install it in {} on the target and start it to restore {}",
+        path.display(),
+        dir,
+        name
+    );
+    report.set("unit", path.display().to_string());
+    Ok(())
+}
@@ -0,0 +1,256 @@
+//! Resumable edits of very large archives (--resumable).
+//!
+//! The edit is written to OUTPUT.new as usual, but an interrupted or failed
+//! run leaves it there with a journal, OUTPUT.new.progress: how many of the
+//! input's entries OUTPUT.new holds in full and how many bytes they make up,
+//! saved (after an fsync of OUTPUT.new) every SAVE_EVERY bytes of output. The
+//! journal names the input by path, size and mtime, and the edit by its
+//! fingerprint; a run with the same ones resumes instead of starting over.
+//!
+//! Resuming runs the edit pass from the start, since the edited entries and
+//! what they leave in the report are needed again, but the entries copied as
+//! they are (the pages images, the bulk of the archive) are skipped without
+//! being read. The edited ones are compared with what OUTPUT.new holds where
+//! they would go, so a run that would write anything else fails instead of
+//! splicing two different edits together. Past the journal's last entry the
+//! edit writes as usual.
+//!
+//! Only the plain path resumes: a local, uncompressed archive and output,
+//! without --io-uring or signing (the signature hashes every byte written).
+
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::report::{self, Report};
+
+/// Output bytes between journal saves; each save is an fsync of the output.
+const SAVE_EVERY: u64 = 256 << 20;
+
+struct State {
+    /// OUTPUT.new, for fsyncs and comparing what is written again.
+    file: File,
+    journal: PathBuf,
+    key: Value,
+    /// Bytes of output the edit pass has produced so far.
+    position: u64,
+    /// Output position at the last save.
+    saved: u64,
+    /// Entries and bytes of output from the run resumed.
+    resumed: Option<(u64, u64)>,
+    /// When the first of the runs of this edit started, Unix seconds.
+    started: u64,
+}
+
+/// Progress of a resumable edit, shared by the edit pass and its output.
+#[derive(Clone)]
+pub struct Progress(Rc<RefCell<State>>);
+
+/// The output of a resumable edit: what it already holds is compared, not
+/// written again.
+pub struct Tracked<W> {
+    inner: W,
+    progress: Progress,
+}
+
+fn journal_path(new_path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.progress", new_path.display()))
+}
+
+/// What a journal has to match for its OUTPUT.new to be resumed: the input
+/// as it was and the same edit.
+fn key(input: &Path, fingerprint: Option<&str>) -> Result<Value, String> {
+    let meta = fs::metadata(input).map_err(|e| format!("{}: {}", input.display(), e))?;
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos().to_string());
+    Ok(json!({
+        "input": input.display().to_string(),
+        "size": meta.len(),
+        "mtime_ns": mtime,
+        "fingerprint": fingerprint,
+    }))
+}
+
+impl Progress {
+    /// Open `new_path` (locked by the caller) for the edit of `input`:
+    /// resumed where the journal says if it belongs to this edit, empty
+    /// otherwise.
+    pub fn open(
+        input: &Path,
+        new_path: &Path,
+        fingerprint: Option<&str>,
+        report: &mut Report,
+    ) -> Result<(Progress, File), String> {
+        let key = key(input, fingerprint)?;
+        let journal = journal_path(new_path);
+        let earlier = fs::read(&journal)
+            .ok()
+            .and_then(|j| serde_json::from_slice::<Value>(&j).ok());
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(new_path)
+            .map_err(|e| format!("open {}: {}", new_path.display(), e))?;
+        let len = file.metadata().map_err(|e| e.to_string())?.len();
+        let resumed = earlier
+            .as_ref()
+            .filter(|j| j["key"] == key)
+            .and_then(|j| Some((j["entries"].as_u64()?, j["written"].as_u64()?)))
+            .filter(|(_, written)| *written <= len);
+        match resumed {
+            Some((entries, written)) => {
+                eprintln!(
+                    "Resuming the edit after {} entries ({} of {} written)",
+                    entries,
+                    report::human(written),
+                    new_path.display()
+                );
+                report.set("resumed", json!({ "entries": entries, "written": written }));
+            }
+            None if earlier.is_some() => {
+                eprintln!(
+                    "{} is from another edit or input; starting over",
+                    journal.display()
+                );
+            }
+            None => {}
+        }
+        let written = resumed.map_or(0, |(_, written)| written);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let started = match (resumed, &earlier) {
+            (Some(_), Some(earlier)) => earlier["started"].as_u64().unwrap_or(now),
+            _ => now,
+        };
+        file.set_len(written)
+            .and_then(|()| file.seek(SeekFrom::End(0)))
+            .map_err(|e| format!("truncate {}: {}", new_path.display(), e))?;
+        let state = State {
+            file: file
+                .try_clone()
+                .map_err(|e| format!("open {}: {}", new_path.display(), e))?,
+            journal,
+            key,
+            position: 0,
+            saved: written,
+            resumed,
+            started,
+        };
+        Ok((Progress(Rc::new(RefCell::new(state))), file))
+    }
+
+    /// When the edit first started, for the entries it adds to be the same
+    /// when written again.
+    pub fn started(&self) -> u64 {
+        self.0.borrow().started
+    }
+
+    pub fn writer<W: Write>(&self, inner: W) -> Tracked<W> {
+        Tracked {
+            inner,
+            progress: self.clone(),
+        }
+    }
+
+    /// Whether the copy of an entry of `size` bytes is already in the
+    /// output, counting it as written if so.
+    pub fn skip(&self, size: u64) -> bool {
+        let mut state = self.0.borrow_mut();
+        let Some((_, written)) = state.resumed else {
+            return false;
+        };
+        // As copy_entry writes it: header, data, padding to 512 bytes
+        let len = 512 + size.next_multiple_of(512);
+        if state.position + len > written {
+            return false;
+        }
+        state.position += len;
+        true
+    }
+
+    /// The first `entries` of the input are in `output`: refuse a resumed
+    /// run that does not line up with the journal, and save the journal if
+    /// SAVE_EVERY bytes were written since it last was.
+    pub fn checkpoint<W: Write>(&self, entries: u64, output: &mut W) -> Result<(), String> {
+        let mut state = self.0.borrow_mut();
+        if let Some((resumed, written)) = state.resumed {
+            if entries == resumed && state.position != written {
+                return Err(format!(
+                    "the resumed edit does not line up with the {} bytes written before; \
+                     remove {} to start over",
+                    written,
+                    state.journal.display()
+                ));
+            }
+            if entries <= resumed {
+                return Ok(());
+            }
+        }
+        if state.position.saturating_sub(state.saved) < SAVE_EVERY {
+            return Ok(());
+        }
+        output
+            .flush()
+            .and_then(|()| state.file.sync_data())
+            .map_err(|e| format!("sync the output: {}", e))?;
+        let journal = json!({
+            "key": state.key,
+            "started": state.started,
+            "entries": entries,
+            "written": state.position,
+        });
+        let tmp = PathBuf::from(format!("{}.tmp", state.journal.display()));
+        fs::write(&tmp, journal.to_string())
+            .and_then(|()| fs::rename(&tmp, &state.journal))
+            .map_err(|e| format!("write {}: {}", state.journal.display(), e))?;
+        state.saved = state.position;
+        Ok(())
+    }
+
+    /// The edit is complete: the journal has served its purpose.
+    pub fn finish(&self) {
+        let _ = fs::remove_file(&self.0.borrow().journal);
+    }
+}
+
+impl<W: Write> Write for Tracked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.progress.0.borrow_mut();
+        let written = state.resumed.map_or(0, |(_, written)| written);
+        if state.position >= written {
+            let n = self.inner.write(buf)?;
+            state.position += n as u64;
+            return Ok(n);
+        }
+        // Already in the output: it has to be the same
+        let n = buf.len().min((written - state.position) as usize);
+        let mut there = vec![0; n];
+        state.file.read_exact_at(&mut there, state.position)?;
+        if there != buf[..n] {
+            return Err(io::Error::other(format!(
+                "the resumed edit differs from what was written before at byte {}; \
+                 remove {} to start over",
+                state.position,
+                state.journal.display()
+            )));
+        }
+        state.position += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,82 @@
+//! Rewriting a checkpoint archive entry by entry, for the commands that
+//! change it outside of an edit (undo, anonymize, inject, delete): the new
+//! archive is written to DEST.new, compressed like the input, and renamed
+//! over DEST once complete. A rewrite that fails leaves DEST as it was and
+//! removes DEST.new.
+
+use std::fs;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use crate::compress::{self, Compression, OutputCompression};
+use crate::edit;
+use crate::interrupt;
+use crate::lock::{self, Lock};
+use crate::replace;
+
+pub type Builder = tar::Builder<compress::Writer<BufWriter<fs::File>>>;
+
+pub struct Rewrite {
+    /// None once finished.
+    builder: Option<Builder>,
+    new_path: PathBuf,
+    dest: PathBuf,
+    _lock: Lock,
+    _partial: interrupt::Guard,
+}
+
+impl Rewrite {
+    /// Start rewriting `input` (already locked by the caller) into `dest`.
+    pub fn start(input: &Path, dest: &Path) -> Result<Rewrite, String> {
+        let new_path = PathBuf::from(format!("{}.new", dest.display()));
+        let lock = lock::output(&new_path)?;
+        let compression = OutputCompression {
+            kind: if compress::is_zstd(input)? {
+                Compression::Zstd
+            } else {
+                Compression::None
+            },
+            ..Default::default()
+        };
+        let out_file = fs::File::create(&new_path)
+            .map_err(|e| format!("create {}: {}", new_path.display(), e))?;
+        let output = compress::Writer::new(BufWriter::new(out_file), &compression)
+            .map_err(|e| format!("zstd: {}", e))?;
+        let partial = interrupt::remove_file(&new_path);
+        Ok(Rewrite {
+            builder: Some(tar::Builder::new(output)),
+            new_path,
+            dest: dest.to_path_buf(),
+            _lock: lock,
+            _partial: partial,
+        })
+    }
+
+    pub fn builder(&mut self) -> &mut Builder {
+        self.builder.as_mut().expect("rewrite already finished")
+    }
+
+    /// Complete the archive and put it in place, owned like `owner` (the
+    /// input, when it is rewritten in place).
+    pub fn finish(mut self, owner: Option<&Path>) -> Result<(), String> {
+        let builder = self.builder.take().expect("rewrite already finished");
+        let written = builder
+            .into_inner()
+            .and_then(|w| w.finish())
+            .map_err(|e| format!("write {}: {}", self.new_path.display(), e))
+            .and_then(|_| owner.map_or(Ok(()), |o| edit::copy_owner(o, &self.new_path)))
+            .and_then(|()| replace::replace(&self.new_path, &self.dest));
+        if written.is_err() {
+            let _ = fs::remove_file(&self.new_path);
+        }
+        written.map(|_| ())
+    }
+}
+
+impl Drop for Rewrite {
+    fn drop(&mut self) {
+        if self.builder.is_some() {
+            let _ = fs::remove_file(&self.new_path);
+        }
+    }
+}
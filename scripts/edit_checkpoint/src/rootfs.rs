@@ -0,0 +1,223 @@
+//! The image the container's root filesystem comes from.
+//!
+//! Podman restores the container on top of the image named by rootfsImageName
+//! and rootfsImageID in config.dump (RawImageName is what the user typed, and
+//! createCommand repeats it); CRI-O keeps the name and ID in the
+//! io.kubernetes.cri-o.ImageName/ImageRef annotations of spec.dump.
+//! --image-map OLD=NEW rewrites a reference the target does not know, e.g. a
+//! tag pulled from a mirror or the digest it has the image under. OLD matches
+//! a name (docker.io/library/ and :latest may be left out) or an ID.
+//!
+//! --image-check asks the target's podman API (podman system service, on a
+//! unix socket or tcp://HOST:PORT) whether the image exists before the edit
+//! is done, and fails with the `podman pull` to run if it does not.
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::metadata::{CONFIG_DUMP_PATH, SPEC_DUMP_PATH};
+use crate::report::Report;
+
+const CRIO_IMAGE_ANNOTATIONS: [&str; 2] = [
+    "io.kubernetes.cri-o.ImageName",
+    "io.kubernetes.cri-o.ImageRef",
+];
+
+/// The fully qualified form of an image name: registry, repository and tag
+/// or digest. IDs (hex, optionally sha256:) are returned unchanged.
+fn normalize(reference: &str) -> String {
+    let hex = reference.strip_prefix("sha256:").unwrap_or(reference);
+    if !hex.is_empty() && hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return hex.to_string();
+    }
+    let (first, _) = reference.split_once('/').unwrap_or(("", reference));
+    let has_registry = first.contains('.') || first.contains(':') || first == "localhost";
+    let mut name = if has_registry {
+        reference.to_string()
+    } else if reference.contains('/') {
+        format!("docker.io/{}", reference)
+    } else {
+        format!("docker.io/library/{}", reference)
+    };
+    let last = name.rsplit('/').next().unwrap_or_default();
+    if !last.contains(':') && !last.contains('@') {
+        name.push_str(":latest");
+    }
+    name
+}
+
+fn lookup<'a>(reference: &str, map: &'a [(String, String)]) -> Option<&'a str> {
+    let reference = normalize(reference);
+    map.iter()
+        .find(|(old, _)| normalize(old) == reference)
+        .map(|(_, new)| new.as_str())
+}
+
+/// Replace the string at `parent[key]` if `map` has it. Returns the old and
+/// new values.
+fn patch_field(
+    parent: &mut Value,
+    key: &str,
+    map: &[(String, String)],
+) -> Option<(String, String)> {
+    let old = parent.get(key)?.as_str()?.to_string();
+    let new = lookup(&old, map)?.to_string();
+    parent[key] = new.as_str().into();
+    Some((old, new))
+}
+
+pub fn patch_config_dump(
+    content: &[u8],
+    map: &[(String, String)],
+    report: &mut Report,
+) -> Result<Vec<u8>, String> {
+    let mut data: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse config.dump: {}", e))?;
+    let mut changes = Vec::new();
+    for key in ["rootfsImageName", "rootfsImageID", "RawImageName"] {
+        if let Some((old, mut new)) = patch_field(&mut data, key, map) {
+            if key == "rootfsImageID" {
+                // Podman stores the bare hex ID
+                new = new.trim_start_matches("sha256:").to_string();
+                data[key] = new.as_str().into();
+            }
+            changes.push((key, old, new));
+        }
+    }
+    // createCommand names the image as typed: the last argument equal to a
+    // name just replaced
+    let names: Vec<(String, String)> = changes
+        .iter()
+        .filter(|(key, _, _)| *key != "rootfsImageID")
+        .map(|(_, old, new)| (old.clone(), new.clone()))
+        .collect();
+    if let Some(args) = data.get_mut("createCommand").and_then(|c| c.as_array_mut()) {
+        let image_arg = args.iter_mut().skip(1).rev().find_map(|arg| {
+            let (old, new) = names.iter().find(|(old, _)| arg == old.as_str())?;
+            *arg = new.as_str().into();
+            Some((old.clone(), new.clone()))
+        });
+        if let Some((old, new)) = image_arg {
+            changes.push(("createCommand", old, new));
+        }
+    }
+    record(CONFIG_DUMP_PATH, changes, report);
+    serde_json::to_vec(&data).map_err(|e| format!("serialize config.dump: {}", e))
+}
+
+pub fn patch_spec_dump(
+    content: &[u8],
+    map: &[(String, String)],
+    report: &mut Report,
+) -> Result<Vec<u8>, String> {
+    let mut data: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse spec.dump: {}", e))?;
+    let mut changes = Vec::new();
+    if let Some(annotations) = data.get_mut("annotations") {
+        for key in CRIO_IMAGE_ANNOTATIONS {
+            if let Some((old, new)) = patch_field(annotations, key, map) {
+                changes.push((key, old, new));
+            }
+        }
+    }
+    record(SPEC_DUMP_PATH, changes, report);
+    serde_json::to_vec(&data).map_err(|e| format!("serialize spec.dump: {}", e))
+}
+
+fn record(file: &str, changes: Vec<(&str, String, String)>, report: &mut Report) {
+    for (field, old, new) in &changes {
+        eprintln!("Image {} in {}: {} → {}", field, file, old, new);
+    }
+    report.extend(
+        "image_map",
+        changes.into_iter().map(|(field, old, new)| {
+            serde_json::json!({"file": file, "field": field, "old": old, "new": new})
+        }),
+    );
+}
+
+/// The image the container will be restored on, after `map`.
+pub fn reference(
+    config: Option<&Value>,
+    spec: Option<&Value>,
+    map: &[(String, String)],
+) -> Option<String> {
+    let name = config
+        .and_then(|c| c.get("rootfsImageName"))
+        .or_else(|| spec.and_then(|s| s.pointer("/annotations/io.kubernetes.cri-o.ImageName")))
+        .and_then(|n| n.as_str())
+        .filter(|n| !n.is_empty())?;
+    Some(lookup(name, map).unwrap_or(name).to_string())
+}
+
+/// Whether the podman service at `api` (unix:///PATH, a socket path,
+/// tcp://HOST:PORT or http://HOST:PORT) has `image`.
+fn image_exists(api: &str, image: &str) -> Result<bool, String> {
+    let path = format!("/v4.0.0/libpod/images/{}/exists", image);
+    let timeout = Duration::from_secs(5);
+    if let Some(host) = api
+        .strip_prefix("tcp://")
+        .or_else(|| api.strip_prefix("http://"))
+    {
+        let url = format!("http://{}{}", host.trim_end_matches('/'), path);
+        let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+        return match agent.get(&url).call() {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(404, _)) => Ok(false),
+            Err(e) => Err(format!("{}: {}", url, e)),
+        };
+    }
+    let socket = api.strip_prefix("unix://").unwrap_or(api);
+    let mut stream =
+        UnixStream::connect(socket).map_err(|e| format!("connect {}: {}", socket, e))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    write!(stream, "GET {} HTTP/1.0\r\nHost: d\r\n\r\n", path)
+        .map_err(|e| format!("{}: {}", socket, e))?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|e| format!("{}: {}", socket, e))?;
+    let status = response.split_whitespace().nth(1).unwrap_or_default();
+    match status {
+        "204" | "200" => Ok(true),
+        "404" => Ok(false),
+        _ => Err(format!(
+            "{}: unexpected response {}",
+            socket,
+            response.lines().next().unwrap_or_default()
+        )),
+    }
+}
+
+/// Fail unless the target behind `api` has `image`, naming the pull to run.
+pub fn check_image(api: &str, image: &str, report: &mut Report) -> Result<(), String> {
+    let present = image_exists(api, image)?;
+    let url = if api.starts_with('/') {
+        format!("unix://{}", api)
+    } else {
+        api.to_string()
+    };
+    let pull = format!("podman --url {} pull {}", url, image);
+    report.set(
+        "image_check",
+        serde_json::json!({
+            "api": api,
+            "image": image,
+            "present": present,
+            "pull": if present { None } else { Some(pull.as_str()) },
+        }),
+    );
+    if !present {
+        return Err(format!(
+            "image {} is not on the target; pull it first:\n  {}",
+            image, pull
+        ));
+    }
+    eprintln!("Image {} is present on the target", image);
+    Ok(())
+}
@@ -0,0 +1,433 @@
+//! s3://BUCKET/KEY checkpoint sources and destinations: AWS S3, or MinIO and
+//! other S3-compatible stores with AWS_ENDPOINT_URL (path-style requests).
+//!
+//! Credentials and region come from AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY,
+//! AWS_SESSION_TOKEN and AWS_REGION (default us-east-1); requests are signed
+//! with SigV4. A checkpoint is read with streaming GETs (one for the metadata,
+//! one for the edit). The edited archive goes up as a multipart upload whose
+//! parts a thread sends while the edit produces the next one, so nothing is
+//! staged on local disk; an upload that fails or is not completed is aborted.
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::report::Report;
+
+/// 16 MiB parts allow archives up to 160 GB (10000 parts).
+const PART_SIZE: usize = 16 * 1024 * 1024;
+/// Parts queued for the upload thread.
+const QUEUED_PARTS: usize = 2;
+const ATTEMPTS: u32 = 3;
+
+pub fn is_s3(path: &str) -> bool {
+    path.starts_with("s3://")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encode all but the unreserved characters (and `/` if `path`).
+fn uri_encode(s: &str, path: bool) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b'/' if path => "/".to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The SigV4 timestamp (YYYYMMDDTHHMMSSZ) of `time`.
+fn amz_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+#[derive(Clone)]
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    token: Option<String>,
+    region: String,
+}
+
+impl Credentials {
+    fn from_env() -> Result<Credentials, String> {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        Ok(Credentials {
+            access_key: var("AWS_ACCESS_KEY_ID").ok_or("s3: AWS_ACCESS_KEY_ID is not set")?,
+            secret_key: var("AWS_SECRET_ACCESS_KEY")
+                .ok_or("s3: AWS_SECRET_ACCESS_KEY is not set")?,
+            token: var("AWS_SESSION_TOKEN"),
+            region: var("AWS_REGION")
+                .or_else(|| var("AWS_DEFAULT_REGION"))
+                .unwrap_or_else(|| "us-east-1".to_string()),
+        })
+    }
+}
+
+/// An object in a bucket, with what it takes to reach it.
+#[derive(Clone)]
+pub struct Object {
+    bucket: String,
+    key: String,
+    /// AWS_ENDPOINT_URL, for S3-compatible stores.
+    endpoint: Option<String>,
+    credentials: Credentials,
+    agent: ureq::Agent,
+}
+
+impl Object {
+    /// The object an s3:// URL names, or None for anything else.
+    pub fn parse(url: &str) -> Result<Option<Object>, String> {
+        let Some(rest) = url.strip_prefix("s3://") else {
+            return Ok(None);
+        };
+        let (bucket, key) = rest
+            .split_once('/')
+            .filter(|(b, k)| !b.is_empty() && !k.is_empty())
+            .ok_or_else(|| format!("{}: expected s3://BUCKET/KEY", url))?;
+        Ok(Some(Object {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            endpoint: env::var("AWS_ENDPOINT_URL").ok().filter(|v| !v.is_empty()),
+            credentials: Credentials::from_env()?,
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(10))
+                .timeout_read(Duration::from_secs(300))
+                .build(),
+        }))
+    }
+
+    fn name(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.key)
+    }
+
+    /// A request for the object signed with SigV4; `query` is in canonical
+    /// order.
+    fn request(&self, method: &str, query: &[(&str, &str)], payload: &[u8]) -> ureq::Request {
+        let key = uri_encode(&self.key, true);
+        let (base, host, path) = match &self.endpoint {
+            Some(endpoint) => {
+                let endpoint = endpoint.trim_end_matches('/');
+                let host = endpoint.split_once("://").map_or(endpoint, |(_, h)| h);
+                let path = format!("/{}/{}", uri_encode(&self.bucket, false), key);
+                (endpoint.to_string(), host.to_string(), path)
+            }
+            None => {
+                let host = format!(
+                    "{}.s3.{}.amazonaws.com",
+                    self.bucket, self.credentials.region
+                );
+                (format!("https://{}", host), host, format!("/{}", key))
+            }
+        };
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k, false), uri_encode(v, false)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let date = amz_date(SystemTime::now());
+        let payload_hash = hex(&Sha256::digest(payload));
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", date.clone()),
+        ];
+        if let Some(token) = &self.credentials.token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            path,
+            query,
+            headers
+                .iter()
+                .map(|(k, v)| format!("{}:{}\n", k, v))
+                .collect::<String>(),
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", &date[..8], self.credentials.region);
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            date,
+            scope,
+            hex(&Sha256::digest(canonical.as_bytes()))
+        );
+        let mut key = hmac(
+            format!("AWS4{}", self.credentials.secret_key).as_bytes(),
+            &date[..8],
+        );
+        for part in [self.credentials.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part);
+        }
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.credentials.access_key,
+            scope,
+            signed_headers,
+            hex(&hmac(&key, &to_sign))
+        );
+        let url = if query.is_empty() {
+            format!("{}{}", base, path)
+        } else {
+            format!("{}{}?{}", base, path, query)
+        };
+        let mut request = self
+            .agent
+            .request(method, &url)
+            .set("Authorization", &authorization);
+        // ureq sets Host itself
+        for (name, value) in &headers[1..] {
+            request = request.set(name, value);
+        }
+        request
+    }
+
+    /// Send a signed request, retrying server errors and transport failures.
+    fn send(
+        &self,
+        method: &str,
+        query: &[(&str, &str)],
+        payload: &[u8],
+    ) -> Result<ureq::Response, String> {
+        let mut last_error = String::new();
+        for attempt in 0..ATTEMPTS {
+            if attempt > 0 {
+                thread::sleep(Duration::from_millis(500 << attempt));
+            }
+            match self.request(method, query, payload).send_bytes(payload) {
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(code, response)) if code < 500 => {
+                    let body = response.into_string().unwrap_or_default();
+                    return Err(format!(
+                        "{} {}: HTTP {} {}",
+                        method,
+                        self.name(),
+                        code,
+                        body
+                    ));
+                }
+                Err(e) => last_error = e.to_string(),
+            }
+        }
+        Err(format!("{} {}: {}", method, self.name(), last_error))
+    }
+
+    /// The object's content, streamed.
+    pub fn get(&self) -> Result<impl Read + Send, String> {
+        Ok(self.send("GET", &[], b"")?.into_reader())
+    }
+}
+
+impl std::fmt::Display for Object {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+/// The text of the first `<tag>` in an XML response.
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let len = xml[start..].find(&format!("</{}>", tag))?;
+    Some(&xml[start..start + len])
+}
+
+/// A multipart upload of the edited archive, written as it is produced.
+/// Must be `complete`d; dropping it aborts the upload.
+pub struct Upload {
+    object: Object,
+    upload_id: String,
+    part: Vec<u8>,
+    parts_sent: u32,
+    bytes: u64,
+    queue: Option<SyncSender<(u32, Vec<u8>)>>,
+    /// Uploads the queued parts and returns their ETags.
+    worker: Option<JoinHandle<Result<Vec<String>, String>>>,
+    completed: bool,
+}
+
+impl Upload {
+    pub fn start(object: &Object) -> Result<Upload, String> {
+        let response = object.send("POST", &[("uploads", "")], b"")?;
+        let body = response.into_string().map_err(|e| e.to_string())?;
+        let upload_id = xml_value(&body, "UploadId")
+            .ok_or_else(|| format!("POST {}?uploads: no UploadId in {}", object, body))?
+            .to_string();
+        let (queue, parts) = mpsc::sync_channel::<(u32, Vec<u8>)>(QUEUED_PARTS);
+        let (worker_object, worker_id) = (object.clone(), upload_id.clone());
+        let worker = thread::spawn(move || {
+            let mut etags = Vec::new();
+            for (number, part) in parts {
+                let number = number.to_string();
+                let response = worker_object.send(
+                    "PUT",
+                    &[("partNumber", &number), ("uploadId", &worker_id)],
+                    &part,
+                )?;
+                let etag = response
+                    .header("ETag")
+                    .ok_or_else(|| format!("part {} of {}: no ETag", number, worker_object))?;
+                etags.push(etag.to_string());
+            }
+            Ok(etags)
+        });
+        Ok(Upload {
+            object: object.clone(),
+            upload_id,
+            part: Vec::with_capacity(PART_SIZE),
+            parts_sent: 0,
+            bytes: 0,
+            queue: Some(queue),
+            worker: Some(worker),
+            completed: false,
+        })
+    }
+
+    /// Hand the buffered part to the upload thread.
+    fn send_part(&mut self) -> Result<(), String> {
+        let part = std::mem::replace(&mut self.part, Vec::with_capacity(PART_SIZE));
+        self.parts_sent += 1;
+        let queue = self.queue.as_ref().expect("upload not finished");
+        if queue.send((self.parts_sent, part)).is_err() {
+            // The thread stopped on an error
+            return Err(self.join().err().unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    fn join(&mut self) -> Result<Vec<String>, String> {
+        self.queue = None;
+        match self.worker.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err("s3 upload thread panicked".to_string()),
+            None => Err("s3 upload already finished".to_string()),
+        }
+    }
+
+    /// Upload the last part and complete the upload.
+    pub fn complete(mut self, report: &mut Report) -> Result<(), String> {
+        if !self.part.is_empty() || self.parts_sent == 0 {
+            self.send_part()?;
+        }
+        let etags = self.join()?;
+        let parts: String = etags
+            .iter()
+            .enumerate()
+            .map(|(i, etag)| {
+                format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    i + 1,
+                    etag
+                )
+            })
+            .collect();
+        let body = format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            parts
+        );
+        let response =
+            self.object
+                .send("POST", &[("uploadId", &self.upload_id)], body.as_bytes())?;
+        // Errors can come as 200 with an Error body
+        let result = response.into_string().map_err(|e| e.to_string())?;
+        if result.contains("<Error>") {
+            return Err(format!("complete upload to {}: {}", self.object, result));
+        }
+        self.completed = true;
+        eprintln!(
+            "Uploaded {} ({} MiB in {} parts)",
+            self.object,
+            self.bytes >> 20,
+            etags.len()
+        );
+        report.set(
+            "upload",
+            serde_json::json!({
+                "object": self.object.name(),
+                "bytes": self.bytes,
+                "parts": etags.len(),
+            }),
+        );
+        Ok(())
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(PART_SIZE - self.part.len());
+        self.part.extend_from_slice(&data[..n]);
+        self.bytes += n as u64;
+        if self.part.len() == PART_SIZE {
+            self.send_part().map_err(io::Error::other)?;
+        }
+        Ok(n)
+    }
+
+    /// Parts go out whole; the last one with `complete`.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        if self.worker.is_some() {
+            let _ = self.join();
+        }
+        match self
+            .object
+            .send("DELETE", &[("uploadId", &self.upload_id)], b"")
+        {
+            Ok(_) => eprintln!("Aborted the upload to {}", self.object),
+            Err(e) => eprintln!(
+                "Warning: could not abort the upload to {}: {}",
+                self.object, e
+            ),
+        }
+    }
+}
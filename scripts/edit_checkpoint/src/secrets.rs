@@ -0,0 +1,128 @@
+//! Remap the host paths secrets are bind-mounted from.
+//!
+//! Podman mounts its secrets from the container's storage (secretsPath in
+//! config.dump), Kubernetes from the kubelet's pod volumes
+//! (.../volumes/kubernetes.io~secret/NAME) into /run/secrets or
+//! /var/run/secrets. Both host paths are node specific. --secrets-map
+//! OLD=NEW replaces the OLD prefix of those sources in config.dump (the
+//! secrets path and the copy of the spec) and in spec.dump; other mounts are
+//! left alone.
+
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::report::Report;
+
+/// Mount destinations secrets are mounted under.
+const SECRET_DESTINATIONS: [&str; 2] = ["/run/secrets", "/var/run/secrets"];
+
+/// One remapped source, for the report.
+pub struct Remapped {
+    pub destination: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// Replace the first OLD prefix of `map` that `path` starts with (whole path
+/// components only).
+pub fn remap_path(path: &str, map: &[(String, String)]) -> Option<String> {
+    map.iter().find_map(|(old, new)| {
+        let rest = Path::new(path)
+            .strip_prefix(old.trim_end_matches('/'))
+            .ok()?;
+        let new = Path::new(new).join(rest);
+        Some(new.to_string_lossy().trim_end_matches('/').to_string())
+    })
+}
+
+fn is_secret_mount(source: &str, destination: &str) -> bool {
+    let under = |dir: &str| Path::new(destination).starts_with(dir);
+    SECRET_DESTINATIONS.iter().any(|d| under(d))
+        || Path::new(source)
+            .components()
+            .any(|c| c.as_os_str() == "kubernetes.io~secret" || c.as_os_str() == "secrets")
+}
+
+/// Remap the secret mount sources of an OCI runtime spec.
+fn patch_mounts(spec: &mut Value, map: &[(String, String)], remapped: &mut Vec<Remapped>) {
+    let mounts = spec.get_mut("mounts").and_then(|m| m.as_array_mut());
+    for mount in mounts.into_iter().flatten() {
+        let destination = mount
+            .get("destination")
+            .and_then(|d| d.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let Some(source) = mount.get("source").and_then(|s| s.as_str()) else {
+            continue;
+        };
+        if !is_secret_mount(source, &destination) {
+            continue;
+        }
+        if let Some(new) = remap_path(source, map) {
+            remapped.push(Remapped {
+                destination,
+                old: source.to_string(),
+                new: new.clone(),
+            });
+            mount["source"] = new.into();
+        }
+    }
+}
+
+pub fn patch_config_dump(
+    content: &[u8],
+    map: &[(String, String)],
+) -> Result<(Vec<u8>, Vec<Remapped>), String> {
+    let mut data: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse config.dump: {}", e))?;
+    let mut remapped = Vec::new();
+    if let Some(old) = data.get("secretsPath").and_then(|p| p.as_str()) {
+        if let Some(new) = remap_path(old, map) {
+            remapped.push(Remapped {
+                destination: "secretsPath".to_string(),
+                old: old.to_string(),
+                new: new.clone(),
+            });
+            data["secretsPath"] = new.into();
+        }
+    }
+    if let Some(spec) = data.get_mut("spec").filter(|s| s.is_object()) {
+        patch_mounts(spec, map, &mut remapped);
+    }
+    let content = serde_json::to_vec(&data).map_err(|e| format!("serialize config.dump: {}", e))?;
+    Ok((content, remapped))
+}
+
+pub fn patch_spec_dump(
+    content: &[u8],
+    map: &[(String, String)],
+) -> Result<(Vec<u8>, Vec<Remapped>), String> {
+    let mut data: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse spec.dump: {}", e))?;
+    let mut remapped = Vec::new();
+    patch_mounts(&mut data, map, &mut remapped);
+    let content = serde_json::to_vec(&data).map_err(|e| format!("serialize spec.dump: {}", e))?;
+    Ok((content, remapped))
+}
+
+/// Log the remapped sources of `file` and add them to the report's list.
+pub fn record(file: &str, remapped: Vec<Remapped>, report: &mut Report) {
+    for r in &remapped {
+        eprintln!(
+            "Secret {} in {}: {} → {}",
+            r.destination, file, r.old, r.new
+        );
+    }
+    report.extend(
+        "secrets",
+        remapped.into_iter().map(|r| {
+            serde_json::json!({
+                "file": file,
+                "destination": r.destination,
+                "old": r.old,
+                "new": r.new,
+            })
+        }),
+    );
+}
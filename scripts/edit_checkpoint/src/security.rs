@@ -0,0 +1,372 @@
+//! Security settings recorded in config.dump and spec.dump that must match
+//! the target node's policy for the restore to be allowed.
+//!
+//! SELinux: podman keeps the process and mount labels in config.dump
+//! (ProcessLabel, MountLabel, labelopts) and spec.dump (process.selinuxLabel,
+//! linux.mountLabel, `context=` mount options) and hands the process label to
+//! CRIU at restore. --selinux-map rewrites them for a different policy;
+//! --selinux-disable drops them as `--security-opt label=disable` would.
+//!
+//! AppArmor: the profile is process.apparmorProfile in spec.dump and in the
+//! copy of the spec inside config.dump; the runtime hands it to CRIU as the
+//! LSM profile to restore into. --apparmor-profile renames it for a target
+//! that has the profile loaded under another name; --apparmor-clear restores
+//! unconfined, which keeps CRIU from re-applying the checkpointed profile.
+//!
+//! Seccomp: linux.seccomp in both specs is what the runtime would compile
+//! for the container; CRIU itself restores each task's filters from the core
+//! images. --seccomp strip removes both, --seccomp replace=FILE swaps the
+//! spec's configuration for FILE (an OCI linux.seccomp object) and leaves the
+//! task filters alone.
+//!
+//! Capabilities: process.capabilities in both specs. --cap-add puts a
+//! capability in the bounding, effective and permitted sets, --cap-drop takes
+//! it out of all five (`--cap-drop ALL` empties them). CRIU restores the
+//! credentials of the checkpointed tasks from their core images, so the
+//! change applies to processes started in the container afterwards, such as
+//! `podman exec`.
+
+use serde_json::Value;
+
+use crate::crit;
+use crate::metadata::{CONFIG_DUMP_PATH, SPEC_DUMP_PATH};
+use crate::report::Report;
+
+/// How to rewrite SELinux labels.
+#[derive(Clone)]
+pub enum SelinuxPatch {
+    /// OLD=NEW pairs. OLD matches a whole label or one of its fields (user,
+    /// role, type, or the MLS level such as s0:c1,c2).
+    Map(Vec<(String, String)>),
+    Disable,
+}
+
+#[derive(Clone, Debug)]
+pub enum SeccompPatch {
+    Strip,
+    Replace(Value),
+}
+
+/// Parse --seccomp: `strip` or `replace=FILE`.
+pub fn parse_seccomp(s: &str) -> Result<SeccompPatch, String> {
+    if s == "strip" {
+        return Ok(SeccompPatch::Strip);
+    }
+    let path = s
+        .strip_prefix("replace=")
+        .ok_or_else(|| format!("{} is not strip or replace=FILE", s))?;
+    let content = std::fs::read(path).map_err(|e| format!("read {}: {}", path, e))?;
+    let profile: Value =
+        serde_json::from_slice(&content).map_err(|e| format!("parse {}: {}", path, e))?;
+    if profile.get("archMap").is_some() {
+        return Err(format!(
+            "{} is a podman/Docker seccomp profile; give the OCI linux.seccomp form",
+            path
+        ));
+    }
+    if profile.get("defaultAction").is_none() {
+        return Err(format!("{} has no defaultAction", path));
+    }
+    Ok(SeccompPatch::Replace(profile))
+}
+
+/// Parse a --cap-add/--cap-drop capability: `net_raw`, `CAP_NET_RAW` or `ALL`.
+pub fn parse_cap(s: &str) -> Result<String, String> {
+    let upper = s.to_ascii_uppercase();
+    if upper == "ALL" {
+        return Ok(upper);
+    }
+    let name = upper.strip_prefix("CAP_").unwrap_or(&upper);
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_') {
+        return Err(format!("{} is not a capability name", s));
+    }
+    Ok(format!("CAP_{}", name))
+}
+
+/// Parse a --cap-add capability; unlike --cap-drop, ALL is not accepted.
+pub fn parse_cap_add(s: &str) -> Result<String, String> {
+    match parse_cap(s)?.as_str() {
+        "ALL" => Err("--cap-add ALL is not supported; name the capabilities".to_string()),
+        cap => Ok(cap.to_string()),
+    }
+}
+
+const CAP_SETS: [&str; 5] = [
+    "bounding",
+    "effective",
+    "inheritable",
+    "permitted",
+    "ambient",
+];
+const CAP_ADD_SETS: [&str; 3] = ["bounding", "effective", "permitted"];
+
+/// Security edits of one pass.
+#[derive(Clone, Default)]
+pub struct SecurityPatch {
+    pub selinux: Option<SelinuxPatch>,
+    /// New AppArmor profile name ("unconfined" to clear).
+    pub apparmor: Option<String>,
+    pub seccomp: Option<SeccompPatch>,
+    /// CAP_* names to add, and to drop ("ALL" for every capability).
+    pub cap_add: Vec<String>,
+    pub cap_drop: Vec<String>,
+}
+
+impl SecurityPatch {
+    pub fn is_empty(&self) -> bool {
+        self.selinux.is_none()
+            && self.apparmor.is_none()
+            && self.seccomp.is_none()
+            && self.cap_add.is_empty()
+            && self.cap_drop.is_empty()
+    }
+
+    /// Whether the task seccomp filters in the core images are dropped.
+    pub fn strips_seccomp(&self) -> bool {
+        matches!(self.seccomp, Some(SeccompPatch::Strip))
+    }
+}
+
+/// Record in the report the security edits that change what the restored
+/// container is allowed to do.
+pub fn record(patch: &SecurityPatch, report: &mut Report) {
+    if !patch.cap_add.is_empty() || !patch.cap_drop.is_empty() {
+        report.set(
+            "capabilities",
+            serde_json::json!({"add": patch.cap_add, "drop": patch.cap_drop}),
+        );
+    }
+    match &patch.seccomp {
+        Some(SeccompPatch::Strip) => {
+            report.warn("seccomp stripped: the container is restored without a syscall filter")
+        }
+        Some(SeccompPatch::Replace(_)) => report.warn(
+            "seccomp replaced in the spec only: the checkpointed task filters are restored as they were",
+        ),
+        None => {}
+    }
+}
+
+/// Disable seccomp for the task in a core-PID.img (and its main thread).
+pub fn strip_core_seccomp(content: &[u8]) -> Result<Vec<u8>, String> {
+    let mut data = crit::decode(content)?;
+    for entry in data
+        .get_mut("entries")
+        .and_then(|e| e.as_array_mut())
+        .into_iter()
+        .flatten()
+    {
+        for core in ["tc", "thread_core"] {
+            let Some(core) = entry.get_mut(core).and_then(|c| c.as_object_mut()) else {
+                continue;
+            };
+            if let Some(mode) = core.get_mut("seccomp_mode") {
+                // Keep the rendering crit used: enum names or numbers
+                *mode = if mode.is_string() {
+                    "disabled".into()
+                } else {
+                    0.into()
+                };
+            }
+            core.remove("seccomp_filter");
+        }
+    }
+    crit::encode(&data)
+}
+
+/// Parse an OLD=NEW pair of a --*-map option.
+pub fn parse_map(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+            Ok((old.to_string(), new.to_string()))
+        }
+        _ => Err(format!("{} is not OLD=NEW", s)),
+    }
+}
+
+fn remap_label(label: &str, map: &[(String, String)]) -> String {
+    let lookup = |s: &str| map.iter().find(|(old, _)| old == s).map(|(_, new)| new);
+    if let Some(new) = lookup(label) {
+        return new.clone();
+    }
+    label
+        .splitn(4, ':')
+        .map(|field| lookup(field).map_or(field, |new| new.as_str()))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Apply `selinux` to the label at `parent[key]`, removing it when disabling.
+/// Returns whether anything changed.
+fn patch_label(parent: &mut Value, key: &str, selinux: &SelinuxPatch) -> bool {
+    let Some(label) = parent.get(key).and_then(|l| l.as_str()) else {
+        return false;
+    };
+    match selinux {
+        SelinuxPatch::Map(map) => {
+            let new = remap_label(label, map);
+            let changed = new != label;
+            parent[key] = new.into();
+            changed
+        }
+        SelinuxPatch::Disable => parent
+            .as_object_mut()
+            .is_some_and(|o| o.remove(key).is_some()),
+    }
+}
+
+pub fn patch_config_dump(content: &[u8], patch: &SecurityPatch) -> Result<Vec<u8>, String> {
+    let mut data: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse config.dump: {}", e))?;
+    if let Some(spec) = data.get_mut("spec").filter(|s| s.is_object()) {
+        patch_spec(spec, patch, CONFIG_DUMP_PATH)?;
+    }
+    if let Some(selinux) = &patch.selinux {
+        let mut changed = patch_label(&mut data, "ProcessLabel", selinux);
+        changed |= patch_label(&mut data, "MountLabel", selinux);
+        if let SelinuxPatch::Disable = selinux {
+            data["labelopts"] = serde_json::json!(["disable"]);
+            eprintln!("Disabled SELinux labeling in config.dump");
+        } else if changed {
+            eprintln!("Remapped SELinux labels in config.dump");
+        }
+    }
+    serde_json::to_vec(&data).map_err(|e| format!("serialize config.dump: {}", e))
+}
+
+pub fn patch_spec_dump(content: &[u8], patch: &SecurityPatch) -> Result<Vec<u8>, String> {
+    let mut data: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse spec.dump: {}", e))?;
+    patch_spec(&mut data, patch, SPEC_DUMP_PATH)?;
+    serde_json::to_vec(&data).map_err(|e| format!("serialize spec.dump: {}", e))
+}
+
+/// Apply `patch` to an OCI runtime spec: spec.dump, or the copy in
+/// config.dump (`file` names which, for the messages).
+fn patch_spec(data: &mut Value, patch: &SecurityPatch, file: &str) -> Result<(), String> {
+    if !data.is_object() {
+        return Err(format!("{} is not an object", file));
+    }
+    if let Some(profile) = &patch.apparmor {
+        if !data.get("process").is_some_and(Value::is_object) {
+            data["process"] = serde_json::json!({});
+        }
+        let old = data["process"]
+            .get("apparmorProfile")
+            .and_then(|p| p.as_str())
+            .unwrap_or("(none)")
+            .to_string();
+        data["process"]["apparmorProfile"] = profile.as_str().into();
+        eprintln!("AppArmor profile in {}: {} → {}", file, old, profile);
+    }
+    if !patch.cap_add.is_empty() || !patch.cap_drop.is_empty() {
+        patch_caps(data, patch, file)?;
+    }
+    match &patch.seccomp {
+        Some(SeccompPatch::Strip) => {
+            let removed = data
+                .get_mut("linux")
+                .and_then(|l| l.as_object_mut())
+                .and_then(|l| l.remove("seccomp"));
+            if removed.is_some() {
+                eprintln!("Removed seccomp from {}", file);
+            }
+        }
+        Some(SeccompPatch::Replace(profile)) => {
+            if !data.get("linux").is_some_and(Value::is_object) {
+                data["linux"] = serde_json::json!({});
+            }
+            data["linux"]["seccomp"] = profile.clone();
+            eprintln!("Replaced seccomp in {}", file);
+        }
+        None => {}
+    }
+    if let Some(selinux) = &patch.selinux {
+        let mut changed = false;
+        if let Some(process) = data.get_mut("process") {
+            changed |= patch_label(process, "selinuxLabel", selinux);
+        }
+        if let Some(linux) = data.get_mut("linux") {
+            changed |= patch_label(linux, "mountLabel", selinux);
+        }
+        let mounts = data.get_mut("mounts").and_then(|m| m.as_array_mut());
+        for mount in mounts.into_iter().flatten() {
+            let Some(options) = mount.get_mut("options").and_then(|o| o.as_array_mut()) else {
+                continue;
+            };
+            let before = options.len();
+            options.retain_mut(|opt| {
+                let Some(label) = opt
+                    .as_str()
+                    .and_then(|o| o.strip_prefix("context="))
+                    .map(|l| l.trim_matches('"').to_string())
+                else {
+                    return true;
+                };
+                match selinux {
+                    SelinuxPatch::Map(map) => {
+                        let new = remap_label(&label, map);
+                        changed |= new != label;
+                        *opt = format!("context=\"{}\"", new).into();
+                        true
+                    }
+                    SelinuxPatch::Disable => false,
+                }
+            });
+            changed |= options.len() != before;
+        }
+        if changed {
+            eprintln!(
+                "{} SELinux labels in {}",
+                match selinux {
+                    SelinuxPatch::Map(_) => "Remapped",
+                    SelinuxPatch::Disable => "Removed",
+                },
+                file
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Apply --cap-drop, then --cap-add, to process.capabilities.
+fn patch_caps(data: &mut Value, patch: &SecurityPatch, file: &str) -> Result<(), String> {
+    let spec = data
+        .as_object_mut()
+        .ok_or_else(|| format!("{} is not an object", file))?;
+    let process = spec
+        .entry("process")
+        .or_insert_with(|| serde_json::json!({}));
+    if !process.is_object() {
+        *process = serde_json::json!({});
+    }
+    if !process.get("capabilities").is_some_and(Value::is_object) {
+        process["capabilities"] = serde_json::json!({});
+    }
+    let caps = &mut process["capabilities"];
+    let drop_all = patch.cap_drop.iter().any(|c| c == "ALL");
+    for set in CAP_SETS {
+        let Some(list) = caps.get_mut(set).and_then(|l| l.as_array_mut()) else {
+            continue;
+        };
+        list.retain(|c| !drop_all && !patch.cap_drop.iter().any(|d| c == d.as_str()));
+    }
+    for set in CAP_ADD_SETS {
+        if !caps.get(set).is_some_and(Value::is_array) {
+            caps[set] = serde_json::json!([]);
+        }
+        let list = caps[set].as_array_mut().expect("set is an array");
+        for cap in &patch.cap_add {
+            if !list.iter().any(|c| c == cap.as_str()) {
+                list.push(cap.as_str().into());
+            }
+        }
+    }
+    eprintln!(
+        "Capabilities in {}: +[{}] -[{}]",
+        file,
+        patch.cap_add.join(","),
+        patch.cap_drop.join(",")
+    );
+    Ok(())
+}
@@ -0,0 +1,234 @@
+//! `sockets`: export the checkpoint's socket table as CSV or JSON.
+//!
+//! One row per INETSK in files.img: protocol, state, source and destination
+//! address and port, inode, and the PID:FD pairs holding it (from the
+//! fdinfo-N.img tables, mapped to processes through ids-PID.img). Useful to
+//! see what a checkpoint will restore, and as input to traffic steering.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::{crit, images, inaddr, proto};
+
+#[derive(Args)]
+pub struct SocketsArgs {
+    /// Checkpoint archive or unpacked checkpoint directory
+    checkpoint: PathBuf,
+    /// Write JSON instead of CSV
+    #[arg(long)]
+    json: bool,
+    /// Write the table to FILE instead of stdout
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+const COLUMNS: [&str; 9] = [
+    "proto", "family", "state", "src_addr", "src_port", "dst_addr", "dst_port", "ino", "fds",
+];
+
+pub fn run(args: &SocketsArgs) -> Result<(), String> {
+    let rows = read_table(&args.checkpoint)?;
+
+    let out = if args.json {
+        serde_json::to_string_pretty(&rows).map_err(|e| e.to_string())? + "\n"
+    } else {
+        let mut out = COLUMNS.join(",") + "\n";
+        for row in &rows {
+            let fields: Vec<String> = COLUMNS
+                .iter()
+                .map(|c| match &row[*c] {
+                    Value::String(s) => s.clone(),
+                    Value::Array(fds) => fds
+                        .iter()
+                        .filter_map(|f| f.as_str())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                    Value::Null => String::new(),
+                    v => v.to_string(),
+                })
+                .collect();
+            out += &(fields.join(",") + "\n");
+        }
+        out
+    };
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, out).map_err(|e| format!("write {}: {}", path.display(), e))?
+        }
+        None => std::io::stdout()
+            .write_all(out.as_bytes())
+            .map_err(|e| e.to_string())?,
+    }
+    eprintln!("{} sockets", rows.len());
+    Ok(())
+}
+
+/// The socket table of a checkpoint archive or directory, one JSON object
+/// per socket with the COLUMNS as keys, in files.img order.
+pub fn read_table(checkpoint: &Path) -> Result<Vec<Value>, String> {
+    let wanted = |name: &str| {
+        name == "files.img"
+            || (name.ends_with(".img") && (name.starts_with("fdinfo-") || name.starts_with("ids-")))
+    };
+    let images = images::read(checkpoint, wanted)?;
+    let files = images
+        .get("files.img")
+        .ok_or_else(|| format!("no files.img in {}", checkpoint.display()))?;
+    // Only the inet sockets are decoded, if the image splits
+    let files = match proto::Split::new(files, proto::FILE_ENTRY_TYPE, &[proto::FD_TYPE_INETSK]) {
+        Some(split) => crit::decode(&split.picked())?,
+        None => crit::decode(files)?,
+    };
+    table(&images, &files)
+}
+
+fn table(images: &BTreeMap<String, Vec<u8>>, files: &Value) -> Result<Vec<Value>, String> {
+    let holders = fd_holders(images)?;
+    let entries = files
+        .get("entries")
+        .and_then(|e| e.as_array())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let mut rows = Vec::new();
+    for entry in entries {
+        if entry.get("type").and_then(|t| t.as_str()) != Some("INETSK") {
+            continue;
+        }
+        let Some(isk) = entry.get("isk") else {
+            continue;
+        };
+        let id = entry.get("id").and_then(|i| i.as_u64()).unwrap_or(0);
+        let mut row = row(isk);
+        row["fds"] = holders.get(&id).cloned().unwrap_or_default().into();
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// The COLUMNS of the INETSK `isk` but fds, with the enums by name.
+pub fn row(isk: &Value) -> Value {
+    let family = name(isk.get("family"), |n| match n {
+        2 => "INET",
+        10 => "INET6",
+        _ => "",
+    });
+    let v6 = family == "INET6";
+    json!({
+        "proto": name(isk.get("proto"), |n| match n {
+            6 => "TCP",
+            17 => "UDP",
+            _ => "",
+        }),
+        "family": family,
+        "state": name(isk.get("state"), tcp_state),
+        "src_addr": addr(isk.get("src_addr"), v6),
+        "src_port": isk.get("src_port"),
+        "dst_addr": addr(isk.get("dst_addr"), v6),
+        "dst_port": isk.get("dst_port"),
+        "ino": isk.get("ino"),
+    })
+}
+
+/// File id → "PID:FD" of every descriptor referring to it. Processes sharing
+/// a descriptor table all hold it; without ids images the table is named
+/// "fdinfo-N" instead of by PID.
+pub fn fd_holders(
+    images: &BTreeMap<String, Vec<u8>>,
+) -> Result<BTreeMap<u64, Vec<String>>, String> {
+    let mut pids: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (image, content) in images {
+        let Some(pid) = image
+            .strip_prefix("ids-")
+            .and_then(|n| n.strip_suffix(".img"))
+        else {
+            continue;
+        };
+        let ids = crit::decode(content)?;
+        if let Some(files_id) = ids.pointer("/entries/0/files_id").and_then(|f| f.as_u64()) {
+            pids.entry(files_id.to_string())
+                .or_default()
+                .push(pid.to_string());
+        }
+    }
+    let mut holders: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+    for (image, content) in images {
+        let Some(table) = image
+            .strip_prefix("fdinfo-")
+            .and_then(|n| n.strip_suffix(".img"))
+        else {
+            continue;
+        };
+        let owners = pids
+            .get(table)
+            .cloned()
+            .unwrap_or_else(|| vec![format!("fdinfo-{}", table)]);
+        let fdinfo = crit::decode(content)?;
+        let entries = fdinfo.get("entries").and_then(|e| e.as_array());
+        for fd in entries.into_iter().flatten() {
+            let (Some(id), Some(num)) = (
+                fd.get("id").and_then(|i| i.as_u64()),
+                fd.get("fd").and_then(|f| f.as_u64()),
+            ) else {
+                continue;
+            };
+            for owner in &owners {
+                holders
+                    .entry(id)
+                    .or_default()
+                    .push(format!("{}:{}", owner, num));
+            }
+        }
+    }
+    Ok(holders)
+}
+
+/// crit renders the socket enums by name; older versions print numbers.
+fn name(value: Option<&Value>, by_number: fn(u64) -> &'static str) -> String {
+    match value {
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v
+            .as_u64()
+            .map(|n| match by_number(n) {
+                "" => n.to_string(),
+                s => s.to_string(),
+            })
+            .unwrap_or_default(),
+        None => String::new(),
+    }
+}
+
+fn tcp_state(n: u64) -> &'static str {
+    match n {
+        1 => "ESTABLISHED",
+        2 => "SYN_SENT",
+        3 => "SYN_RECV",
+        4 => "FIN_WAIT1",
+        5 => "FIN_WAIT2",
+        6 => "TIME_WAIT",
+        7 => "CLOSE",
+        8 => "CLOSE_WAIT",
+        9 => "LAST_ACK",
+        10 => "LISTEN",
+        11 => "CLOSING",
+        _ => "",
+    }
+}
+
+/// An address array as a string: crit prints dotted/colon strings, or the
+/// raw in_addr/in6_addr words (network byte order in host memory).
+pub fn addr(value: Option<&Value>, v6: bool) -> String {
+    let Some(words) = value.and_then(|v| v.as_array()) else {
+        return String::new();
+    };
+    if let Some(s) = words.first().and_then(|w| w.as_str()) {
+        return s.to_string();
+    }
+    match value.and_then(inaddr::decode) {
+        Some(ip) if ip.is_ipv6() == v6 => ip.to_string(),
+        _ => String::new(),
+    }
+}
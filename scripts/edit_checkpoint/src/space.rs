@@ -0,0 +1,80 @@
+//! Free space for an edit: checked before it starts, and explained when a
+//! write runs out of it anyway.
+//!
+//! The edited archive is written as OUTPUT.new while the input is still
+//! there, so the output's filesystem needs about the input's size free
+//! (crit's input and output are in memory, see crit.rs). An edit that runs
+//! out of space midway fails, which removes OUTPUT.new and leaves the input
+//! as it was.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::report::{self, Report};
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+fn available(path: &Path) -> Option<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: c_path is NUL-terminated, and statvfs fills stat when it
+    // returns 0
+    let stat = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    Some(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+/// The directory `path` is (to be) created in.
+fn dir_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Refuse to start editing `input` into `output` when the output's
+/// filesystem lacks the input's size (less the `written` bytes a resumed
+/// edit has there). Filesystems that do not say how much is free are not
+/// checked.
+pub fn check(input: &Path, output: &Path, written: u64, report: &mut Report) -> Result<(), String> {
+    let needed = fs::metadata(input)
+        .map_err(|e| format!("{}: {}", input.display(), e))?
+        .len()
+        .saturating_sub(written);
+    let dir = dir_of(output);
+    let Some(free) = available(dir) else {
+        return Ok(());
+    };
+    report.set("output_free_bytes", free);
+    if free < needed {
+        return Err(format!(
+            "{} has {} free, the edit needs about {}; free some space or write the \
+             output elsewhere with --output",
+            dir.display(),
+            report::human(free),
+            report::human(needed)
+        ));
+    }
+    Ok(())
+}
+
+/// `error`, saying what became of the output if it is about running out of
+/// space.
+pub fn explain(error: String) -> String {
+    let enospc = io::Error::from_raw_os_error(libc::ENOSPC).to_string();
+    if error.contains(&enospc) {
+        format!(
+            "{}; removed the partial output, the input is as it was",
+            error
+        )
+    } else {
+        error
+    }
+}
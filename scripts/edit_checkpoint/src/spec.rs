@@ -0,0 +1,66 @@
+//! Edits of spec.dump, the container's OCI runtime spec, which podman reuses
+//! to recreate the container at restore.
+//!
+//! CRIU options for the restore travel as criu-restore.conf (CRIU config file
+//! syntax, one option per line) next to spec.dump. podman extracts the archive
+//! into the container's userdata directory, and the `org.criu.config`
+//! annotation points runc/crun's CRIU restore call at the file there. The
+//! userdata path is taken from spec.dump's own bind mounts, so it holds as
+//! long as the container keeps its ID (no `--name` at restore).
+
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+pub const CRIU_CONFIG_PATH: &str = "criu-restore.conf";
+const CRIU_CONFIG_ANNOTATION: &str = "org.criu.config";
+
+/// criu-restore.conf content for `opts` given as on the CRIU command line
+/// ("--tcp-close", "ghost-limit=10M" or "ghost-limit 10M").
+pub fn criu_config(opts: &[String]) -> Vec<u8> {
+    let mut content = String::new();
+    for opt in opts {
+        let opt = opt.trim().trim_start_matches('-');
+        let line = match opt.split_once('=') {
+            Some((name, value)) => format!("{} {}", name, value),
+            None => opt.to_string(),
+        };
+        content += &line;
+        content.push('\n');
+    }
+    content.into_bytes()
+}
+
+/// The userdata directory podman restores this container into, from the
+/// source of the bind mounts podman creates there (resolv.conf, hosts, ...).
+fn userdata_dir(spec: &Value) -> Option<PathBuf> {
+    spec.get("mounts")?
+        .as_array()?
+        .iter()
+        .filter_map(|m| m.get("source").and_then(|s| s.as_str()))
+        .map(Path::new)
+        .filter(|s| s.parent().is_some_and(|p| p.ends_with("userdata")))
+        .find_map(|s| s.parent().map(Path::to_path_buf))
+}
+
+/// The userdata directory spec.dump's mounts name.
+pub fn userdata(content: &[u8]) -> Option<PathBuf> {
+    userdata_dir(&serde_json::from_slice(content).ok()?)
+}
+
+/// Point `org.criu.config` at criu-restore.conf in `userdata`, or in the
+/// userdata directory spec.dump's mounts name when it is not given.
+pub fn set_criu_config(content: &[u8], userdata: Option<&Path>) -> Result<Vec<u8>, String> {
+    let mut data: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse spec.dump: {}", e))?;
+    let dir = userdata
+        .map(Path::to_path_buf)
+        .or_else(|| userdata_dir(&data))
+        .ok_or("cannot tell the userdata directory from spec.dump mounts")?;
+    let path = dir.join(CRIU_CONFIG_PATH).display().to_string();
+    if !data.get("annotations").is_some_and(Value::is_object) {
+        data["annotations"] = serde_json::json!({});
+    }
+    data["annotations"][CRIU_CONFIG_ANNOTATION] = path.into();
+    serde_json::to_vec(&data).map_err(|e| format!("serialize spec.dump: {}", e))
+}
@@ -0,0 +1,160 @@
+//! The summary of an edit: a row per value it changed (entry, field, old →
+//! new, action), kept as the report's `summary` list and printed as a table
+//! once the edit is done.
+
+use std::io::IsTerminal;
+
+use serde_json::{json, Value};
+
+use crate::images::FILES_IMG_PATH;
+use crate::report::Report;
+
+const SOCKET_FIELDS: [&str; 6] = [
+    "family", "state", "src_addr", "src_port", "dst_addr", "dst_port",
+];
+
+pub fn add(report: &mut Report, entry: &str, field: &str, old: &str, new: &str, action: &str) {
+    let row = json!({
+        "entry": entry,
+        "field": field,
+        "old": old,
+        "new": new,
+        "action": action,
+    });
+    report.extend("summary", [row]);
+}
+
+fn text(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+    }
+}
+
+/// Rows for the metadata values the audit recorded as changed.
+pub fn metadata(report: &mut Report, changes: &[Value]) {
+    for change in changes {
+        let (before, after) = (change.get("before"), change.get("after"));
+        let action = match (before, after) {
+            (None, _) => "added",
+            (_, None) => "removed",
+            _ => "changed",
+        };
+        add(
+            report,
+            change["entry"].as_str().unwrap_or(""),
+            change["pointer"].as_str().unwrap_or(""),
+            &text(before),
+            &text(after),
+            action,
+        );
+    }
+}
+
+/// Rows for the sockets that differ between `before` and `after`, the
+/// sockets::row of each INETSK of files.img before and after the edit.
+pub fn sockets(report: &mut Report, before: &[Value], after: &[Value]) {
+    for (old, new) in before.iter().zip(after) {
+        let changed = |field: &str| old[field] != new[field];
+        // Closing and translating change several fields at once
+        let whole = if changed("state") {
+            Some("close")
+        } else if changed("family") {
+            Some("nat64")
+        } else {
+            None
+        };
+        for field in SOCKET_FIELDS.into_iter().filter(|f| changed(f)) {
+            let action = whole.unwrap_or(match field {
+                "src_addr" if new[field] == "0.0.0.0" || new[field] == "::ffff:0.0.0.0" => {
+                    "wildcard"
+                }
+                "src_addr" => "rewrite",
+                "src_port" => "move",
+                _ => "changed",
+            });
+            add(
+                report,
+                FILES_IMG_PATH,
+                &format!("ino {} {}", old["ino"], field),
+                &text(old.get(field)),
+                &text(new.get(field)),
+                action,
+            );
+        }
+    }
+}
+
+/// Rows for the `patched` entries no value was recorded for, e.g. images
+/// rewritten whole.
+pub fn entries(report: &mut Report, patched: &[&str], skip: &[&str]) {
+    let listed: Vec<String> = rows(report)
+        .iter()
+        .filter_map(|row| row["entry"].as_str().map(str::to_string))
+        .collect();
+    for entry in patched {
+        if !skip.contains(entry) && !listed.iter().any(|l| l == entry) {
+            add(report, entry, "", "", "", "rewritten");
+        }
+    }
+}
+
+fn rows(report: &Report) -> Vec<Value> {
+    report
+        .get("summary")
+        .and_then(|s| s.as_array())
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Print the summary table to stderr, in color on a terminal unless
+/// NO_COLOR is set.
+pub fn print(report: &Report) {
+    let rows = rows(report);
+    if rows.is_empty() {
+        eprintln!("Nothing changed");
+        return;
+    }
+    let color = std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    let paint = |s: &str, code: &str| {
+        if color && !s.is_empty() {
+            format!("\x1b[{}m{}\x1b[0m", code, s)
+        } else {
+            s.to_string()
+        }
+    };
+    let columns = ["entry", "field", "old", "new"];
+    let mut widths = columns.map(str::len);
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(columns) {
+            *width = (*width).max(text(row.get(column)).chars().count());
+        }
+    }
+    let pad = |s: &str, width: usize| format!("{}{}", s, " ".repeat(width - s.chars().count()));
+    eprintln!(
+        "{}  {}  {}    {}  action",
+        pad("entry", widths[0]),
+        pad("field", widths[1]),
+        pad("old", widths[2]),
+        pad("new", widths[3])
+    );
+    for row in &rows {
+        let cell = |column: &str| text(row.get(column));
+        let (old, new) = (cell("old"), cell("new"));
+        let arrow = if old.is_empty() && new.is_empty() {
+            "  "
+        } else {
+            "→ "
+        };
+        eprintln!(
+            "{}  {}  {}  {}{}  {}",
+            pad(&cell("entry"), widths[0]),
+            pad(&cell("field"), widths[1]),
+            paint(&pad(&old, widths[2]), "31"),
+            arrow,
+            paint(&pad(&new, widths[3]), "32"),
+            paint(&cell("action"), "1"),
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! `tcp-repair`: the TCP repair state CRIU recorded for each connection, as
+//! the restore puts it back: the sequence numbers and queue lengths, the
+//! windows and the negotiated options of tcp-stream-INODE.img, next to the
+//! connection's addresses from files.img. For debugging flows that stall
+//! after the restore, e.g. once redirected through the switch to the target.
+//!
+//! The send queue (sndq) holds the data not acknowledged yet; its first
+//! sndq - unsent bytes went out before the dump and are retransmitted after
+//! the restore. The receive queue (rcvq) holds what the process had not read.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use serde_json::{json, Value};
+
+use crate::{crit, images, sockets};
+
+#[derive(Args)]
+pub struct TcpRepairArgs {
+    /// Checkpoint archive or unpacked checkpoint directory
+    checkpoint: PathBuf,
+    /// Write JSON instead of the table
+    #[arg(long)]
+    json: bool,
+}
+
+/// tcp_info options bits, as CRIU records them in opt_mask.
+const OPTIONS: [(u64, &str); 4] = [(1, "ts"), (2, "sack"), (4, "wscale"), (8, "ecn")];
+
+const COLUMNS: [&str; 12] = [
+    "src", "dst", "state", "snd_seq", "sndq", "unsent", "rcv_seq", "rcvq", "snd_wnd", "rcv_wnd",
+    "wscale", "mss",
+];
+
+fn options(mask: u64) -> String {
+    OPTIONS
+        .iter()
+        .filter(|(bit, _)| mask & bit != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// What in `stream` can explain a stall.
+fn notes(stream: &Value) -> Vec<String> {
+    let field = |key: &str| stream.get(key).and_then(|v| v.as_u64()).unwrap_or(0);
+    let mut notes = Vec::new();
+    let unacked = field("outq_len").saturating_sub(field("unsq_len"));
+    if unacked > 0 {
+        notes.push(format!("{} bytes to retransmit", unacked));
+    }
+    if stream.get("snd_wnd").is_some() && field("snd_wnd") == 0 {
+        notes.push("peer's window closed".to_string());
+    }
+    if stream.get("rcv_wnd").is_some() && field("rcv_wnd") == 0 {
+        notes.push("own window closed".to_string());
+    }
+    notes
+}
+
+/// One row per TCP connection with a repair state (or missing one).
+fn rows(checkpoint: &Path) -> Result<Vec<Value>, String> {
+    let table = sockets::read_table(checkpoint)?;
+    let streams = images::read(checkpoint, |name| {
+        name.starts_with("tcp-stream-") && name.ends_with(".img")
+    })?;
+    let mut rows = Vec::new();
+    for socket in &table {
+        if socket["proto"] != "TCP" || socket["state"] == "LISTEN" {
+            continue;
+        }
+        let Some(ino) = socket["ino"].as_u64() else {
+            continue;
+        };
+        let endpoint = |addr: &str, port: &str| {
+            let addr = socket[addr].as_str().unwrap_or("");
+            if addr.contains(':') {
+                format!("[{}]:{}", addr, socket[port])
+            } else {
+                format!("{}:{}", addr, socket[port])
+            }
+        };
+        let mut row = json!({
+            "src": endpoint("src_addr", "src_port"),
+            "dst": endpoint("dst_addr", "dst_port"),
+            "state": socket["state"],
+            "ino": ino,
+            "fds": socket["fds"],
+        });
+        let Some(content) = streams.get(&format!("tcp-stream-{:x}.img", ino)) else {
+            row["notes"] = json!(["no tcp-stream image (dumped without --tcp-established?)"]);
+            rows.push(row);
+            continue;
+        };
+        let data = crit::decode(content)?;
+        let stream = data.pointer("/entries/0").cloned().unwrap_or(Value::Null);
+        let get = |key: &str| stream.get(key).cloned().unwrap_or(Value::Null);
+        row["snd_seq"] = get("outq_seq");
+        row["sndq"] = get("outq_len");
+        row["unsent"] = get("unsq_len");
+        row["rcv_seq"] = get("inq_seq");
+        row["rcvq"] = get("inq_len");
+        row["snd_wnd"] = get("snd_wnd");
+        row["rcv_wnd"] = get("rcv_wnd");
+        row["snd_wl1"] = get("snd_wl1");
+        row["rcv_wup"] = get("rcv_wup");
+        row["max_window"] = get("max_window");
+        row["wscale"] = match (get("snd_wscale"), get("rcv_wscale")) {
+            (Value::Null, Value::Null) => Value::Null,
+            (snd, rcv) => format!("{}/{}", snd, rcv).replace("null", "-").into(),
+        };
+        row["mss"] = get("mss_clamp");
+        row["timestamp"] = get("timestamp");
+        if let Some(mask) = stream.get("opt_mask").and_then(|m| m.as_u64()) {
+            row["options"] = options(mask).into();
+        }
+        row["notes"] = json!(notes(&stream));
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+pub fn run(args: &TcpRepairArgs) -> Result<(), String> {
+    let rows = rows(&args.checkpoint)?;
+    let out = if args.json {
+        serde_json::to_string_pretty(&rows).map_err(|e| e.to_string())? + "\n"
+    } else {
+        let cell = |row: &Value, column: &str| match &row[column] {
+            Value::String(s) => s.clone(),
+            Value::Null => "-".to_string(),
+            v => v.to_string(),
+        };
+        let mut widths: Vec<usize> = COLUMNS.iter().map(|c| c.len()).collect();
+        for row in &rows {
+            for (width, column) in widths.iter_mut().zip(COLUMNS) {
+                *width = (*width).max(cell(row, column).len());
+            }
+        }
+        let line = |cells: Vec<String>| {
+            let padded: Vec<String> = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect();
+            padded.join("  ").trim_end().to_string() + "\n"
+        };
+        let mut out = line(COLUMNS.iter().map(|c| c.to_string()).collect());
+        for row in &rows {
+            out += &line(COLUMNS.iter().map(|c| cell(row, c)).collect());
+            let notes = row["notes"]
+                .as_array()
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            for note in notes.iter().filter_map(|n| n.as_str()) {
+                out += &format!("  ^ {}\n", note);
+            }
+        }
+        out
+    };
+    std::io::stdout()
+        .write_all(out.as_bytes())
+        .map_err(|e| e.to_string())?;
+    eprintln!("{} TCP connections", rows.len());
+    Ok(())
+}
@@ -0,0 +1,44 @@
+//! Wall-clock timestamps of the migration phases, for downtime accounting.
+//!
+//! The marks are taken on the machine running edit_checkpoint, so per-phase
+//! downtime can be computed without correlating logs across nodes. They are
+//! written into the archive as TIMELINE_PATH (marks known at edit time) and
+//! into the JSON report (all marks). Values are Unix time in microseconds.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Value};
+
+pub const TIMELINE_PATH: &str = "migration-timeline.json";
+
+#[derive(Default)]
+pub struct Timeline {
+    marks: Map<String, Value>,
+}
+
+impl Timeline {
+    /// Record the current time as `name`.
+    pub fn mark(&mut self, name: &str) {
+        let us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        self.marks.insert(name.to_string(), us.into());
+    }
+
+    /// Keep marks from an earlier edit of the same archive that this run has
+    /// not set itself.
+    pub fn merge_entry(&mut self, content: &[u8]) {
+        let Ok(Value::Object(earlier)) = serde_json::from_slice(content) else {
+            eprintln!("Warning: ignoring unparsable {}", TIMELINE_PATH);
+            return;
+        };
+        for (name, value) in earlier {
+            self.marks.entry(name).or_insert(value);
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::Object(self.marks.clone())
+    }
+}
@@ -0,0 +1,129 @@
+//! Patch the time namespace image (checkpoint/timens-ID.img).
+//!
+//! CRIU records the container's CLOCK_MONOTONIC and CLOCK_BOOTTIME at dump and
+//! at restore sets the namespace offsets so both clocks resume from those
+//! values on the target, whatever its uptime. --timens-advance moves both
+//! forward (or back) by the time the migration took, so timers that were due
+//! meanwhile are not all shifted into the future; --timens-clock sets one
+//! clock to the value restored processes should see, e.g. the target's own
+//! uptime to make the offset zero.
+
+use serde_json::Value;
+
+use crate::crit;
+use crate::report::Report;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Clock {
+    Monotonic,
+    Boottime,
+}
+
+impl Clock {
+    fn key(self) -> &'static str {
+        match self {
+            Clock::Monotonic => "monotonic",
+            Clock::Boottime => "boottime",
+        }
+    }
+}
+
+/// Clock edits of one pass, in nanoseconds.
+#[derive(Clone, Default)]
+pub struct TimensPatch {
+    pub advance: Option<i64>,
+    pub set: Vec<(Clock, i64)>,
+}
+
+impl TimensPatch {
+    pub fn is_empty(&self) -> bool {
+        self.advance.is_none() && self.set.is_empty()
+    }
+}
+
+/// Parse seconds with an optional fraction and sign, e.g. `-2.5`, into
+/// nanoseconds.
+pub fn parse_secs(s: &str) -> Result<i64, String> {
+    let secs: f64 = s
+        .parse()
+        .ok()
+        .filter(|v: &f64| v.is_finite())
+        .ok_or_else(|| format!("{} is not a number of seconds", s))?;
+    Ok((secs * 1e9).round() as i64)
+}
+
+/// Parse --timens-clock: `monotonic=SECS` or `boottime=SECS`.
+pub fn parse_clock(s: &str) -> Result<(Clock, i64), String> {
+    let (clock, secs) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{} is not CLOCK=SECS", s))?;
+    let clock = match clock {
+        "monotonic" => Clock::Monotonic,
+        "boottime" => Clock::Boottime,
+        _ => {
+            return Err(format!(
+                "unknown clock {}; use monotonic or boottime",
+                clock
+            ))
+        }
+    };
+    let ns = parse_secs(secs)?;
+    if ns < 0 {
+        return Err(format!("{} is negative", s));
+    }
+    Ok((clock, ns))
+}
+
+/// checkpoint/timens-ID.img.
+pub fn is_timens_img(path: &str) -> bool {
+    path.strip_prefix("checkpoint/timens-")
+        .and_then(|n| n.strip_suffix(".img"))
+        .is_some_and(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn to_ns(ts: &Value) -> i64 {
+    let field = |k: &str| ts.get(k).and_then(|v| v.as_i64()).unwrap_or(0);
+    field("tv_sec") * 1_000_000_000 + field("tv_nsec")
+}
+
+fn to_secs(ns: i64) -> f64 {
+    ns as f64 / 1e9
+}
+
+pub fn patch(content: &[u8], patch: &TimensPatch, report: &mut Report) -> Result<Vec<u8>, String> {
+    let mut data = crit::decode(content)?;
+    let entry = data
+        .pointer_mut("/entries/0")
+        .filter(|e| e.is_object())
+        .ok_or("timens image has no entry")?;
+    let mut changes = Vec::new();
+    for clock in [Clock::Monotonic, Clock::Boottime] {
+        let old = entry.get(clock.key()).map_or(0, to_ns);
+        let set = patch.set.iter().rev().find(|(c, _)| *c == clock);
+        let new = set.map_or(old, |(_, ns)| *ns) + patch.advance.unwrap_or(0);
+        if new < 0 {
+            return Err(format!(
+                "{} would be {:.3}s; a clock cannot go below zero",
+                clock.key(),
+                to_secs(new)
+            ));
+        }
+        entry[clock.key()] = serde_json::json!({
+            "tv_sec": new / 1_000_000_000,
+            "tv_nsec": new % 1_000_000_000,
+        });
+        eprintln!(
+            "timens {}: {:.3}s → {:.3}s",
+            clock.key(),
+            to_secs(old),
+            to_secs(new)
+        );
+        changes.push(serde_json::json!({
+            "clock": clock.key(),
+            "old": to_secs(old),
+            "new": to_secs(new),
+        }));
+    }
+    report.set("timens", changes);
+    crit::encode(&data)
+}
@@ -0,0 +1,208 @@
+//! Rules applied inside the tmpfs images of a checkpoint.
+//!
+//! CRIU dumps the contents of each tmpfs the container mounts (/run, /tmp,
+//! /dev/shm when they are not the host's) as a gzipped tar,
+//! checkpoint/tmpfs-dev-N.tar.gz.img, and unpacks it into the new tmpfs at
+//! restore. Services keep PID files, sockets' addresses and generated
+//! configs there, some of them with the container's address in. Each such
+//! image is unpacked in memory and written again with:
+//!
+//! - --tmpfs-path-map OLD=NEW: entries under OLD (a path in the tmpfs, from
+//!   its root) moved under NEW, hard links along with them;
+//! - --tmpfs-replace OLD=NEW: the bytes OLD replaced with NEW in the regular
+//!   files;
+//! - --tmpfs-addr: old_addr replaced with new_addr in the regular files,
+//!   where it is a whole address (not part of a longer one), unless
+//!   --add-addr keeps it.
+//!
+//! An image none of them changes is kept as it was, compression and all.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::metadata::{AddrPatch, NetworkPatch};
+use crate::report::Report;
+use crate::secrets;
+use crate::summary;
+
+#[derive(Default)]
+pub struct TmpfsPatch {
+    pub path_map: Vec<(String, String)>,
+    pub replace: Vec<(String, String)>,
+    pub addr: bool,
+}
+
+impl TmpfsPatch {
+    pub fn is_empty(&self) -> bool {
+        self.path_map.is_empty() && self.replace.is_empty() && !self.addr
+    }
+}
+
+pub fn is_tmpfs_img(path: &str) -> bool {
+    path.strip_prefix("checkpoint/tmpfs-dev-")
+        .and_then(|n| n.strip_suffix(".tar.gz.img"))
+        .is_some_and(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// One content rule: replace `old` with `new`, only where `old` is a whole
+/// address if `whole_addr`.
+struct Replace<'a> {
+    old: &'a [u8],
+    new: &'a [u8],
+    whole_addr: bool,
+}
+
+impl Replace<'_> {
+    /// Replace in `data`, returning how many times.
+    fn apply(&self, data: &mut Vec<u8>) -> usize {
+        let mut out = Vec::with_capacity(data.len());
+        let mut count = 0;
+        let mut i = 0;
+        while i < data.len() {
+            if data[i..].starts_with(self.old) && (!self.whole_addr || self.bounded(data, i)) {
+                out.extend_from_slice(self.new);
+                i += self.old.len();
+                count += 1;
+            } else {
+                out.push(data[i]);
+                i += 1;
+            }
+        }
+        if count > 0 {
+            *data = out;
+        }
+        count
+    }
+
+    /// Whether the match at `at` is not part of a longer address, such as
+    /// 10.0.0.1 in 10.0.0.12 or 110.0.0.1.
+    fn bounded(&self, data: &[u8], at: usize) -> bool {
+        let before = at.checked_sub(1).map(|i| data[i]);
+        let after = data.get(at + self.old.len()).copied();
+        !before.is_some_and(|b| b.is_ascii_digit() || b == b'.')
+            && !after.is_some_and(|b| b.is_ascii_digit())
+    }
+}
+
+/// Move `path` (as the tmpfs tar has it, e.g. ./run/app.pid) by `map`.
+fn remap(path: &Path, map: &[(String, String)]) -> Option<PathBuf> {
+    let text = path.to_string_lossy();
+    let (dot, rel) = match text.strip_prefix("./") {
+        Some(rel) => ("./", rel),
+        None => ("", text.trim_start_matches('/')),
+    };
+    let new = secrets::remap_path(&format!("/{}", rel), map)?;
+    Some(PathBuf::from(format!(
+        "{}{}",
+        dot,
+        new.trim_start_matches('/')
+    )))
+}
+
+/// Apply `patch` to the tmpfs image at `image`, returning the new image, or
+/// None if nothing in it changed.
+pub fn patch(
+    image: &str,
+    content: &[u8],
+    patch: &TmpfsPatch,
+    net: &NetworkPatch,
+    report: &mut Report,
+) -> Result<Option<Vec<u8>>, String> {
+    let old_addr = net.old_addr.to_string();
+    let mut rules: Vec<Replace> = patch
+        .replace
+        .iter()
+        .map(|(old, new)| Replace {
+            old: old.as_bytes(),
+            new: new.as_bytes(),
+            whole_addr: false,
+        })
+        .collect();
+    // A secondary new_addr leaves old_addr in place
+    if let (true, AddrPatch::Replace(new)) = (patch.addr, &net.addr) {
+        rules.push(Replace {
+            old: old_addr.as_bytes(),
+            new: new.as_bytes(),
+            whole_addr: true,
+        });
+    }
+    let error = |e: std::io::Error| format!("{}: {}", image, e);
+    let mut archive = tar::Archive::new(GzDecoder::new(content));
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let mut changed = false;
+    for entry in archive.entries().map_err(error)? {
+        let mut entry = entry.map_err(error)?;
+        let path = entry.path().map_err(error)?.into_owned();
+        let mut header = entry.header().clone();
+        let pax: Vec<(String, Vec<u8>)> = match entry.pax_extensions().map_err(error)? {
+            Some(exts) => exts
+                .filter_map(|e| e.ok())
+                .filter_map(|e| Some((e.key().ok()?.to_string(), e.value_bytes().to_vec())))
+                .filter(|(k, _)| !matches!(k.as_str(), "path" | "linkpath" | "size"))
+                .collect(),
+            None => Vec::new(),
+        };
+        if !pax.is_empty() {
+            builder
+                .append_pax_extensions(pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())))
+                .map_err(error)?;
+        }
+        let new_path = remap(&path, &patch.path_map);
+        if let Some(new_path) = &new_path {
+            let (old, new) = (path.display().to_string(), new_path.display().to_string());
+            summary::add(report, image, "path", &old, &new, "moved");
+            changed = true;
+        }
+        let path = new_path.unwrap_or(path);
+        let kind = header.entry_type();
+        if kind.is_hard_link() {
+            let link = entry.link_name().map_err(error)?.unwrap_or_default();
+            let link = remap(&link, &patch.path_map).unwrap_or(link.into_owned());
+            builder
+                .append_link(&mut header, &path, &link)
+                .map_err(error)?;
+            continue;
+        }
+        if kind.is_symlink() {
+            let link = entry.link_name().map_err(error)?.unwrap_or_default();
+            builder
+                .append_link(&mut header, &path, &link)
+                .map_err(error)?;
+            continue;
+        }
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).map_err(error)?;
+        if kind.is_gnu_sparse() {
+            // Read back in full; written as a plain file
+            header.set_entry_type(tar::EntryType::Regular);
+        }
+        if kind.is_file() || kind.is_gnu_sparse() {
+            for rule in &rules {
+                let count = rule.apply(&mut data);
+                if count > 0 {
+                    let field = path.display().to_string();
+                    let old = String::from_utf8_lossy(rule.old);
+                    let new = String::from_utf8_lossy(rule.new);
+                    let action = format!("replaced ×{}", count);
+                    summary::add(report, image, &field, &old, &new, &action);
+                    changed = true;
+                }
+            }
+        }
+        builder
+            .append_data(&mut header, &path, data.as_slice())
+            .map_err(error)?;
+    }
+    if !changed {
+        return Ok(None);
+    }
+    let mut encoder = builder.into_inner().map_err(error)?;
+    encoder.flush().map_err(error)?;
+    let patched = encoder.finish().map_err(error)?;
+    report.extend("tmpfs_patched", [image.into()]);
+    Ok(Some(patched))
+}
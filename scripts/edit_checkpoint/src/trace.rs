@@ -0,0 +1,247 @@
+//! OpenTelemetry trace export (OTLP/HTTP, JSON encoding).
+//!
+//! Enabled by the standard OTEL_EXPORTER_OTLP_ENDPOINT (e.g.
+//! http://jaeger:4318) or OTEL_EXPORTER_OTLP_TRACES_ENDPOINT variables; spans
+//! are buffered and posted in one request when the run ends. OTEL_SERVICE_NAME
+//! overrides the service name, and a W3C TRACEPARENT variable makes the run a
+//! child of the caller's trace. Requests to the controller carry a traceparent
+//! header so its spans land in the same trace.
+//!
+//! Spans also mark the phases of --event-fd progress events (see events.rs),
+//! whether or not they are exported.
+
+use std::cell::RefCell;
+use std::env;
+use std::fmt::Display;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::events;
+
+struct Recorder {
+    endpoint: String,
+    trace_id: [u8; 16],
+    /// Span id from TRACEPARENT, parent of the root span.
+    remote_parent: Option<[u8; 8]>,
+    spans: Vec<Value>,
+}
+
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+thread_local! {
+    /// Ids of the open spans on this thread, innermost last.
+    static STACK: RefCell<Vec<[u8; 8]>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Turn on recording if an OTLP endpoint is configured.
+pub fn init() {
+    let endpoint = match env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+        Ok(url) => url,
+        Err(_) => match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            Ok(url) => format!("{}/v1/traces", url.trim_end_matches('/')),
+            Err(_) => return,
+        },
+    };
+    let parent = env::var("TRACEPARENT")
+        .ok()
+        .and_then(|tp| parse_traceparent(&tp));
+    let mut trace_id = [0u8; 16];
+    match parent {
+        Some((id, _)) => trace_id = id,
+        None => fill_random(&mut trace_id),
+    }
+    *RECORDER.lock().unwrap() = Some(Recorder {
+        endpoint,
+        trace_id,
+        remote_parent: parent.map(|(_, span)| span),
+        spans: Vec::new(),
+    });
+}
+
+/// An open span; recorded when dropped. A no-op when tracing is off.
+pub struct Span {
+    id: Option<[u8; 8]>,
+    parent: Option<[u8; 8]>,
+    name: &'static str,
+    start_ns: u64,
+    started: Instant,
+    attrs: Vec<Value>,
+    error: Option<String>,
+}
+
+/// Open a span as a child of the innermost open span on this thread.
+pub fn span(name: &'static str) -> Span {
+    events::emit("progress", json!({ "phase": name, "state": "start" }));
+    let enabled = RECORDER.lock().unwrap().is_some();
+    if !enabled {
+        return Span {
+            id: None,
+            parent: None,
+            name,
+            start_ns: 0,
+            started: Instant::now(),
+            attrs: Vec::new(),
+            error: None,
+        };
+    }
+    let mut id = [0u8; 8];
+    fill_random(&mut id);
+    let parent = STACK.with(|s| {
+        let mut stack = s.borrow_mut();
+        let parent = stack.last().copied();
+        stack.push(id);
+        parent
+    });
+    Span {
+        id: Some(id),
+        parent,
+        name,
+        start_ns: now_ns(),
+        started: Instant::now(),
+        attrs: Vec::new(),
+        error: None,
+    }
+}
+
+impl Span {
+    pub fn attr(&mut self, key: &str, value: impl Display) {
+        if self.id.is_some() {
+            self.attrs.push(json!({
+                "key": key,
+                "value": { "stringValue": value.to_string() },
+            }));
+        }
+    }
+
+    /// Mark the span as failed.
+    pub fn fail(&mut self, message: &str) {
+        self.error = Some(message.to_string());
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let mut event = json!({
+            "phase": self.name,
+            "state": "end",
+            "duration_ms": self.started.elapsed().as_millis() as u64,
+        });
+        if let Some(message) = &self.error {
+            event["error"] = message.as_str().into();
+        }
+        events::emit("progress", event);
+        let Some(id) = self.id else { return };
+        STACK.with(|s| s.borrow_mut().retain(|open| *open != id));
+        let mut guard = RECORDER.lock().unwrap();
+        let Some(rec) = guard.as_mut() else { return };
+        let parent = self.parent.or(rec.remote_parent);
+        let mut span = json!({
+            "traceId": hex(&rec.trace_id),
+            "spanId": hex(&id),
+            "name": self.name,
+            "kind": 1,
+            "startTimeUnixNano": self.start_ns.to_string(),
+            "endTimeUnixNano": now_ns().to_string(),
+            "attributes": std::mem::take(&mut self.attrs),
+        });
+        if let Some(parent) = parent {
+            span["parentSpanId"] = hex(&parent).into();
+        }
+        if let Some(message) = &self.error {
+            span["status"] = json!({ "code": 2, "message": message });
+        }
+        rec.spans.push(span);
+    }
+}
+
+/// W3C traceparent for the innermost open span, to propagate to HTTP calls.
+pub fn traceparent() -> Option<String> {
+    let guard = RECORDER.lock().unwrap();
+    let rec = guard.as_ref()?;
+    let span = STACK.with(|s| s.borrow().last().copied())?;
+    Some(format!("00-{}-{}-01", hex(&rec.trace_id), hex(&span)))
+}
+
+/// Post the recorded spans and start a new trace for any that follow.
+/// Export problems are warnings, never failures.
+pub fn flush() {
+    let (endpoint, spans) = {
+        let mut guard = RECORDER.lock().unwrap();
+        let Some(rec) = guard.as_mut() else { return };
+        if rec.remote_parent.is_none() {
+            fill_random(&mut rec.trace_id);
+        }
+        (rec.endpoint.clone(), std::mem::take(&mut rec.spans))
+    };
+    if spans.is_empty() {
+        return;
+    }
+    let service = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "edit_checkpoint".to_string());
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": service } }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "edit_checkpoint", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    });
+    let result = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(2))
+        .build()
+        .post(&endpoint)
+        .set("Content-Type", "application/json")
+        .send_string(&body.to_string());
+    if let Err(e) = result {
+        eprintln!("Warning: trace export to {} failed: {}", endpoint, e);
+    }
+}
+
+fn parse_traceparent(tp: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = tp.trim().split('-');
+    let (_version, trace, span) = (parts.next()?, parts.next()?, parts.next()?);
+    let mut trace_id = [0u8; 16];
+    let mut span_id = [0u8; 8];
+    unhex(trace, &mut trace_id)?;
+    unhex(span, &mut span_id)?;
+    Some((trace_id, span_id))
+}
+
+fn fill_random(buf: &mut [u8]) {
+    let ok = std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(buf))
+        .is_ok();
+    if !ok {
+        // Unique enough for trace ids if urandom is unavailable
+        let seed = now_ns() ^ u64::from(std::process::id()).rotate_left(32);
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = (seed.rotate_left(i as u32 * 8) as u8) ^ i as u8;
+        }
+    }
+}
+
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str, out: &mut [u8]) -> Option<()> {
+    if s.len() != out.len() * 2 {
+        return None;
+    }
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(())
+}
@@ -0,0 +1,329 @@
+//! `tui`: browse a checkpoint archive and pick what the edit does to each
+//! socket, for when a migration goes wrong and the flags would take too long
+//! to get right.
+//!
+//! The Entries tab lists the archive's entries; the selected one is shown
+//! decoded: the JSON metadata pretty-printed, CRIU images through crit. The
+//! Sockets tab is the socket table of `sockets`, where each socket can be set
+//! to wildcard, rewrite, skip or close (see --socket-rules). Writing leaves
+//! the browser and runs the edit with those choices as `ino=` rules.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+
+use clap::Args;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListState, Paragraph, Row, Table, TableState, Tabs};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::Value;
+
+use crate::policy::{self, Action};
+use crate::report::{self, Report};
+use crate::{applied, compress, crit, sockets, EditOptions, NewAddr};
+
+#[derive(Args)]
+pub struct TuiArgs {
+    /// Checkpoint archive
+    checkpoint: String,
+    /// The container's address on the target
+    new_addr: String,
+    /// The address the sockets are bound to (default: detected)
+    #[arg(long, value_name = "ADDR")]
+    old_addr: Option<String>,
+    /// Write the edited archive to FILE instead of over the input
+    #[arg(long, short, value_name = "FILE")]
+    output: Option<String>,
+    /// Write the edit's JSON report to FILE
+    #[arg(long, value_name = "FILE")]
+    report: Option<String>,
+}
+
+/// Entries up to this size are kept to be shown; larger ones are pages or
+/// layers, with nothing to read in them.
+const SHOWN: u64 = 4 << 20;
+
+const ACTIONS: [(char, Action); 4] = [
+    ('w', Action::Wildcard),
+    ('r', Action::Rewrite),
+    ('s', Action::Skip),
+    ('c', Action::Close),
+];
+
+#[derive(PartialEq)]
+enum Tab {
+    Entries,
+    Sockets,
+}
+
+struct App {
+    tab: Tab,
+    entries: Vec<(String, u64)>,
+    contents: BTreeMap<String, Vec<u8>>,
+    list: ListState,
+    /// The selected entry, decoded, and how far it is scrolled.
+    view: Vec<String>,
+    scroll: u16,
+    sockets: Vec<Value>,
+    actions: Vec<Option<Action>>,
+    table: TableState,
+}
+
+impl App {
+    fn load(path: &Path) -> Result<App, String> {
+        let mut archive = tar::Archive::new(compress::open(path)?);
+        let mut entries = Vec::new();
+        let mut contents = BTreeMap::new();
+        for entry in archive.entries().map_err(|e| e.to_string())? {
+            let mut entry = entry.map_err(|e| e.to_string())?;
+            let name = entry.path().map_err(|e| e.to_string())?;
+            let name = name.to_string_lossy().trim_start_matches("./").to_string();
+            let size = entry.size();
+            if entry.header().entry_type().is_file() && size <= SHOWN {
+                let mut content = Vec::new();
+                entry
+                    .read_to_end(&mut content)
+                    .map_err(|e| format!("read {}: {}", name, e))?;
+                contents.insert(name.clone(), content);
+            }
+            entries.push((name, size));
+        }
+        let sockets = sockets::read_table(path)?;
+        let mut app = App {
+            tab: Tab::Entries,
+            entries,
+            contents,
+            list: ListState::default().with_selected(Some(0)),
+            view: Vec::new(),
+            scroll: 0,
+            actions: vec![None; sockets.len()],
+            sockets,
+            table: TableState::default().with_selected(Some(0)),
+        };
+        app.show();
+        Ok(app)
+    }
+
+    /// Decode the selected entry into the view.
+    fn show(&mut self) {
+        self.scroll = 0;
+        let Some((name, size)) = self.list.selected().and_then(|i| self.entries.get(i)) else {
+            self.view = Vec::new();
+            return;
+        };
+        let Some(content) = self.contents.get(name) else {
+            self.view = vec![format!("{} ({}), not loaded", name, report::human(*size))];
+            return;
+        };
+        let text = if name.starts_with("checkpoint/") && name.ends_with(".img") {
+            crit::decode(content)
+                .and_then(|json| serde_json::to_string_pretty(&json).map_err(|e| e.to_string()))
+                .unwrap_or_else(|e| format!("crit decode: {}", e))
+        } else if let Ok(json) = serde_json::from_slice::<Value>(content) {
+            serde_json::to_string_pretty(&json).unwrap_or_default()
+        } else if let Ok(text) = std::str::from_utf8(content) {
+            text.to_string()
+        } else {
+            format!("binary, {}", report::human(*size))
+        };
+        self.view = text.lines().map(str::to_string).collect();
+    }
+
+    /// The socket choices as --socket-rules.
+    fn rules(&self) -> String {
+        self.sockets
+            .iter()
+            .zip(&self.actions)
+            .filter_map(|(socket, action)| {
+                let action = format!("{:?}", action.as_ref()?).to_lowercase();
+                Some(format!("ino={} {}", socket["ino"].as_u64()?, action))
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [tabs, body, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let selected = match self.tab {
+            Tab::Entries => 0,
+            Tab::Sockets => 1,
+        };
+        frame.render_widget(
+            Tabs::new(["Entries", "Sockets"])
+                .select(selected)
+                .highlight_style(Style::new().add_modifier(Modifier::REVERSED)),
+            tabs,
+        );
+        let highlight = Style::new().add_modifier(Modifier::REVERSED);
+        match self.tab {
+            Tab::Entries => {
+                let [left, right] =
+                    Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)])
+                        .areas(body);
+                let items = self
+                    .entries
+                    .iter()
+                    .map(|(name, size)| format!("{:>6}  {}", report::human(*size), name));
+                let list = List::new(items)
+                    .block(Block::bordered().title("entries"))
+                    .highlight_style(highlight);
+                frame.render_stateful_widget(list, left, &mut self.list);
+                let lines: Vec<Line> = self.view.iter().map(|l| Line::raw(l.as_str())).collect();
+                let view = Paragraph::new(lines)
+                    .block(Block::bordered())
+                    .scroll((self.scroll, 0));
+                frame.render_widget(view, right);
+                frame.render_widget(
+                    Line::raw("↑↓ entry  PgUp/PgDn scroll  Tab sockets  W write  q quit"),
+                    help,
+                );
+            }
+            Tab::Sockets => {
+                let columns = [
+                    "proto", "state", "src_addr", "src_port", "dst_addr", "dst_port",
+                ];
+                let cell = |v: &Value| match v {
+                    Value::String(s) => s.clone(),
+                    Value::Null => String::new(),
+                    v => v.to_string(),
+                };
+                let rows = self
+                    .sockets
+                    .iter()
+                    .zip(&self.actions)
+                    .map(|(socket, action)| {
+                        let mut cells: Vec<String> =
+                            columns.iter().map(|c| cell(&socket[c])).collect();
+                        cells.push(socket["fds"].as_array().map_or(String::new(), |fds| {
+                            fds.iter().map(cell).collect::<Vec<_>>().join(" ")
+                        }));
+                        cells.push(
+                            action.map_or(String::new(), |a| format!("{:?}", a).to_lowercase()),
+                        );
+                        Row::new(cells)
+                    });
+                let header = Row::new(columns.iter().chain(&["fds", "action"]).copied())
+                    .style(Style::new().add_modifier(Modifier::BOLD));
+                let widths = [
+                    Constraint::Length(5),
+                    Constraint::Length(11),
+                    Constraint::Min(15),
+                    Constraint::Length(8),
+                    Constraint::Min(15),
+                    Constraint::Length(8),
+                    Constraint::Min(8),
+                    Constraint::Length(8),
+                ];
+                let table = Table::new(rows, widths)
+                    .header(header)
+                    .block(Block::bordered().title("sockets"))
+                    .row_highlight_style(highlight);
+                frame.render_stateful_widget(table, body, &mut self.table);
+                frame.render_widget(
+                    Line::raw("↑↓ socket  w wildcard  r rewrite  s skip  c close  - default  Tab entries  W write  q quit"),
+                    help,
+                );
+            }
+        }
+    }
+
+    /// Handle events until the operator quits (None) or writes (the rules).
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<Option<String>, String> {
+        loop {
+            terminal
+                .draw(|frame| self.draw(frame))
+                .map_err(|e| e.to_string())?;
+            let Event::Key(key) = event::read().map_err(|e| e.to_string())? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+                KeyCode::Char('W') => return Ok(Some(self.rules())),
+                KeyCode::Tab => {
+                    self.tab = match self.tab {
+                        Tab::Entries => Tab::Sockets,
+                        Tab::Sockets => Tab::Entries,
+                    }
+                }
+                KeyCode::Up | KeyCode::Char('k') if self.tab == Tab::Entries => {
+                    self.list.select_previous();
+                    self.show();
+                }
+                KeyCode::Down | KeyCode::Char('j') if self.tab == Tab::Entries => {
+                    self.list.select_next();
+                    self.show();
+                }
+                KeyCode::PageDown => self.scroll = self.scroll.saturating_add(20),
+                KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(20),
+                KeyCode::Up | KeyCode::Char('k') => self.table.select_previous(),
+                KeyCode::Down | KeyCode::Char('j') => self.table.select_next(),
+                KeyCode::Char(c) if self.tab == Tab::Sockets => {
+                    let Some(row) = self.table.selected().filter(|i| *i < self.actions.len())
+                    else {
+                        continue;
+                    };
+                    if c == '-' {
+                        self.actions[row] = None;
+                    } else if let Some((_, action)) = ACTIONS.iter().find(|(k, _)| *k == c) {
+                        self.actions[row] = Some(*action);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+pub fn run(args: &TuiArgs) -> Result<(), String> {
+    let path = Path::new(&args.checkpoint);
+    if path.is_dir() {
+        return Err(format!(
+            "{} is a directory; tui takes an archive",
+            path.display()
+        ));
+    }
+    let mut app = App::load(path)?;
+    let mut terminal = ratatui::init();
+    let chosen = app.run(&mut terminal);
+    ratatui::restore();
+    let Some(rules) = chosen? else {
+        return Ok(());
+    };
+    eprintln!(
+        "Socket rules: {}",
+        if rules.is_empty() { "none" } else { &rules }
+    );
+    let opts = EditOptions {
+        socket_rules: policy::parse_rules(&rules)?.0,
+        output: args.output.clone(),
+        fingerprint: Some(applied::fingerprint(&format!(
+            "{:?} {} {}",
+            args.old_addr, args.new_addr, rules
+        ))),
+        ..Default::default()
+    };
+    let mut report = Report::default();
+    report.set("checkpoint", args.checkpoint.as_str());
+    let result = crate::run(
+        &args.checkpoint,
+        args.old_addr.as_deref(),
+        NewAddr::Fixed(&args.new_addr),
+        &opts,
+        &mut report,
+    );
+    if let Some(path) = &args.report {
+        report.write(Path::new(path))?;
+    }
+    result
+}
@@ -0,0 +1,169 @@
+//! `undo`: reverse the last edit of a checkpoint archive from its audit entry,
+//! for a migration aborted after the edit, when the container has to be
+//! restored on the source again and the pre-edit copy is gone.
+//!
+//! Each value the edit changed in network.status, config.dump and spec.dump
+//! is put back as recorded, after checking it still has the value the edit
+//! left. The CRIU images are left as edited: sockets bound to old_addr were
+//! rebound to the wildcard address, which restores on the source as well.
+//! Running undo again reverses the edit before that.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use serde_json::Value;
+
+use crate::applied::APPLIED_PATH;
+use crate::audit::AUDIT_PATH;
+use crate::compress;
+use crate::edit;
+use crate::lock;
+use crate::metadata::NETWORK_STATUS_PATH;
+use crate::rewrite::Rewrite;
+
+#[derive(Args)]
+pub struct UndoArgs {
+    /// Edited checkpoint archive, reverted in place
+    archive: PathBuf,
+}
+
+/// The audit entries of the archive at `path`, oldest first.
+fn read_audit(path: &Path) -> Result<Vec<Value>, String> {
+    let mut archive = tar::Archive::new(compress::open(path)?);
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        if entry.path().map_err(|e| e.to_string())?.to_str() == Some(AUDIT_PATH) {
+            return match serde_json::from_reader(entry) {
+                Ok(Value::Array(entries)) => Ok(entries),
+                _ => Err(format!(
+                    "{} in {} is unparsable",
+                    AUDIT_PATH,
+                    path.display()
+                )),
+            };
+        }
+    }
+    Err(format!(
+        "{} has no {}; was it edited by an older edit_checkpoint?",
+        path.display(),
+        AUDIT_PATH
+    ))
+}
+
+/// Set the value at `pointer` in `doc`, or remove it if `value` is None.
+fn set(doc: &mut Value, pointer: &str, value: Option<Value>) -> Result<(), String> {
+    let Some((parent, key)) = pointer.rsplit_once('/') else {
+        *doc = value.unwrap_or(Value::Null);
+        return Ok(());
+    };
+    let key = key.replace("~1", "/").replace("~0", "~");
+    match (doc.pointer_mut(parent), value) {
+        (Some(Value::Object(map)), Some(value)) => {
+            map.insert(key, value);
+        }
+        (Some(Value::Object(map)), None) => {
+            map.remove(&key);
+        }
+        (Some(Value::Array(items)), Some(value)) => {
+            let item = key
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| items.get_mut(i))
+                .ok_or_else(|| format!("no {} to restore", pointer))?;
+            *item = value;
+        }
+        _ => return Err(format!("no {} to restore {} in", parent, pointer)),
+    }
+    Ok(())
+}
+
+/// `content` of the metadata entry at `path` with `changes` reversed.
+fn revert(path: &str, content: &[u8], changes: &[&Value]) -> Result<Vec<u8>, String> {
+    let mut doc: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse {}: {}", path, e))?;
+    for change in changes.iter().rev() {
+        let pointer = change["pointer"]
+            .as_str()
+            .ok_or_else(|| format!("{}: a change without a pointer", AUDIT_PATH))?;
+        if doc.pointer(pointer) != change.get("after") {
+            return Err(format!(
+                "{}{} has changed since the edit; not undoing it",
+                path, pointer
+            ));
+        }
+        set(&mut doc, pointer, change.get("before").cloned())?;
+    }
+    let serialized = if path == NETWORK_STATUS_PATH {
+        serde_json::to_vec_pretty(&doc)
+    } else {
+        serde_json::to_vec(&doc)
+    };
+    serialized.map_err(|e| format!("serialize {}: {}", path, e))
+}
+
+pub fn run(args: &UndoArgs) -> Result<(), String> {
+    let path = args.archive.as_path();
+    let _input_lock = lock::input(path)?;
+    let mut audit = read_audit(path)?;
+    let last = audit
+        .pop()
+        .ok_or_else(|| format!("{} lists no edits", AUDIT_PATH))?;
+    let changes: Vec<&Value> = last["changes"].as_array().into_iter().flatten().collect();
+
+    let mut rewrite = Rewrite::start(path, path)?;
+    let builder = rewrite.builder();
+    let mut archive = tar::Archive::new(compress::open(path)?);
+    let mut restored = 0;
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let name = entry
+            .path()
+            .map_err(|e| e.to_string())?
+            .display()
+            .to_string();
+        // The marker is of the edit undone; the audit entry is rewritten below
+        if name == APPLIED_PATH || name == AUDIT_PATH {
+            continue;
+        }
+        let mut header = entry.header().clone();
+        let entry_changes: Vec<&Value> = changes
+            .iter()
+            .copied()
+            .filter(|c| c["entry"] == name.as_str())
+            .collect();
+        let result = if entry_changes.is_empty() {
+            builder.append(&header, &mut entry)
+        } else {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).map_err(|e| e.to_string())?;
+            let reverted = revert(&name, &content, &entry_changes)?;
+            restored += entry_changes.len();
+            header.set_size(reverted.len() as u64);
+            header.set_cksum();
+            builder.append(&header, reverted.as_slice())
+        };
+        result.map_err(|e| format!("write {}: {}", name, e))?;
+    }
+    if !audit.is_empty() {
+        let content = serde_json::to_vec_pretty(&audit).map_err(|e| e.to_string())?;
+        edit::append_new(builder, AUDIT_PATH, &content)?;
+    }
+    rewrite.finish(Some(path))?;
+
+    eprintln!(
+        "Undid the edit {} → {} by {} on {}: restored {} values",
+        last["old_addr"].as_str().unwrap_or("?"),
+        last["new_addr"].as_str().unwrap_or("(none)"),
+        last["user"].as_str().unwrap_or("?"),
+        last["host"].as_str().unwrap_or("?"),
+        restored
+    );
+    if restored < changes.len() {
+        eprintln!(
+            "Warning: {} recorded changes are in entries missing from the archive",
+            changes.len() - restored
+        );
+    }
+    Ok(())
+}
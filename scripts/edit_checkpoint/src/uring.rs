@@ -0,0 +1,325 @@
+//! io_uring file I/O for the edit pass (`--io-uring`, built with the
+//! io_uring feature).
+//!
+//! The reader keeps DEPTH reads of the input in flight ahead of the tar loop,
+//! the writer keeps DEPTH writes of the output in flight behind it, so on
+//! NVMe the edit waits on the device queue instead of on one syscall per
+//! buffer. They stand in for the BufReader/BufWriter over a File, with
+//! buffers of the same (--read-buffer/--write-buffer) size.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+use io_uring::{opcode, types, IoUring};
+
+const DEPTH: usize = 8;
+
+fn check(res: i32) -> io::Result<usize> {
+    if res < 0 {
+        Err(io::Error::from_raw_os_error(-res))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+struct Slot {
+    buf: Vec<u8>,
+    offset: u64,
+    /// Bytes requested while in flight; bytes available once completed.
+    len: usize,
+    done: Option<io::Result<usize>>,
+}
+
+/// Submit what is queued and wait for completions until `slots[want]` is
+/// done (or, with `want` None, for any one completion).
+fn wait(ring: &mut IoUring, slots: &mut [Slot], want: Option<usize>) -> io::Result<()> {
+    loop {
+        if want.is_some_and(|i| slots[i].done.is_some()) {
+            return Ok(());
+        }
+        ring.submit_and_wait(1)?;
+        let mut any = false;
+        for cqe in ring.completion() {
+            let slot = &mut slots[cqe.user_data() as usize];
+            slot.done = Some(check(cqe.result()));
+            any = true;
+        }
+        if want.is_none() && any {
+            return Ok(());
+        }
+    }
+}
+
+/// Sequential reader with read-ahead.
+pub struct UringReader {
+    file: File,
+    ring: IoUring,
+    slots: Vec<Slot>,
+    /// Slots in file order: in flight or holding unread data.
+    queue: VecDeque<usize>,
+    /// Read position in the slot at the front of the queue.
+    pos: usize,
+    next_offset: u64,
+    eof: bool,
+    buf_size: usize,
+}
+
+impl UringReader {
+    pub fn new(file: File, buf_size: usize) -> io::Result<Self> {
+        let mut reader = UringReader {
+            file,
+            ring: IoUring::new(DEPTH as u32)?,
+            slots: (0..DEPTH)
+                .map(|_| Slot {
+                    buf: vec![0; buf_size],
+                    offset: 0,
+                    len: 0,
+                    done: None,
+                })
+                .collect(),
+            queue: VecDeque::with_capacity(DEPTH),
+            pos: 0,
+            next_offset: 0,
+            eof: false,
+            buf_size,
+        };
+        for i in 0..DEPTH {
+            reader.submit(i)?;
+        }
+        Ok(reader)
+    }
+
+    fn submit(&mut self, i: usize) -> io::Result<()> {
+        let slot = &mut self.slots[i];
+        slot.offset = self.next_offset;
+        slot.len = self.buf_size;
+        slot.done = None;
+        self.next_offset += self.buf_size as u64;
+        let sqe = opcode::Read::new(
+            types::Fd(self.file.as_raw_fd()),
+            slot.buf.as_mut_ptr(),
+            self.buf_size as u32,
+        )
+        .offset(slot.offset)
+        .build()
+        .user_data(i as u64);
+        // SAFETY: the buffer lives in self.slots and is not touched until the
+        // read completes; Drop waits for reads still in flight.
+        unsafe { self.ring.submission().push(&sqe) }
+            .map_err(|_| io::Error::other("io_uring submission queue full"))?;
+        self.queue.push_back(i);
+        Ok(())
+    }
+
+    /// Wait for the front slot and make its length the bytes read, filling
+    /// a short read up synchronously so the following slots line up.
+    fn complete_front(&mut self) -> io::Result<()> {
+        let i = self.queue[0];
+        wait(&mut self.ring, &mut self.slots, Some(i))?;
+        let slot = &mut self.slots[i];
+        let mut n = match slot.done.take().expect("completed") {
+            Ok(n) => n,
+            Err(e) => {
+                slot.done = Some(Ok(0));
+                return Err(e);
+            }
+        };
+        while n > 0 && n < self.buf_size {
+            match self
+                .file
+                .read_at(&mut slot.buf[n..], slot.offset + n as u64)?
+            {
+                0 => break,
+                m => n += m,
+            }
+        }
+        slot.len = n;
+        slot.done = Some(Ok(n));
+        Ok(())
+    }
+}
+
+impl BufRead for UringReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        loop {
+            let Some(&i) = self.queue.front() else {
+                return Ok(&[]);
+            };
+            if self.slots[i].done.is_none() {
+                self.complete_front()?;
+            }
+            let len = self.slots[i].len;
+            if len == 0 {
+                // End of file: the reads behind this one are past it
+                self.eof = true;
+            }
+            if self.pos < len {
+                return Ok(&self.slots[i].buf[self.pos..len]);
+            }
+            if self.eof {
+                return Ok(&[]);
+            }
+            // Front slot used up: reuse it for the next read ahead
+            self.queue.pop_front();
+            self.pos = 0;
+            self.submit(i)?;
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+impl Read for UringReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.fill_buf()?;
+        let n = out.len().min(buf.len());
+        out[..n].copy_from_slice(&buf[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl Drop for UringReader {
+    fn drop(&mut self) {
+        // The kernel may still write into the buffers
+        while self.slots.iter().any(|s| s.done.is_none()) {
+            if wait(&mut self.ring, &mut self.slots, None).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Sequential writer with write-behind.
+pub struct UringWriter {
+    file: File,
+    ring: IoUring,
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    /// Slot being filled.
+    current: Option<usize>,
+    next_offset: u64,
+    buf_size: usize,
+}
+
+impl UringWriter {
+    pub fn new(file: File, buf_size: usize) -> io::Result<Self> {
+        Ok(UringWriter {
+            file,
+            ring: IoUring::new(DEPTH as u32)?,
+            slots: (0..DEPTH)
+                .map(|_| Slot {
+                    buf: Vec::with_capacity(buf_size),
+                    offset: 0,
+                    len: 0,
+                    done: Some(Ok(0)),
+                })
+                .collect(),
+            free: (0..DEPTH).collect(),
+            current: None,
+            next_offset: 0,
+            buf_size,
+        })
+    }
+
+    /// Retire completed writes, writing out the rest of any short one.
+    fn reap(&mut self) -> io::Result<()> {
+        for i in 0..DEPTH {
+            if self.free.contains(&i) || Some(i) == self.current {
+                continue;
+            }
+            let Some(done) = self.slots[i].done.take() else {
+                continue;
+            };
+            let slot = &mut self.slots[i];
+            let result = done.and_then(|written| match slot.buf.get(written..slot.len) {
+                Some(rest) if !rest.is_empty() => {
+                    self.file.write_all_at(rest, slot.offset + written as u64)
+                }
+                _ => Ok(()),
+            });
+            slot.buf.clear();
+            slot.done = Some(Ok(0));
+            self.free.push(i);
+            result?;
+        }
+        Ok(())
+    }
+
+    fn submit_current(&mut self) -> io::Result<()> {
+        let Some(i) = self.current.take() else {
+            return Ok(());
+        };
+        let slot = &mut self.slots[i];
+        slot.offset = self.next_offset;
+        slot.len = slot.buf.len();
+        slot.done = None;
+        self.next_offset += slot.len as u64;
+        let sqe = opcode::Write::new(
+            types::Fd(self.file.as_raw_fd()),
+            slot.buf.as_ptr(),
+            slot.len as u32,
+        )
+        .offset(slot.offset)
+        .build()
+        .user_data(i as u64);
+        // SAFETY: the buffer lives in self.slots and is not touched until the
+        // write completes; Drop waits for writes still in flight.
+        unsafe { self.ring.submission().push(&sqe) }
+            .map_err(|_| io::Error::other("io_uring submission queue full"))?;
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    fn in_flight(&self) -> bool {
+        self.slots.iter().any(|s| s.done.is_none())
+    }
+}
+
+impl Write for UringWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let i = match self.current {
+            Some(i) => i,
+            None => {
+                while self.free.is_empty() {
+                    wait(&mut self.ring, &mut self.slots, None)?;
+                    self.reap()?;
+                }
+                let i = self.free.pop().expect("free slot");
+                self.current = Some(i);
+                i
+            }
+        };
+        let buf = &mut self.slots[i].buf;
+        let n = data.len().min(self.buf_size - buf.len());
+        buf.extend_from_slice(&data[..n]);
+        if buf.len() == self.buf_size {
+            self.submit_current()?;
+        }
+        Ok(n)
+    }
+
+    /// Submit the partial buffer and wait for every write.
+    fn flush(&mut self) -> io::Result<()> {
+        self.submit_current()?;
+        while self.in_flight() {
+            wait(&mut self.ring, &mut self.slots, None)?;
+        }
+        self.reap()
+    }
+}
+
+impl Drop for UringWriter {
+    fn drop(&mut self) {
+        while self.in_flight() {
+            if wait(&mut self.ring, &mut self.slots, None).is_err() {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//! Translate user namespace ID mappings, e.g. to restore a rootless podman
+//! checkpoint as rootful (no user namespace) or the other way round.
+//!
+//! The container's mappings live in spec.dump (linux.uidMappings/gidMappings
+//! and the user namespace) and config.dump (idMappings). rootfs-diff.tar holds
+//! the container's changed files with host IDs under the old mapping; each
+//! entry's owner is translated to the container ID and back out through the
+//! new mapping. IDs the new mapping does not cover become 65534 (nobody).
+
+use std::io::Read;
+
+use serde_json::{json, Value};
+
+pub const ROOTFS_DIFF_PATH: &str = "rootfs-diff.tar";
+const OVERFLOW_ID: u64 = 65534;
+
+/// One range of a mapping, as in podman's --uidmap CONTAINER:HOST:SIZE.
+#[derive(Clone, Copy, Debug)]
+pub struct IdMap {
+    pub container: u64,
+    pub host: u64,
+    pub size: u64,
+}
+
+pub fn parse_idmap(s: &str) -> Result<IdMap, String> {
+    let fields: Vec<u64> = s
+        .split(':')
+        .map(|f| {
+            f.parse()
+                .map_err(|_| format!("{} is not CONTAINER:HOST:SIZE", s))
+        })
+        .collect::<Result<_, _>>()?;
+    match fields[..] {
+        [container, host, size] if size > 0 => Ok(IdMap {
+            container,
+            host,
+            size,
+        }),
+        _ => Err(format!("{} is not CONTAINER:HOST:SIZE", s)),
+    }
+}
+
+/// UID and GID mappings of a container. Empty means no user namespace: the
+/// container's IDs are host IDs.
+#[derive(Clone, Default)]
+pub struct IdMaps {
+    pub uid: Vec<IdMap>,
+    pub gid: Vec<IdMap>,
+}
+
+impl IdMaps {
+    fn is_host(&self) -> bool {
+        self.uid.is_empty() && self.gid.is_empty()
+    }
+}
+
+fn to_container(maps: &[IdMap], host: u64) -> Option<u64> {
+    if maps.is_empty() {
+        return Some(host);
+    }
+    maps.iter()
+        .find(|m| (m.host..m.host + m.size).contains(&host))
+        .map(|m| m.container + host - m.host)
+}
+
+fn to_host(maps: &[IdMap], container: u64) -> Option<u64> {
+    if maps.is_empty() {
+        return Some(container);
+    }
+    maps.iter()
+        .find(|m| (m.container..m.container + m.size).contains(&container))
+        .map(|m| m.host + container - m.container)
+}
+
+fn maps_from_spec(value: Option<&Value>) -> Vec<IdMap> {
+    value
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|m| {
+            Some(IdMap {
+                container: m.get("containerID")?.as_u64()?,
+                host: m.get("hostID")?.as_u64()?,
+                size: m.get("size")?.as_u64()?,
+            })
+        })
+        .collect()
+}
+
+/// The mappings spec.dump was checkpointed with.
+pub fn spec_idmaps(content: &[u8]) -> Result<IdMaps, String> {
+    let data: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse spec.dump: {}", e))?;
+    Ok(IdMaps {
+        uid: maps_from_spec(data.pointer("/linux/uidMappings")),
+        gid: maps_from_spec(data.pointer("/linux/gidMappings")),
+    })
+}
+
+pub fn patch_spec_dump(content: &[u8], new: &IdMaps) -> Result<Vec<u8>, String> {
+    let mut data: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse spec.dump: {}", e))?;
+    let spec_maps = |maps: &[IdMap]| {
+        maps.iter()
+            .map(|m| json!({"containerID": m.container, "hostID": m.host, "size": m.size}))
+            .collect::<Vec<_>>()
+    };
+    let spec = data.as_object_mut().ok_or("spec.dump is not an object")?;
+    let linux = spec.entry("linux").or_insert_with(|| json!({}));
+    if !linux.is_object() {
+        *linux = json!({});
+    }
+    let linux = linux
+        .as_object_mut()
+        .ok_or("spec.dump linux is not an object")?;
+    if new.is_host() {
+        linux.remove("uidMappings");
+        linux.remove("gidMappings");
+    } else {
+        linux.insert("uidMappings".into(), spec_maps(&new.uid).into());
+        linux.insert("gidMappings".into(), spec_maps(&new.gid).into());
+    }
+    let namespaces = linux
+        .entry("namespaces")
+        .or_insert_with(|| json!([]))
+        .as_array_mut()
+        .ok_or("spec.dump linux.namespaces is not a list")?;
+    namespaces.retain(|ns| ns.get("type").and_then(|t| t.as_str()) != Some("user"));
+    if !new.is_host() {
+        namespaces.push(json!({"type": "user"}));
+    }
+    serde_json::to_vec(&data).map_err(|e| format!("serialize spec.dump: {}", e))
+}
+
+pub fn patch_config_dump(content: &[u8], new: &IdMaps) -> Result<Vec<u8>, String> {
+    let mut data: Value =
+        serde_json::from_slice(content).map_err(|e| format!("parse config.dump: {}", e))?;
+    let config_maps = |maps: &[IdMap]| {
+        maps.iter()
+            .map(|m| json!({"container_id": m.container, "host_id": m.host, "size": m.size}))
+            .collect::<Vec<_>>()
+    };
+    if new.is_host() {
+        data.as_object_mut().map(|o| o.remove("idMappings"));
+    } else {
+        data["idMappings"] = json!({
+            "HostUIDMapping": false,
+            "HostGIDMapping": false,
+            "UIDMap": config_maps(&new.uid),
+            "GIDMap": config_maps(&new.gid),
+        });
+    }
+    serde_json::to_vec(&data).map_err(|e| format!("serialize config.dump: {}", e))
+}
+
+/// Rewrite the owners in rootfs-diff.tar from `old` to `new` host IDs.
+pub fn remap_rootfs_diff(content: &[u8], old: &IdMaps, new: &IdMaps) -> Result<Vec<u8>, String> {
+    let mut archive = tar::Archive::new(content);
+    let mut builder = tar::Builder::new(Vec::with_capacity(content.len()));
+    let mut unmapped = 0u32;
+    let mut translate = |old: &[IdMap], new: &[IdMap], id: u64| match to_container(old, id)
+        .and_then(|c| to_host(new, c))
+    {
+        Some(id) => id,
+        None => {
+            unmapped += 1;
+            OVERFLOW_ID
+        }
+    };
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let path = entry.path().map_err(|e| e.to_string())?.into_owned();
+        let link = entry
+            .link_name()
+            .map_err(|e| e.to_string())?
+            .map(|l| l.into_owned());
+        // Keep xattrs and the like; ownership, names and size are set anew
+        let pax: Vec<(String, Vec<u8>)> = match entry.pax_extensions().map_err(|e| e.to_string())? {
+            Some(exts) => exts
+                .filter_map(|e| e.ok())
+                .filter_map(|e| Some((e.key().ok()?.to_string(), e.value_bytes().to_vec())))
+                .filter(|(k, _)| {
+                    !matches!(
+                        k.as_str(),
+                        "path" | "linkpath" | "size" | "uid" | "gid" | "uname" | "gname"
+                    )
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let mut header = entry.header().clone();
+        let uid = header.uid().map_err(|e| e.to_string())?;
+        let gid = header.gid().map_err(|e| e.to_string())?;
+        header.set_uid(translate(&old.uid, &new.uid, uid));
+        header.set_gid(translate(&old.gid, &new.gid, gid));
+        if !pax.is_empty() {
+            builder
+                .append_pax_extensions(pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())))
+                .map_err(|e| e.to_string())?;
+        }
+        let result = match link {
+            Some(link) => builder.append_link(&mut header, &path, &link),
+            None => {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).map_err(|e| e.to_string())?;
+                builder.append_data(&mut header, &path, data.as_slice())
+            }
+        };
+        result.map_err(|e| format!("{} {}: {}", ROOTFS_DIFF_PATH, path.display(), e))?;
+    }
+    if unmapped > 0 {
+        eprintln!(
+            "Warning: {} owner IDs in {} are outside the new mapping; set to {}",
+            unmapped, ROOTFS_DIFF_PATH, OVERFLOW_ID
+        );
+    }
+    builder.into_inner().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(s: &str) -> IdMap {
+        parse_idmap(s).unwrap()
+    }
+
+    #[test]
+    fn parses_idmaps() {
+        let m = map("0:100000:65536");
+        assert_eq!((m.container, m.host, m.size), (0, 100000, 65536));
+        for s in ["0:100000", "0:100000:0", "0:x:1", "0:1:2:3"] {
+            assert!(parse_idmap(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn translates_through_the_ranges() {
+        // Rootless: root is the user, the rest come from /etc/subuid
+        let rootless = [map("0:1000:1"), map("1:100000:65536")];
+        assert_eq!(to_container(&rootless, 1000), Some(0));
+        assert_eq!(to_container(&rootless, 100000), Some(1));
+        assert_eq!(to_container(&rootless, 100032), Some(33));
+        assert_eq!(to_container(&rootless, 165536), None);
+        assert_eq!(to_host(&rootless, 0), Some(1000));
+        assert_eq!(to_host(&rootless, 65536), Some(165535));
+        assert_eq!(to_host(&rootless, 65537), None);
+        // No user namespace: IDs are host IDs
+        assert_eq!(to_container(&[], 1000), Some(1000));
+        assert_eq!(to_host(&[], 33), Some(33));
+    }
+
+    #[test]
+    fn rewrites_spec_dump_mappings() {
+        let spec = br#"{"linux": {"uidMappings": [{"containerID": 0, "hostID": 1000, "size": 1}],
+            "namespaces": [{"type": "pid"}, {"type": "user"}]}}"#;
+        let old = spec_idmaps(spec).unwrap();
+        assert_eq!((old.uid.len(), old.gid.len()), (1, 0));
+        assert_eq!(old.uid[0].host, 1000);
+
+        let out = patch_spec_dump(spec, &IdMaps::default()).unwrap();
+        let out: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(out["linux"], json!({"namespaces": [{"type": "pid"}]}));
+
+        let new = IdMaps {
+            uid: vec![map("0:200000:65536")],
+            gid: vec![map("0:300000:65536")],
+        };
+        let out = patch_spec_dump(br#"{"process": {}}"#, &new).unwrap();
+        let out: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            out["linux"],
+            json!({
+                "uidMappings": [{"containerID": 0, "hostID": 200000, "size": 65536}],
+                "gidMappings": [{"containerID": 0, "hostID": 300000, "size": 65536}],
+                "namespaces": [{"type": "user"}],
+            })
+        );
+        assert!(patch_spec_dump(b"[]", &new).is_err());
+    }
+
+    #[test]
+    fn rewrites_config_dump_mappings() {
+        let new = IdMaps {
+            uid: vec![map("0:200000:65536")],
+            gid: vec![],
+        };
+        let out = patch_config_dump(b"{}", &new).unwrap();
+        let out: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            out["idMappings"]["UIDMap"],
+            json!([{"container_id": 0, "host_id": 200000, "size": 65536}])
+        );
+        let out =
+            patch_config_dump(&serde_json::to_vec(&out).unwrap(), &IdMaps::default()).unwrap();
+        let out: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(out, json!({}));
+    }
+
+    #[test]
+    fn remaps_rootfs_diff_owners() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, uid) in [
+            ("etc/owned", 100032u64),
+            ("etc/root", 1000),
+            ("etc/stray", 5),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_uid(uid);
+            header.set_gid(uid);
+            header.set_cksum();
+            builder.append_data(&mut header, path, &[][..]).unwrap();
+        }
+        let diff = builder.into_inner().unwrap();
+        let old = IdMaps {
+            uid: vec![map("0:1000:1"), map("1:100000:65536")],
+            gid: vec![map("0:1000:1"), map("1:100000:65536")],
+        };
+        // Rootful: container IDs become host IDs
+        let out = remap_rootfs_diff(&diff, &old, &IdMaps::default()).unwrap();
+        let owners: Vec<(u64, u64)> = tar::Archive::new(out.as_slice())
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let header = e.unwrap().header().clone();
+                (header.uid().unwrap(), header.gid().unwrap())
+            })
+            .collect();
+        assert_eq!(owners, [(33, 33), (0, 0), (OVERFLOW_ID, OVERFLOW_ID)]);
+    }
+}
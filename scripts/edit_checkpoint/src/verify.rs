@@ -0,0 +1,235 @@
+//! `verify-restore`: check a restored container against the edited checkpoint.
+//!
+//! Compares `podman container inspect` of the restored container (locally or
+//! on --host over SSH) with what the checkpoint says it should be: running,
+//! IPv4 address, MAC address (unless restored with --ignore-static-mac),
+//! hostname and port bindings. Prints one line per check and writes a
+//! pass/fail JSON report with --report; exits non-zero if any check fails.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use serde_json::Value;
+
+use crate::metadata::{self, MetadataFiles};
+use crate::remote;
+use crate::report::Report;
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    /// Restored container name or ID
+    container: String,
+    /// The edited checkpoint archive or unpacked directory it was restored from
+    #[arg(long, value_name = "PATH")]
+    checkpoint: String,
+    /// Inspect the container on this host over SSH instead of locally
+    #[arg(long, value_name = "USER@HOST")]
+    host: Option<String>,
+    /// Expect this address instead of the one in the checkpoint
+    #[arg(long, value_name = "ADDR")]
+    expect_addr: Option<String>,
+    /// Skip the MAC check (restored with --ignore-static-mac)
+    #[arg(long)]
+    ignore_mac: bool,
+    /// Write a JSON report of the checks to FILE
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+}
+
+/// What the checkpoint says the restored container should look like.
+#[derive(Default)]
+struct Expected {
+    addr: Option<String>,
+    mac: Option<String>,
+    hostname: Option<String>,
+    ports: Option<BTreeSet<String>>,
+}
+
+pub fn run(args: &VerifyArgs, report: &mut Report) -> Result<(), String> {
+    report.set("container", args.container.as_str());
+    report.set("checkpoint", args.checkpoint.as_str());
+    let files = MetadataFiles::read(Path::new(&args.checkpoint))?;
+    let mut expected = expected(&files);
+    if let Some(addr) = &args.expect_addr {
+        expected.addr = Some(addr.clone());
+    }
+    if args.ignore_mac {
+        expected.mac = None;
+    }
+
+    let argv = remote::argv(
+        "EDIT_CHECKPOINT_PODMAN",
+        "podman",
+        &["container", "inspect", &args.container],
+    );
+    let out = remote::command(args.host.as_deref(), &argv)
+        .output()
+        .map_err(|e| format!("run {}: {}", argv.join(" "), e))?;
+    if !out.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            argv.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    let inspect: Value = serde_json::from_slice(&out.stdout)
+        .map_err(|e| format!("parse podman container inspect: {}", e))?;
+    let actual = inspect
+        .get(0)
+        .ok_or_else(|| format!("{} not found", args.container))?;
+
+    let networks: Vec<&Value> = actual
+        .pointer("/NetworkSettings/Networks")
+        .and_then(|n| n.as_object())
+        .map(|n| n.values().collect())
+        .unwrap_or_default();
+    let addrs: Vec<String> = networks
+        .iter()
+        .filter_map(|n| n.get("IPAddress").and_then(|a| a.as_str()))
+        .filter(|a| !a.is_empty())
+        .map(str::to_string)
+        .collect();
+    let macs: Vec<String> = networks
+        .iter()
+        .filter_map(|n| n.get("MacAddress").and_then(|a| a.as_str()))
+        .filter(|a| !a.is_empty())
+        .map(|a| a.to_lowercase())
+        .collect();
+    let running = actual
+        .pointer("/State/Running")
+        .and_then(|r| r.as_bool())
+        .unwrap_or(false);
+
+    let mut checks = Vec::new();
+    let mut check = |name: &str, expected: Value, actual: Value, pass: bool| {
+        eprintln!(
+            "{} {:<8} expected {}, got {}",
+            if pass { "PASS" } else { "FAIL" },
+            name,
+            expected,
+            actual
+        );
+        checks.push(serde_json::json!({
+            "check": name,
+            "expected": expected,
+            "actual": actual,
+            "pass": pass,
+        }));
+    };
+    check("running", true.into(), running.into(), running);
+    if let Some(addr) = &expected.addr {
+        check(
+            "addr",
+            addr.as_str().into(),
+            addrs.clone().into(),
+            addrs.contains(addr),
+        );
+    }
+    if let Some(mac) = &expected.mac {
+        check(
+            "mac",
+            mac.as_str().into(),
+            macs.clone().into(),
+            macs.contains(mac),
+        );
+    }
+    if let Some(hostname) = &expected.hostname {
+        let got = actual
+            .pointer("/Config/Hostname")
+            .and_then(|h| h.as_str())
+            .unwrap_or_default();
+        check(
+            "hostname",
+            hostname.as_str().into(),
+            got.into(),
+            got == hostname,
+        );
+    }
+    if let Some(ports) = &expected.ports {
+        let got = inspect_ports(actual);
+        check(
+            "ports",
+            ports.iter().map(String::as_str).collect::<Vec<_>>().into(),
+            got.iter().map(String::as_str).collect::<Vec<_>>().into(),
+            &got == ports,
+        );
+    }
+
+    let failed = checks.iter().filter(|c| c["pass"] == false).count();
+    report.set("checks", checks);
+    report.set("verdict", if failed == 0 { "pass" } else { "fail" });
+    if failed > 0 {
+        return Err(format!("{} check(s) failed", failed));
+    }
+    Ok(())
+}
+
+fn expected(files: &MetadataFiles) -> Expected {
+    let [status, config, spec] = files.parsed();
+    Expected {
+        addr: files.addr().map(|(addr, _)| addr),
+        mac: status.as_ref().and_then(metadata::network_status_mac),
+        hostname: spec
+            .as_ref()
+            .and_then(|s| s.get("hostname"))
+            .and_then(|h| h.as_str())
+            .filter(|h| !h.is_empty())
+            .map(str::to_string),
+        ports: config.as_ref().and_then(config_ports),
+    }
+}
+
+/// Port bindings in config.dump as "CONTAINER/PROTO->HOST_IP:HOST" strings.
+/// Podman 4 writes newPortMappings, older versions portMappings.
+fn config_ports(config: &Value) -> Option<BTreeSet<String>> {
+    let (mappings, new) = match config.get("newPortMappings").and_then(|m| m.as_array()) {
+        Some(m) => (m, true),
+        None => (config.get("portMappings")?.as_array()?, false),
+    };
+    let mut ports = BTreeSet::new();
+    for m in mappings {
+        let field = |k_new: &str, k_old: &str| m.get(if new { k_new } else { k_old });
+        let container = field("container_port", "containerPort").and_then(|p| p.as_u64())?;
+        let host = field("host_port", "hostPort").and_then(|p| p.as_u64())?;
+        let host_ip = field("host_ip", "hostIP")
+            .and_then(|p| p.as_str())
+            .unwrap_or("");
+        let range = m.get("range").and_then(|r| r.as_u64()).unwrap_or(1).max(1);
+        let protocols = field("protocol", "protocol")
+            .and_then(|p| p.as_str())
+            .unwrap_or("tcp");
+        for proto in protocols.split(',') {
+            for i in 0..range {
+                ports.insert(binding(
+                    &format!("{}/{}", container + i, proto),
+                    host_ip,
+                    &(host + i).to_string(),
+                ));
+            }
+        }
+    }
+    Some(ports)
+}
+
+/// Port bindings of the inspected container in the same form as config_ports.
+fn inspect_ports(actual: &Value) -> BTreeSet<String> {
+    let mut ports = BTreeSet::new();
+    let bindings = actual
+        .pointer("/NetworkSettings/Ports")
+        .and_then(|p| p.as_object());
+    for (port, hosts) in bindings.into_iter().flatten() {
+        for host in hosts.as_array().into_iter().flatten() {
+            let host_ip = host.get("HostIp").and_then(|h| h.as_str()).unwrap_or("");
+            let host_port = host.get("HostPort").and_then(|h| h.as_str()).unwrap_or("");
+            ports.insert(binding(port, host_ip, host_port));
+        }
+    }
+    ports
+}
+
+fn binding(port: &str, host_ip: &str, host_port: &str) -> String {
+    // The unspecified address shows up as either "" or 0.0.0.0
+    let host_ip = if host_ip == "0.0.0.0" { "" } else { host_ip };
+    format!("{}->{}:{}", port, host_ip, host_port)
+}
@@ -0,0 +1,273 @@
+//! `watch`: edit checkpoint archives as they are dropped into a spool
+//! directory.
+//!
+//! Waits on inotify for *.tar files closed after writing or moved into the
+//! inbox, edits each with the first rule whose pattern matches its file name,
+//! and moves it to the outbox with a `NAME.report.json` next to it. Archives
+//! that match no rule or fail to edit go to the failed directory. Archives
+//! already in the inbox at startup are processed first.
+//!
+//! The rules file is a JSON array, e.g.
+//!
+//! ```json
+//! [
+//!   {"match": "web-*.tar", "new_addr": "192.168.12.9", "dns_server": ["10.0.0.53"]},
+//!   {"match": "*.tar", "ipam_network": "switch-net", "ipam_host": "node2"}
+//! ]
+//! ```
+//!
+//! Each rule sets exactly one of new_addr, ipam_network or clear_static_ip,
+//! and optionally old_addr, ipam_host, add_addr, dns_server, dns_search,
+//! alias and criu_opt with the meaning of the command-line options of the
+//! same name.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use inotify::{Inotify, WatchMask};
+use serde_json::Value;
+
+use crate::edit::EditOptions;
+use crate::report::Report;
+use crate::{ipam, trace, NewAddr};
+
+#[derive(Args)]
+pub struct WatchArgs {
+    /// Directory the source node drops checkpoint archives into
+    #[arg(value_name = "INBOX")]
+    inbox: PathBuf,
+    /// Directory edited archives and their reports are moved to
+    #[arg(value_name = "OUTBOX")]
+    outbox: PathBuf,
+    /// JSON rules file (see `watch` in the module docs)
+    #[arg(long, value_name = "FILE")]
+    rules: PathBuf,
+    /// Directory for archives that could not be edited (default: INBOX/failed)
+    #[arg(long, value_name = "DIR")]
+    failed: Option<PathBuf>,
+}
+
+/// One entry of the rules file.
+struct Rule {
+    pattern: String,
+    old_addr: Option<String>,
+    new_addr: Option<String>,
+    ipam_network: Option<String>,
+    ipam_host: Option<String>,
+    clear_static_ip: bool,
+    opts: EditOptions,
+}
+
+impl Rule {
+    fn parse(value: &Value) -> Result<Rule, String> {
+        let string = |key: &str| -> Result<Option<String>, String> {
+            match value.get(key) {
+                None | Some(Value::Null) => Ok(None),
+                Some(Value::String(s)) => Ok(Some(s.clone())),
+                Some(_) => Err(format!("{} must be a string", key)),
+            }
+        };
+        let list = |key: &str| -> Result<Option<Vec<String>>, String> {
+            match value.get(key) {
+                None | Some(Value::Null) => Ok(None),
+                Some(Value::Array(items)) => items
+                    .iter()
+                    .map(|i| {
+                        i.as_str()
+                            .map(str::to_string)
+                            .ok_or_else(|| format!("{} must be a list of strings", key))
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(Some),
+                Some(_) => Err(format!("{} must be a list of strings", key)),
+            }
+        };
+        let flag = |key: &str| value.get(key).and_then(Value::as_bool).unwrap_or(false);
+
+        let pattern = string("match")?.ok_or("match is required")?;
+        let rule = Rule {
+            old_addr: string("old_addr")?,
+            new_addr: string("new_addr")?,
+            ipam_network: string("ipam_network")?,
+            ipam_host: string("ipam_host")?,
+            clear_static_ip: flag("clear_static_ip"),
+            opts: EditOptions {
+                secondary: flag("add_addr"),
+                dns_servers: list("dns_server")?,
+                dns_search: list("dns_search")?,
+                aliases: list("alias")?,
+                criu_opts: list("criu_opt")?,
+                ..Default::default()
+            },
+            pattern,
+        };
+        let sources = [
+            rule.new_addr.is_some(),
+            rule.ipam_network.is_some(),
+            rule.clear_static_ip,
+        ];
+        if sources.iter().filter(|s| **s).count() != 1 {
+            return Err(format!(
+                "rule {}: set exactly one of new_addr, ipam_network, clear_static_ip",
+                rule.pattern
+            ));
+        }
+        if rule.clear_static_ip && rule.opts.secondary {
+            return Err(format!(
+                "rule {}: add_addr conflicts with clear_static_ip",
+                rule.pattern
+            ));
+        }
+        Ok(rule)
+    }
+
+    fn new_addr(&self) -> NewAddr<'_> {
+        match (&self.new_addr, &self.ipam_network) {
+            (Some(addr), _) => NewAddr::Fixed(addr),
+            (None, Some(network)) => NewAddr::Ipam(ipam::IpamQuery {
+                network,
+                host: self.ipam_host.as_deref(),
+            }),
+            (None, None) => NewAddr::Clear,
+        }
+    }
+}
+
+fn load_rules(path: &Path) -> Result<Vec<Rule>, String> {
+    let content = fs::read(path).map_err(|e| format!("read rules {}: {}", path.display(), e))?;
+    let rules: Value = serde_json::from_slice(&content)
+        .map_err(|e| format!("parse rules {}: {}", path.display(), e))?;
+    rules
+        .as_array()
+        .ok_or_else(|| format!("{}: expected a JSON array of rules", path.display()))?
+        .iter()
+        .map(Rule::parse)
+        .collect()
+}
+
+pub fn run(args: &WatchArgs) -> Result<(), String> {
+    let rules = load_rules(&args.rules)?;
+    let failed = args
+        .failed
+        .clone()
+        .unwrap_or_else(|| args.inbox.join("failed"));
+    for dir in [&args.outbox, &failed] {
+        fs::create_dir_all(dir).map_err(|e| format!("create {}: {}", dir.display(), e))?;
+    }
+
+    // Watch before listing so nothing dropped in between is missed
+    let mut inotify = Inotify::init().map_err(|e| format!("inotify: {}", e))?;
+    inotify
+        .watches()
+        .add(&args.inbox, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+        .map_err(|e| format!("watch {}: {}", args.inbox.display(), e))?;
+    eprintln!(
+        "Watching {} ({} rules) → {}",
+        args.inbox.display(),
+        rules.len(),
+        args.outbox.display()
+    );
+
+    let mut pending: Vec<PathBuf> = fs::read_dir(&args.inbox)
+        .map_err(|e| format!("read {}: {}", args.inbox.display(), e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    pending.sort();
+    for path in pending {
+        process(args, &rules, &failed, &path);
+    }
+
+    let mut buffer = [0u8; 4096];
+    loop {
+        let events = inotify
+            .read_events_blocking(&mut buffer)
+            .map_err(|e| format!("inotify: {}", e))?;
+        let names: Vec<PathBuf> = events
+            .filter_map(|e| e.name.map(|n| args.inbox.join(n)))
+            .collect();
+        for path in names {
+            process(args, &rules, &failed, &path);
+        }
+    }
+}
+
+/// Edit one archive from the inbox and move it out. Failures are logged and
+/// the archive is moved to `failed`; they never stop the watch.
+fn process(args: &WatchArgs, rules: &[Rule], failed: &Path, path: &Path) {
+    let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+        return;
+    };
+    if !name.ends_with(".tar") || name.starts_with('.') || !path.is_file() {
+        return;
+    }
+    let mut root = trace::span("watch edit");
+    root.attr("checkpoint", name);
+    let mut report = Report::default();
+    report.set("checkpoint", name);
+
+    let result = match rules.iter().find(|r| glob_match(&r.pattern, name)) {
+        Some(rule) => {
+            eprintln!("{}: rule {}", name, rule.pattern);
+            report.set("rule", rule.pattern.as_str());
+            let tar_path = path.to_string_lossy();
+            crate::run(
+                &tar_path,
+                rule.old_addr.as_deref(),
+                rule.new_addr(),
+                &rule.opts,
+                &mut report,
+            )
+        }
+        None => Err("no rule matches".to_string()),
+    };
+    report.set("status", if result.is_ok() { "ok" } else { "error" });
+    if let Err(e) = &result {
+        root.fail(e);
+        report.set("error", e.as_str());
+    }
+    drop(root);
+    trace::flush();
+
+    let dest = if result.is_ok() { &args.outbox } else { failed };
+    let stem = name.trim_end_matches(".tar");
+    let moved = fs::rename(path, dest.join(name))
+        .map_err(|e| format!("move to {}: {}", dest.display(), e))
+        .and_then(|_| report.write(&dest.join(format!("{}.report.json", stem))));
+    match (&result, moved) {
+        (Ok(()), Ok(())) => eprintln!("{} → {}", name, args.outbox.display()),
+        (Err(e), Ok(())) => eprintln!("{}: {} (moved to {})", name, e, failed.display()),
+        (_, Err(e)) => eprintln!("{}: {}", name, e),
+    }
+}
+
+/// Shell-style match of `name` against `pattern` with `*` and `?`.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (p, n): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut pi, mut ni) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        match p.get(pi) {
+            Some('*') => {
+                backtrack = Some((pi, ni));
+                pi += 1;
+            }
+            Some(c) if *c == '?' || *c == n[ni] => {
+                pi += 1;
+                ni += 1;
+            }
+            _ => match backtrack {
+                // Let the last * absorb one more character
+                Some((star, matched)) => {
+                    pi = star + 1;
+                    ni = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}